@@ -1,6 +1,6 @@
 from .regex import create_fsm_index_end_to_end
 
-from faster_outlines.lib import TokenVocabulary, Write, Generate
+from faster_outlines.lib import TokenVocabulary, Write, Generate, Stop
 
 
 from .guide import RegexGuide
@@ -11,6 +11,7 @@ __all__ = [
     "create_fsm_index_end_to_end",
     "Generate",
     "Write",
+    "Stop",
     "RegexGuide",
     "LazyVLLMRegexGuide"
 ]
@@ -1,4 +1,5 @@
-from typing import Dict, List, Optional, Set
+from typing import Callable, Dict, List, Optional, Set, Tuple
+import numpy
 
 class Write:
     """Write instruction for direct token sequences.
@@ -16,9 +17,37 @@ class Generate:
     Attributes:
         tokens (Optional[List[int]]): The tokens that lead to a valid completion
             if generated. A value of None indicates that all tokens are allowed.
+        priorities (Optional[List[float]]): Per token priorities, parallel to `tokens`,
+            if requested via `get_next_instruction_with_priorities`. Lower is closer
+            to completing the pattern.
+        terminal_choices (bool): True when every allowed token completes the
+            pattern, so generation ends after whichever token is chosen.
     """
 
     tokens: Optional[List[int]]
+    priorities: Optional[List[float]]
+    terminal_choices: bool
+
+class Stop:
+    """Stop instruction, given where the pattern is complete and nothing may follow.
+
+    Only returned by indexes created with `emit_stop=True`, which give it instead
+    of `Write([eos_token_id])`, so generation can end without appending EOS.
+    """
+
+    ...
+
+class StateOutOfBoundsError(ValueError):
+    """Raised when a state does not exist in the FSM."""
+    ...
+
+class StateNotComputedError(ValueError):
+    """Raised when a state is not reachable, so it is never computed."""
+    ...
+
+class ComputeCancelledError(ValueError):
+    """Raised when computing the index failed before finishing."""
+    ...
 
 class TokenVocabulary:
     """TokenVocabulary manages a vocabulary of tokens, with serialization support.
@@ -28,15 +57,50 @@ class TokenVocabulary:
     """
 
     def __init__(
-        self, vocab_dict: Dict[str, int], eos_token_id: int, special_tokens: Set[str]
+        self,
+        vocab_dict: Dict[str, int],
+        eos_token_id: int,
+        special_tokens: Set[str],
+        from_sentencepiece: Optional[bool] = True,
     ) -> None:
         """Initialize the TokenVocabulary.
 
         Args:
-            vocab_dict (Dict[str, List[int]]): Dictionary mapping token strings
-                to their corresponding list of integer values.
+            vocab_dict (Dict[str, int]): Dictionary mapping token strings to
+                their integer IDs. Tokens keep the dictionary's insertion order.
             eos_token_id (int): The end-of-sequence token identifier.
             special_tokens (Set[str]): Set of tokens to exclude from processing.
+            from_sentencepiece (Optional[bool]): Whether tokens use sentencepiece's
+                "▁" for spaces (Llama) rather than byte-level "Ġ" (GPT-2).
+                Only sentencepiece vocabularies read "<0xXX>" tokens as bytes.
+                Defaults to True, so byte-level vocabularies need False, or
+                None to detect the tokenizer type from the vocabulary.
+        """
+        ...
+
+    @staticmethod
+    def from_pairs(
+        pairs: List[Tuple[str, int]],
+        eos_token_id: int,
+        special_tokens: Optional[Set[str]] = None,
+        from_sentencepiece: Optional[bool] = True,
+    ) -> "TokenVocabulary":
+        """Build a TokenVocabulary from (token, id) pairs, without building a dict.
+
+        Equivalent to the constructor, tokens keep the order of `pairs`.
+
+        Args:
+            pairs (List[Tuple[str, int]]): Token strings and their integer IDs,
+                e.g. a tokenizer's vocabulary in ID order.
+            eos_token_id (int): The end-of-sequence token identifier.
+            special_tokens (Optional[Set[str]]): Set of tokens to exclude from processing.
+            from_sentencepiece (Optional[bool]): Same as for the constructor.
+
+        Returns:
+            TokenVocabulary: The new vocabulary.
+
+        Raises:
+            ValueError: If `pairs` is empty.
         """
         ...
 
@@ -88,15 +152,188 @@ class TokenVocabulary:
 def create_fsm_index_end_to_end_rs(
     fsm_info: FSMInfo,
     vocabulary: TokenVocabulary,
+    state_callback: Optional[Callable[[int], None]] = None,
+    reserved_token_ids: Optional[Set[int]] = None,
+    lazy: bool = False,
+    max_token_len: Optional[int] = None,
+    byte_alphabet: bool = False,
+    emit_stop: bool = False,
+    vocab_order: bool = False,
 ) -> "LazyFSMIndex":
     """Create a LazyFSMIndex instance.
 
     Args:
         fsm_info: FSM definition and configuration.
         vocabulary: Token vocabulary for the FSM.
+        state_callback: Called with each state ID as it finishes computing.
+            Runs on the compute thread and acquires the GIL per call, which
+            can slow down computation of large FSMs.
+        reserved_token_ids: Token IDs never offered at any state, e.g. unused
+            reserved slots. Unlike `special_tokens`, these are matched by ID.
+        lazy: Compute nothing in the background, each state is computed by the
+            first lookup which needs it instead. Saves work for short generations
+            over large FSMs, at the cost of a scan on each newly visited state.
+        max_token_len: Skip tokens longer than this many characters while
+            scanning, which speeds up vocabularies with long merged tokens.
+            Only safe for bounded patterns which can never match that many
+            characters, e.g. `[0-9]{1,4}`. With unbounded patterns like `.*`
+            it drops tokens which would have matched.
+        byte_alphabet: The FSM's transition keys are the 256 byte values, e.g.
+            an FSM built over UTF-8 bytes for a byte-level BPE tokenizer. Tokens
+            are then walked as the raw bytes they stand for, and the FSM's
+            `alphabet_symbol_mapping` is not used.
+        emit_stop: Return `Stop` instead of `Write([eos_token_id])` where the
+            pattern is complete and nothing may follow. States with no allowed
+            token before the pattern is complete still write EOS.
+        vocab_order: List allowed tokens in the order they were given to
+            `vocabulary`, i.e. the insertion order of its dict or the order of
+            its pairs, then tokens added by `add_token`, instead of by ID. For
+            samplers which tie-break by vocabulary order. Keeps a rank per token
+            ID alongside the index. IDs not in `vocabulary` come last.
 
     Returns:
         LazyFSMIndex: New FSM index instance.
+
+    Raises:
+        RuntimeError: If a transition targets a state past the last state
+            with transitions plus one, e.g. from off-by-one state numbering.
+    """
+    ...
+
+class RegexGuide:
+    """Adapter giving a LazyFSMIndex the method surface of outlines' `RegexGuide`."""
+
+    def __init__(self, index: "LazyFSMIndex") -> None: ...
+
+    @property
+    def initial_state(self) -> int:
+        """The FSM's start state."""
+        ...
+
+    @property
+    def eos_token_id(self) -> int: ...
+
+    @property
+    def index(self) -> "LazyFSMIndex":
+        """The wrapped index."""
+        ...
+
+    def get_next_instruction(self, state: int) -> "Write | Generate | Stop":
+        """Get next instruction, same as `LazyFSMIndex.get_next_instruction`."""
+        ...
+
+    def get_next_state(self, state: int, token_id: int) -> int:
+        """Get next state, -1 once generation has to stop."""
+        ...
+
+    def is_final_state(self, state: int) -> bool:
+        """Whether the pattern may end at `state`. -1 is always final."""
+        ...
+
+def initial_instruction(fsm_info: FSMInfo, vocabulary: TokenVocabulary) -> "Write | Generate":
+    """Compute the instruction for the first token only, without building an index.
+
+    Runs synchronously and computes just the initial state, for callers which
+    only need to constrain the very first token.
+
+    Args:
+        fsm_info: FSM definition and configuration.
+        vocabulary: Token vocabulary for the FSM.
+
+    Returns:
+        Union[Write, Generate]: Same as `get_next_instruction(0)` on a full index.
+    """
+    ...
+
+def estimate_complexity(fsm_info: FSMInfo, vocabulary: TokenVocabulary) -> Dict[str, int]:
+    """Gauge how large an index would get, without computing it.
+
+    Only inspects the FSM's transitions and alphabet, so it is cheap enough for a
+    gateway to reject pattern bombs before building them.
+
+    Args:
+        fsm_info: FSM definition and configuration.
+        vocabulary: Token vocabulary for the FSM, only its size is used.
+
+    Returns:
+        Dict[str, int]: `num_states`, `num_reachable`, `has_wildcard` (a bool, True if
+            a reachable state accepts any character) and `estimated_max_transitions`,
+            a rough count of the index's (token, next state) entries.
+    """
+    ...
+
+def effective_num_threads() -> int:
+    """Get the number of threads computing the states of one index.
+
+    Resolved once from `FASTER_OUTLINES_COMPUTE_THREADS`, 1 if unset or invalid.
+
+    Returns:
+        int: Threads per index computation.
+    """
+    ...
+
+def get_fsm_cache_key(
+    pattern: str,
+    vocabulary: TokenVocabulary,
+    reserved_token_ids: Optional[Set[int]] = None,
+    max_token_len: Optional[int] = None,
+    byte_alphabet: bool = False,
+    eof_required_finals: Optional[List[int]] = None,
+) -> int:
+    """Compute the cache key of an index without building it.
+
+    Matches the key `create_fsm_index_end_to_end_rs` uses with the same options,
+    so a gateway can check `is_cached` and route to a warm worker. Indexes built
+    with `FASTER_OUTLINES_CANONICALIZE_FSM` are keyed by FSM structure instead.
+
+    Args:
+        pattern: The pattern the FSM was built from.
+        vocabulary: Token vocabulary the index would be built with.
+        reserved_token_ids: Same as for `create_fsm_index_end_to_end_rs`.
+        max_token_len: Same as for `create_fsm_index_end_to_end_rs`.
+        byte_alphabet: Same as for `create_fsm_index_end_to_end_rs`.
+        eof_required_finals: `FSMInfo.eof_required_finals` of the FSM, which
+            are keyed along with the pattern.
+
+    Returns:
+        int: The cache key hash.
+    """
+    ...
+
+def is_cached(hash: int) -> bool:
+    """Check whether an FSM is cached, without counting as a use of the entry.
+
+    Args:
+        hash: Cache key, e.g. from `get_fsm_cache_key`.
+
+    Returns:
+        bool: True if the cache holds an entry for `hash`.
+    """
+    ...
+
+def get_cached_fsm(hash: int) -> Optional[dict]:
+    """Copy out a cache entry, without counting as a use of it.
+
+    Lets external schedulers inspect what a warm worker holds.
+
+    Args:
+        hash: Cache key, e.g. from `get_fsm_cache_key`.
+
+    Returns:
+        Optional[dict]: None if nothing is cached under `hash`. Otherwise a dict
+            with `pattern`, `first_state`, `finals`, `vocab_len`, `eos_token_id`,
+            and `states_to_token_maps` as `{state: {token_id: next_state}}`,
+            holding the states with at least one allowed token.
+    """
+    ...
+
+def list_cached_patterns() -> List[Tuple[int, str]]:
+    """List what is currently in the FSM cache.
+
+    Useful for checking what is actually cached when outputs look stale.
+
+    Returns:
+        List[Tuple[int, str]]: (cache key hash, pattern) for every cached FSM.
     """
     ...
 
@@ -114,24 +351,88 @@ class LazyFSMIndex:
             token_id: Input token ID.
 
         Returns:
-            Optional[int]: Next state ID if valid transition exists.
+            Optional[int]: Next state ID if valid transition exists, -1 once
+                generation has to stop.
+        """
+        ...
+
+    def advance(self, state: int, token_ids: List[int]) -> Optional[int]:
+        """Walk several tokens at once, e.g. to skip past a forced prefix.
+
+        Args:
+            state: Current state ID.
+            token_ids: Token IDs to walk, in order.
+
+        Returns:
+            Optional[int]: Resulting state ID (-1 once the pattern is complete),
+                or None if any token is not allowed.
         """
         ...
 
-    def get_next_instruction(self, state: int) -> "Write | Generate":
+    def get_next_instruction(self, state: int) -> "Write | Generate | Stop":
         """Get next instruction for pattern-guided generation.
 
         Args:
             state: Current state ID.
 
         Returns:
-            Union[Write, Generate]: Next instruction for generation.
+            Union[Write, Generate, Stop]: Next instruction for generation. At a final
+                state which tokens can still continue from, EOS is offered
+                alongside those tokens. `Stop` only if created with `emit_stop`.
+        """
+        ...
+
+    def get_next_instruction_with_priorities(self, state: int) -> "Write | Generate | Stop":
+        """Get next instruction, with a priority per allowed token.
+
+        A token's priority is the number of transitions from the state it leads
+        to, to the nearest final state, or infinity if none can be reached.
+
+        Args:
+            state: Current state ID.
+
+        Returns:
+            Union[Write, Generate, Stop]: Next instruction, `Generate.priorities` filled in.
+        """
+        ...
+
+    def get_next_instruction_timeout(self, state: int, timeout_ms: int) -> "Optional[Write | Generate | Stop]":
+        """Get next instruction, giving up if the state is not computed in time.
+
+        Useful under a per-step latency budget, falling back to unconstrained
+        generation instead of blocking on a slow state.
+
+        Args:
+            state: Current state ID.
+            timeout_ms: Longest time to wait for the state, in milliseconds.
+
+        Returns:
+            Optional[Union[Write, Generate, Stop]]: Same as `get_next_instruction`, or
+                None if the state was not ready within `timeout_ms`.
+        """
+        ...
+
+    def get_next_instruction_with_forced_tokens(self, state: int) -> "Write | Generate | Stop":
+        """Get next instruction, writing runs of forced tokens in one step.
+
+        While the current state is not final and allows exactly one token, that
+        token is forced. The whole run up to the next branch point is returned as
+        a single `Write`, so it costs no decode steps. Move past it with `advance`.
+
+        Args:
+            state: Current state ID.
+
+        Returns:
+            Union[Write, Generate, Stop]: `Write` of the forced tokens, or the same
+                instruction as `get_next_instruction` if `state` is not forced.
         """
         ...
 
     def collect_finished_states(self) -> Dict[int, Dict[int, int]]:
         """Collect newly computed state transitions.
 
+        Every state is returned exactly once across calls.
+
         Returns:
             Dict[int, Dict[int, int]]: Map of state ID to transitions.
 
@@ -140,19 +441,281 @@ class LazyFSMIndex:
         """
         ...
 
+    def collect_finished_states_ordered(self) -> List[Tuple[int, Dict[int, int]]]:
+        """Collect newly computed state transitions in completion order.
+
+        Returns:
+            List[Tuple[int, Dict[int, int]]]: (state ID, transitions) pairs, in
+                the order the states finished computing.
+
+        Raises:
+            ValueError: If collection fails.
+        """
+        ...
+
+    def reset_stream_cursor(self) -> None:
+        """Rewind `collect_finished_states` to the start.
+
+        The next call returns every state finished so far again, e.g. to replay
+        them after a consumer crashed.
+        """
+        ...
+
+    def to_outlines_states_to_token_maps(self) -> Dict[int, Dict[int, int]]:
+        """Export the index in the format of outlines' `RegexGuide.states_to_token_maps`.
+
+        Blocks until computation finishes.
+
+        Returns:
+            Dict[int, Dict[int, int]]: Map of state ID to {token ID: next state},
+                holding only states with at least one allowed token.
+        """
+        ...
+
+    def to_json(self) -> str:
+        """Serialize the whole constraint as JSON, for clients generating outside Python.
+
+        Blocks until computation finishes.
+
+        Returns:
+            str: A JSON object with `initial`, `finals`, `eos` and `states`, where
+                `states` maps each state ID to {token ID: next state}. A state
+                missing from `states` only allows `eos`.
+        """
+        ...
+
+    def write_transitions_jsonl(self, path: str) -> None:
+        """Write the transitions to a file as JSON lines, without building a dict.
+
+        Each line is `{"state": s, "token": t, "next": n}`. States are written
+        breadth-first from the initial state as they are computed, so multi-GB FSMs
+        can be exported out-of-core.
+
+        Args:
+            path: File to create or overwrite.
+
+        Raises:
+            OSError: The file could not be written.
+            ComputeCancelledError: Computation failed before finishing.
+        """
+        ...
+
+    def get_states(self, states: List[int]) -> List[Optional[Dict[int, int]]]:
+        """Get the transition tables of several states in one call.
+
+        Blocks on each state still being computed.
+
+        Args:
+            states: State IDs to look up.
+
+        Returns:
+            List[Optional[Dict[int, int]]]: {token ID: next state} per requested
+                state, in order, or None for states that do not exist.
+        """
+        ...
+
+    def dedup_maps(self) -> int:
+        """Make states with identical token maps share a single map.
+
+        Waits for computation to finish. Saves memory for FSMs with many
+        equivalent states, e.g. ones which were not minimized.
+
+        Returns:
+            int: Number of states reusing the map of an earlier state.
+        """
+        ...
+
+    def patch_vocab(self, added: List[Tuple[str, List[int]]]) -> None:
+        """Add a handful of tokens to a finished index without recomputing it.
+
+        Waits for computation to finish first.
+
+        Args:
+            added: (token string, token IDs) pairs to add.
+
+        Raises:
+            ValueError: If an added token reaches a state that was never
+                computed, in which case the index must be rebuilt.
+        """
+        ...
+
+    @property
+    def eos_token_id(self) -> int:
+        """Get the end-of-sequence token identifier used by this index.
+
+        Returns:
+            int: The EOS token ID.
+        """
+        ...
+
+    def with_eos_token_id(self, eos_token_id: int) -> "LazyFSMIndex":
+        """Return this index with a different EOS token, sharing the computed maps.
+
+        Useful to reuse a cached FSM with a tokenizer whose EOS differs.
+
+        Args:
+            eos_token_id: EOS token ID for the returned index.
+
+        Returns:
+            LazyFSMIndex: Index identical to this one except for its EOS token.
+        """
+        ...
+
+    def extend_pattern(self, suffix_fsm: FSMInfo, vocabulary: TokenVocabulary) -> "LazyFSMIndex":
+        """Build the index of this pattern followed by `suffix_fsm`, e.g. for chained generation.
+
+        Waits for this index to finish, then reuses its token maps for the states
+        the suffix cannot affect instead of recomputing them.
+
+        Args:
+            suffix_fsm: FSM of the pattern to append.
+            vocabulary: The vocabulary this index was built from.
+
+        Returns:
+            LazyFSMIndex: Index of the concatenated pattern.
+
+        Raises:
+            ValueError: If this pattern has EOF required finals, so nothing can follow it.
+        """
+        ...
+
+    def transition_key_for_char(self, c: str) -> int:
+        """Get the FSM transition key a character maps to (debug utility).
+
+        Args:
+            c: A single character.
+
+        Returns:
+            int: Transition key, or the pattern's "anything else" key if the
+                character is not part of the pattern's alphabet.
+        """
+        ...
+
     def await_state(self, state_index: int) -> None:
         """Wait for specific state computation to complete.
 
+        On an index created with `lazy=True` the state is computed right away on
+        the calling thread instead, so calling this for the next state before
+        `get_next_instruction` drives computation with no speculative work.
+
         Args:
             state_index: State ID to wait for.
 
         Raises:
-            ValueError: If state index invalid.
+            StateOutOfBoundsError: If the state does not exist in the FSM.
+            StateNotComputedError: If computation finished without reaching the
+                state, since it is unreachable. Waiting again will not help.
+            ComputeCancelledError: If computing the index failed, see `compute_error`.
         """
         ...
 
     def await_finished(self) -> None:
-        """Wait for all state computations to complete."""
+        """Wait for all state computations to complete.
+
+        Raises:
+            RuntimeError: If computing the index failed, see `compute_error`.
+        """
+        ...
+
+    @property
+    def compute_error(self) -> Optional[str]:
+        """Error which ended computation early, if any.
+
+        States left uncomputed behave as dead ends instead of blocking forever.
+
+        Returns:
+            Optional[str]: The panic message of the compute thread, or None.
+        """
+        ...
+
+    def pause(self) -> None:
+        """Halt background computation before its next state, without cancelling it.
+
+        Meant for resource governance, e.g. while a server is under memory pressure.
+        Already computed states stay readable, while awaiting any other state blocks
+        until `resume` is called. Affects every copy of the index.
+        """
+        ...
+
+    def resume(self) -> None:
+        """Continue background computation halted by `pause`."""
+        ...
+
+    @property
+    def is_paused(self) -> bool:
+        """Whether background computation is currently paused.
+
+        Returns:
+            bool: True between `pause` and `resume`.
+        """
+        ...
+
+    def finalize(self) -> None:
+        """Compact a long-lived index once computation is done.
+
+        Waits for computation to finish, shrinks every state map and drops the
+        per-state completion flags, lowering steady-state memory.
+        """
+        ...
+
+    def memory_footprint(self) -> int:
+        """Estimate the bytes held by this index, for capacity planning.
+
+        Waits for computation to finish. Maps shared with the cache are counted
+        by every index holding them, so do not sum footprints of one pattern.
+
+        Returns:
+            int: Approximate size in bytes.
+        """
+        ...
+
+    def has_transitions(self, state: int) -> bool:
+        """Check whether any token is allowed at a state, without building the token list.
+
+        Args:
+            state: State ID to check.
+
+        Returns:
+            bool: False if the state is -1, unknown, or dead-ended.
+        """
+        ...
+
+    def branching_factor(self, state: int) -> int:
+        """Count the distinct states a single token can lead to from a state.
+
+        Args:
+            state: State ID to inspect.
+
+        Returns:
+            int: Number of distinct next states, 0 if the state is -1, unknown, or dead-ended.
+        """
+        ...
+
+    def total_allowed_transitions(self) -> int:
+        """Count the (state, token) transitions of the whole index.
+
+        A cheap measure of how permissive a pattern is: `.*` allows most tokens
+        from every state, `yes|no` only a handful. EOS is not counted. Blocks
+        until computation finishes.
+
+        Returns:
+            int: Sum of the number of allowed tokens over all states.
+        """
+        ...
+
+    def export_all_masks(self, vocab_size: int) -> Tuple[numpy.ndarray, numpy.ndarray]:
+        """Export the allowed-token bitmask of every computed state, e.g. for a GPU kernel.
+
+        Blocks until computation finishes. Takes states * ceil(vocab_size / 64) * 8 bytes.
+
+        Args:
+            vocab_size: Number of tokens per mask, larger token IDs are left out.
+
+        Returns:
+            Tuple[numpy.ndarray, numpy.ndarray]: uint32 state IDs, and a uint64 array of
+                shape (len(states), ceil(vocab_size / 64)) where bit t % 64 of word t // 64
+                is set if token t is allowed.
+        """
         ...
 
     def get_allowed_token_ids(self, state: int) -> List[int]:
@@ -162,15 +725,75 @@ class LazyFSMIndex:
             state: State ID to check.
 
         Returns:
-            List[int]: Allowed token IDs.
+            List[int]: Allowed token IDs, sorted, or in vocabulary order if
+                created with `vocab_order`. Includes EOS at final states.
+        """
+        ...
+
+    def reachable_states(self) -> List[int]:
+        """Get the states reachable from the initial state by FSM transitions.
+
+        States outside this list are never visited during generation.
+
+        Returns:
+            List[int]: Sorted reachable state IDs.
+        """
+        ...
+
+    def allowed_after_bytes(self, state: int, bytes: bytes) -> List[int]:
+        """Get allowed tokens after generation stopped partway through a token.
+
+        Args:
+            state: State ID the partial token started from.
+            bytes: Bytes of the partial token emitted so far.
+
+        Returns:
+            List[int]: Token IDs allowed after the bytes, or an empty list if the
+                bytes do not match the pattern or end in an incomplete character.
+        """
+        ...
+
+    def allowed_tokens_union(self, states: List[int]) -> List[int]:
+        """Get the tokens allowed at any of several states, e.g. for speculative decoding.
+
+        Args:
+            states: Candidate state IDs.
+
+        Returns:
+            List[int]: Sorted, deduplicated token IDs allowed at any of the states.
+        """
+        ...
+
+    def longest_allowed_token(self, state: int, vocabulary: TokenVocabulary) -> Optional[int]:
+        """Get the allowed token with the longest string, for maximal-munch decoding.
+
+        Args:
+            state: State ID to check.
+            vocabulary: The vocabulary the index was built from, supplying token strings.
+
+        Returns:
+            Optional[int]: ID of the allowed token with the most characters, lowest ID
+                on ties, or None if only EOS is allowed. EOS is never returned.
+        """
+        ...
+
+    def get_allowed_token_ids_u32(self, state: int) -> List[int]:
+        """Get allowed tokens for state without narrowing ids to i32.
+
+        Args:
+            state: State ID to check.
+
+        Returns:
+            List[int]: Allowed token IDs, exact for ids above 2**31 - 1.
         """
         ...
 
     def __repr__(self) -> str:
-        """Get string representation.
+        """Get string representation, without waiting for computation.
 
         Returns:
-            str: Debug representation of FSM index.
+            str: Debug representation of FSM index, with its computation status,
+                the number of states computed so far, and the first computed maps.
         """
         ...
 
@@ -183,9 +806,28 @@ class FSMInfo:
         alphabet_symbol_mapping: Dict[str, int],
         alphabet_anything_value: int,
         states: List[int],
-        pattern: str
+        pattern: str,
+        eof_required_finals: Optional[List[int]] = None
     ) -> None: ...
-    
+
+    @staticmethod
+    def from_json_file(path: str) -> "FSMInfo":
+        """Load an FSM from a file holding the bytes of `__getstate__`.
+
+        The JSON is parsed in Rust, skipping the per-entry conversion of a Python
+        `transitions` dict, which dominates for FSMs with many transitions.
+
+        Args:
+            path: Path of the serialized FSM.
+
+        Returns:
+            FSMInfo: The loaded FSM.
+
+        Raises:
+            ValueError: If the file cannot be read or does not hold a serialized FSM.
+        """
+        ...
+
     @property
     def initial(self) -> int: ...
     
@@ -205,4 +847,35 @@ class FSMInfo:
     def states(self) -> List[int]: ...
     
     @property
-    def pattern(self) -> str: ...
\ No newline at end of file
+    def pattern(self) -> str: ...
+
+    @property
+    def eof_required_finals(self) -> List[int]:
+        """Final states which must be followed by EOS, e.g. the end of a `$` anchored pattern."""
+        ...
+
+    def complement(self) -> "FSMInfo":
+        """Returns an FSM accepting exactly the strings this one rejects.
+
+        Missing transitions are routed to an added trap state before final and
+        non-final states are swapped, so the result is a complete DFA.
+
+        Returns:
+            FSMInfo: The complement FSM, with its own pattern and so its own cache entry.
+
+        Raises:
+            ValueError: If the complete transition table exceeds
+                `FASTER_OUTLINES_MAX_TRANSITION_CELLS`.
+        """
+        ...
+
+    def minimize(self) -> "FSMInfo":
+        """Returns the equivalent FSM with the fewest states.
+
+        Unreachable and dead-end states are dropped and states accepting the same
+        suffixes merged, so an index built from it computes fewer state maps.
+
+        Returns:
+            FSMInfo: The minimized FSM, with the same pattern and alphabet.
+        """
+        ...
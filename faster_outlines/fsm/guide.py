@@ -4,7 +4,7 @@ from .regex import (
     create_fsm_index_tokenizer,
     create_fsm_info
 )
-from faster_outlines.lib import TokenVocabulary
+from faster_outlines.lib import TokenVocabulary, RegexGuide as _RegexGuideAdapter
 
 class RegexGuide():
     """Guide to generate text in the language of a regular expression."""
@@ -14,9 +14,21 @@ class RegexGuide():
             self.fsm,
             _
         ) = create_fsm_index_end_to_end(regex_string, tokenizer)
+        self._bind_index(tokenizer)
+
+    def _bind_index(self, tokenizer):
+        guide = _RegexGuideAdapter(self.fsm)
         self.eos_token_id = tokenizer.eos_token_id
-        self.get_next_state = self.fsm.get_next_state
-        self.get_next_instruction = self.fsm.get_next_instruction
+        self.initial_state = guide.initial_state
+        self.get_next_state = guide.get_next_state
+        self.get_next_instruction = guide.get_next_instruction
+        self.is_final_state = guide.is_final_state
+
+    @property
+    def states_to_token_maps(self):
+        """Outlines-compatible `{state: {token_id: next_state}}` map.
+        Blocks until the index is fully computed."""
+        return self.fsm.to_outlines_states_to_token_maps()
 
     @classmethod
     def from_interegular_fsm(
@@ -26,5 +38,5 @@ class RegexGuide():
         fsm = interegular_fsm.reduce()
         fsm = create_fsm_info(fsm)
         instance.fsm = create_fsm_index_tokenizer(fsm, tokenizer)
-        instance.eos_token_id = tokenizer.eos_token_id
+        instance._bind_index(tokenizer)
         return instance
\ No newline at end of file
@@ -26,11 +26,55 @@
 // Code from https://github.com/m-ou-se/atomic-wait
 // Modified to work with AtoimicBool, and have non-spuratic waking await functions.
 // modifications by Nathan Hoos, 2024
+//
+// Memory ordering: every flag waited on here, the per-state notifiers, `computing_finished`
+// and the pause flag, is only ever set with a `Release` store, made after the data it
+// guards is written and before the wake. Waiters load it with `Acquire`, so a waiter which
+// sees the new value also sees that data, e.g. the state's token map, whichever way it
+// returned. Neither side needs a single total order over several flags, so `SeqCst` would
+// only add fences. The futex / umtx syscalls compare the value in the kernel, and the
+// fallback checks it under the same lock wakers take after storing, so no wake is lost.
+// Keep loads at least `Acquire`: `Relaxed` would let readers see a flag before its map.
 #![allow(dead_code)]
+use std::time::{Duration, Instant};
+
 #[cfg(target_os = "linux")]
 pub mod platform {
+    use super::{deadline_after, fallback, remaining_until};
     use core::sync::atomic::{AtomicBool, Ordering};
     use libc;
+    use std::sync::Once;
+    use std::time::Duration;
+
+    // Set once futex turns out to be unusable, e.g. blocked by a seccomp profile
+    // in a locked down container. From then on waiters use the condvar fallback.
+    static FUTEX_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+    static FUTEX_PROBE: Once = Once::new();
+
+    /// Whether futex failed with ENOSYS / EPERM, probing it on first use.
+    #[inline]
+    pub fn futex_unavailable() -> bool {
+        FUTEX_PROBE.call_once(|| {
+            let probe = AtomicBool::new(false);
+            wake_all_futex(&probe);
+        });
+        FUTEX_UNAVAILABLE.load(Ordering::Relaxed)
+    }
+
+    /// Switches every later `wait` to the condvar fallback.
+    pub(crate) fn disable_futex() {
+        FUTEX_UNAVAILABLE.store(true, Ordering::Relaxed);
+    }
+
+    /// Records futex as unavailable if `ret` is an ENOSYS / EPERM failure.
+    fn check_futex_result(ret: libc::c_long) {
+        if ret == -1 {
+            let errno = std::io::Error::last_os_error().raw_os_error();
+            if matches!(errno, Some(libc::ENOSYS) | Some(libc::EPERM)) {
+                disable_futex();
+            }
+        }
+    }
 
     // These need to wait in a loop,
     // because futex's while very performant,
@@ -38,50 +82,160 @@ pub mod platform {
     // so we have to deal with that by checking before returning.
     #[inline]
     pub fn wait(a: &AtomicBool, expected: bool) {
-        while a.load(Ordering::SeqCst) == expected {
+        while a.load(Ordering::Acquire) == expected {
+            if futex_unavailable() {
+                return fallback::wait(a, expected);
+            }
             let expected_int = if expected { 1 } else { 0 };
             unsafe {
-                libc::syscall(
+                let ret = libc::syscall(
                     libc::SYS_futex,
                     a as *const _ as *const i32,
                     libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
                     expected_int,
                     core::ptr::null::<libc::timespec>(),
                 );
+                check_futex_result(ret);
+            }
+        }
+    }
+
+    /// Same as `wait`, but gives up once `timeout` has passed.
+    /// Returns whether the value changed from `expected`.
+    #[inline]
+    pub fn wait_timeout(a: &AtomicBool, expected: bool, timeout: Duration) -> bool {
+        let Some(deadline) = deadline_after(timeout) else {
+            wait(a, expected);
+            return true;
+        };
+        while a.load(Ordering::Acquire) == expected {
+            let Some(remaining) = remaining_until(deadline) else {
+                return false;
+            };
+            if futex_unavailable() {
+                return fallback::wait_timeout(a, expected, remaining);
+            }
+            let expected_int = if expected { 1 } else { 0 };
+            // FUTEX_WAIT takes a relative timeout.
+            let timeout = libc::timespec {
+                tv_sec: remaining.as_secs() as libc::time_t,
+                tv_nsec: remaining.subsec_nanos() as libc::c_long,
+            };
+            unsafe {
+                let ret = libc::syscall(
+                    libc::SYS_futex,
+                    a as *const _ as *const i32,
+                    libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                    expected_int,
+                    &timeout as *const libc::timespec,
+                );
+                check_futex_result(ret);
             }
         }
+        true
     }
-    
 
+    // The wakers always issue the futex wake, and also notify the fallback
+    // once futex is unavailable. Waiters which went to sleep on the futex
+    // before the switch are still woken that way.
     #[inline]
     pub fn wake_one(ptr: *const AtomicBool) {
         unsafe {
-            libc::syscall(
+            let ret = libc::syscall(
                 libc::SYS_futex,
                 ptr as *const _ as *const i32,
                 libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
                 1i32,
             );
+            check_futex_result(ret);
+        }
+        if futex_unavailable() {
+            fallback::wake_all();
         }
     }
 
     #[inline]
     pub fn wake_all(ptr: *const AtomicBool) {
+        wake_all_futex(ptr);
+        if futex_unavailable() {
+            fallback::wake_all();
+        }
+    }
+
+    #[inline]
+    fn wake_all_futex(ptr: *const AtomicBool) {
         unsafe {
-            libc::syscall(
+            let ret = libc::syscall(
                 libc::SYS_futex,
                 ptr as *const _ as *const i32,
                 libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
                 i32::MAX,
             );
+            check_futex_result(ret);
+        }
+    }
+}
+
+/// Condvar based waiting, for when futex is blocked.
+///
+/// There is a single condvar for every atomic, so each wake wakes every fallback
+/// waiter, which re-checks its own atomic. Wakers take the lock after storing the
+/// new value, and waiters check the value under the lock, so no wake is lost.
+pub mod fallback {
+    use super::{deadline_after, remaining_until};
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Condvar, Mutex};
+    use std::time::Duration;
+
+    static LOCK: Mutex<()> = Mutex::new(());
+    static CONDVAR: Condvar = Condvar::new();
+
+    pub fn wait(a: &AtomicBool, expected: bool) {
+        let mut guard = LOCK.lock().unwrap();
+        while a.load(Ordering::Acquire) == expected {
+            guard = CONDVAR.wait(guard).unwrap();
         }
     }
+
+    pub fn wait_timeout(a: &AtomicBool, expected: bool, timeout: Duration) -> bool {
+        let Some(deadline) = deadline_after(timeout) else {
+            wait(a, expected);
+            return true;
+        };
+        let mut guard = LOCK.lock().unwrap();
+        while a.load(Ordering::Acquire) == expected {
+            let Some(remaining) = remaining_until(deadline) else {
+                return false;
+            };
+            guard = CONDVAR.wait_timeout(guard, remaining).unwrap().0;
+        }
+        true
+    }
+
+    pub fn wake_all() {
+        let _guard = LOCK.lock().unwrap();
+        CONDVAR.notify_all();
+    }
+}
+
+/// `None` if the deadline is too far out to represent, which is as good as no timeout.
+fn deadline_after(timeout: Duration) -> Option<Instant> {
+    Instant::now().checked_add(timeout)
+}
+
+/// Time left until `deadline`, `None` once it has passed.
+fn remaining_until(deadline: Instant) -> Option<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
 }
 
 #[cfg(target_os = "freebsd")]
 pub mod platform {
+    use super::{deadline_after, remaining_until};
     use core::sync::atomic::{AtomicBool, Ordering};
     use libc;
+    use std::time::Duration;
 
     // These need to wait in a loop,
     // because futex's while very performant,
@@ -89,7 +243,7 @@ pub mod platform {
     // so we have to deal with that by checking before returning.
     #[inline]
     pub fn wait(a: &AtomicBool, expected: bool) {
-        while a.load(Ordering::SeqCst) == expected {
+        while a.load(Ordering::Acquire) == expected {
             let expected_int = if expected { 1 } else { 0 };
             let ptr: *const AtomicBool = a;
             unsafe {
@@ -104,6 +258,38 @@ pub mod platform {
         }
     }
 
+    /// Same as `wait`, but gives up once `timeout` has passed.
+    /// Returns whether the value changed from `expected`.
+    #[inline]
+    pub fn wait_timeout(a: &AtomicBool, expected: bool, timeout: Duration) -> bool {
+        let Some(deadline) = deadline_after(timeout) else {
+            wait(a, expected);
+            return true;
+        };
+        while a.load(Ordering::Acquire) == expected {
+            let Some(remaining) = remaining_until(deadline) else {
+                return false;
+            };
+            let expected_int = if expected { 1 } else { 0 };
+            let ptr: *const AtomicBool = a;
+            // A relative timeout is passed as a timespec, with its size in place of `uaddr`.
+            let mut timeout = libc::timespec {
+                tv_sec: remaining.as_secs() as libc::time_t,
+                tv_nsec: remaining.subsec_nanos() as libc::c_long,
+            };
+            unsafe {
+                libc::_umtx_op(
+                    ptr as *mut libc::c_void,
+                    libc::UMTX_OP_WAIT_UINT_PRIVATE,
+                    expected_int as libc::c_ulong,
+                    core::mem::size_of::<libc::timespec>() as *mut libc::c_void,
+                    &mut timeout as *mut libc::timespec as *mut libc::c_void,
+                );
+            }
+        }
+        true
+    }
+
 
     #[inline]
     pub fn wake_one(ptr: *const AtomicBool) {
@@ -167,7 +353,7 @@ mod tests {
 
         // Set the atomic to true and wake one thread.
         atomic_bool.store(true, Ordering::SeqCst);
-        platform::wake_one(Arc::as_ptr(&atomic_bool) as *const AtomicBool);
+        platform::wake_one(Arc::as_ptr(&atomic_bool));
 
         // Wait for the thread to finish.
         handle.join().expect("Thread panicked");
@@ -200,12 +386,142 @@ mod tests {
 
         // Set the atomic to true and wake all threads.
         atomic_bool.store(true, Ordering::SeqCst);
-        platform::wake_all(Arc::as_ptr(&atomic_bool) as *const AtomicBool);
+        platform::wake_all(Arc::as_ptr(&atomic_bool));
 
         // Wait for all threads to finish.
         for handle in handles {
             handle.join().expect("Thread panicked");
         }
     }
+
+    #[test]
+    fn test_fallback_wait_and_wake() {
+        let atomic_bool = Arc::new(AtomicBool::new(false));
+        let barrier = Arc::new(Barrier::new(4));
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let (atomic_clone, barrier_clone) = (atomic_bool.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier_clone.wait();
+                    super::fallback::wait(&atomic_clone, false);
+                    assert!(atomic_clone.load(Ordering::SeqCst));
+                })
+            })
+            .collect();
+
+        barrier.wait();
+        thread::sleep(Duration::from_millis(100));
+        atomic_bool.store(true, Ordering::SeqCst);
+        super::fallback::wake_all();
+
+        for handle in handles {
+            handle.join().expect("Thread panicked");
+        }
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let atomic_bool = Arc::new(AtomicBool::new(false));
+        assert!(!platform::wait_timeout(&atomic_bool, false, Duration::from_millis(20)));
+        assert!(!super::fallback::wait_timeout(&atomic_bool, false, Duration::from_millis(20)));
+
+        let setter = {
+            let atomic_clone = atomic_bool.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                atomic_clone.store(true, Ordering::SeqCst);
+                platform::wake_all(Arc::as_ptr(&atomic_clone));
+            })
+        };
+        assert!(platform::wait_timeout(&atomic_bool, false, Duration::from_secs(60)));
+        assert!(atomic_bool.load(Ordering::SeqCst));
+        setter.join().expect("Thread panicked");
+
+        // Already changed, and a timeout too large for a deadline, both return at once.
+        assert!(platform::wait_timeout(&atomic_bool, false, Duration::ZERO));
+        assert!(platform::wait_timeout(&atomic_bool, false, Duration::MAX));
+    }
+
+    // cargo test --release bench_wait -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_wait_many_waiters() {
+        const FLAGS: usize = 1024;
+        const WAITERS: usize = 64;
+        const SET_WAITS: usize = 10_000_000;
+
+        // Hot path: the flag is already set, as for every computed state.
+        let set = AtomicBool::new(true);
+        let start = std::time::Instant::now();
+        for _ in 0..SET_WAITS {
+            platform::wait(std::hint::black_box(&set), false);
+        }
+        println!("set flag:          {:?} per wait", start.elapsed() / SET_WAITS as u32);
+
+        // Every waiter walks the flags in order while a single writer publishes them.
+        let flags: Arc<Vec<AtomicBool>> = Arc::new((0..FLAGS).map(|_| AtomicBool::new(false)).collect());
+        let barrier = Arc::new(Barrier::new(WAITERS + 1));
+        let handles: Vec<_> = (0..WAITERS)
+            .map(|_| {
+                let (flags, barrier) = (flags.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    for flag in flags.iter() {
+                        platform::wait(flag, false);
+                    }
+                })
+            })
+            .collect();
+        barrier.wait();
+        let start = std::time::Instant::now();
+        for flag in flags.iter() {
+            flag.store(true, Ordering::Release);
+            platform::wake_all(flag);
+        }
+        for handle in handles {
+            handle.join().expect("Thread panicked");
+        }
+        println!("{} waiters:        {:?} for {} flags", WAITERS, start.elapsed(), FLAGS);
+    }
+
+    // Simulates futex being blocked: waits already parked on the futex and
+    // new ones on the condvar must both be woken after the switch.
+    // The switch is permanent, so it runs in a child process of the test binary,
+    // leaving the other tests on the futex path.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_switch_to_fallback_wakes_every_waiter() {
+        const IN_CHILD: &str = "FASTER_OUTLINES_TEST_FUTEX_SWITCH";
+        if std::env::var_os(IN_CHILD).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "atomic_wait::tests::test_switch_to_fallback_wakes_every_waiter"])
+                .env(IN_CHILD, "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success() && stdout.contains("1 passed"), "{}", stdout);
+            return;
+        }
+
+        let atomic_bool = Arc::new(AtomicBool::new(false));
+        let futex_waiter = {
+            let atomic_clone = atomic_bool.clone();
+            thread::spawn(move || platform::wait(&atomic_clone, false))
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        platform::disable_futex();
+        assert!(platform::futex_unavailable());
+        let fallback_waiter = {
+            let atomic_clone = atomic_bool.clone();
+            thread::spawn(move || platform::wait(&atomic_clone, false))
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        atomic_bool.store(true, Ordering::SeqCst);
+        platform::wake_all(Arc::as_ptr(&atomic_bool));
+        futex_waiter.join().expect("Thread panicked");
+        fallback_waiter.join().expect("Thread panicked");
+    }
 }
 
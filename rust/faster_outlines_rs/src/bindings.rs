@@ -27,32 +27,71 @@ use serde::{Serialize, Deserialize};
 use once_cell::sync::Lazy;
 use rustc_hash::{FxHashMap, FxHashSet};
 use anyhow::{Result, Context};
+use std::sync::Arc;
+use std::time::Duration;
 
 use pyo3::{
     wrap_pyfunction,
     prelude::*,
+    types::{PyBytes, PyDict},
     exceptions::{
+        PyIOError,
         PyRuntimeError,
         PyValueError,
     }
 };
 use crate::{
     lazy_index::{
+        IndexError,
         LazyFSMIndex
     },
     caching::{
-        MODULE_STATE
+        MODULE_STATE,
+        cached_fsm_snapshot,
+        get_fsm_cache_key,
+        is_fsm_cached,
+        list_cached_patterns
     },
     types::{
         Write,
         Generate,
         Instruction,
         FSMInfo,
-        TransitionMap
+        StateCallback,
+        TransitionMap,
+        ComputeOptions
     },
-    vocab::TokenVocabulary,
+    environment::COMPUTE_THREADS,
+    vocab::{TokenVocabulary, VocabError},
+    initial_instruction,
+    estimate_complexity,
 };
 
+#[allow(unexpected_cfgs)]
+mod exceptions {
+    use pyo3::{create_exception, exceptions::PyValueError};
+
+    create_exception!(lib, StateOutOfBoundsError, PyValueError, "Raised when a state does not exist in the FSM.");
+    create_exception!(lib, StateNotComputedError, PyValueError, "Raised when a state is not reachable, so it is never computed.");
+    create_exception!(lib, ComputeCancelledError, PyValueError, "Raised when computing the index failed before finishing.");
+}
+
+impl From<VocabError> for PyErr {
+    fn from(err: VocabError) -> Self {
+        PyValueError::new_err(err.to_string())
+    }
+}
+
+impl From<IndexError> for PyErr {
+    fn from(err: IndexError) -> Self {
+        match err {
+            IndexError::OutOfBounds { .. } => exceptions::StateOutOfBoundsError::new_err(err.to_string()),
+            IndexError::NotComputed { .. } => exceptions::StateNotComputedError::new_err(err.to_string()),
+            IndexError::Cancelled { .. } => exceptions::ComputeCancelledError::new_err(err.to_string()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[pyclass(
     name = "TokenVocabulary",
@@ -72,14 +111,20 @@ impl PyTokenVocabulary {
 impl PyTokenVocabulary {
     // We need to allow none arguments, so pickle'ing works.
     /// Initializes the TokenVocabulary from a Python dictionary.
+    /// `from_sentencepiece` defaults to true, and is detected from the vocabulary when `None`.
+    /// Tokens keep the insertion order of the dictionary, which `vocab_order` indexes rank by.
     #[new]
-    #[pyo3(signature = (py_dict=None, eos_token_id=None, special_tokens=None))]
-    pub fn new(py_dict: Option<FxHashMap<String, u32>>, eos_token_id: Option<u32>, special_tokens: Option<FxHashSet<String>>) -> PyResult<Self> {
+    #[pyo3(signature = (py_dict=None, eos_token_id=None, special_tokens=None, from_sentencepiece=Some(true)))]
+    pub fn new(py_dict: Option<&Bound<'_, PyDict>>, eos_token_id: Option<u32>, special_tokens: Option<FxHashSet<String>>, from_sentencepiece: Option<bool>) -> PyResult<Self> {
         match (py_dict, eos_token_id, special_tokens) {
             // Normal construction
             (Some(dict), Some(eos), Some(special)) => {
-                let token_vocabulary = TokenVocabulary::from_raw_vocab(dict, eos, Some(special), Some(true))
-                    .with_context(|| format!("Failed to create token vocabulary due to error."))?;
+                // Pairs rather than a map, which would lose the dictionary's order.
+                let pairs = dict
+                    .iter()
+                    .map(|(token, id)| Ok((token.extract()?, id.extract()?)))
+                    .collect::<PyResult<Vec<(String, u32)>>>()?;
+                let token_vocabulary = TokenVocabulary::from_raw_pairs(pairs, eos, Some(special), from_sentencepiece)?;
                 Ok(PyTokenVocabulary { vocab: token_vocabulary })
             },
             // Pickle reconstruction (empty instance to be filled by __setstate__)
@@ -96,6 +141,19 @@ impl PyTokenVocabulary {
         }
     }
 
+    /// Builds the TokenVocabulary from `(token, id)` pairs, without a dictionary.
+    #[staticmethod]
+    #[pyo3(signature = (pairs, eos_token_id, special_tokens=None, from_sentencepiece=Some(true)))]
+    pub fn from_pairs(
+        pairs: Vec<(String, u32)>,
+        eos_token_id: u32,
+        special_tokens: Option<FxHashSet<String>>,
+        from_sentencepiece: Option<bool>,
+    ) -> PyResult<Self> {
+        let vocab = TokenVocabulary::from_raw_pairs(pairs, eos_token_id, special_tokens, from_sentencepiece)?;
+        Ok(PyTokenVocabulary { vocab })
+    }
+
     #[getter]
     pub fn eos_token_id(&self) -> PyResult<u32> {
         Ok(self.vocab.eos_token_id)
@@ -164,24 +222,51 @@ impl From<Write> for PyWrite {
 pub struct PyGenerate {
     #[pyo3(get, set)]
     pub tokens: Option<Vec<i32>>,
+    #[pyo3(get, set)]
+    pub priorities: Option<Vec<f32>>,
+    #[pyo3(get, set)]
+    pub terminal_choices: bool,
 }
 
 #[pymethods]
 impl PyGenerate {
     #[new]
-    #[pyo3(signature = (tokens=None))]
-    pub fn new(tokens: Option<Vec<i32>>) -> Self {
-        PyGenerate { tokens }
+    #[pyo3(signature = (tokens=None, priorities=None, terminal_choices=false))]
+    pub fn new(tokens: Option<Vec<i32>>, priorities: Option<Vec<f32>>, terminal_choices: bool) -> Self {
+        PyGenerate { tokens, priorities, terminal_choices }
     }
 
     pub fn __repr__(&self) -> PyResult<String> {
-        Ok(format!("Generate({:?})", self.tokens))
+        match &self.priorities {
+            Some(priorities) => Ok(format!("Generate({:?}, priorities={:?})", self.tokens, priorities)),
+            None => Ok(format!("Generate({:?})", self.tokens)),
+        }
     }
 }
 
 impl From<Generate> for PyGenerate {
     fn from(generate: Generate) -> Self {
-        PyGenerate { tokens: generate.tokens }
+        PyGenerate {
+            tokens: generate.tokens,
+            priorities: generate.priorities,
+            terminal_choices: generate.terminal_choices,
+        }
+    }
+}
+
+/// Signals that the pattern is complete and generation should end without emitting EOS.
+#[pyclass(name = "Stop")]
+pub struct PyStop;
+
+#[pymethods]
+impl PyStop {
+    #[new]
+    pub fn new() -> Self {
+        PyStop
+    }
+
+    pub fn __repr__(&self) -> PyResult<String> {
+        Ok("Stop()".to_string())
     }
 }
 
@@ -196,6 +281,7 @@ impl IntoPy<PyObject> for Instruction {
                 let py_generate: PyGenerate = generate.into();
                 py_generate.into_py(py)
             }
+            Instruction::Stop => PyStop.into_py(py),
         }
     }
 }
@@ -209,23 +295,36 @@ pub struct PyFSMInfo(FSMInfo);
 #[pymethods]
 impl PyFSMInfo {
     #[new]
+    #[pyo3(signature = (initial, finals, transitions, alphabet_symbol_mapping, alphabet_anything_value, pattern, eof_required_finals=None))]
     pub fn new(
         initial: u32,
         finals: Vec<u32>,
         transitions: FxHashMap<(u32,u32), u32>,
         alphabet_symbol_mapping: FxHashMap<String, u32>,
         alphabet_anything_value: u32,
-        pattern: String
-    ) -> Self {
-        let transitions_map: TransitionMap = transitions.into();
-        PyFSMInfo(FSMInfo {
-            initial: initial,
-            finals: finals,
+        pattern: String,
+        eof_required_finals: Option<Vec<u32>>,
+    ) -> PyResult<Self> {
+        let transitions_map: TransitionMap = transitions
+            .try_into()
+            .map_err(|e: anyhow::Error| PyValueError::new_err(e.to_string()))?;
+        Ok(PyFSMInfo(FSMInfo {
+            initial,
+            finals,
             transitions: transitions_map,
-            alphabet_symbol_mapping: alphabet_symbol_mapping,
-            alphabet_anything_value: alphabet_anything_value,
-            pattern: pattern,
-        })
+            alphabet_symbol_mapping,
+            alphabet_anything_value,
+            pattern,
+            eof_required_finals: eof_required_finals.unwrap_or_default(),
+        }))
+    }
+
+    /// Loads an FSM saved with `__getstate__`, parsed in Rust without a Python dict.
+    #[staticmethod]
+    pub fn from_json_file(py: Python<'_>, path: std::path::PathBuf) -> PyResult<Self> {
+        py.allow_threads(|| FSMInfo::from_json_file(&path))
+            .map(PyFSMInfo)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
     }
 
     #[getter]
@@ -253,6 +352,22 @@ impl PyFSMInfo {
         self.0.pattern.clone()
     }
 
+    #[getter]
+    pub fn eof_required_finals(&self) -> Vec<u32> {
+        self.0.eof_required_finals.clone()
+    }
+
+    pub fn complement(&self) -> PyResult<PyFSMInfo> {
+        self.0
+            .complement()
+            .map(PyFSMInfo)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    pub fn minimize(&self) -> PyFSMInfo {
+        PyFSMInfo(self.0.minimize())
+    }
+
     pub fn __getstate__(&self, py: Python) -> PyResult<PyObject> {
         let serialized = serde_json::to_string(&self.0)
             .map_err(|e| PyErr::new::<PyValueError, _>(e.to_string()))?;
@@ -285,15 +400,22 @@ pub struct PyLazyFSMIndex {
 impl PyLazyFSMIndex {
     pub fn new(
         fsm_info: FSMInfo,
-        vocabulary: &TokenVocabulary
+        vocabulary: &TokenVocabulary,
+        options: ComputeOptions,
+        lazy: bool
     ) -> Result<Self> {
-        Ok(PyLazyFSMIndex {
-            inner: LazyFSMIndex::new(
+        fsm_info.check_transition_targets()?;
+        let inner = if lazy {
+            LazyFSMIndex::new_on_demand(fsm_info, vocabulary, vocabulary.eos_token_id, options)
+        } else {
+            LazyFSMIndex::new_with_options(
                 fsm_info,
                 vocabulary, 
-                vocabulary.eos_token_id
+                vocabulary.eos_token_id,
+                options
             )
-        })
+        };
+        Ok(PyLazyFSMIndex { inner })
     }
 }
 
@@ -301,24 +423,46 @@ impl PyLazyFSMIndex {
 impl PyLazyFSMIndex {
     
 
-    pub fn get_next_state(&self, state: i32, token_id: u32) -> Option<i32> {
-        self.inner.get_next_state(state, token_id)
-    }
-
-    pub fn get_next_instruction(&self, state: i32) -> PyResult<PyObject> {
-        Python::with_gil(|py| {
-            let instruction = self.inner.get_next_instruction(state);
-            match instruction {
-                Instruction::Write(write) => {
-                    let py_write: PyWrite = write.into();
-                    Ok(py_write.into_py(py))
-                },
-                Instruction::Generate(generate) => {
-                    let py_generate: PyGenerate = generate.into();
-                    Ok(py_generate.into_py(py))
-                }
+    // Every method which may block on the compute thread releases the GIL while
+    // waiting, since a Python state callback needs it to make progress.
+
+    pub fn get_next_state(&self, py: Python<'_>, state: i32, token_id: u32) -> Option<i32> {
+        py.allow_threads(|| self.inner.get_next_state(state, token_id))
+    }
+
+    pub fn advance(&self, py: Python<'_>, state: i32, token_ids: Vec<u32>) -> Option<i32> {
+        py.allow_threads(|| self.inner.advance(state, &token_ids))
+    }
+
+    pub fn get_next_instruction(&self, py: Python<'_>, state: i32) -> PyResult<PyObject> {
+        let instruction = py.allow_threads(|| self.inner.get_next_instruction(state));
+        match instruction {
+            Instruction::Write(write) => {
+                let py_write: PyWrite = write.into();
+                Ok(py_write.into_py(py))
+            },
+            Instruction::Generate(generate) => {
+                let py_generate: PyGenerate = generate.into();
+                Ok(py_generate.into_py(py))
             }
-        })
+            Instruction::Stop => Ok(PyStop.into_py(py)),
+        }
+    }
+
+    pub fn get_next_instruction_with_priorities(&self, py: Python<'_>, state: i32) -> PyObject {
+        py.allow_threads(|| self.inner.get_next_instruction_with_priorities(state))
+            .into_py(py)
+    }
+
+    /// Returns None if the state is not computed within `timeout_ms` milliseconds.
+    pub fn get_next_instruction_timeout(&self, py: Python<'_>, state: i32, timeout_ms: u64) -> Option<PyObject> {
+        py.allow_threads(|| self.inner.get_next_instruction_timeout(state, Duration::from_millis(timeout_ms)))
+            .map(|instruction| instruction.into_py(py))
+    }
+
+    pub fn get_next_instruction_with_forced_tokens(&self, py: Python<'_>, state: i32) -> PyObject {
+        py.allow_threads(|| self.inner.get_next_instruction_with_forced_tokens(state))
+            .into_py(py)
     }
 
     pub fn collect_finished_states(&mut self) -> PyResult<FxHashMap<u32, FxHashMap<u32, u32>>> {
@@ -326,17 +470,173 @@ impl PyLazyFSMIndex {
             .map_err(|e| PyValueError::new_err(e.to_string()))
     }
 
-    pub fn await_state(&self, state_index: u32) -> PyResult<()> {
-        self.inner.await_state(state_index)
+    pub fn collect_finished_states_ordered(&mut self) -> PyResult<Vec<(u32, FxHashMap<u32, u32>)>> {
+        self.inner.collect_finished_states_ordered()
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    pub fn reset_stream_cursor(&mut self) {
+        self.inner.reset_stream_cursor()
+    }
+
+    /// Returns `{state: {token_id: next_state}}`, the structure of outlines'
+    /// `RegexGuide.states_to_token_maps`. Blocks until computation finishes.
+    pub fn to_outlines_states_to_token_maps(&self, py: Python<'_>) -> FxHashMap<u32, FxHashMap<u32, u32>> {
+        py.allow_threads(|| self.inner.to_outlines_states_to_token_maps())
+    }
+
+    /// Returns the constraint as a JSON string, see `LazyFSMIndex::to_json`.
+    /// Blocks until computation finishes.
+    pub fn to_json(&self, py: Python<'_>) -> PyResult<String> {
+        py.allow_threads(|| self.inner.to_json())
             .map_err(|e| PyValueError::new_err(e.to_string()))
     }
 
-    pub fn await_finished(&self) {
-        self.inner.await_finished()
+    /// Writes the transitions to `path` as JSON lines, see `LazyFSMIndex::write_transitions_jsonl`.
+    /// States are written as they are computed.
+    pub fn write_transitions_jsonl(&self, py: Python<'_>, path: std::path::PathBuf) -> PyResult<()> {
+        let file = std::fs::File::create(&path)
+            .map_err(|e| PyIOError::new_err(format!("Failed to create {}: {}", path.display(), e)))?;
+        py.allow_threads(|| self.inner.write_transitions_jsonl(std::io::BufWriter::new(file)))
+            .map_err(|e| match e.downcast::<IndexError>() {
+                Ok(err) => err.into(),
+                Err(e) => PyIOError::new_err(format!("{:#}", e)),
+            })
+    }
+
+    /// Returns a `{token_id: next_state}` dict per requested state, or None if it does not exist.
+    pub fn get_states(&self, py: Python<'_>, states: Vec<u32>) -> Vec<Option<FxHashMap<u32, u32>>> {
+        py.allow_threads(|| self.inner.get_state_maps(&states))
+    }
+
+    /// Returns `(states, masks)` as numpy arrays, `masks` shaped `(len(states), ceil(vocab_size / 64))`.
+    pub fn export_all_masks(&self, py: Python<'_>, vocab_size: usize) -> PyResult<(PyObject, PyObject)> {
+        let (states, masks) = py.allow_threads(|| self.inner.export_all_masks(vocab_size));
+        let numpy = py.import_bound("numpy")?;
+
+        let states = numpy.call_method1("array", (states, "uint32"))?;
+        let mask_bytes: Vec<u8> = masks.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        let masks = numpy
+            .call_method1("frombuffer", (PyBytes::new_bound(py, &mask_bytes), "uint64"))?
+            .call_method1("reshape", (states.len()?, vocab_size.div_ceil(64)))?;
+        Ok((states.into_py(py), masks.into_py(py)))
+    }
+
+    pub fn dedup_maps(&mut self, py: Python<'_>) -> usize {
+        py.allow_threads(|| self.inner.dedup_maps())
+    }
+
+    pub fn patch_vocab(&mut self, py: Python<'_>, added: Vec<(String, Vec<u32>)>) -> PyResult<()> {
+        py.allow_threads(|| self.inner.patch_vocab(&added))
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    #[getter]
+    pub fn eos_token_id(&self) -> u32 {
+        self.inner.eos_token_id()
+    }
+
+    pub fn with_eos_token_id(&self, eos_token_id: u32) -> PyLazyFSMIndex {
+        PyLazyFSMIndex { inner: self.inner.clone().with_eos_token_id(eos_token_id) }
+    }
+
+    pub fn extend_pattern(
+        &self,
+        py: Python<'_>,
+        suffix_fsm: PyFSMInfo,
+        vocabulary: Py<PyTokenVocabulary>,
+    ) -> PyResult<PyLazyFSMIndex> {
+        let suffix: FSMInfo = suffix_fsm.into();
+        let v = vocabulary.borrow(py);
+        let v = v.vocab_as_ref();
+        py.allow_threads(|| self.inner.extend_pattern(&suffix, v))
+            .map(|inner| PyLazyFSMIndex { inner })
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    pub fn transition_key_for_char(&self, c: char) -> u32 {
+        self.inner.transition_key_for_char(c)
+    }
+
+    pub fn await_state(&self, py: Python<'_>, state_index: u32) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.inner.await_state(state_index))?)
+    }
+
+    pub fn await_finished(&self, py: Python<'_>) -> PyResult<()> {
+        py.allow_threads(|| self.inner.await_finished());
+        match self.inner.compute_error() {
+            Some(error) => Err(PyRuntimeError::new_err(format!("Computing the index failed: {}", error))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.inner.pause()
     }
 
-    pub fn get_allowed_token_ids(&self, state: i32) -> Vec<i32> {
-        self.inner.get_allowed_token_ids(state)
+    pub fn resume(&self) {
+        self.inner.resume()
+    }
+
+    #[getter]
+    pub fn is_paused(&self) -> bool {
+        self.inner.is_paused()
+    }
+
+    #[getter]
+    pub fn compute_error(&self) -> Option<String> {
+        self.inner.compute_error()
+    }
+
+    pub fn finalize(&mut self, py: Python<'_>) {
+        py.allow_threads(|| self.inner.finalize())
+    }
+
+    pub fn memory_footprint(&self, py: Python<'_>) -> usize {
+        py.allow_threads(|| self.inner.memory_footprint())
+    }
+
+    pub fn has_transitions(&self, py: Python<'_>, state: i32) -> bool {
+        py.allow_threads(|| self.inner.has_transitions(state))
+    }
+
+    pub fn branching_factor(&self, py: Python<'_>, state: i32) -> usize {
+        py.allow_threads(|| self.inner.branching_factor(state))
+    }
+
+    pub fn total_allowed_transitions(&self, py: Python<'_>) -> usize {
+        py.allow_threads(|| self.inner.total_allowed_transitions())
+    }
+
+    pub fn reachable_states(&self) -> Vec<u32> {
+        self.inner.reachable_states().ones().map(|state| state as u32).collect()
+    }
+
+    pub fn allowed_after_bytes(&self, py: Python<'_>, state: i32, bytes: Vec<u8>) -> Vec<i32> {
+        py.allow_threads(|| self.inner.allowed_after_bytes(state, &bytes))
+    }
+
+    pub fn allowed_tokens_union(&self, py: Python<'_>, states: Vec<i32>) -> Vec<i32> {
+        py.allow_threads(|| self.inner.allowed_tokens_union(&states))
+    }
+
+    pub fn longest_allowed_token(
+        &self,
+        py: Python<'_>,
+        state: i32,
+        vocabulary: Py<PyTokenVocabulary>,
+    ) -> Option<i32> {
+        let v = vocabulary.borrow(py);
+        let v = v.vocab_as_ref();
+        py.allow_threads(|| self.inner.longest_allowed_token(state, v))
+    }
+
+    pub fn get_allowed_token_ids(&self, py: Python<'_>, state: i32) -> Vec<i32> {
+        py.allow_threads(|| self.inner.get_allowed_token_ids(state))
+    }
+
+    pub fn get_allowed_token_ids_u32(&self, py: Python<'_>, state: i32) -> Vec<u32> {
+        py.allow_threads(|| self.inner.get_allowed_token_ids_u32(state))
     }
 
     pub fn __repr__(&self) -> String {
@@ -344,36 +644,237 @@ impl PyLazyFSMIndex {
     }
 }
 
+/// Adapter with the method surface of outlines' `RegexGuide`,
+/// so an index can be passed wherever outlines expects a guide.
+#[pyclass(name = "RegexGuide")]
+#[derive(Clone)]
+pub struct PyRegexGuide {
+    index: PyLazyFSMIndex,
+}
+
+#[pymethods]
+impl PyRegexGuide {
+    #[new]
+    pub fn new(index: PyLazyFSMIndex) -> Self {
+        PyRegexGuide { index }
+    }
+
+    #[getter]
+    pub fn initial_state(&self) -> i32 {
+        self.index.inner.initial_state() as i32
+    }
+
+    #[getter]
+    pub fn eos_token_id(&self) -> u32 {
+        self.index.inner.eos_token_id()
+    }
+
+    #[getter]
+    pub fn index(&self) -> PyLazyFSMIndex {
+        self.index.clone()
+    }
+
+    pub fn get_next_instruction(&self, py: Python<'_>, state: i32) -> PyResult<PyObject> {
+        self.index.get_next_instruction(py, state)
+    }
+
+    pub fn get_next_state(&self, py: Python<'_>, state: i32, token_id: u32) -> i32 {
+        self.index.get_next_state(py, state, token_id).unwrap_or(-1)
+    }
+
+    pub fn is_final_state(&self, state: i32) -> bool {
+        self.index.inner.is_final_state(state)
+    }
+}
+
+/// `state_callback`, if given, is called with each state id as it finishes computing.
+/// It runs on the compute thread and must acquire the GIL for every call,
+/// so it can noticeably slow down computation of large FSMs.
+///
+/// `reserved_token_ids`, if given, are never offered at any state.
+///
+/// `max_token_len`, if given, skips longer tokens while scanning. Only safe for bounded
+/// patterns, see `ComputeOptions::max_token_len`.
+///
+/// With `lazy`, nothing is computed in the background, each state is computed by the
+/// first lookup which needs it, see `LazyFSMIndex::new_on_demand`.
+///
+/// With `byte_alphabet`, the FSM's transition keys are byte values, and tokens are walked
+/// as raw bytes, see `ComputeOptions::byte_alphabet`.
+///
+/// With `emit_stop`, strict finals give `Stop` instead of `Write([EOS])`,
+/// see `LazyFSMIndex::with_stop_instruction`.
+///
+/// With `vocab_order`, allowed tokens are listed in vocabulary order instead of by id,
+/// see `LazyFSMIndex::with_vocab_order`.
 #[pyfunction(name = "create_fsm_index_end_to_end_rs")]
-#[pyo3(text_signature = "(fsm_info, vocabulary, eos_token_id)")]
+#[pyo3(signature = (fsm_info, vocabulary, state_callback=None, reserved_token_ids=None, lazy=false, max_token_len=None, byte_alphabet=false, emit_stop=false, vocab_order=false))]
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn create_fsm_index_end_to_end_<'py>(
     py: Python<'py>,
     fsm_info: PyFSMInfo,
     vocabulary: Py<PyTokenVocabulary>,
+    state_callback: Option<PyObject>,
+    reserved_token_ids: Option<FxHashSet<u32>>,
+    lazy: bool,
+    max_token_len: Option<usize>,
+    byte_alphabet: bool,
+    emit_stop: bool,
+    vocab_order: bool,
 ) -> PyResult<PyLazyFSMIndex> {
     let f: FSMInfo = fsm_info.into();
     let v = vocabulary.borrow(py);
     let v = v.vocab_as_ref();
-    let result: Result<PyLazyFSMIndex> = (|| {
-        
-        PyLazyFSMIndex::new(f, v)
-            .context("Failed to create FSM index")
-    })();
+    let options = compute_options(reserved_token_ids, max_token_len, byte_alphabet);
+    let result: Result<PyLazyFSMIndex> = PyLazyFSMIndex::new(f, v, options, lazy)
+        .context("Failed to create FSM index");
 
-    result.map_err(|e| {
+    let mut index = result.map_err(|e| {
         PyRuntimeError::new_err(format!("FSM index creation failed: {:#}", e))
-    })
+    })?;
+    index.inner = index.inner.with_stop_instruction(emit_stop);
+    if vocab_order {
+        index.inner = index.inner.with_vocab_order(v);
+    }
+
+    if let Some(callback) = state_callback {
+        let hook: StateCallback = Arc::new(move |state| {
+            Python::with_gil(|py| {
+                if let Err(e) = callback.call1(py, (state,)) {
+                    e.print(py);
+                }
+            })
+        });
+        py.allow_threads(|| index.inner.set_state_callback(hook));
+    }
+
+    Ok(index)
+}
+
+/// Options set by the keyword arguments of `create_fsm_index_end_to_end_rs`, the rest from the environment.
+fn compute_options(
+    reserved_token_ids: Option<FxHashSet<u32>>,
+    max_token_len: Option<usize>,
+    byte_alphabet: bool,
+) -> ComputeOptions {
+    ComputeOptions {
+        reserved_token_ids: reserved_token_ids.map(|ids| ids.into_iter().collect()),
+        max_token_len,
+        byte_alphabet,
+        ..ComputeOptions::default()
+    }
+}
+
+/// Cache key `create_fsm_index_end_to_end_rs` uses for `pattern` with the same options,
+/// to check `is_cached` before building. Indexes built with
+/// `FASTER_OUTLINES_CANONICALIZE_FSM` are keyed by FSM structure instead.
+#[pyfunction(name = "get_fsm_cache_key")]
+#[pyo3(signature = (pattern, vocabulary, reserved_token_ids=None, max_token_len=None, byte_alphabet=false, eof_required_finals=None))]
+pub(crate) fn get_fsm_cache_key_(
+    py: Python<'_>,
+    pattern: &str,
+    vocabulary: Py<PyTokenVocabulary>,
+    reserved_token_ids: Option<FxHashSet<u32>>,
+    max_token_len: Option<usize>,
+    byte_alphabet: bool,
+    eof_required_finals: Option<Vec<u32>>,
+) -> u64 {
+    let v = vocabulary.borrow(py);
+    let v = v.vocab_as_ref();
+    let options = compute_options(reserved_token_ids, max_token_len, byte_alphabet);
+    let eof_required_finals = eof_required_finals.unwrap_or_default();
+    py.allow_threads(|| get_fsm_cache_key(pattern, &eof_required_finals, v, &options))
+}
+
+/// Whether an FSM is cached under `hash`, without counting as a use of the entry.
+#[pyfunction(name = "is_cached")]
+pub(crate) fn is_cached_(hash: u64) -> bool {
+    is_fsm_cached(hash)
+}
+
+/// Instruction for the first token only, computed on the calling thread without building an index.
+#[pyfunction(name = "initial_instruction")]
+pub(crate) fn initial_instruction_(
+    py: Python<'_>,
+    fsm_info: PyFSMInfo,
+    vocabulary: Py<PyTokenVocabulary>,
+) -> PyObject {
+    let f: FSMInfo = fsm_info.into();
+    let v = vocabulary.borrow(py);
+    let v = v.vocab_as_ref();
+    py.allow_threads(|| initial_instruction(&f, v)).into_py(py)
+}
+
+/// Index size estimate from the FSM structure alone, as a dict of `ComplexityReport`'s fields.
+#[pyfunction(name = "estimate_complexity")]
+pub(crate) fn estimate_complexity_(
+    py: Python<'_>,
+    fsm_info: PyFSMInfo,
+    vocabulary: Py<PyTokenVocabulary>,
+) -> PyResult<PyObject> {
+    let f: FSMInfo = fsm_info.into();
+    let v = vocabulary.borrow(py);
+    let v = v.vocab_as_ref();
+    let report = py.allow_threads(|| estimate_complexity(&f, v));
+
+    let dict = PyDict::new_bound(py);
+    dict.set_item("num_states", report.num_states)?;
+    dict.set_item("num_reachable", report.num_reachable)?;
+    dict.set_item("has_wildcard", report.has_wildcard)?;
+    dict.set_item("estimated_max_transitions", report.estimated_max_transitions)?;
+    Ok(dict.into_py(py))
+}
+
+/// Threads computing the states of one index, as resolved from `FASTER_OUTLINES_COMPUTE_THREADS`.
+#[pyfunction(name = "effective_num_threads")]
+pub(crate) fn effective_num_threads_() -> usize {
+    *COMPUTE_THREADS
+}
+
+/// The cache entry for `hash` as a dict of `CachedFSMSnapshot`'s fields, or None.
+#[pyfunction(name = "get_cached_fsm")]
+pub(crate) fn get_cached_fsm_(py: Python<'_>, hash: u64) -> PyResult<Option<PyObject>> {
+    let Some(snapshot) = py.allow_threads(|| cached_fsm_snapshot(hash)) else {
+        return Ok(None);
+    };
+
+    let dict = PyDict::new_bound(py);
+    dict.set_item("pattern", snapshot.pattern)?;
+    dict.set_item("first_state", snapshot.first_state)?;
+    dict.set_item("finals", snapshot.finals)?;
+    dict.set_item("vocab_len", snapshot.vocab_len)?;
+    dict.set_item("eos_token_id", snapshot.eos_token_id)?;
+    dict.set_item("states_to_token_maps", snapshot.states_to_token_maps)?;
+    Ok(Some(dict.into_py(py)))
+}
+
+/// Lists `(hash, pattern)` for every FSM currently in the cache.
+#[pyfunction(name = "list_cached_patterns")]
+pub(crate) fn list_cached_patterns_() -> Vec<(u64, String)> {
+    list_cached_patterns()
 }
 
 #[pymodule]
 pub fn lib(m: &Bound<'_, PyModule>) -> PyResult<()> {
     Lazy::force(&MODULE_STATE);
     m.add_function(wrap_pyfunction!(create_fsm_index_end_to_end_, m)?)?;
+    m.add_function(wrap_pyfunction!(list_cached_patterns_, m)?)?;
+    m.add_function(wrap_pyfunction!(get_fsm_cache_key_, m)?)?;
+    m.add_function(wrap_pyfunction!(is_cached_, m)?)?;
+    m.add_function(wrap_pyfunction!(get_cached_fsm_, m)?)?;
+    m.add_function(wrap_pyfunction!(initial_instruction_, m)?)?;
+    m.add_function(wrap_pyfunction!(estimate_complexity_, m)?)?;
+    m.add_function(wrap_pyfunction!(effective_num_threads_, m)?)?;
 
     m.add_class::<PyFSMInfo>()?;
     m.add_class::<PyLazyFSMIndex>()?;
+    m.add_class::<PyRegexGuide>()?;
     m.add_class::<PyTokenVocabulary>()?;
     m.add_class::<PyWrite>()?;
     m.add_class::<PyGenerate>()?;
+    m.add_class::<PyStop>()?;
+    m.add("StateOutOfBoundsError", m.py().get_type_bound::<exceptions::StateOutOfBoundsError>())?;
+    m.add("StateNotComputedError", m.py().get_type_bound::<exceptions::StateNotComputedError>())?;
+    m.add("ComputeCancelledError", m.py().get_type_bound::<exceptions::ComputeCancelledError>())?;
     Ok(())
 }
@@ -21,46 +21,320 @@
 */
 
 use crate::{
-    environment::{DISABLE_CACHE, FSM_CACHE_SIZE},
-    types::ThreadSafeCell,
+    environment::{DISABLE_CACHE, FSM_CACHE_SHARDS, FSM_CACHE_SIZE},
+    tokenizer_index::LazyStateScanner,
+    types::{StateCompletionLog, StateNotifierMap, StatesToTokenMaps},
+    vocab::TokenVocabulary,
 };
 use lru::LruCache;
 use once_cell::sync::Lazy;
 use rustc_hash::FxHashMap;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone)]
 pub(crate) struct CachedFSM {
-    pub states_to_token_maps: Arc<Vec<ThreadSafeCell<FxHashMap<u32, u32>>>>,
+    pub states_to_token_maps: StatesToTokenMaps,
     pub first_state: u32,
     pub finals: Vec<u32>,
     pub hash: u64,
+    /// Pattern the entry was built for, kept for `list_cached_patterns`.
+    pub pattern: String,
+    /// Full length of the vocabulary the maps were built from.
+    /// The cache key only samples the vocabulary, so this and `eos_token_id`
+    /// are checked on retrieval to catch key collisions.
+    pub vocab_len: usize,
+    pub eos_token_id: u32,
+    /// Completion state handed to every index built from this entry. Every state is
+    /// finished, so these are never written again and all hits can share them
+    /// instead of allocating a notifier per state each time.
+    pub state_notifiers: StateNotifierMap,
+    pub completion_log: Arc<StateCompletionLog>,
+    pub alphabet_symbol_mapping: Arc<FxHashMap<char, u32>>,
+    /// Vocabulary the maps were built from, for scanning states no token leads to.
+    pub state_scanner: Arc<LazyStateScanner>,
+}
+
+impl CachedFSM {
+    /// Wraps finished maps into a cache entry, marking every state as computed.
+    pub fn new(
+        states_to_token_maps: StatesToTokenMaps,
+        first_state: u32,
+        finals: Vec<u32>,
+        hash: u64,
+        pattern: String,
+        state_scanner: Arc<LazyStateScanner>,
+        alphabet_symbol_mapping: Arc<FxHashMap<char, u32>>,
+    ) -> Self {
+        let vocabulary = state_scanner.vocabulary();
+        let num_states = states_to_token_maps.len();
+        let state_notifiers: StateNotifierMap =
+            Arc::new((0..num_states).map(|_| Arc::new(AtomicBool::new(true))).collect());
+        let completion_log = StateCompletionLog::with_capacity(num_states);
+        for state in 0..num_states {
+            completion_log.push(state as u32);
+        }
+
+        CachedFSM {
+            states_to_token_maps,
+            first_state,
+            finals,
+            hash,
+            pattern,
+            vocab_len: vocabulary.len(),
+            eos_token_id: vocabulary.eos_token_id,
+            state_notifiers,
+            completion_log: Arc::new(completion_log),
+            alphabet_symbol_mapping,
+            state_scanner,
+        }
+    }
+
+    /// Cheap integrity check that this entry was built for `vocabulary`.
+    fn matches(&self, hash: u64, vocabulary: &TokenVocabulary) -> bool {
+        self.hash == hash
+            && self.vocab_len == vocabulary.len()
+            && self.eos_token_id == vocabulary.eos_token_id
+    }
+}
+
+/// Contents of a cache entry, for schedulers which look into the cache, see `cached_fsm_snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedFSMSnapshot {
+    pub pattern: String,
+    pub first_state: u32,
+    pub finals: Vec<u32>,
+    /// Length and EOS token of the vocabulary the entry was built from.
+    pub vocab_len: usize,
+    pub eos_token_id: u32,
+    /// `{state: {token_id: next_state}}`, holding the states with at least one
+    /// allowed token, like `LazyFSMIndex::to_outlines_states_to_token_maps`.
+    pub states_to_token_maps: FxHashMap<u32, FxHashMap<u32, u32>>,
+}
+
+impl From<&CachedFSM> for CachedFSMSnapshot {
+    fn from(cached_fsm: &CachedFSM) -> Self {
+        let states_to_token_maps = cached_fsm
+            .states_to_token_maps
+            .iter()
+            .zip(cached_fsm.state_notifiers.iter())
+            .enumerate()
+            .filter_map(|(state, (cell, notifier))| {
+                cell.read_after(notifier)
+                    .filter(|state_map| !state_map.is_empty())
+                    .map(|state_map| (state as u32, FxHashMap::clone(state_map)))
+            })
+            .collect();
+        CachedFSMSnapshot {
+            pattern: cached_fsm.pattern.clone(),
+            first_state: cached_fsm.first_state,
+            finals: cached_fsm.finals.clone(),
+            vocab_len: cached_fsm.vocab_len,
+            eos_token_id: cached_fsm.eos_token_id,
+            states_to_token_maps,
+        }
+    }
 }
 
 pub(crate) struct ModuleState {
-    pub fsm_cache: Mutex<LruCache<u64, Arc<CachedFSM>>>,
+    /// LRU cache split into independently locked shards, see `FASTER_OUTLINES_CACHE_SHARDS`.
+    pub fsm_cache: Vec<Mutex<LruCache<u64, Arc<CachedFSM>>>>,
+}
+
+impl ModuleState {
+    /// Splits `cache_size` entries over `shards` LRUs, rounding each shard's capacity up.
+    pub fn new(cache_size: usize, shards: usize) -> Self {
+        let shards = shards.max(1);
+        let shard_size = std::num::NonZeroUsize::new(cache_size.div_ceil(shards).max(1)).unwrap();
+        ModuleState {
+            fsm_cache: (0..shards).map(|_| Mutex::new(LruCache::new(shard_size))).collect(),
+        }
+    }
+
+    fn shard(&self, key: u64) -> &Mutex<LruCache<u64, Arc<CachedFSM>>> {
+        &self.fsm_cache[(key % self.fsm_cache.len() as u64) as usize]
+    }
+
+    pub fn get(&self, hash: u64, vocabulary: &TokenVocabulary) -> Option<Arc<CachedFSM>> {
+        let mut cache = self.shard(hash).lock().unwrap();
+        match cache.get(&hash) {
+            Some(cached_fsm) if cached_fsm.matches(hash, vocabulary) => Some(Arc::clone(cached_fsm)),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, cached_fsm: CachedFSM, cache_key: u64) {
+        let mut cache = self.shard(cache_key).lock().unwrap();
+        cache.put(cache_key, Arc::new(cached_fsm));
+    }
+
+    /// The entry for `hash`, whichever vocabulary it was built from.
+    /// Does not count as a use, so eviction order is unchanged.
+    pub fn peek(&self, hash: u64) -> Option<Arc<CachedFSM>> {
+        self.shard(hash).lock().unwrap().peek(&hash).cloned()
+    }
+
+    /// Whether there is an entry for `hash`, whichever vocabulary it was built from.
+    /// Does not count as a use, so eviction order is unchanged.
+    pub fn contains(&self, hash: u64) -> bool {
+        self.shard(hash).lock().unwrap().contains(&hash)
+    }
+
+    /// `(hash, pattern)` of every entry, shard by shard, most recently used first within
+    /// a shard. Does not count as a use, so eviction order is unchanged.
+    pub fn list(&self) -> Vec<(u64, String)> {
+        self.fsm_cache
+            .iter()
+            .flat_map(|shard| {
+                let cache = shard.lock().unwrap();
+                cache
+                    .iter()
+                    .map(|(&hash, cached_fsm)| (hash, cached_fsm.pattern.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
 }
 
-pub(crate) static MODULE_STATE: Lazy<ModuleState> = Lazy::new(|| ModuleState {
-    fsm_cache: Mutex::new(LruCache::new(
-        std::num::NonZeroUsize::new(*FSM_CACHE_SIZE).unwrap(),
-    )),
-});
+pub(crate) static MODULE_STATE: Lazy<ModuleState> =
+    Lazy::new(|| ModuleState::new(*FSM_CACHE_SIZE, *FSM_CACHE_SHARDS));
 
-pub fn get_cached_fsm(hash: u64) -> Option<Arc<CachedFSM>> {
+/// Looks up a cached FSM, treating entries built from a different
+/// vocabulary than `vocabulary` (a key collision) as a cache miss.
+pub fn get_cached_fsm(hash: u64, vocabulary: &TokenVocabulary) -> Option<Arc<CachedFSM>> {
     if *DISABLE_CACHE {
         return None;
     }
 
-    let mut cache = MODULE_STATE.fsm_cache.lock().unwrap();
-    if let Some(cached_fsm) = cache.get(&hash) {
-        Some(Arc::clone(cached_fsm))
-    } else {
-        None
+    MODULE_STATE.get(hash, vocabulary)
+}
+
+/// Whether an FSM is cached under `hash`, e.g. one from `get_fsm_cache_key`,
+/// so callers can decide where to build before paying for it.
+pub fn is_fsm_cached(hash: u64) -> bool {
+    if *DISABLE_CACHE {
+        return false;
+    }
+
+    MODULE_STATE.contains(hash)
+}
+
+/// Copies out the cache entry for `hash`, if any, without counting as a use of it.
+///
+/// The entry itself stays shared with the indexes built from it, only its maps are cloned.
+pub fn cached_fsm_snapshot(hash: u64) -> Option<CachedFSMSnapshot> {
+    if *DISABLE_CACHE {
+        return None;
     }
+
+    MODULE_STATE.peek(hash).map(|cached_fsm| CachedFSMSnapshot::from(cached_fsm.as_ref()))
 }
 
 pub fn insert_fsm_to_cache(cached_fsm: CachedFSM, cache_key: u64) {
-    let mut cache = MODULE_STATE.fsm_cache.lock().unwrap();
-    cache.put(cache_key, Arc::new(cached_fsm));
+    MODULE_STATE.insert(cached_fsm, cache_key);
+}
+
+/// Lists the `(hash, pattern)` of every cached FSM, for checking what is actually
+/// cached when outputs look stale.
+pub fn list_cached_patterns() -> Vec<(u64, String)> {
+    MODULE_STATE.list()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ThreadSafeCell;
+    use std::time::{Duration, Instant};
+
+    fn entry(hash: u64, vocabulary: &TokenVocabulary) -> CachedFSM {
+        let maps = Arc::new(vec![ThreadSafeCell::new(Arc::default())]);
+        CachedFSM::new(
+            maps,
+            0,
+            vec![0],
+            hash,
+            format!("pattern_{}", hash),
+            Arc::new(LazyStateScanner::new(Arc::new(vocabulary.clone()))),
+            Arc::new(FxHashMap::default()),
+        )
+    }
+
+    #[test]
+    fn test_shards_evict_independently() {
+        let vocab = TokenVocabulary::default();
+        // Two shards of two entries each, even keys in shard 0, odd keys in shard 1.
+        let state = ModuleState::new(4, 2);
+        for key in [1, 0, 2, 4] {
+            state.insert(entry(key, &vocab), key);
+        }
+
+        // Shard 0 evicted its least recently used key, shard 1 kept its only one.
+        assert!(state.get(0, &vocab).is_none());
+        for key in [1, 2, 4] {
+            assert!(state.get(key, &vocab).is_some(), "key {}", key);
+        }
+    }
+
+    #[test]
+    fn test_list_cached_patterns() {
+        let vocab = TokenVocabulary::default();
+        let state = ModuleState::new(4, 2);
+        for key in [3, 8, 5] {
+            state.insert(entry(key, &vocab), key);
+        }
+
+        let mut listed = state.list();
+        listed.sort();
+        assert_eq!(
+            listed,
+            vec![
+                (3, "pattern_3".to_string()),
+                (5, "pattern_5".to_string()),
+                (8, "pattern_8".to_string()),
+            ]
+        );
+    }
+
+    /// Total time `threads` threads spend waiting on cache locks,
+    /// each looking up and inserting its own keys.
+    fn lock_wait_time(state: &ModuleState, threads: u64, ops: u64) -> Duration {
+        let vocab = TokenVocabulary::default();
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|thread| {
+                    let vocab = &vocab;
+                    scope.spawn(move || {
+                        let mut waited = Duration::ZERO;
+                        for op in 0..ops {
+                            let key = op * threads + thread;
+                            let start = Instant::now();
+                            let mut cache = state.shard(key).lock().unwrap();
+                            waited += start.elapsed();
+                            if cache.get(&key).is_none() {
+                                cache.put(key, Arc::new(entry(key, vocab)));
+                            }
+                        }
+                        waited
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).sum()
+        })
+    }
+
+    // cargo test --release bench_sharded_cache_lock_wait -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_sharded_cache_lock_wait() {
+        let threads = 8;
+        let ops = 20_000;
+        for shards in [1, 4, 16] {
+            let state = ModuleState::new(1024, shards);
+            let waited = lock_wait_time(&state, threads, ops);
+            println!(
+                "{} shard(s): {:?} total lock wait over {} threads x {} ops",
+                shards, waited, threads, ops
+            );
+        }
+    }
 }
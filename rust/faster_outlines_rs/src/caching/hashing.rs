@@ -20,23 +20,35 @@
 * THE SOFTWARE.
 */
 
-use crate::vocab::TokenVocabulary;
+use crate::{
+    environment::HASH_SAMPLE_SIZE,
+    types::{ComputeOptions, FSMInfo},
+    vocab::TokenVocabulary,
+};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
 // Since iterating threw the entire vocab and getting a hash for it would be too costly,
 // we do the following hash function:
-//     1. Get the first 100 tokens of vocab and hash them.
+//     1. Get the first `HASH_SAMPLE_SIZE` (default 100) tokens of vocab and hash them.
 //     2. Hash the length of tokenizer.
-//     3. Hash both length and hash of first 100 tokens together to get a combined hash.
+//     3. Hash both length and hash of the sampled tokens together to get a combined hash.
 //
 // This takes only nearly no time, where hashing the whole vocab of 128k tokens can take up to 128ms, which is way too long
+// for the default. Vocabs which only differ past the sample can collide though, so setting
+// `FASTER_OUTLINES_HASH_SAMPLE=0` hashes the whole vocab instead.
 pub fn hash_token_vocabulary(vocabulary: &TokenVocabulary) -> u64 {
+    hash_token_vocabulary_sampled(vocabulary, *HASH_SAMPLE_SIZE)
+}
+
+/// Same as `hash_token_vocabulary`, with an explicit sample size. A `sample_size` of 0 hashes every token.
+pub fn hash_token_vocabulary_sampled(vocabulary: &TokenVocabulary, sample_size: usize) -> u64 {
     let mut hasher = DefaultHasher::new();
     vocabulary.len().hash(&mut hasher);
+    vocabulary.byte_tokens.hash(&mut hasher);
 
-    if vocabulary.len() > 100 {
-        let partition_key = vocabulary.iter().map(|(k, _)| k).nth(99).unwrap();
+    if sample_size != 0 && vocabulary.len() > sample_size {
+        let partition_key = vocabulary.iter().map(|(k, _)| k).nth(sample_size - 1).unwrap();
 
         for (key, value) in vocabulary.iter().filter(|(k, _)| k <= &partition_key) {
             key.hash(&mut hasher);
@@ -52,12 +64,66 @@ pub fn hash_token_vocabulary(vocabulary: &TokenVocabulary) -> u64 {
     hasher.finish()
 }
 
-pub fn get_fsm_cache_key(pattern: &str, vocabulary: &TokenVocabulary) -> u64 {
+/// Cache key of the index for `pattern` over `vocabulary`.
+///
+/// `eof_required_finals` are those of the FSM. They change the index without
+/// necessarily changing the pattern string, so they are keyed along with it.
+pub fn get_fsm_cache_key(
+    pattern: &str,
+    eof_required_finals: &[u32],
+    vocabulary: &TokenVocabulary,
+    options: &ComputeOptions,
+) -> u64 {
     let vocab_hash = hash_token_vocabulary(vocabulary);
     let mut hasher = DefaultHasher::new();
 
     pattern.hash(&mut hasher);
+    let mut eof_required_finals = eof_required_finals.to_vec();
+    eof_required_finals.sort_unstable();
+    eof_required_finals.hash(&mut hasher);
+    vocab_hash.hash(&mut hasher);
+    options.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Same as `get_fsm_cache_key`, but hashing the FSM's structure in place of its pattern.
+///
+/// Meant for FSMs from `FSMInfo::canonicalize`, where equivalent patterns have identical
+/// structure. For other FSMs, equal keys still mean the indexes are interchangeable.
+pub fn get_fsm_structure_cache_key(
+    fsm_info: &FSMInfo,
+    vocabulary: &TokenVocabulary,
+    options: &ComputeOptions,
+) -> u64 {
+    let vocab_hash = hash_token_vocabulary(vocabulary);
+    let mut hasher = DefaultHasher::new();
+
+    fsm_info.initial.hash(&mut hasher);
+    let mut finals = fsm_info.finals.clone();
+    finals.sort_unstable();
+    finals.hash(&mut hasher);
+    let mut eof_required_finals = fsm_info.eof_required_finals.clone();
+    eof_required_finals.sort_unstable();
+    eof_required_finals.hash(&mut hasher);
+
+    let mut alphabet: Vec<(&String, &u32)> = fsm_info.alphabet_symbol_mapping.iter().collect();
+    alphabet.sort_unstable();
+    alphabet.hash(&mut hasher);
+    fsm_info.alphabet_anything_value.hash(&mut hasher);
+
+    let max_key = alphabet
+        .iter()
+        .map(|&(_, &key)| key)
+        .fold(fsm_info.alphabet_anything_value, u32::max) as usize;
+    for state in fsm_info.transitions.states() {
+        for key in 0..=max_key {
+            fsm_info.transitions.get_transition(state, key).hash(&mut hasher);
+        }
+    }
+
     vocab_hash.hash(&mut hasher);
+    options.hash(&mut hasher);
 
     hasher.finish()
 }
@@ -83,3 +149,82 @@ fn test_hash_token_vocabulary() {
 
     assert_ne!(hash1, hash2);
 }
+
+#[test]
+fn test_full_hash_sees_tokens_past_sample() {
+    let mut vocab = TokenVocabulary::default();
+    for i in 0..150 {
+        vocab.add_token(format!("{:03}", i), vec![i as u32]);
+    }
+
+    // Only token 149 differs, which lies outside a 100 token sample.
+    let mut changed = vocab.clone();
+    changed.values[149] = vec![999];
+
+    assert_eq!(
+        hash_token_vocabulary_sampled(&vocab, 100),
+        hash_token_vocabulary_sampled(&changed, 100)
+    );
+    assert_ne!(
+        hash_token_vocabulary_sampled(&vocab, 0),
+        hash_token_vocabulary_sampled(&changed, 0)
+    );
+}
+
+#[test]
+fn test_equivalent_patterns_share_structure_key() {
+    use crate::types::FSMInfoBuilder;
+
+    let vocab = TokenVocabulary::default();
+    let options = ComputeOptions::default();
+
+    // "a|b" as interegular leaves it, one final per branch.
+    let a_or_b = FSMInfoBuilder::new()
+        .alphabet_symbol('a', 0)
+        .alphabet_symbol('b', 1)
+        .anything_value(2)
+        .add_transition(0, 0, 1)
+        .add_transition(0, 1, 2)
+        .add_final(1)
+        .add_final(2)
+        .pattern("a|b".to_string())
+        .build()
+        .unwrap();
+    // "[ab]", with a third symbol which behaves like the anything value.
+    let ab_class = FSMInfoBuilder::new()
+        .alphabet_symbol('a', 1)
+        .alphabet_symbol('b', 1)
+        .alphabet_symbol('c', 2)
+        .anything_value(0)
+        .add_transition(0, 1, 1)
+        .add_final(1)
+        .pattern("[ab]".to_string())
+        .build()
+        .unwrap();
+
+    assert_ne!(
+        get_fsm_cache_key(&a_or_b.pattern, &[], &vocab, &options),
+        get_fsm_cache_key(&ab_class.pattern, &[], &vocab, &options)
+    );
+    let (a_or_b, ab_class) = (a_or_b.canonicalize().unwrap(), ab_class.canonicalize().unwrap());
+    assert_eq!(
+        get_fsm_structure_cache_key(&a_or_b, &vocab, &options),
+        get_fsm_structure_cache_key(&ab_class, &vocab, &options)
+    );
+
+    let ab = FSMInfoBuilder::new()
+        .alphabet_symbol('a', 0)
+        .alphabet_symbol('b', 1)
+        .add_transition(0, 0, 1)
+        .add_transition(1, 1, 2)
+        .add_final(2)
+        .pattern("ab".to_string())
+        .build()
+        .unwrap()
+        .canonicalize()
+        .unwrap();
+    assert_ne!(
+        get_fsm_structure_cache_key(&a_or_b, &vocab, &options),
+        get_fsm_structure_cache_key(&ab, &vocab, &options)
+    );
+}
@@ -20,6 +20,7 @@
 * THE SOFTWARE.
 */
 
+#[allow(clippy::module_inception)]
 mod caching;
 mod hashing;
 pub use caching::*;
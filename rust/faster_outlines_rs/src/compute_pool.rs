@@ -0,0 +1,222 @@
+/* The MIT License (MIT)
+* Copyright (c) 2024 Nathan Hoos
+*
+* Permission is hereby granted, free of charge, to any person obtaining a copy
+* of this software and associated documentation files (the "Software"), to deal
+* in the Software without restriction, including without limitation the rights
+* to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+* copies of the Software, and to permit persons to whom the Software is
+* furnished to do so, subject to the following conditions:
+* 
+* The above copyright notice and this permission notice shall be included in
+* all copies or substantial portions of the Software.
+* 
+* THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+* IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+* FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+* AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+* LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+* OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+* THE SOFTWARE.
+*/
+
+use crate::atomic_wait::platform::wait;
+use crate::environment::MAX_COMPUTE_THREADS;
+use once_cell::sync::Lazy;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Runs background FSM computations on at most `max_threads` OS threads at a time.
+///
+/// Jobs submitted while every slot is busy are queued, not spawned, and run by the
+/// next thread to free up. Threads only exist while there is work, so an idle pool
+/// holds no threads. This is a counting semaphore around `thread::spawn`,
+/// where waiting happens in the queue instead of in blocked threads.
+///
+/// A job blocked in `wait_while_set`, e.g. a paused computation, gives its slot up
+/// meanwhile, so paused indexes never starve the queue.
+pub(crate) struct ComputePool {
+    max_threads: usize,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    /// Signaled whenever a slot is given up, for jobs waiting to take theirs back.
+    slot_freed: Condvar,
+}
+
+struct PoolState {
+    running: usize,
+    /// Jobs done waiting in `wait_while_set`, which get the next free slot before the queue.
+    resuming: usize,
+    queue: VecDeque<Job>,
+}
+
+thread_local! {
+    /// The pool and bound of the job running on this thread, if it is a pool thread.
+    static CURRENT_POOL: RefCell<Option<(Arc<Shared>, usize)>> = const { RefCell::new(None) };
+}
+
+impl ComputePool {
+    pub fn new(max_threads: usize) -> Self {
+        ComputePool {
+            max_threads: max_threads.max(1),
+            shared: Arc::new(Shared {
+                state: Mutex::new(PoolState {
+                    running: 0,
+                    resuming: 0,
+                    queue: VecDeque::new(),
+                }),
+                slot_freed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Runs `job` on a background thread, or queues it if `max_threads` are already busy.
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.running >= self.max_threads || state.resuming > 0 {
+            state.queue.push_back(Box::new(job));
+            return;
+        }
+        state.running += 1;
+        drop(state);
+
+        start_thread(Arc::clone(&self.shared), self.max_threads, Box::new(job));
+    }
+}
+
+/// Runs `job` and then queued jobs on a new thread holding one slot of the pool.
+fn start_thread(shared: Arc<Shared>, max_threads: usize, job: Job) {
+    thread::spawn(move || {
+        CURRENT_POOL.with(|current| *current.borrow_mut() = Some((Arc::clone(&shared), max_threads)));
+        let mut job = job;
+        loop {
+            job();
+            let mut state = shared.state.lock().unwrap();
+            if state.resuming > 0 {
+                state.running -= 1;
+                shared.slot_freed.notify_one();
+                return;
+            }
+            match state.queue.pop_front() {
+                Some(next) => job = next,
+                None => {
+                    state.running -= 1;
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Blocks while `flag` is set, like `wait(flag, true)`.
+///
+/// Called from a job of a `ComputePool`, the job's slot goes to the next queued job
+/// meanwhile, and is taken back once `flag` clears, waiting for a free slot if every
+/// one is busy by then. Anywhere else, e.g. on a thread of a blocking build, it only waits.
+pub(crate) fn wait_while_set(flag: &AtomicBool) {
+    if !flag.load(Ordering::Acquire) {
+        return;
+    }
+    let Some((shared, max_threads)) = CURRENT_POOL.with(|current| current.borrow().clone()) else {
+        wait(flag, true);
+        return;
+    };
+
+    {
+        let mut state = shared.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(next) => start_thread(Arc::clone(&shared), max_threads, next),
+            None => {
+                state.running -= 1;
+                shared.slot_freed.notify_one();
+            }
+        }
+    }
+
+    wait(flag, true);
+
+    let mut state = shared.state.lock().unwrap();
+    state.resuming += 1;
+    while state.running >= max_threads {
+        state = shared.slot_freed.wait(state).unwrap();
+    }
+    state.resuming -= 1;
+    state.running += 1;
+}
+
+/// Pool every `LazyFSMIndex` computes on, bounded by `FASTER_OUTLINES_MAX_COMPUTE_THREADS`.
+pub(crate) static COMPUTE_POOL: Lazy<ComputePool> =
+    Lazy::new(|| ComputePool::new(MAX_COMPUTE_THREADS.unwrap_or(usize::MAX)));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_concurrency_never_exceeds_bound() {
+        let max_threads = 3;
+        let pool = ComputePool::new(max_threads);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let num_jobs = 50;
+        for _ in 0..num_jobs {
+            let (running, peak, done_tx) = (Arc::clone(&running), Arc::clone(&peak), done_tx.clone());
+            pool.spawn(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(2));
+                running.fetch_sub(1, Ordering::SeqCst);
+                done_tx.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..num_jobs {
+            done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= max_threads);
+
+        // Jobs report done just before their thread gives up its slot.
+        for _ in 0..1000 {
+            if pool.shared.state.lock().unwrap().running == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pool.shared.state.lock().unwrap().running, 0);
+    }
+
+    #[test]
+    fn test_paused_job_gives_up_its_slot() {
+        let pool = ComputePool::new(1);
+        let paused = Arc::new(AtomicBool::new(true));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        {
+            let (paused, done_tx) = (Arc::clone(&paused), done_tx.clone());
+            pool.spawn(move || {
+                wait_while_set(&paused);
+                done_tx.send("paused").unwrap();
+            });
+        }
+        // Queued behind the paused job, it only runs if that job released the slot.
+        pool.spawn(move || done_tx.send("queued").unwrap());
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(10)).unwrap(), "queued");
+
+        paused.store(false, Ordering::Release);
+        crate::atomic_wait::platform::wake_all(&*paused);
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(10)).unwrap(), "paused");
+    }
+}
@@ -54,6 +54,33 @@ pub static FSM_CACHE_SIZE: Lazy<usize> = Lazy::new(|| {
         .unwrap_or(50)
 });
 
+/// Number of independently locked shards the FSM cache is split into.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_CACHE_SHARDS` environment variable.
+/// Defaults to 1, a single LRU behind one lock. 0 is treated as 1.
+///
+/// ```bash
+/// export FASTER_OUTLINES_CACHE_SHARDS=[INTEGER]
+/// ```
+///
+/// # Behavior
+/// Each key goes to shard `key % shards`, and each shard is its own LRU holding
+/// `ceil(FASTER_OUTLINES_CACHE_SIZE / shards)` FSMs. Threads looking up different
+/// patterns then mostly take different locks, which helps servers building many
+/// distinct patterns concurrently.
+///
+/// # Tradeoff
+/// Eviction is per shard, so with more than one shard the least recently used FSM
+/// overall is not necessarily the one evicted, and total capacity rounds up.
+pub static FSM_CACHE_SHARDS: Lazy<usize> = Lazy::new(|| {
+    env::var("FASTER_OUTLINES_CACHE_SHARDS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1)
+        .max(1)
+});
+
 /// Global flag to disable the FSM caching system.
 ///
 /// # Environment Configuration
@@ -70,7 +97,8 @@ pub static FSM_CACHE_SIZE: Lazy<usize> = Lazy::new(|| {
 ///   - "1"
 ///   - "true"
 ///   - "yes"
-///   Case insensitive for all values
+///
+/// Case insensitive for all values
 ///
 /// control the env var like so:
 /// ```bash
@@ -90,16 +118,239 @@ pub static FSM_CACHE_SIZE: Lazy<usize> = Lazy::new(|| {
 /// 4. Ensuring deterministic behavior
 ///
 /// # Logging Behavior
-/// Prints confirmation message to stdout when cache is disabled
-pub static DISABLE_CACHE: Lazy<bool> =
-    Lazy::new(|| match env::var("FASTER_OUTLINES_DISABLE_CACHE") {
+/// Logs a confirmation message at info level through the `log` crate when cache is disabled.
+/// Nothing is written to stdout; embedders see it only if they install a logger.
+pub static DISABLE_CACHE: Lazy<bool> = Lazy::new(|| {
+    let is_disabled = env_flag("FASTER_OUTLINES_DISABLE_CACHE");
+    if is_disabled {
+        log_cache_disabled();
+    }
+    is_disabled
+});
+
+fn log_cache_disabled() {
+    log::info!("Cache is disabled via DISABLE_CACHE environment variable.");
+}
+
+/// Global flag forcing FSM index computation onto the calling thread.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_FORCE_SEQUENTIAL` environment variable.
+/// Accepts the same values as `FASTER_OUTLINES_DISABLE_CACHE`.
+///
+/// ```bash
+/// export FASTER_OUTLINES_FORCE_SEQUENTIAL=1
+/// ```
+///
+/// # Behavior
+/// When enabled, `LazyFSMIndex::new` computes every state before returning
+/// instead of handing the work to a background thread. The resulting index
+/// is identical to the threaded one, it is just fully computed up front.
+///
+/// # When to Enable
+/// This is intended for benchmarking and debugging only. It gives timings
+/// that are not perturbed by thread scheduling, which makes apples-to-apples
+/// comparisons against outlines possible. Do not enable it in production,
+/// as it removes the lazy behavior that makes the first states available early.
+pub static FORCE_SEQUENTIAL: Lazy<bool> =
+    Lazy::new(|| env_flag("FASTER_OUTLINES_FORCE_SEQUENTIAL"));
+
+/// Lossy cap on the number of tokens recorded per wildcard state.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_MAX_TOKENS_PER_STATE` environment variable.
+/// Unset (the default) means no cap.
+///
+/// ```bash
+/// export FASTER_OUTLINES_MAX_TOKENS_PER_STATE=[INTEGER]
+/// ```
+///
+/// # Behavior
+/// Patterns like `".{1,10}"` take the `alphabet_anything_value` transition for
+/// nearly every character, so each of their states accepts almost the whole vocabulary.
+/// With the cap set, states which have a wildcard transition only keep a representative
+/// subset of their allowed tokens: one token per distinct end state, then the
+/// lowest token ids up to the cap. The EOS token is always kept.
+///
+/// # Warning
+/// This is lossy. Tokens which are valid for the pattern get dropped,
+/// trading completeness of the constraint for memory.
+pub static MAX_TOKENS_PER_STATE: Lazy<Option<usize>> = Lazy::new(|| {
+    env::var("FASTER_OUTLINES_MAX_TOKENS_PER_STATE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+});
+
+/// Number of threads computing the states of a single FSM index.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_COMPUTE_THREADS` environment variable.
+/// Defaults to 1, the single background compute thread.
+///
+/// ```bash
+/// export FASTER_OUTLINES_COMPUTE_THREADS=[INTEGER]
+/// ```
+///
+/// # Behavior
+/// With more than one thread, the reachable states are computed by a pool of
+/// workers sharing a work-stealing BFS frontier, instead of one state at a time.
+/// The resulting index is identical, states just finish in a different order.
+/// This mostly helps branchy patterns, where many states are reachable at once.
+/// A linear pattern only ever has one state on the frontier, so extra threads sit idle.
+/// Sequential builds, see `FASTER_OUTLINES_FORCE_SEQUENTIAL`, ignore it and never spawn workers.
+pub static COMPUTE_THREADS: Lazy<usize> = Lazy::new(|| {
+    env::var("FASTER_OUTLINES_COMPUTE_THREADS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1)
+});
+
+/// Global bound on the number of background threads computing FSM indexes.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_MAX_COMPUTE_THREADS` environment variable.
+/// Unset (the default) means no bound, every index gets its own thread.
+///
+/// ```bash
+/// export FASTER_OUTLINES_MAX_COMPUTE_THREADS=[INTEGER]
+/// ```
+///
+/// # Behavior
+/// Each `LazyFSMIndex::new` which misses the cache computes on a background thread.
+/// Under a burst of requests that can mean hundreds of OS threads fighting over the cores.
+/// With the bound set, indexes created while every thread is busy are queued, and
+/// computed in order as threads free up. Their states can still be awaited as usual,
+/// the wait just includes the time spent in the queue.
+///
+/// This bounds indexes, not threads per index: with `FASTER_OUTLINES_COMPUTE_THREADS`
+/// above 1, each running index uses that many workers.
+/// Paused indexes, see `LazyFSMIndex::pause`, give their thread up until resumed.
+pub static MAX_COMPUTE_THREADS: Lazy<Option<usize>> = Lazy::new(|| {
+    env::var("FASTER_OUTLINES_MAX_COMPUTE_THREADS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+});
+
+/// Upper bound on the number of cells in an FSM's dense transition table.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_MAX_TRANSITION_CELLS` environment variable.
+/// Defaults to 2^26 cells, 256 MiB of `u32`s.
+///
+/// ```bash
+/// export FASTER_OUTLINES_MAX_TRANSITION_CELLS=[INTEGER]
+/// ```
+///
+/// # Behavior
+/// The table holds `(max_state_id + 1) * (max_transition_key + 1)` cells. Building
+/// an `FSMInfo` whose table would be larger fails with an error instead of allocating.
+/// Real patterns stay far below the default, hitting it usually means the state ids
+/// were numbered sparsely or by a buggy converter.
+pub static MAX_TRANSITION_CELLS: Lazy<usize> = Lazy::new(|| {
+    env::var("FASTER_OUTLINES_MAX_TRANSITION_CELLS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1 << 26)
+});
+
+/// Number of tokens sampled when hashing a vocabulary for the FSM cache key.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_HASH_SAMPLE` environment variable.
+/// Defaults to 100. Set to 0 to hash the whole vocabulary.
+///
+/// ```bash
+/// export FASTER_OUTLINES_HASH_SAMPLE=[INTEGER]
+/// ```
+///
+/// # Latency vs Safety
+/// - Small sample → Hashing takes nearly no time, but two vocabs which only differ
+///   in tokens outside the sample (e.g. two checkpoints differing in late added tokens)
+///   get the same hash and share cached FSMs, which is wrong.
+/// - 0 → Every token is hashed, so such vocabs never collide, at the cost of
+///   up to ~128ms per index for a 128k token vocab.
+pub static HASH_SAMPLE_SIZE: Lazy<usize> = Lazy::new(|| {
+    env::var("FASTER_OUTLINES_HASH_SAMPLE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100)
+});
+
+/// Global flag making FSM indexes cache by FSM structure instead of pattern string.
+///
+/// # Environment Configuration
+/// Set via `FASTER_OUTLINES_CANONICALIZE_FSM` environment variable.
+/// Accepts the same values as `FASTER_OUTLINES_DISABLE_CACHE`.
+///
+/// ```bash
+/// export FASTER_OUTLINES_CANONICALIZE_FSM=1
+/// ```
+///
+/// # Behavior
+/// When enabled, each FSM is minimized and renumbered by `FSMInfo::canonicalize` before
+/// its index is built, and the cache key hashes the resulting structure in place of the
+/// pattern. Different regexes compiling to the same language, like `"a|b"` and `"[ab]"`,
+/// then share one cache entry instead of each being computed.
+///
+/// # Tradeoff
+/// Minimizing costs a few passes over the FSM on every index creation, cache hit or not.
+/// State ids of the index are those of the canonical FSM, not of the FSM passed in.
+pub static CANONICALIZE_FSM: Lazy<bool> =
+    Lazy::new(|| env_flag("FASTER_OUTLINES_CANONICALIZE_FSM"));
+
+/// Parses a boolean environment variable. "1", "true" and "yes"
+/// (case insensitive) are truthy, anything else, including unset, is false.
+fn env_flag(name: &str) -> bool {
+    match env::var(name) {
         Ok(val) => {
             let val_lower = val.to_lowercase();
-            let is_disabled = val_lower == "1" || val_lower == "true" || val_lower == "yes";
-            if is_disabled {
-                println!("Cache is disabled via DISABLE_CACHE environment variable.");
-            }
-            is_disabled
+            val_lower == "1" || val_lower == "true" || val_lower == "yes"
         }
         Err(_) => false,
-    });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::sync::Mutex;
+
+    struct RecordingLogger {
+        messages: Mutex<Vec<(Level, String)>>,
+    }
+
+    impl Log for RecordingLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: RecordingLogger = RecordingLogger {
+        messages: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn test_cache_disabled_message_goes_through_log() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Info);
+
+        log_cache_disabled();
+
+        // Tests running in parallel may log too, so only this message is looked for.
+        let messages = LOGGER.messages.lock().unwrap();
+        assert!(messages.contains(&(
+            Level::Info,
+            "Cache is disabled via DISABLE_CACHE environment variable.".to_string()
+        )));
+    }
+}
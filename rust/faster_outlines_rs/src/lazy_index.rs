@@ -20,21 +20,82 @@
 * THE SOFTWARE.
 */
 
-use crate::types::{StateNotifierMap, StatesToTokenMaps};
+use crate::types::{
+    ConcatState, SeededStates, StateCallback, StateCallbackSlot, StateCompletionLog,
+    StateNotifierMap, StatesToTokenMaps,
+};
 use crate::{
-    atomic_wait::platform::{wait, wake_all},
-    caching::{get_cached_fsm, get_fsm_cache_key, insert_fsm_to_cache, CachedFSM},
-    tokenizer_index::create_fsm_index_end_to_end,
-    types::{FSMInfo, Generate, Instruction, ThreadSafeCell, Write},
+    atomic_wait::platform::{wait, wait_timeout, wake_all},
+    environment::{COMPUTE_THREADS, DISABLE_CACHE, FORCE_SEQUENTIAL},
+    caching::{
+        get_cached_fsm, get_fsm_cache_key, get_fsm_structure_cache_key, insert_fsm_to_cache,
+        CachedFSM,
+    },
+    compute_pool::{ComputePool, COMPUTE_POOL},
+    tokenizer_index::{
+        create_fsm_index_end_to_end, create_fsm_index_end_to_end_parallel, publish_state,
+        byte_transition_keys, token_transition_keys, walk_fsm_len, LazyStateScanner, StateScanner,
+    },
+    types::{ComputeOptions, FSMInfo, Generate, Instruction, ThreadSafeCell, Write},
     vocab::TokenVocabulary,
 };
 use anyhow::Result;
-use rustc_hash::FxHashMap;
+use once_cell::sync::{Lazy, OnceCell};
+#[cfg(feature = "json")]
+use serde::{Deserialize, Serialize};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use std::any::Any;
+#[cfg(feature = "json")]
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
 use fixedbitset::FixedBitSet;
 
+/// The document written by `LazyFSMIndex::to_json`.
+#[cfg(feature = "json")]
+#[derive(Debug, Serialize, Deserialize)]
+struct ConstraintJson {
+    initial: u32,
+    finals: Vec<u32>,
+    eos: u32,
+    states: BTreeMap<u32, BTreeMap<u32, u32>>,
+}
+
+/// Errors from waiting on a state, so callers can tell a retry from giving up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexError {
+    /// The state does not exist in the FSM.
+    OutOfBounds { state: u32, total: usize },
+    /// The state exists, but computation finished without it, since it is not
+    /// reachable from the initial state.
+    NotComputed { state: u32 },
+    /// The compute thread panicked or ran past its deadline before finishing,
+    /// or the FSM was refused, see `LazyFSMIndex::compute_error`.
+    Cancelled { reason: String },
+}
+
+impl std::fmt::Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexError::OutOfBounds { state, total } => {
+                write!(f, "State {} is out of bounds, the FSM has {} states", state, total)
+            }
+            IndexError::NotComputed { state } => write!(
+                f,
+                "State {} was not computed, it is not reachable from the initial state",
+                state
+            ),
+            IndexError::Cancelled { reason } => write!(f, "Computing the index failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
 /// LazyFSMIndex implements a lazy-loading finite state machine (FSM) for efficient token sequence matching.
 /// It processes state transitions asynchronously and caches results for improved performance.
 ///
@@ -69,6 +130,27 @@ pub struct LazyFSMIndex {
     /// the final states of the fsm
     finals: Vec<u32>,
 
+    /// The FSM the index was built from, kept for re-walking tokens.
+    fsm_info: Arc<FSMInfo>,
+
+    /// Character to transition key mapping of the FSM, kept for diagnostics.
+    alphabet_symbol_mapping: Arc<FxHashMap<char, u32>>,
+
+    /// `TokenVocabulary::byte_tokens` of the vocabulary the index was built from,
+    /// so tokens added later are walked the same way.
+    byte_tokens: bool,
+
+    /// Scanner over the vocabulary the index was built from, shared with the cache entry,
+    /// for states computed on demand and those `allowed_after_bytes` scans.
+    state_scanner: Arc<LazyStateScanner>,
+
+    /// The options the index was built with, which `extend_pattern` builds the extension with.
+    options: ComputeOptions,
+
+    /// Whether strict finals give `Instruction::Stop` instead of `Write([EOS])`,
+    /// see `with_stop_instruction`.
+    emit_stop: bool,
+
     /// For notifying waiters when a state is finished.
     state_notifiers: StateNotifierMap,
 
@@ -76,48 +158,470 @@ pub struct LazyFSMIndex {
     /// over the notifiers to check if they are all finished.
     computing_finished: Arc<AtomicBool>,
 
+    /// States `collect_finished_states` has returned. Like `collect_cursor`,
+    /// owned by each clone rather than shared, see `collect_finished_states`.
     returned_states: FixedBitSet,
 
-    // Bypasses all awaiting mechanisms, if the map is cached.
+    /// Order in which states finished, appended to by the compute thread.
+    completion_log: Arc<StateCompletionLog>,
+
+    /// Position in `completion_log` up to which `collect_finished_states` has looked.
+    collect_cursor: usize,
+
+    /// Optional hook the compute thread calls as each state finishes.
+    state_callback: StateCallbackSlot,
+
+    /// Per state distance to the nearest final, computed on first use by
+    /// `get_next_instruction_with_priorities`.
+    distances_to_final: Arc<OnceCell<Vec<u32>>>,
+
+    /// Per state sorted result of `get_allowed_token_ids_u32`, allocated on first use.
+    allowed_tokens: Arc<OnceCell<Vec<AllowedTokens>>>,
+
+    /// Rank of each token id in vocabulary iteration order, ordering allowed tokens
+    /// instead of their ids when set, see `with_vocab_order`.
+    vocab_order: Option<Arc<FxHashMap<u32, u32>>>,
+
+    /// Set for indexes computing each state on first access, see `new_on_demand`.
+    on_demand: Option<Arc<OnDemand>>,
+
+    /// Message of the panic which ended computation early, see `compute_error`.
+    compute_error: Arc<OnceCell<String>>,
+
+    /// States computation ended without reaching, see `release_pending_states`.
+    unreached_states: Arc<OnceCell<FixedBitSet>>,
+
+    /// Holds the compute thread between states while set, see `pause`.
+    paused: Arc<PauseFlag>,
+
+    // Bypasses all awaiting mechanisms, if the map is cached or finalized.
     is_cached: bool
 }
 
+/// Memoized allowed tokens of one state. The tokens are only kept from the state's
+/// second lookup on, so states visited once, the bulk of most FSMs, cost no memory.
+#[derive(Default)]
+struct AllowedTokens {
+    looked_up: AtomicBool,
+    sorted: OnceCell<Vec<u32>>,
+}
+
+/// How `LazyFSMIndex::build` computes an index. The default computes it in the background,
+/// as `LazyFSMIndex::new` does.
+#[derive(Default)]
+struct BuildConfig {
+    /// Compute on the calling thread before returning, see `new_blocking`.
+    sequential: bool,
+    /// Precomputed maps published instead of scanning their states, see `extend_pattern`.
+    seeded: SeededStates,
+    /// Compute each state on first access instead, see `new_on_demand`.
+    on_demand: bool,
+    /// Stops the computation at its next state once cancelled, see `new_with_deadline`.
+    /// Such builds are never shared with other builds.
+    cancellation: Option<Arc<Cancellation>>,
+    /// Replaces `FSMInfo::char_symbol_mapping`, see `new_with_alphabet`.
+    alphabet: Option<Arc<FxHashMap<char, u32>>>,
+}
+
+/// Cancels the computation of a build by `LazyFSMIndex::new_with_deadline`.
+struct Cancellation {
+    /// Checked by the computation before each state.
+    cancelled: Arc<AtomicBool>,
+    /// The computation while it waits for a thread of `pool`, see `cancel`.
+    queued: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    pool: &'static ComputePool,
+}
+
+impl Cancellation {
+    fn new(pool: &'static ComputePool) -> Arc<Self> {
+        Arc::new(Cancellation { cancelled: Arc::default(), queued: Mutex::new(None), pool })
+    }
+
+    /// Runs `compute` on a thread of the pool, or on the thread calling `cancel`
+    /// if no thread of the pool has started it by then.
+    fn spawn(self: &Arc<Self>, compute: impl FnOnce() + Send + 'static) {
+        *self.queued.lock().unwrap() = Some(Box::new(compute));
+        let cancellation = Arc::clone(self);
+        self.pool.spawn(move || cancellation.run_queued());
+    }
+
+    fn run_queued(&self) {
+        let compute = self.queued.lock().unwrap().take();
+        if let Some(compute) = compute {
+            compute();
+        }
+    }
+
+    /// Stops the computation at its next state. A computation still queued for a pool
+    /// slot is run here instead, where it returns right away, so cancelling never waits
+    /// for the pool to free up.
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+        self.run_queued();
+    }
+}
+
+/// Per-state claims of an index built by `LazyFSMIndex::new_on_demand`.
+struct OnDemand {
+    /// Set by the one thread computing a state, others wait on that state's notifier,
+    /// which keeps each map single-writer like the compute thread does.
+    claimed: Vec<AtomicBool>,
+}
+
+/// Held while `compute_on_demand` computes a state it claimed. If the scan panics, the state
+/// is published with an empty map and `compute_error` is set, so other threads waiting on
+/// it return `IndexError::Cancelled` instead of blocking forever.
+struct OnDemandClaim<'a> {
+    index: &'a LazyFSMIndex,
+    state: usize,
+}
+
+impl Drop for OnDemandClaim<'_> {
+    fn drop(&mut self) {
+        let notifier = &self.index.state_notifiers[self.state];
+        if notifier.load(Ordering::Acquire) {
+            return;
+        }
+        let _ = self
+            .index
+            .compute_error
+            .set(format!("computing state {} on demand panicked", self.state));
+        // SAFETY: the claim still makes this thread the only writer of the map, and
+        // readers do not look at it before the notifier is set.
+        unsafe { *self.index.states_to_token_maps[self.state].get() = Arc::default() };
+        notifier.store(true, Ordering::Release);
+        wake_all(&**notifier);
+    }
+}
+
+/// Pause flag shared by the clones of an index and its compute thread.
+///
+/// Dropping the last clone resumes computation, so a paused index which is
+/// thrown away does not keep a pool thread parked forever.
+struct PauseFlag(Arc<AtomicBool>);
+
+impl Drop for PauseFlag {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+        wake_all(&*self.0);
+    }
+}
+
+/// Text of a panic payload, as the default panic hook would print it.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "compute thread panicked".to_string()),
+    }
+}
+
+/// Computations started by `LazyFSMIndex::build` which have not finished yet, by cache key.
+///
+/// Entries are removed by the compute thread once its maps are in the cache. A build which
+/// misses the cache checks here and then the cache again under this lock, before inserting
+/// its own entry, so it finds a finished index, a running one, or neither, and never starts
+/// the same work twice. The lock is held only for those lookups and the insert.
+static IN_FLIGHT: Lazy<Mutex<FxHashMap<u64, InFlight>>> = Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+/// What a build needs to attach to a running computation instead of starting its own.
+struct InFlight {
+    states_to_token_maps: StatesToTokenMaps,
+    state_notifiers: StateNotifierMap,
+    computing_finished: Arc<AtomicBool>,
+    completion_log: Arc<StateCompletionLog>,
+    state_callback: StateCallbackSlot,
+    compute_error: Arc<OnceCell<String>>,
+    unreached_states: Arc<OnceCell<FixedBitSet>>,
+    /// The pause flag owned by the indexes reading this computation, without keeping it
+    /// alive, so dropping all of them still resumes a paused computation.
+    paused: Weak<PauseFlag>,
+    /// Flag the compute thread waits on, wrapped again if every owner was dropped.
+    pause_flag: Arc<AtomicBool>,
+    alphabet_symbol_mapping: Arc<FxHashMap<char, u32>>,
+    state_scanner: Arc<LazyStateScanner>,
+    /// Checked like a cache entry's, since the key only samples the vocabulary.
+    vocab_len: usize,
+    eos_token_id: u32,
+}
+
+impl InFlight {
+    fn matches(&self, vocabulary: &TokenVocabulary) -> bool {
+        self.vocab_len == vocabulary.len() && self.eos_token_id == vocabulary.eos_token_id
+    }
+
+    /// An index reading this computation's maps, which behaves like a clone of the
+    /// index that started it: the state callback slot and the pause flag are shared too.
+    /// Dropping it, like dropping a clone, leaves a pause by another owner in place.
+    fn attach(
+        &mut self,
+        fsm_info: Arc<FSMInfo>,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        options: ComputeOptions,
+    ) -> LazyFSMIndex {
+        let paused = self.paused.upgrade().unwrap_or_else(|| {
+            let paused = Arc::new(PauseFlag(Arc::clone(&self.pause_flag)));
+            self.paused = Arc::downgrade(&paused);
+            paused
+        });
+        LazyFSMIndex {
+            states_to_token_maps: Arc::clone(&self.states_to_token_maps),
+            first_state: fsm_info.initial,
+            eos_token_id,
+            finals: fsm_info.finals.clone(),
+            fsm_info,
+            alphabet_symbol_mapping: Arc::clone(&self.alphabet_symbol_mapping),
+            byte_tokens: vocabulary.byte_tokens,
+            state_scanner: Arc::clone(&self.state_scanner),
+            options,
+            computing_finished: Arc::clone(&self.computing_finished),
+            state_notifiers: Arc::clone(&self.state_notifiers),
+            returned_states: FixedBitSet::with_capacity(self.states_to_token_maps.len()),
+            completion_log: Arc::clone(&self.completion_log),
+            collect_cursor: 0,
+            state_callback: Arc::clone(&self.state_callback),
+            distances_to_final: Arc::new(OnceCell::new()),
+            allowed_tokens: Arc::new(OnceCell::new()),
+            vocab_order: None,
+            on_demand: None,
+            compute_error: Arc::clone(&self.compute_error),
+            unreached_states: Arc::clone(&self.unreached_states),
+            paused,
+            emit_stop: false,
+            is_cached: false,
+        }
+    }
+}
+
+/// Publishes every state the compute thread never finished with an empty map, once it
+/// ended or panicked, so nothing waits on them forever. They are recorded in `unreached`
+/// before their notifiers are set, for `await_state` to tell them from computed states.
+fn release_pending_states(
+    maps: &StatesToTokenMaps,
+    notifiers: &StateNotifierMap,
+    unreached: &OnceCell<FixedBitSet>,
+) {
+    let mut pending = FixedBitSet::with_capacity(notifiers.len());
+    pending.extend(
+        notifiers
+            .iter()
+            .enumerate()
+            .filter(|(_, notifier)| !notifier.load(Ordering::Acquire))
+            .map(|(state, _)| state),
+    );
+    let pending = unreached.get_or_init(|| pending);
+    for state in pending.ones() {
+        // SAFETY: the compute thread is gone, so this is the only writer of the map,
+        // and readers do not look at it before the notifier is set.
+        unsafe { *maps[state].get() = Arc::default() };
+        notifiers[state].store(true, Ordering::Release);
+        wake_all(&*notifiers[state]);
+    }
+}
+
 // This impl block holds all methods which are not feature specific,
 // Other impl blocks are specific to where the object is being used from ( i.e. python, rust )
 impl LazyFSMIndex {
-    pub fn new<'v>(fsm_info: FSMInfo, vocabulary: &'v TokenVocabulary, eos_token_id: u32) -> Self {
-        let vocabulary = vocabulary.clone();
-        let cache_key = get_fsm_cache_key(&fsm_info.pattern, &vocabulary);
+    /// Starts computing the index in the background, or serves it from the cache.
+    ///
+    /// A build for a pattern and vocabulary which another thread is still computing
+    /// attaches to that computation instead of repeating it. The index returned then
+    /// behaves like a clone of the other one, sharing its state callback and pause flag.
+    pub fn new(fsm_info: FSMInfo, vocabulary: &TokenVocabulary, eos_token_id: u32) -> Self {
+        Self::new_with_options(fsm_info, vocabulary, eos_token_id, ComputeOptions::default())
+    }
 
-        let cache_entry = { get_cached_fsm(cache_key) };
+    /// Same as `new`, but with explicit compute options instead of the environment defaults.
+    pub fn new_with_options(
+        fsm_info: FSMInfo,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        options: ComputeOptions,
+    ) -> Self {
+        let config = BuildConfig { sequential: *FORCE_SEQUENTIAL, ..BuildConfig::default() };
+        Self::build(fsm_info, vocabulary, eos_token_id, options, config)
+    }
 
-        match cache_entry {
-            Some(cached_fsm) => {
+    /// Same as `new_with_options`, but with the FSM's alphabet already keyed by `char`,
+    /// as `FSMInfo::char_symbol_mapping` would build it, so servers reusing the alphabet
+    /// of a pattern family build that table once instead of on every index.
+    ///
+    /// A table which does not match `fsm_info`'s alphabet, see
+    /// `FSMInfo::matches_char_symbol_mapping`, is ignored with a warning and the table is
+    /// built as usual, since maps computed with it would be cached and shared under the key
+    /// of the pattern. It is also ignored with `canonicalize_fsm`, which renumbers the keys.
+    pub fn new_with_alphabet(
+        fsm_info: FSMInfo,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        options: ComputeOptions,
+        alphabet_symbol_mapping: Arc<FxHashMap<char, u32>>,
+    ) -> Self {
+        let alphabet = fsm_info.matches_char_symbol_mapping(&alphabet_symbol_mapping);
+        if !alphabet {
+            log::warn!(
+                "Ignoring an alphabet table which does not match the alphabet of pattern {:?}.",
+                fsm_info.pattern
+            );
+        }
+        let config = BuildConfig {
+            sequential: *FORCE_SEQUENTIAL,
+            alphabet: alphabet.then_some(alphabet_symbol_mapping),
+            ..BuildConfig::default()
+        };
+        Self::build(fsm_info, vocabulary, eos_token_id, options, config)
+    }
 
-                let state_notifiers: StateNotifierMap = Arc::new(
-                    (0..fsm_info.transitions.len())
-                        .map(|_| Arc::new(AtomicBool::new(true)))
-                        .collect(),
-                );
-                let returned_states_set = FixedBitSet::with_capacity(fsm_info.transitions.len());
-
-                let fsm_index = LazyFSMIndex {
-                    states_to_token_maps: Arc::clone(&cached_fsm.states_to_token_maps),
-                    first_state: cached_fsm.first_state.clone(),
-                    eos_token_id: eos_token_id,
-                    finals: cached_fsm.finals.clone(),
-                    computing_finished: Arc::new(AtomicBool::new(true)),
-                    state_notifiers: state_notifiers,
-                    returned_states: returned_states_set,
-                    is_cached: true,
-                };
+    /// Same as `new_with_options`, but nothing is computed in the background: each state's
+    /// map is computed on the calling thread the first time it is looked up.
+    ///
+    /// Meant for short generations which only visit a few states of a large FSM, where
+    /// eagerly computing every reachable state would mostly be wasted work. Each lookup
+    /// of a new state pays for its scan instead. Methods which need the whole index, like
+    /// `await_finished` or `to_outlines_states_to_token_maps`, compute every reachable state.
+    /// The maps are not inserted into the cache, though a cached index is still used if present.
+    pub fn new_on_demand(
+        fsm_info: FSMInfo,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        options: ComputeOptions,
+    ) -> Self {
+        let config = BuildConfig { on_demand: true, ..BuildConfig::default() };
+        Self::build(fsm_info, vocabulary, eos_token_id, options, config)
+    }
+
+    /// Builds the index on the calling thread, returning only once every
+    /// reachable state has been computed.
+    ///
+    /// The result is identical to the one produced by `new`, but timing is not
+    /// perturbed by thread scheduling. Meant for benchmarking and debugging,
+    /// see `FASTER_OUTLINES_FORCE_SEQUENTIAL`.
+    pub fn new_blocking(fsm_info: FSMInfo, vocabulary: &TokenVocabulary, eos_token_id: u32) -> Self {
+        let config = BuildConfig { sequential: true, ..BuildConfig::default() };
+        Self::build(fsm_info, vocabulary, eos_token_id, ComputeOptions::default(), config)
+    }
+
+    /// Same as `new`, but computation is cancelled if it has not finished within `deadline`,
+    /// bounding what a single pattern can cost, e.g. for patterns from untrusted users.
+    ///
+    /// The deadline includes time spent queued for a thread, see
+    /// `FASTER_OUTLINES_MAX_COMPUTE_THREADS`, a build still queued then is cancelled unstarted.
+    ///
+    /// Blocks until computation finishes or is cancelled. Returns the index and whether every
+    /// reachable state was computed. A cancelled index keeps the states computed in time, the
+    /// others are dead ends, so generation reaching them is ended with EOS. See `compute_error`.
+    /// Cancelled indexes are not cached, and these builds never share another build's computation.
+    pub fn new_with_deadline(
+        fsm_info: FSMInfo,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        deadline: Duration,
+    ) -> (Self, bool) {
+        Self::build_with_deadline(fsm_info, vocabulary, eos_token_id, deadline, &COMPUTE_POOL)
+    }
+
+    /// `new_with_deadline`, computing on `pool`.
+    fn build_with_deadline(
+        fsm_info: FSMInfo,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        deadline: Duration,
+        pool: &'static ComputePool,
+    ) -> (Self, bool) {
+        let cancellation = Cancellation::new(pool);
+        let config = BuildConfig { cancellation: Some(Arc::clone(&cancellation)), ..BuildConfig::default() };
+        let index = Self::build(fsm_info, vocabulary, eos_token_id, ComputeOptions::default(), config);
+        if !wait_timeout(&index.computing_finished, false, deadline) {
+            cancellation.cancel();
+            index.await_finished();
+        }
+        let completed = index.compute_error.get().is_none();
+        (index, completed)
+    }
+
+    /// Every constructor ends up here, see `BuildConfig` for how they differ.
+    fn build(
+        fsm_info: FSMInfo,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        options: ComputeOptions,
+        config: BuildConfig,
+    ) -> Self {
+        let BuildConfig { sequential, seeded, on_demand, cancellation, alphabet } = config;
+        let (fsm_info, cache_key) = if options.canonicalize_fsm {
+            // Equivalent patterns minimize to the same structure, so they share one entry.
+            let canonical = fsm_info.canonicalize().unwrap_or(fsm_info);
+            let cache_key = get_fsm_structure_cache_key(&canonical, vocabulary, &options);
+            (canonical, cache_key)
+        } else {
+            let cache_key =
+                get_fsm_cache_key(&fsm_info.pattern, &fsm_info.eof_required_finals, vocabulary, &options);
+            (fsm_info, cache_key)
+        };
+        let alphabet = alphabet.filter(|_| !options.canonicalize_fsm);
+        // Builds computing in the background share a computation already running for the
+        // same key, see `IN_FLIGHT`. Blocking and on-demand builds compute on the caller,
+        // and a cancellable one could cancel the work of the builds attached to it.
+        let shared = !sequential && !on_demand && cancellation.is_none() && !*DISABLE_CACHE;
+        let cache_entry = get_cached_fsm(cache_key, vocabulary);
+        let index_options = options.clone();
+        let invalid = fsm_info.check_transition_targets().err().map(|e| e.to_string());
+        let fsm_info = Arc::new(fsm_info);
 
-                return fsm_index;
+        match cache_entry {
+            Some(cached_fsm) => Self::from_cache(&cached_fsm, fsm_info, vocabulary, eos_token_id, index_options),
+            // An invalid FSM takes the background path, which fails it without scanning.
+            None if on_demand && invalid.is_none() => {
+                let num_states = fsm_info.transitions.len() + 1;
+                let alphabet_symbol_mapping = alphabet.unwrap_or_else(|| Arc::new(fsm_info.char_symbol_mapping()));
+                let state_scanner = Arc::new(LazyStateScanner::new(Arc::new(vocabulary.clone())));
+                LazyFSMIndex {
+                    states_to_token_maps: Arc::new(
+                        (0..num_states).map(|_| ThreadSafeCell::new(Arc::default())).collect(),
+                    ),
+                    first_state: fsm_info.initial,
+                    eos_token_id,
+                    finals: fsm_info.finals.clone(),
+                    alphabet_symbol_mapping,
+                    byte_tokens: vocabulary.byte_tokens,
+                    state_scanner,
+                    options: index_options,
+                    fsm_info,
+                    computing_finished: Arc::new(AtomicBool::new(false)),
+                    state_notifiers: Arc::new(
+                        (0..num_states).map(|_| Arc::new(AtomicBool::new(false))).collect(),
+                    ),
+                    returned_states: FixedBitSet::with_capacity(num_states),
+                    completion_log: Arc::new(StateCompletionLog::with_capacity(num_states)),
+                    collect_cursor: 0,
+                    state_callback: Arc::new(Mutex::new(None)),
+                    distances_to_final: Arc::new(OnceCell::new()),
+                    allowed_tokens: Arc::new(OnceCell::new()),
+                    vocab_order: None,
+                    on_demand: Some(Arc::new(OnDemand {
+                        claimed: (0..num_states).map(|_| AtomicBool::new(false)).collect(),
+                    })),
+                    compute_error: Arc::new(OnceCell::new()),
+                    unreached_states: Arc::new(OnceCell::new()),
+                    paused: Arc::new(PauseFlag(Arc::new(AtomicBool::new(false)))),
+                    emit_stop: false,
+                    is_cached: false,
+                }
             }
             None => {
-                let results: Arc<Vec<ThreadSafeCell<FxHashMap<u32, u32>>>> = Arc::new(
+                let vocab_len = vocabulary.len();
+                let vocab_eos_token_id = vocabulary.eos_token_id;
+                let state_scanner = Arc::new(LazyStateScanner::new(Arc::new(vocabulary.clone())));
+                let state_scanner_clone = Arc::clone(&state_scanner);
+                let alphabet_symbol_mapping = alphabet.unwrap_or_else(|| Arc::new(fsm_info.char_symbol_mapping()));
+                let alphabet_symbol_mapping_clone = Arc::clone(&alphabet_symbol_mapping);
+
+                let results: StatesToTokenMaps = Arc::new(
                     (0..fsm_info.transitions.len() + 1)
-                        .map(|_| ThreadSafeCell::new(FxHashMap::default()))
+                        .map(|_| ThreadSafeCell::new(Arc::default()))
                         .collect::<Vec<_>>(),
                 );
 
@@ -127,6 +631,7 @@ impl LazyFSMIndex {
                         .collect(),
                 );
 
+                let fsm_info_clone = Arc::clone(&fsm_info);
                 let state_notifiers_clone = Arc::clone(&state_notifiers);
                 let computing_finished = Arc::new(AtomicBool::new(false));
                 let computing_finished_clone = Arc::clone(&computing_finished);
@@ -136,39 +641,233 @@ impl LazyFSMIndex {
                 let finals_clone = Arc::clone(&finals);
                 let cache_key_clone = cache_key;
                 let returned_states_set = FixedBitSet::with_capacity(fsm_info.transitions.len() + 1);
+                let state_callback: StateCallbackSlot = Arc::new(Mutex::new(None));
+                let state_callback_clone = Arc::clone(&state_callback);
+                let completion_log =
+                    Arc::new(StateCompletionLog::with_capacity(fsm_info.transitions.len() + 1));
+                let completion_log_clone = Arc::clone(&completion_log);
+                let compute_error = Arc::new(OnceCell::new());
+                let compute_error_clone = Arc::clone(&compute_error);
+                let unreached_states = Arc::new(OnceCell::new());
+                let unreached_states_clone = Arc::clone(&unreached_states);
+                let paused = Arc::new(PauseFlag(Arc::new(AtomicBool::new(false))));
+                let paused_clone = Arc::clone(&paused.0);
+                let cancelled = cancellation.as_ref().map_or_else(Arc::default, |c| Arc::clone(&c.cancelled));
 
-                thread::spawn(move || {
-                    create_fsm_index_end_to_end(
-                        &fsm_info,
-                        &vocabulary,
-                        &results_clone,
-                        &state_notifiers_clone,
-                    );
-                    let cached_fsm = CachedFSM {
-                        first_state: first_state,
-                        states_to_token_maps: Arc::clone(&results_clone),
-                        finals: finals_clone.to_vec(),
-                        hash: cache_key_clone.clone(),
+                let compute = move || {
+                    let outcome = match invalid {
+                        Some(error) => Err(error),
+                        None => panic::catch_unwind(AssertUnwindSafe(|| {
+                            // Before the vocabulary is walked, for builds cancelled while queued.
+                            if cancelled.load(Ordering::Acquire) {
+                                false
+                            // Sequential builds run on the caller and must not spawn threads.
+                            } else if *COMPUTE_THREADS > 1 && !sequential {
+                                create_fsm_index_end_to_end_parallel(
+                                    &fsm_info_clone,
+                                    state_scanner_clone.vocabulary(),
+                                    &alphabet_symbol_mapping_clone,
+                                    &results_clone,
+                                    &state_notifiers_clone,
+                                    &state_callback_clone,
+                                    &completion_log_clone,
+                                    &options,
+                                    &seeded,
+                                    &paused_clone,
+                                    &cancelled,
+                                    *COMPUTE_THREADS,
+                                )
+                            } else {
+                                create_fsm_index_end_to_end(
+                                    &fsm_info_clone,
+                                    state_scanner_clone.vocabulary(),
+                                    &alphabet_symbol_mapping_clone,
+                                    &results_clone,
+                                    &state_notifiers_clone,
+                                    &state_callback_clone,
+                                    &completion_log_clone,
+                                    &options,
+                                    &seeded,
+                                    &paused_clone,
+                                    &cancelled,
+                                )
+                            }
+                        }))
+                        .map_err(|payload| panic_message(payload.as_ref()))
+                        // Only a computation stopped with states left counts as cancelled.
+                        .and_then(|finished| {
+                            if finished {
+                                Ok(())
+                            } else {
+                                Err("computation was cancelled before every state was computed".to_string())
+                            }
+                        }),
                     };
-                    insert_fsm_to_cache(cached_fsm, cache_key_clone);
+                    match outcome {
+                        Ok(()) => {
+                            // Before the maps are shared with the cache, which reads every state.
+                            release_pending_states(&results_clone, &state_notifiers_clone, &unreached_states_clone);
+                            let cached_fsm = CachedFSM::new(
+                                Arc::clone(&results_clone),
+                                first_state,
+                                finals_clone.to_vec(),
+                                cache_key_clone,
+                                fsm_info_clone.pattern.clone(),
+                                state_scanner_clone,
+                                alphabet_symbol_mapping_clone,
+                            );
+                            insert_fsm_to_cache(cached_fsm, cache_key_clone);
+                        }
+                        // Waiters would otherwise block forever on states nobody computes.
+                        // The partial index is not cached.
+                        Err(error) => {
+                            let _ = compute_error_clone.set(error);
+                            release_pending_states(&results_clone, &state_notifiers_clone, &unreached_states_clone);
+                        }
+                    }
+                    {
+                        // After the cache insert, so later builds find the maps there instead.
+                        let mut in_flight = IN_FLIGHT.lock().unwrap();
+                        let is_ours = in_flight
+                            .get(&cache_key_clone)
+                            .is_some_and(|running| Arc::ptr_eq(&running.states_to_token_maps, &results_clone));
+                        if is_ours {
+                            in_flight.remove(&cache_key_clone);
+                        }
+                    }
                     computing_finished_clone.store(true, Ordering::Release);
                     wake_all(&*computing_finished_clone);
-                });
+                };
+
+                let mut in_flight = shared.then(|| IN_FLIGHT.lock().unwrap());
+                if let Some(in_flight) = in_flight.as_mut() {
+                    // Another build may have started or finished the same work since the cache lookup.
+                    if let Some(running) =
+                        in_flight.get_mut(&cache_key).filter(|running| running.matches(vocabulary))
+                    {
+                        return running.attach(fsm_info, vocabulary, eos_token_id, index_options);
+                    }
+                    if let Some(cached_fsm) = get_cached_fsm(cache_key, vocabulary) {
+                        return Self::from_cache(&cached_fsm, fsm_info, vocabulary, eos_token_id, index_options);
+                    }
+                    // A colliding key from another vocabulary keeps its entry, this build is not shared.
+                    in_flight.entry(cache_key).or_insert_with(|| InFlight {
+                        states_to_token_maps: Arc::clone(&results),
+                        state_notifiers: Arc::clone(&state_notifiers),
+                        computing_finished: Arc::clone(&computing_finished),
+                        completion_log: Arc::clone(&completion_log),
+                        state_callback: Arc::clone(&state_callback),
+                        compute_error: Arc::clone(&compute_error),
+                        unreached_states: Arc::clone(&unreached_states),
+                        paused: Arc::downgrade(&paused),
+                        pause_flag: Arc::clone(&paused.0),
+                        alphabet_symbol_mapping: Arc::clone(&alphabet_symbol_mapping),
+                        state_scanner: Arc::clone(&state_scanner),
+                        vocab_len,
+                        eos_token_id: vocab_eos_token_id,
+                    });
+                }
+                drop(in_flight);
+
+                if sequential {
+                    compute();
+                } else if let Some(cancellation) = &cancellation {
+                    cancellation.spawn(compute);
+                } else {
+                    COMPUTE_POOL.spawn(compute);
+                }
+
                 let finals = finals.to_vec();
                 LazyFSMIndex {
                     states_to_token_maps: results,
-                    first_state: first_state,
-                    eos_token_id: eos_token_id,
-                    finals: finals,
-                    computing_finished: computing_finished,
-                    state_notifiers: state_notifiers,
+                    first_state,
+                    eos_token_id,
+                    finals,
+                    fsm_info,
+                    alphabet_symbol_mapping,
+                    byte_tokens: vocabulary.byte_tokens,
+                    state_scanner,
+                    options: index_options,
+                    computing_finished,
+                    state_notifiers,
                     returned_states: returned_states_set,
+                    completion_log,
+                    collect_cursor: 0,
+                    state_callback,
+                    distances_to_final: Arc::new(OnceCell::new()),
+                    allowed_tokens: Arc::new(OnceCell::new()),
+                    vocab_order: None,
+                    on_demand: None,
+                    compute_error,
+                    unreached_states,
+                    paused,
+                    emit_stop: false,
                     is_cached: false,
                 }
             }
         }
     }
 
+    /// An index serving the maps of a cache entry.
+    /// A hit only clones `Arc`s out of the entry, nothing is allocated per state.
+    fn from_cache(
+        cached_fsm: &CachedFSM,
+        fsm_info: Arc<FSMInfo>,
+        vocabulary: &TokenVocabulary,
+        eos_token_id: u32,
+        options: ComputeOptions,
+    ) -> Self {
+        LazyFSMIndex {
+            states_to_token_maps: Arc::clone(&cached_fsm.states_to_token_maps),
+            first_state: cached_fsm.first_state,
+            eos_token_id,
+            finals: cached_fsm.finals.clone(),
+            fsm_info,
+            alphabet_symbol_mapping: Arc::clone(&cached_fsm.alphabet_symbol_mapping),
+            byte_tokens: vocabulary.byte_tokens,
+            state_scanner: Arc::clone(&cached_fsm.state_scanner),
+            options,
+            computing_finished: Arc::new(AtomicBool::new(true)),
+            state_notifiers: Arc::clone(&cached_fsm.state_notifiers),
+            returned_states: FixedBitSet::with_capacity(cached_fsm.states_to_token_maps.len()),
+            completion_log: Arc::clone(&cached_fsm.completion_log),
+            collect_cursor: 0,
+            state_callback: Arc::new(Mutex::new(None)),
+            distances_to_final: Arc::new(OnceCell::new()),
+            allowed_tokens: Arc::new(OnceCell::new()),
+            vocab_order: None,
+            on_demand: None,
+            compute_error: Arc::new(OnceCell::new()),
+            unreached_states: Arc::new(OnceCell::new()),
+            paused: Arc::new(PauseFlag(Arc::new(AtomicBool::new(false)))),
+            emit_stop: false,
+            is_cached: true,
+        }
+    }
+
+    /// Computes `state` on the calling thread if this index computes states on demand
+    /// and no other thread has claimed it yet. Returns right away otherwise.
+    fn compute_on_demand(&self, state: u32) {
+        let Some(on_demand) = &self.on_demand else {
+            return;
+        };
+        let index = state as usize;
+        let Some(claimed) = on_demand.claimed.get(index) else {
+            return;
+        };
+        if claimed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let _claim = OnDemandClaim { index: self, state: index };
+        let token_ids_end_states = self.scanner().scan(index);
+        // SAFETY: the claim makes this thread the only writer of the map, and readers
+        // only look at it once `publish_state` sets its notifier.
+        let map = unsafe { self.states_to_token_maps[index].get() };
+        Arc::make_mut(map).extend(token_ids_end_states);
+        publish_state(index, &self.state_notifiers, &self.state_callback, &self.completion_log);
+    }
+
     /// Retrieves token transition map for state.
     /// Blocks if state computation pending.
     ///
@@ -182,36 +881,15 @@ impl LazyFSMIndex {
     /// - O(1) access after computation
     /// - Blocking if state pending
     fn get_state_map(&self, state: u32) -> Option<&FxHashMap<u32, u32>> {
-        match self.is_cached {
-            false => {
-                if state as usize >= self.states_to_token_maps.len() {
-                    return None;
-                }
-        
-                let notifier = {
-                    match self.state_notifiers.get(state as usize) {
-                        Some(notifier_ref) => notifier_ref,
-                        None => return None,
-                    }
-                };
-        
-                let atomic = &**notifier;
-                wait(&atomic, false); // if the value is false, wait.
-        
-                let cell = &self.states_to_token_maps[state as usize];
-                return Some(unsafe { &*cell.get_ref() });
-            },
-            true => {
-                if state as usize >= self.states_to_token_maps.len() {
-                    return None;
-                } else {
-                    let cell = &self.states_to_token_maps[state as usize];
-                    return Some(unsafe { &*cell.get_ref() });
-                }
+        let cell = self.states_to_token_maps.get(state as usize)?;
+        let notifier = self.state_notifiers.get(state as usize)?;
 
-            }
+        if !self.is_cached {
+            self.compute_on_demand(state);
+            wait(notifier, false); // if the value is false, wait.
         }
-        
+
+        cell.read_after(notifier).map(Arc::as_ref)
     }
 
     /// Tests if state represents pattern match.
@@ -222,9 +900,47 @@ impl LazyFSMIndex {
     /// - finals: Pattern-specific terminals
     ///
     #[inline(always)]
-    fn is_final_state(&self, state: i32) -> bool {
+    pub fn is_final_state(&self, state: i32) -> bool {
         // Check if the state is the "final" or invalid state
-        state == -1 || self.finals.contains(&(state as u32))
+        if state == -1 {
+            return true;
+        }
+        let current_state = self.resolve_state(state);
+        self.finals.contains(&current_state)
+    }
+
+    /// Whether generation must stop at `state`: it is -1, or a final state with no
+    /// outgoing tokens. Finals like the loop state of `"a*"` may stop or go on, so they
+    /// do not end generation. Blocks until `state` is computed.
+    fn ends_generation(&self, state: i32) -> bool {
+        self.is_final_state(state) && !self.has_transitions(state)
+    }
+
+    /// Whether every token of `map` leads to a state where generation must end,
+    /// see `Generate::terminal_choices`. EOS, offered at finals, ends it too.
+    fn all_choices_terminal(&self, map: &FxHashMap<u32, u32>) -> bool {
+        map.values().all(|&end_state| self.fsm_info.is_terminal(end_state))
+    }
+
+    /// The FSM state `state` stands for, resolving the alias 0 to the initial state.
+    /// -1 is left to the callers, which each handle it their own way.
+    #[inline(always)]
+    fn resolve_state(&self, state: i32) -> u32 {
+        if state == 0 {
+            self.first_state
+        } else {
+            state as u32
+        }
+    }
+
+    /// Instruction for a state without allowed tokens: `Stop` at -1 and finals if
+    /// `emit_stop` is set, `Write([EOS])` otherwise, including at dead ends.
+    fn end_instruction(&self, state: i32) -> Instruction {
+        if self.emit_stop && self.is_final_state(state) {
+            Instruction::Stop
+        } else {
+            Instruction::Write(Write::new(vec![self.eos_token_id as i32]))
+        }
     }
 
     /// Checks global computation status.
@@ -232,32 +948,37 @@ impl LazyFSMIndex {
     fn is_computing_finished(&self) -> bool {
         self.computing_finished.load(Ordering::Acquire)
     }
+
+    /// The scanner of this index, built on first use.
+    fn scanner(&self) -> &StateScanner {
+        self.state_scanner.get(&self.fsm_info, &self.alphabet_symbol_mapping, &self.options)
+    }
+
+    /// Whether computation ended without reaching `state`, which then has an empty map.
+    fn was_unreached(&self, state: u32) -> bool {
+        self.unreached_states.get().is_some_and(|unreached| unreached.contains(state as usize))
+    }
 }
 
 // All public methods.
 impl LazyFSMIndex {
     /// Based on the current state + token_id ( transition ) generated,
     /// figure out what state in the FSM this state + transition would arrive at.
+    ///
+    /// Returns -1 once generation has to stop: on EOS, on a token with no transition,
+    /// or on reaching a final state no token leads out of.
     pub fn get_next_state(&self, state: i32, token_id: u32) -> Option<i32> {
         // Handle special states (-1 is final state, 0 is the first state alias)
-        if state == -1 {
-            return Some(-1);
-        }
-
-        if token_id == self.eos_token_id || self.finals.contains(&(state as u32)) {
+        if state == -1 || token_id == self.eos_token_id {
             return Some(-1);
         }
 
-        let current_state = if state == 0 {
-            self.first_state
-        } else {
-            state as u32
-        };
+        let current_state = self.resolve_state(state);
 
         if let Some(map) = self.get_state_map(current_state) {
             if let Some(&next_state_u32) = map.get(&token_id) {
                 let next_state = next_state_u32 as i32;
-                if self.is_final_state(next_state) {
+                if self.ends_generation(next_state) {
                     Some(-1)
                 } else {
                     Some(next_state)
@@ -270,6 +991,35 @@ impl LazyFSMIndex {
         }
     }
 
+    /// Walks several tokens at once from `state`, e.g. to fast-forward past a forced prefix.
+    ///
+    /// Returns the resulting state, with the same conventions as `get_next_state`
+    /// (EOS and final states without outgoing tokens map to -1),
+    /// or `None` if any token has no valid transition.
+    /// Unlike looping `get_next_state`, a final state reached mid-way is walked through
+    /// instead of ending the walk, since the remaining tokens say generation goes on.
+    pub fn advance(&self, state: i32, token_ids: &[u32]) -> Option<i32> {
+        let mut state = state;
+        for &token_id in token_ids {
+            if state == -1 {
+                return None;
+            }
+            if token_id == self.eos_token_id {
+                state = -1;
+                continue;
+            }
+
+            let current_state = self.resolve_state(state);
+            state = *self.get_state_map(current_state)?.get(&token_id)? as i32;
+        }
+
+        if self.ends_generation(state) {
+            Some(-1)
+        } else {
+            Some(state)
+        }
+    }
+
     /// Generates next pattern-matching instruction.
     ///
     /// # Instructions
@@ -278,141 +1028,3008 @@ impl LazyFSMIndex {
     /// - Generate(None): Unconstrained
     ///
     /// # State Handling
-    /// - Terminal: Write([EOS])
+    /// - Terminal: Write([EOS]), or Stop, see `with_stop_instruction`
     /// - Valid: Generate(allowed_tokens)
+    /// - Final with outgoing tokens: Generate(allowed_tokens + [EOS]), stopping is as valid as going on
+    /// - Final without outgoing tokens: Write([EOS]), or Stop, see `with_stop_instruction`
     /// - Invalid: Write([EOS])
     ///
     pub fn get_next_instruction(&self, state: i32) -> Instruction {
-        if self.is_final_state(state) {
-            return Instruction::Write(Write::new(vec![self.eos_token_id as i32]));
+        if state == -1 {
+            return self.end_instruction(state);
         }
 
-        let current_state = if state == 0 {
-            self.first_state
-        } else {
-            state as u32
-        };
+        let current_state = self.resolve_state(state);
 
-        if let Some(map) = self.get_state_map(current_state) {
-            let allowed = map.keys().cloned().map(|k| k as i32).collect::<Vec<i32>>();
-            Instruction::Generate(Generate::new(Some(allowed)))
-        } else {
-            Instruction::Write(Write::new(vec![self.eos_token_id as i32]))
+        match self.get_state_map(current_state) {
+            Some(map) if !map.is_empty() => {
+                let mut allowed = map.keys().cloned().map(|k| k as i32).collect::<Vec<i32>>();
+                if self.is_final_state(state) && !map.contains_key(&self.eos_token_id) {
+                    allowed.push(self.eos_token_id as i32);
+                }
+                let mut generate = Generate::new(Some(allowed));
+                generate.terminal_choices = self.all_choices_terminal(map);
+                Instruction::Generate(generate)
+            }
+            _ => self.end_instruction(state),
         }
     }
 
-    /// Blocks until specific state completes
-    /// computation, and can be retrieved.
+    /// Same as `get_next_instruction`, but a `Generate` also carries a priority per token:
+    /// the number of transitions from the state the token leads to, to the nearest final state.
     ///
-    /// # Errors
-    /// - State index out of bounds
-    /// - State not scheduled for computation
-    pub fn await_state(&self, state_index: u32) -> Result<()> {
-        if (state_index as usize) >= self.states_to_token_maps.len() {
-            bail!(
-                "State {} is not in computed states, and is not set to be computed. Does this state exist?",
-                state_index
-            );
+    /// Lower is closer to completing the pattern, so samplers preferring short completions
+    /// can favor low priorities. Tokens which can no longer reach a final state get `f32::INFINITY`.
+    /// At a final state, EOS is offered too, with priority 0.
+    pub fn get_next_instruction_with_priorities(&self, state: i32) -> Instruction {
+        if state == -1 {
+            return self.end_instruction(state);
         }
 
-        let notifier = &self.state_notifiers[state_index as usize];
-        let atomic = &**notifier;
-        wait(&atomic, false);
-        Ok(())
-    }
+        let current_state = self.resolve_state(state);
 
-    /// Blocks until all states finish.
-    pub fn await_finished(&self) {
-        wait(&self.computing_finished, false);
+        match self.get_state_map(current_state) {
+            Some(map) if !map.is_empty() => {
+                let distances = self
+                    .distances_to_final
+                    .get_or_init(|| self.fsm_info.distances_to_final());
+                let (mut tokens, mut priorities): (Vec<i32>, Vec<f32>) = map
+                    .iter()
+                    .map(|(&token_id, &end_state)| {
+                        let priority = match distances.get(end_state as usize) {
+                            Some(&distance) if distance != u32::MAX => distance as f32,
+                            _ => f32::INFINITY,
+                        };
+                        (token_id as i32, priority)
+                    })
+                    .unzip();
+                if self.is_final_state(state) && !map.contains_key(&self.eos_token_id) {
+                    tokens.push(self.eos_token_id as i32);
+                    priorities.push(0.0);
+                }
+                let mut generate = Generate::with_priorities(tokens, priorities);
+                generate.terminal_choices = self.all_choices_terminal(map);
+                Instruction::Generate(generate)
+            }
+            _ => self.end_instruction(state),
+        }
     }
 
-    /// Collects newly computed state transitions.
-    /// 
-    /// This is an api which takes no arguments, and is useful for people building on top of 
-    /// the computed transitions computation of `LazyFSMIndex` who want access to the raw state transitions
-    /// map in realtime, while it is being computed.
-    pub fn collect_finished_states(&mut self) -> Result<FxHashMap<u32, FxHashMap<u32, u32>>> {
-        let mut finished_states = FxHashMap::default();
-        let total_states = self.states_to_token_maps.len();
-        
-        for index in 0..total_states {
-            if self.returned_states.contains(index) {
-                continue;
+    /// Same as `get_next_instruction`, but a run of forced tokens is returned as one `Write`.
+    ///
+    /// From `state`, while the current state is not final and allows exactly one token,
+    /// that token is appended and its end state followed, up to the next branch point.
+    /// Writing the whole run saves a decode step per forced token. The caller moves past
+    /// it with `advance`. If `state` itself is not forced, this is `get_next_instruction`.
+    pub fn get_next_instruction_with_forced_tokens(&self, state: i32) -> Instruction {
+        let mut forced = Vec::new();
+        let mut visited = FxHashSet::default();
+        let mut current = state;
+        // A cycle of forced states never reaches a branch point, so each state is followed once.
+        while current != -1 && !self.is_final_state(current) && visited.insert(current) {
+            let current_state = self.resolve_state(current);
+            match self.get_state_map(current_state) {
+                Some(map) if map.len() == 1 => {
+                    let (&token_id, &end_state) = map.iter().next().unwrap();
+                    forced.push(token_id as i32);
+                    current = end_state as i32;
+                }
+                _ => break,
             }
+        }
 
-            let state_is_done = {
-                let notifier = &self.state_notifiers[index];
-                let atomic = &**notifier;
-                atomic.load(Ordering::Acquire)
-            };
+        if forced.is_empty() {
+            return self.get_next_instruction(state);
+        }
+        Instruction::Write(Write::new(forced))
+    }
 
-            if state_is_done {
-                if let Some(state_map) = self.get_state_map(index as u32) {
-                    finished_states.insert(index as u32, state_map.clone());
-                    self.returned_states.set(index, true);
-                } else {
+    /// Same as `get_next_instruction`, but gives up after `timeout` if the state is still
+    /// being computed, e.g. to fall back to unconstrained generation within a latency budget.
+    ///
+    /// Returns `None` on timeout. A state which is never computed is a dead end once
+    /// computation ends, as in an index served from the cache.
+    /// An index built by `new_on_demand` computes an unclaimed state on the calling thread
+    /// first, which is not bounded by `timeout`, only waiting on another thread's claim is.
+    pub fn get_next_instruction_timeout(&self, state: i32, timeout: Duration) -> Option<Instruction> {
+        if state != -1 && !self.is_cached {
+            let current_state = self.resolve_state(state);
+            if let Some(notifier) = self.state_notifiers.get(current_state as usize) {
+                self.compute_on_demand(current_state);
+                if !wait_timeout(notifier, false, timeout) {
+                    return None;
                 }
-            } else {
             }
         }
-        Ok(finished_states)
+        Some(self.get_next_instruction(state))
     }
 
-    /// Retrieve a vector of allowed Token ID's at the state `state`
+    /// Whether any token can be generated from `state`, without collecting the allowed tokens.
     ///
-    /// This is an alternative to the Instruction based API used
-    /// in `get_next_instruction`. The Instruction based API is
-    /// preffered, but this can be useful for debugging or more manual
-    /// implementations / logic about state transition / token ID selection.
-    pub fn get_allowed_token_ids(&self, state: i32) -> Vec<i32> {
+    /// Returns `false` for -1 and for states that do not exist or have dead-ended.
+    /// Blocks like `get_next_instruction` if the state is still being computed.
+    pub fn has_transitions(&self, state: i32) -> bool {
         if state == -1 {
-            return vec![self.eos_token_id as i32];
-        }
-        match self.get_state_map(state as u32) {
-            Some(next_tokens_to_end_states) => next_tokens_to_end_states
-                .keys()
-                .cloned()
-                .map(|k| k as i32)
-                .collect(),
-            None => return vec![self.eos_token_id as i32],
+            return false;
         }
+
+        let current_state = self.resolve_state(state);
+
+        self.get_state_map(current_state)
+            .is_some_and(|map| !map.is_empty())
     }
 
-    ///* Python Magic methods *///
-    /// WARNING: THIS WILL BLOCK UNTIL FSM IS FINISHED COMPUTING!
-    pub fn __repr__(&self) -> String {
-        while !self.is_computing_finished() {
-            thread::sleep(std::time::Duration::from_millis(1));
+    /// Number of distinct states one token can lead to from `state`.
+    ///
+    /// Counts end states, not tokens, so it measures how permissive the pattern is
+    /// at this point. Returns 0 for -1 and for states that do not exist or have dead-ended.
+    /// Blocks like `get_next_instruction` if the state is still being computed.
+    pub fn branching_factor(&self, state: i32) -> usize {
+        if state == -1 {
+            return 0;
         }
 
-        let states: String = self
-            .states_to_token_maps
-            .iter()
-            .take(10)
-            .enumerate()
-            .map(|(index, cell)| {
-                let state_map = unsafe { &*cell.get_ref() };
-                format!("{}: {:?}", index, state_map)
-            })
-            .collect::<Vec<String>>()
-            .join(", ");
+        let current_state = self.resolve_state(state);
 
-        let states_display = if self.states_to_token_maps.len() > 10 {
-            format!("{}, ...", states)
-        } else {
-            states
-        };
+        self.get_state_map(current_state)
+            .map_or(0, |map| map.values().collect::<FxHashSet<_>>().len())
+    }
 
-        let finals = self
-            .finals
-            .iter()
-            .map(|state| state.to_string())
-            .collect::<Vec<String>>()
-            .join(", ");
+    /// Total `(state, token)` transitions in the index, the sum of every state's map size.
+    ///
+    /// A cheap measure of how permissive a pattern is over this vocabulary, e.g. for
+    /// dashboards: a nearly unconstrained `.*` allows most tokens from every state, while
+    /// `yes|no` allows a handful. EOS, offered at finals without being stored in the
+    /// maps, is not counted. Blocks until computation finishes.
+    pub fn total_allowed_transitions(&self) -> usize {
+        self.await_finished();
 
-        format!(
-            "LazyFSMIndex(first_state={}, eos_token_id={}, finals=[{}], states={{{}}})",
-            self.first_state, self.eos_token_id, finals, states_display
+        self.states_to_token_maps
+            .iter()
+            .zip(self.state_notifiers.iter())
+            .filter_map(|(cell, notifier)| cell.read_after(notifier))
+            .map(|state_map| state_map.len())
+            .sum()
+    }
+
+    /// Blocks until specific state completes
+    /// computation, and can be retrieved. 0 stands for the initial state, as elsewhere.
+    ///
+    /// On an index built by `new_on_demand`, which has no background thread, the state is
+    /// computed right here on the calling thread. A generation loop calling this for the
+    /// next state before `get_next_instruction` then does no speculative work at all.
+    ///
+    /// # Errors
+    /// - `IndexError::OutOfBounds`: the state does not exist
+    /// - `IndexError::NotComputed`: computation finished without reaching the state.
+    ///   Indexes from the cache, or finalized ones, treat such states as dead ends instead.
+    /// - `IndexError::Cancelled`: the compute thread panicked or was cancelled, or the FSM was refused,
+    ///   see `compute_error`
+    pub fn await_state(&self, state_index: u32) -> Result<(), IndexError> {
+        let state_index = self.resolve_state(state_index as i32);
+        let total = self.states_to_token_maps.len();
+        if state_index as usize >= total {
+            return Err(IndexError::OutOfBounds { state: state_index, total });
+        }
+
+        self.compute_on_demand(state_index);
+        // States computation never reaches are published too once it ends.
+        wait(&self.state_notifiers[state_index as usize], false);
+        if let Some(reason) = self.compute_error() {
+            return Err(IndexError::Cancelled { reason });
+        }
+        if self.was_unreached(state_index) {
+            return Err(IndexError::NotComputed { state: state_index });
+        }
+        Ok(())
+    }
+
+    /// Message of the panic which ended the compute thread early, if it panicked.
+    /// Also set without computing anything for an FSM with transitions to states an
+    /// index has no slot for, see `FSMInfo::check_transition_targets`, and when
+    /// `new_with_deadline` cancels the computation.
+    ///
+    /// The states it never finished are published with empty maps, so waiters return
+    /// instead of blocking forever, and the index is not cached. Lookups on such an
+    /// index behave as if those states were dead ends.
+    pub fn compute_error(&self) -> Option<String> {
+        self.compute_error.get().cloned()
+    }
+
+    /// Halts background computation before its next state, e.g. while a server is under
+    /// memory pressure, without cancelling it. States already computed stay readable,
+    /// while waiting on any other state blocks until `resume`.
+    ///
+    /// Shared by every clone of the index, and by indexes attached to the same computation,
+    /// see `new`. The computation resumes once `resume` is called or every index sharing it
+    /// is dropped. Has no effect on an index built from the cache or by `new_on_demand`,
+    /// which has no background computation.
+    ///
+    /// A paused computation does not count against `FASTER_OUTLINES_MAX_COMPUTE_THREADS`:
+    /// queued indexes compute in its place, and it waits for a free thread once resumed.
+    pub fn pause(&self) {
+        self.paused.0.store(true, Ordering::Release);
+    }
+
+    /// Lets background computation continue after `pause`.
+    pub fn resume(&self) {
+        self.paused.0.store(false, Ordering::Release);
+        wake_all(&*self.paused.0);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.0.load(Ordering::Acquire)
+    }
+
+    /// The FSM's start state. 0 is accepted as an alias for it everywhere.
+    pub fn initial_state(&self) -> u32 {
+        self.first_state
+    }
+
+    pub fn eos_token_id(&self) -> u32 {
+        self.eos_token_id
+    }
+
+    /// Returns this index with a different EOS token, e.g. to reuse a cached FSM
+    /// with a tokenizer whose EOS differs.
+    ///
+    /// Cheap, the computed maps are shared rather than copied: EOS only decides what
+    /// is offered at final and dead-ended states, never which tokens lead where.
+    pub fn with_eos_token_id(mut self, eos_token_id: u32) -> Self {
+        self.eos_token_id = eos_token_id;
+        // The memoized tokens include the old EOS at finals.
+        self.allowed_tokens = Arc::new(OnceCell::new());
+        self
+    }
+
+    /// Returns this index with `Instruction::Stop` instead of `Write([EOS])` where the
+    /// pattern is complete and generation must end, for decoders which stop without
+    /// appending EOS to the output. Dead ends still give `Write([EOS])`, since the
+    /// pattern is not complete there. Off by default.
+    pub fn with_stop_instruction(mut self, emit_stop: bool) -> Self {
+        self.emit_stop = emit_stop;
+        self
+    }
+
+    /// Returns this index with `get_allowed_token_ids` in the order `vocabulary` iterates
+    /// its tokens instead of by id, for samplers which tie-break by vocabulary order.
+    /// Off by default.
+    ///
+    /// `vocabulary` should be the one the index was built from. The rank of every token id
+    /// is kept next to the maps, a map entry per token, which is why this is opt-in.
+    /// Ids missing from `vocabulary`, like an EOS known only by id, come last, by id.
+    pub fn with_vocab_order(mut self, vocabulary: &TokenVocabulary) -> Self {
+        let mut ranks = FxHashMap::default();
+        for (rank, token_id) in vocabulary.iter_values().flatten().enumerate() {
+            ranks.entry(*token_id).or_insert(rank as u32);
+        }
+        self.vocab_order = Some(Arc::new(ranks));
+        // The memoized tokens are in id order.
+        self.allowed_tokens = Arc::new(OnceCell::new());
+        self
+    }
+
+    /// Blocks until all states finish.
+    ///
+    /// An index built by `new_on_demand` computes its remaining reachable states here.
+    pub fn await_finished(&self) {
+        if self.on_demand.is_some() && !self.is_computing_finished() {
+            let mut seen = FixedBitSet::with_capacity(self.states_to_token_maps.len());
+            seen.insert(self.first_state as usize);
+            let mut frontier = vec![self.first_state];
+            while let Some(state) = frontier.pop() {
+                let Some(map) = self.get_state_map(state) else {
+                    continue;
+                };
+                for &end_state in map.values() {
+                    if !seen.put(end_state as usize) {
+                        frontier.push(end_state);
+                    }
+                }
+            }
+            self.computing_finished.store(true, Ordering::Release);
+            wake_all(&*self.computing_finished);
+        }
+        wait(&self.computing_finished, false);
+    }
+
+    /// Compacts a long-lived index once it is fully computed.
+    ///
+    /// Waits for computation to finish, shrinks every state map to fit, and replaces the
+    /// per-state notifiers with a single shared flag, so lookups no longer wait on anything.
+    /// States which were never computed become empty, as in an index served from the cache.
+    ///
+    /// Maps are shrunk in place if this index is their only owner. Otherwise, e.g. while the
+    /// cache or a clone shares them, this index gets its own compacted copy, like `patch_vocab`.
+    pub fn finalize(&mut self) {
+        self.await_finished();
+
+        let notifiers = Arc::clone(&self.state_notifiers);
+        match Arc::get_mut(&mut self.states_to_token_maps) {
+            Some(maps) => {
+                for (cell, notifier) in maps.iter_mut().zip(notifiers.iter()) {
+                    // SAFETY: `&mut` access to the only owner of the maps, nobody else can read them.
+                    let map = unsafe { cell.get() };
+                    if !notifier.load(Ordering::Acquire) {
+                        *map = Arc::default();
+                    } else if let Some(map) = Arc::get_mut(map) {
+                        map.shrink_to_fit();
+                    }
+                }
+            }
+            None => {
+                let compacted: Vec<ThreadSafeCell<Arc<FxHashMap<u32, u32>>>> = self
+                    .states_to_token_maps
+                    .iter()
+                    .zip(notifiers.iter())
+                    .map(|(cell, notifier)| {
+                        let mut map = cell.read_after(notifier).map(|map| FxHashMap::clone(map)).unwrap_or_default();
+                        map.shrink_to_fit();
+                        ThreadSafeCell::new(Arc::new(map))
+                    })
+                    .collect();
+                self.states_to_token_maps = Arc::new(compacted);
+            }
+        }
+
+        let computed = Arc::new(AtomicBool::new(true));
+        self.state_notifiers = Arc::new(vec![computed; self.states_to_token_maps.len()]);
+        self.is_cached = true;
+    }
+
+    /// Estimates the bytes held by this index, for capacity planning.
+    ///
+    /// Waits for computation to finish, then counts every state's token map by its
+    /// capacity, plus the per-state bookkeeping, the distances cache and the FSM itself.
+    /// Maps shared with the cache or with clones are counted in full by each holder,
+    /// so footprints of indexes for the same pattern should not be summed.
+    /// A map shared by several states, see `dedup_maps`, is counted once.
+    pub fn memory_footprint(&self) -> usize {
+        self.await_finished();
+
+        // hashbrown keeps one control byte per bucket next to each `(key, value)` pair.
+        let bucket_size = std::mem::size_of::<(u32, u32)>() + 1;
+        let mut counted = FxHashSet::default();
+        let maps: usize = self
+            .states_to_token_maps
+            .iter()
+            .map(|cell| {
+                // SAFETY: computation is finished, so nothing writes to the maps anymore.
+                let map = unsafe { cell.get_ref() };
+                let heap = if counted.insert(Arc::as_ptr(map)) { map.capacity() * bucket_size } else { 0 };
+                std::mem::size_of::<ThreadSafeCell<Arc<FxHashMap<u32, u32>>>>() + heap
+            })
+            .sum();
+        let notifiers = self.state_notifiers.len() * std::mem::size_of::<Arc<AtomicBool>>();
+        let distances = self
+            .distances_to_final
+            .get()
+            .map_or(0, |distances| distances.capacity() * std::mem::size_of::<u32>());
+        let allowed_tokens = self.allowed_tokens.get().map_or(0, |memo| {
+            memo.iter()
+                .map(|state| {
+                    std::mem::size_of::<AllowedTokens>()
+                        + state.sorted.get().map_or(0, |sorted| sorted.capacity() * std::mem::size_of::<u32>())
+                })
+                .sum()
+        });
+        let fsm = std::mem::size_of::<FSMInfo>() + self.fsm_info.transitions.heap_size();
+        let vocab_order = self
+            .vocab_order
+            .as_ref()
+            .map_or(0, |ranks| ranks.capacity() * (std::mem::size_of::<(u32, u32)>() + 1));
+
+        std::mem::size_of::<Self>()
+            + maps
+            + notifiers
+            + distances
+            + allowed_tokens
+            + vocab_order
+            + fsm
+            + self.finals.capacity() * std::mem::size_of::<u32>()
+            + self.alphabet_symbol_mapping.capacity() * (std::mem::size_of::<(char, u32)>() + 1)
+            + self.returned_states.len() / 8
+    }
+
+    /// Registers a hook called with each state id as soon as that state is computed.
+    ///
+    /// States which finished before registration are reported right away on the
+    /// calling thread, so every computed state is seen exactly once. The rest are
+    /// reported from the compute thread, so the hook should be cheap.
+    /// For an index served from the cache, every state is reported immediately.
+    pub fn with_state_callback(self, callback: impl Fn(u32) + Send + Sync + 'static) -> Self {
+        self.set_state_callback(Arc::new(callback));
+        self
+    }
+
+    /// Non-consuming version of `with_state_callback`, replacing any previous hook.
+    /// Like the pause flag, the hook is shared with clones and with indexes attached to
+    /// the same computation, see `new`, so setting it through one replaces the others'.
+    pub fn set_state_callback(&self, callback: StateCallback) {
+        // A previous hook which panicked poisons the lock, see `publish_state`.
+        let mut slot = self.state_callback.lock().unwrap_or_else(PoisonError::into_inner);
+        // The log, unlike the notifiers, leaves out states published unreached at the end.
+        for state in self.completion_log.since(0) {
+            callback(state);
+        }
+        *slot = Some(callback);
+    }
+
+    /// Collects newly computed state transitions.
+    /// 
+    /// This is an api which takes no arguments, and is useful for people building on top of 
+    /// the computed transitions computation of `LazyFSMIndex` who want access to the raw state transitions
+    /// map in realtime, while it is being computed.
+    ///
+    /// Every state is returned exactly once across calls. Each call only looks at
+    /// the states finished since the previous one, so polling is amortized O(newly finished).
+    ///
+    /// # Clones
+    /// The position of this stream belongs to each `LazyFSMIndex` value, not to the shared
+    /// maps. A clone starts where the original stood when it was cloned, and from then on
+    /// each returns every remaining state once. So every consumer which should see all
+    /// states needs its own clone, made before its first call, while consumers splitting
+    /// the states between them have to share one value, e.g. behind a `Mutex`.
+    pub fn collect_finished_states(&mut self) -> Result<FxHashMap<u32, FxHashMap<u32, u32>>> {
+        Ok(self.collect_finished_states_ordered()?.into_iter().collect())
+    }
+
+    /// Same as `collect_finished_states`, but returns the states in the order they
+    /// finished computing. Concatenating the results of successive calls gives the
+    /// full completion order, with no state repeated.
+    pub fn collect_finished_states_ordered(&mut self) -> Result<Vec<(u32, FxHashMap<u32, u32>)>> {
+        let mut finished_states = Vec::new();
+
+        for state in self.completion_log.since(self.collect_cursor) {
+            self.collect_cursor += 1;
+            let index = state as usize;
+            if self.returned_states.contains(index) {
+                continue;
+            }
+
+            let notifier = &self.state_notifiers[index];
+            if let Some(state_map) = self.states_to_token_maps[index].read_after(notifier) {
+                finished_states.push((state, FxHashMap::clone(state_map)));
+                self.returned_states.set(index, true);
+            }
+        }
+        Ok(finished_states)
+    }
+
+    /// Rewinds the stream of `collect_finished_states` to the start, so the next call
+    /// returns every state finished so far again, e.g. to replay them to a consumer which
+    /// crashed. Only this value is rewound, clones keep their own position.
+    pub fn reset_stream_cursor(&mut self) {
+        self.returned_states.clear();
+        self.collect_cursor = 0;
+    }
+
+    /// Makes states with identical token maps share a single map, e.g. the equivalent
+    /// states of an FSM which was not minimized. Returns how many states reuse the map
+    /// of an earlier state.
+    ///
+    /// Waits for computation to finish. Like `patch_vocab`, this index gets its own
+    /// table of maps, while clones of it and the cache keep the original one.
+    pub fn dedup_maps(&mut self) -> usize {
+        self.await_finished();
+
+        let mut by_hash: FxHashMap<u64, Vec<Arc<FxHashMap<u32, u32>>>> = FxHashMap::default();
+        let mut deduplicated = 0;
+        let maps: Vec<ThreadSafeCell<Arc<FxHashMap<u32, u32>>>> = self
+            .states_to_token_maps
+            .iter()
+            .zip(self.state_notifiers.iter())
+            .map(|(cell, notifier)| {
+                let Some(map) = cell.read_after(notifier) else {
+                    return ThreadSafeCell::new(Arc::default());
+                };
+                // Iteration order differs between equal maps, so hash the sorted entries.
+                let mut entries: Vec<(u32, u32)> = map.iter().map(|(&token_id, &end_state)| (token_id, end_state)).collect();
+                entries.sort_unstable();
+                let mut hasher = FxHasher::default();
+                entries.hash(&mut hasher);
+
+                let candidates = by_hash.entry(hasher.finish()).or_default();
+                let shared = match candidates.iter().find(|candidate| candidate.as_ref() == map.as_ref()) {
+                    Some(candidate) => {
+                        deduplicated += 1;
+                        Arc::clone(candidate)
+                    }
+                    None => {
+                        candidates.push(Arc::clone(map));
+                        Arc::clone(map)
+                    }
+                };
+                ThreadSafeCell::new(shared)
+            })
+            .collect();
+
+        self.states_to_token_maps = Arc::new(maps);
+        deduplicated
+    }
+
+    /// Incrementally adds tokens to a finished index, e.g. after a LoRA or added tokens
+    /// grew the vocabulary by a handful of entries, without recomputing the whole FSM.
+    ///
+    /// Waits for computation to finish, then walks only the `added` tokens (already
+    /// processed token strings, as stored in `TokenVocabulary`) from every computed state,
+    /// inserting the new `(token_id, end_state)` pairs.
+    ///
+    /// The patch is copy-on-write: this index gets its own patched maps, while clones
+    /// of it and the cache keep sharing the original ones, so no reader ever sees a
+    /// map being mutated. States already handed out by `collect_finished_states`
+    /// are not returned again.
+    ///
+    /// # Errors
+    /// - An added token leads to a state that was never computed. That state would need
+    ///   a scan of the full vocabulary, so the index has to be rebuilt instead.
+    pub fn patch_vocab(&mut self, added: &[(String, Vec<u32>)]) -> Result<()> {
+        self.await_finished();
+
+        let added_transition_keys: Vec<Vec<u32>> = added
+            .iter()
+            .map(|(token, _)| self.token_transition_keys(token))
+            .collect();
+
+        let mut patched: Vec<Arc<FxHashMap<u32, u32>>> = Vec::with_capacity(self.states_to_token_maps.len());
+        for (state, (cell, notifier)) in self
+            .states_to_token_maps
+            .iter()
+            .zip(self.state_notifiers.iter())
+            .enumerate()
+        {
+            let Some(state_map) = cell.read_after(notifier) else {
+                patched.push(Arc::default());
+                continue;
+            };
+            if self.fsm_info.is_eof_required(state as u32) {
+                patched.push(Arc::clone(state_map));
+                continue;
+            }
+
+            let mut state_map = FxHashMap::clone(state_map);
+            for ((token, token_ids), transition_keys) in added.iter().zip(&added_transition_keys) {
+                let Some(&token_id) = token_ids.last() else {
+                    continue;
+                };
+                match walk_fsm_len(&self.fsm_info, transition_keys, state as u32, false) {
+                    Some((matched_len, end_state)) if matched_len == transition_keys.len() => {
+                        let end_state_computed = self
+                            .state_notifiers
+                            .get(end_state as usize)
+                            .is_some_and(|notifier| notifier.load(Ordering::Acquire))
+                            && !self.was_unreached(end_state);
+                        if !end_state_computed {
+                            bail!(
+                                "Token {:?} leads to state {}, which was never computed. Rebuild the index instead.",
+                                token,
+                                end_state
+                            );
+                        }
+                        state_map.insert(token_id, end_state);
+                    }
+                    _ => {}
+                }
+            }
+            patched.push(Arc::new(state_map));
+        }
+
+        self.states_to_token_maps = Arc::new(patched.into_iter().map(ThreadSafeCell::new).collect());
+        self.allowed_tokens = Arc::new(OnceCell::new());
+        Ok(())
+    }
+
+    /// Builds the index of this pattern followed by `suffix_fsm`, e.g. for chained generation,
+    /// reusing this index's maps for the states the suffix cannot affect.
+    ///
+    /// `vocabulary` must be the one this index was built from, the index does not keep it.
+    /// Waits for computation of this index to finish. The new index is computed like any
+    /// other, in the background with the options this index was built with, except that
+    /// the combined FSM is not canonicalized.
+    ///
+    /// # Reuse
+    /// The combined FSM is built by `FSMInfo::concat`. Its states still purely inside this
+    /// pattern, with no suffix state active, whose nearest final is at least as many characters
+    /// away as the longest token, keep this index's token map with end states renamed: no token
+    /// from them can finish this pattern mid-token and go on into the suffix. Everything else is
+    /// recomputed: states closer to a final of this pattern, and every state inside the suffix.
+    ///
+    /// # Errors
+    /// - This pattern has EOF required finals, so nothing can follow it.
+    /// - The combined transition table exceeds `FASTER_OUTLINES_MAX_TRANSITION_CELLS`.
+    pub fn extend_pattern(
+        &self,
+        suffix_fsm: &FSMInfo,
+        vocabulary: &TokenVocabulary,
+    ) -> Result<LazyFSMIndex> {
+        let (fsm_info, states) = self.fsm_info.concat_with_states(suffix_fsm)?;
+        self.await_finished();
+
+        let max_token_chars = vocabulary
+            .tokens
+            .iter()
+            .map(|token| self.token_transition_keys(token).len() as u32)
+            .max()
+            .unwrap_or(0);
+        let distances = self
+            .distances_to_final
+            .get_or_init(|| self.fsm_info.distances_to_final());
+        let ids: FxHashMap<&ConcatState, u32> = states
+            .iter()
+            .enumerate()
+            .map(|(id, state)| (state, id as u32))
+            .collect();
+        // The combined state a token ending at `prefix_state` leads to.
+        let combined_end_state = |prefix_state: u32| {
+            let suffix_states = if self.finals.contains(&prefix_state) {
+                vec![suffix_fsm.initial]
+            } else {
+                Vec::new()
+            };
+            ids.get(&(Some(prefix_state), suffix_states)).copied()
+        };
+
+        let mut seeded = SeededStates::default();
+        for (id, (prefix_state, suffix_states)) in states.iter().enumerate() {
+            let Some(prefix_state) = *prefix_state else {
+                continue;
+            };
+            let distance = distances.get(prefix_state as usize).copied().unwrap_or(u32::MAX);
+            if !suffix_states.is_empty() || distance < max_token_chars {
+                continue;
+            }
+
+            let index = prefix_state as usize;
+            let (Some(cell), Some(notifier)) =
+                (self.states_to_token_maps.get(index), self.state_notifiers.get(index))
+            else {
+                continue;
+            };
+            let Some(state_map) = cell.read_after(notifier) else {
+                continue;
+            };
+            let renamed: Option<Vec<(u32, u32)>> = state_map
+                .iter()
+                .map(|(&token_id, &end_state)| combined_end_state(end_state).map(|end| (token_id, end)))
+                .collect();
+            if let Some(renamed) = renamed {
+                seeded.insert(id, renamed);
+            }
+        }
+
+        let config = BuildConfig { sequential: *FORCE_SEQUENTIAL, seeded, ..BuildConfig::default() };
+        let mut extended = Self::build(
+            fsm_info,
+            vocabulary,
+            self.eos_token_id,
+            // Seeds are keyed by the concatenation's own state ids, which canonicalizing renumbers.
+            ComputeOptions { canonicalize_fsm: false, ..self.options.clone() },
+            config,
         )
+        .with_stop_instruction(self.emit_stop);
+        extended.vocab_order = self.vocab_order.clone();
+        Ok(extended)
+    }
+
+    /// Returns the FSM transition key a character maps to,
+    /// or `alphabet_anything_value` if the pattern does not mention it.
+    ///
+    /// Useful when diagnosing why a character takes the wildcard path.
+    pub fn transition_key_for_char(&self, c: char) -> u32 {
+        self.alphabet_symbol_mapping
+            .get(&c)
+            .copied()
+            .unwrap_or(self.fsm_info.alphabet_anything_value)
+    }
+
+    /// Transition keys of a processed token string, as the compute thread walks it.
+    fn token_transition_keys(&self, token: &str) -> Vec<u32> {
+        if self.options.byte_alphabet {
+            byte_transition_keys(token, self.byte_tokens)
+        } else {
+            token_transition_keys(token, &self.fsm_info, &self.alphabet_symbol_mapping, self.byte_tokens)
+        }
+    }
+
+    /// `{token_id: next_state}` maps of several states in one call, in the order requested,
+    /// e.g. to pull a handful of tables across FFI at once rather than one call per state.
+    ///
+    /// Blocks on each state like `get_next_instruction` if it is still being computed.
+    /// States which do not exist get `None`.
+    pub fn get_state_maps(&self, states: &[u32]) -> Vec<Option<FxHashMap<u32, u32>>> {
+        states
+            .iter()
+            .map(|&state| self.get_state_map(state).cloned())
+            .collect()
+    }
+
+    /// Exports the full index in the format of outlines' `RegexGuide.states_to_token_maps`.
+    ///
+    /// This is the canonical outlines-compatible export: `{state: {token_id: next_state}}`,
+    /// holding only the states which have at least one allowed token, exactly like outlines
+    /// omits states it never wrote a transition for. Blocks until computation finishes.
+    pub fn to_outlines_states_to_token_maps(&self) -> FxHashMap<u32, FxHashMap<u32, u32>> {
+        self.await_finished();
+
+        self.states_to_token_maps
+            .iter()
+            .zip(self.state_notifiers.iter())
+            .enumerate()
+            .filter_map(|(state, (cell, notifier))| {
+                cell.read_after(notifier)
+                    .filter(|state_map| !state_map.is_empty())
+                    .map(|state_map| (state as u32, FxHashMap::clone(state_map)))
+            })
+            .collect()
+    }
+
+    /// Serializes the whole constraint as JSON, for clients driving generation outside Rust.
+    ///
+    /// The result is `{"initial", "finals", "eos", "states": {state: {token_id: next_state}}}`,
+    /// with `states` holding the same states as `to_outlines_states_to_token_maps`, ordered by id.
+    /// A state missing from `states` only allows `eos`. Blocks until computation finishes.
+    /// Needs the `json` feature.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<String> {
+        let states = self
+            .to_outlines_states_to_token_maps()
+            .into_iter()
+            .map(|(state, map)| (state, map.into_iter().collect()))
+            .collect();
+        let constraint = ConstraintJson {
+            initial: self.first_state,
+            finals: self.finals.clone(),
+            eos: self.eos_token_id,
+            states,
+        };
+        Ok(serde_json::to_string(&constraint)?)
+    }
+
+    /// Exports the allowed-token mask of every computed state, e.g. to upload the whole
+    /// constraint to a GPU once and index it by state on-device. Blocks until computation finishes.
+    ///
+    /// Returns the state ids, ascending, and a flattened row-major bitmask matrix with one
+    /// row of `ceil(vocab_size / 64)` words per state. Bit `t % 64` of word `t / 64` in a row
+    /// is set if token `t` is allowed at that state, as returned by `get_allowed_token_ids_u32`.
+    /// Token ids of `vocab_size` and above are left out.
+    ///
+    /// # Memory
+    /// The matrix takes `states × ceil(vocab_size / 64) × 8` bytes, dense regardless of how
+    /// many tokens each state allows. For 1000 states over a 128k vocabulary that is 16 MB.
+    pub fn export_all_masks(&self, vocab_size: usize) -> (Vec<u32>, Vec<u64>) {
+        self.await_finished();
+
+        let words_per_state = vocab_size.div_ceil(64);
+        let states: Vec<u32> = self
+            .states_to_token_maps
+            .iter()
+            .zip(self.state_notifiers.iter())
+            .enumerate()
+            .filter(|(_, (cell, notifier))| cell.read_after(notifier).is_some())
+            .map(|(state, _)| state as u32)
+            .filter(|&state| !self.was_unreached(state))
+            .collect();
+
+        let mut masks = vec![0u64; states.len() * words_per_state];
+        for (row, &state) in masks.chunks_exact_mut(words_per_state.max(1)).zip(&states) {
+            for token_id in self.get_allowed_token_ids_u32(state as i32) {
+                let token_id = token_id as usize;
+                if token_id < vocab_size {
+                    row[token_id / 64] |= 1 << (token_id % 64);
+                }
+            }
+        }
+
+        (states, masks)
+    }
+
+    /// Streams the transitions of every computed state to `writer` as JSON lines, one
+    /// `{"state":s,"token":t,"next":n}` object per line, for exporting FSMs too large to
+    /// hold as one `to_json` string or Python dict.
+    ///
+    /// States are written breadth-first from the initial state, following token transitions,
+    /// each as soon as it is computed, so exporting overlaps computation and only one state's
+    /// lines are buffered at a time. Intermediate states no token ends in are never waited on.
+    /// The lines cover the same states as `to_outlines_states_to_token_maps`, with each state's
+    /// tokens sorted.
+    ///
+    /// # Errors
+    /// Writing fails, or computation was cancelled before finishing, see `compute_error`.
+    /// Lines written before the error are left in `writer`.
+    pub fn write_transitions_jsonl(&self, mut writer: impl std::io::Write) -> Result<()> {
+        let mut seen = FixedBitSet::with_capacity(self.states_to_token_maps.len());
+        seen.insert(self.first_state as usize);
+        let mut frontier = VecDeque::from([self.first_state]);
+        while let Some(state) = frontier.pop_front() {
+            let Some(map) = self.get_state_map(state) else {
+                continue;
+            };
+
+            let mut transitions: Vec<(u32, u32)> = map.iter().map(|(&token_id, &end_state)| (token_id, end_state)).collect();
+            transitions.sort_unstable();
+            for (token_id, end_state) in transitions {
+                writeln!(writer, r#"{{"state":{},"token":{},"next":{}}}"#, state, token_id, end_state)?;
+                if !seen.put(end_state as usize) {
+                    frontier.push_back(end_state);
+                }
+            }
+        }
+        writer.flush()?;
+        // A cancelled computation publishes the states it did not reach with empty maps.
+        if let Some(reason) = self.compute_error() {
+            return Err(IndexError::Cancelled { reason }.into());
+        }
+        Ok(())
+    }
+
+    /// Retrieve a vector of allowed Token ID's at the state `state`
+    ///
+    /// This is an alternative to the Instruction based API used
+    /// in `get_next_instruction`. The Instruction based API is
+    /// preffered, but this can be useful for debugging or more manual
+    /// implementations / logic about state transition / token ID selection.
+    ///
+    /// At a final state the EOS token is included, since stopping there is legal
+    /// even if tokens could continue the match. Token ID's are sorted, or in vocabulary
+    /// order, see `with_vocab_order`.
+    ///
+    /// The result for a state is kept from its second lookup on, so hot states like
+    /// the loop of `.*` only pay for a copy, not for collecting and sorting the map.
+    ///
+    /// Token ID's above `i32::MAX` wrap to negative values here,
+    /// use `get_allowed_token_ids_u32` if the vocabulary may contain them.
+    pub fn get_allowed_token_ids(&self, state: i32) -> Vec<i32> {
+        self.get_allowed_token_ids_u32(state)
+            .into_iter()
+            .map(|k| k as i32)
+            .collect()
+    }
+
+    /// Same as `get_allowed_token_ids`, but keeps the token ID's as `u32`,
+    /// so the full range of ID's is returned unchanged.
+    pub fn get_allowed_token_ids_u32(&self, state: i32) -> Vec<u32> {
+        let current_state = self.resolve_state(state);
+        if state == -1 || self.fsm_info.is_eof_required(current_state) {
+            return vec![self.eos_token_id];
+        }
+        match self.get_state_map(current_state) {
+            Some(next_tokens_to_end_states) => {
+                let memo = self
+                    .allowed_tokens
+                    .get_or_init(|| (0..self.states_to_token_maps.len()).map(|_| AllowedTokens::default()).collect());
+                let memo = &memo[current_state as usize];
+                if let Some(sorted) = memo.sorted.get() {
+                    return sorted.clone();
+                }
+
+                let allowed = self.sorted_allowed_tokens(state, next_tokens_to_end_states);
+                if memo.looked_up.swap(true, Ordering::Relaxed) {
+                    let _ = memo.sorted.set(allowed.clone());
+                }
+                allowed
+            }
+            None => vec![self.eos_token_id],
+        }
+    }
+
+    /// The tokens of `state`'s map, plus EOS at a final state, in the order
+    /// `get_allowed_token_ids` returns them.
+    fn sorted_allowed_tokens(&self, state: i32, next_tokens_to_end_states: &FxHashMap<u32, u32>) -> Vec<u32> {
+        let mut allowed: Vec<u32> = next_tokens_to_end_states.keys().cloned().collect();
+        if self.is_final_state(state) && !next_tokens_to_end_states.contains_key(&self.eos_token_id) {
+            allowed.push(self.eos_token_id);
+        }
+        match &self.vocab_order {
+            Some(ranks) => allowed.sort_unstable_by_key(|token_id| {
+                (ranks.get(token_id).copied().unwrap_or(u32::MAX), *token_id)
+            }),
+            None => allowed.sort_unstable(),
+        }
+        allowed
+    }
+
+    /// States reachable from the initial state by following FSM transitions.
+    ///
+    /// Computed by a BFS over the character level transitions, so it is a superset of the
+    /// states tokens can lead to. States outside the set are never visited during generation.
+    /// Useful for pruning, and for gauging how complex a pattern is.
+    pub fn reachable_states(&self) -> FixedBitSet {
+        self.fsm_info.reachable_states()
+    }
+
+    /// Tokens allowed once generation has already emitted `bytes` past `state`,
+    /// for byte-level decoding which can stop in the middle of a token.
+    ///
+    /// `bytes` are walked through the FSM character by character, via the alphabet mapping,
+    /// to the intermediate state the partial token ends in. The tokens allowed there are returned,
+    /// with the same conventions as `get_allowed_token_ids`.
+    ///
+    /// Returns an empty `Vec` if `bytes` do not match the pattern from `state`, or are not
+    /// valid UTF-8. The FSM consumes whole characters, so a trailing incomplete character
+    /// has no state to continue from either.
+    ///
+    /// The intermediate state may be one which no whole token leads to, so computation
+    /// never reaches it, or has not reached it yet. Such states are scanned here instead
+    /// of being waited for, and the result is not kept.
+    ///
+    /// Over a byte alphabet, see `ComputeOptions::byte_alphabet`, `bytes` are walked
+    /// byte by byte instead, so they may end in the middle of a character.
+    pub fn allowed_after_bytes(&self, state: i32, bytes: &[u8]) -> Vec<i32> {
+        if state == -1 {
+            return vec![self.eos_token_id as i32];
+        }
+        let transition_keys: Vec<u32> = if self.options.byte_alphabet {
+            bytes.iter().map(|&byte| u32::from(byte)).collect()
+        } else {
+            let Ok(prefix) = std::str::from_utf8(bytes) else {
+                return Vec::new();
+            };
+            prefix.chars().map(|c| self.transition_key_for_char(c)).collect()
+        };
+
+        let current_state = self.resolve_state(state);
+        let intermediate_state = if transition_keys.is_empty() {
+            current_state
+        } else {
+            match walk_fsm_len(&self.fsm_info, &transition_keys, current_state, false) {
+                Some((matched_len, end_state)) if matched_len == transition_keys.len() => end_state,
+                _ => return Vec::new(),
+            }
+        };
+
+        // On-demand indexes compute any state on lookup. Otherwise a state not computed yet,
+        // or published empty because computation never reached it, is scanned. An empty map
+        // is also all a cache hit knows of such states, so a dead end is scanned again too.
+        let index = intermediate_state as usize;
+        let scan = self.on_demand.is_none()
+            && !self.fsm_info.is_eof_required(intermediate_state)
+            && self.states_to_token_maps.get(index).is_some_and(|cell| {
+                cell.read_after(&self.state_notifiers[index]).is_none_or(|map| map.is_empty())
+            });
+        if !scan {
+            return self.get_allowed_token_ids(intermediate_state as i32);
+        }
+        let next_tokens_to_end_states: FxHashMap<u32, u32> = self.scanner().scan(index).into_iter().collect();
+        self.sorted_allowed_tokens(intermediate_state as i32, &next_tokens_to_end_states)
+            .into_iter()
+            .map(|k| k as i32)
+            .collect()
+    }
+
+    /// Tokens allowed at any of `states`, deduplicated and sorted.
+    ///
+    /// Meant for lookahead / tree-based speculative decoding, which needs the combined
+    /// admissible set of several candidate states before committing to a branch.
+    /// Each state contributes the same tokens as `get_allowed_token_ids`.
+    pub fn allowed_tokens_union(&self, states: &[i32]) -> Vec<i32> {
+        let mut union = FxHashSet::default();
+        for &state in states {
+            let current_state = self.resolve_state(state);
+            match self.get_state_map(current_state) {
+                Some(map) if state != -1 && !self.fsm_info.is_eof_required(current_state) => {
+                    union.extend(map.keys().copied());
+                    if self.is_final_state(state) {
+                        union.insert(self.eos_token_id);
+                    }
+                }
+                _ => {
+                    union.insert(self.eos_token_id);
+                }
+            }
+        }
+
+        let mut allowed: Vec<i32> = union.into_iter().map(|k| k as i32).collect();
+        allowed.sort_unstable();
+        allowed
+    }
+
+    /// The allowed token at `state` whose string is longest in characters, for maximal-munch
+    /// decoding which prefers consuming as much of the pattern per step as it can.
+    ///
+    /// The index keeps only token ID's, so the strings come from `vocabulary`, which should
+    /// be the one the index was built from. Ties go to the lowest token ID. The EOS token is
+    /// never returned; `None` means only EOS is allowed at `state`.
+    pub fn longest_allowed_token(&self, state: i32, vocabulary: &TokenVocabulary) -> Option<i32> {
+        let current_state = self.resolve_state(state);
+        if state == -1 || self.fsm_info.is_eof_required(current_state) {
+            return None;
+        }
+        let map = self.get_state_map(current_state)?;
+
+        vocabulary
+            .iter()
+            .flat_map(|(token, ids)| ids.iter().map(move |&id| (token, id)))
+            .filter(|&(_, id)| id != self.eos_token_id && map.contains_key(&id))
+            .max_by_key(|&(token, id)| (token.chars().count(), std::cmp::Reverse(id)))
+            .map(|(_, id)| id as i32)
+    }
+
+    //* Python Magic methods *//
+    /// Debug string, reporting progress without waiting for computation.
+    ///
+    /// Shows whether computation is still running, how many states are computed so far,
+    /// and the maps of the first 10 computed states.
+    pub fn __repr__(&self) -> String {
+        let status = if self.is_cached || self.is_computing_finished() {
+            "finished"
+        } else {
+            "computing"
+        };
+
+        let computed: Vec<(usize, &FxHashMap<u32, u32>)> = self
+            .states_to_token_maps
+            .iter()
+            .zip(self.state_notifiers.iter())
+            .enumerate()
+            .filter_map(|(index, (cell, notifier))| cell.read_after(notifier).map(|map| (index, map.as_ref())))
+            .filter(|&(index, _)| !self.was_unreached(index as u32))
+            .collect();
+
+        let states: String = computed
+            .iter()
+            .take(10)
+            .map(|(index, state_map)| format!("{}: {:?}", index, state_map))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let states_display = if computed.len() > 10 {
+            format!("{}, ...", states)
+        } else {
+            states
+        };
+
+        let finals = self
+            .finals
+            .iter()
+            .map(|state| state.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        format!(
+            "LazyFSMIndex(status={}, computed_states={}, first_state={}, eos_token_id={}, finals=[{}], states={{{}}})",
+            status,
+            computed.len(),
+            self.first_state,
+            self.eos_token_id,
+            finals,
+            states_display
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{FSMInfoBuilder, TransitionMap};
+
+    /// FSM for the pattern "[a-c]+". `pattern` only feeds the cache key,
+    /// so tests pass distinct values to keep their indexes independent.
+    pub(crate) fn abc_plus_fsm(pattern: &str) -> FSMInfo {
+        let mut transitions = FxHashMap::default();
+        for key in 0..3 {
+            transitions.insert((0, key), 1);
+            transitions.insert((1, key), 1);
+        }
+        let transitions: TransitionMap = transitions.try_into().unwrap();
+
+        FSMInfo {
+            initial: 0,
+            finals: vec![1],
+            transitions,
+            alphabet_symbol_mapping: [("a", 0), ("b", 1), ("c", 2)]
+                .into_iter()
+                .map(|(symbol, key)| (symbol.to_string(), key))
+                .collect(),
+            alphabet_anything_value: 3,
+            pattern: pattern.to_string(),
+            eof_required_finals: Vec::new(),
+        }
+    }
+
+    pub(crate) fn abc_vocab() -> TokenVocabulary {
+        let tokens: FxHashMap<String, Vec<u32>> = [
+            ("a", 1),
+            ("b", 2),
+            ("c", 3),
+            ("ab", 4),
+            ("bc", 5),
+            ("d", 6),
+            ("ad", 7),
+        ]
+        .into_iter()
+        .map(|(token, id)| (token.to_string(), vec![id]))
+        .collect();
+        TokenVocabulary::from_hashmap(tokens, 0)
+    }
+
+    #[test]
+    fn test_new_blocking_matches_threaded() {
+        let vocab = abc_vocab();
+        let mut threaded = LazyFSMIndex::new(abc_plus_fsm("new_blocking/threaded"), &vocab, 0);
+        let mut blocking =
+            LazyFSMIndex::new_blocking(abc_plus_fsm("new_blocking/blocking"), &vocab, 0);
+
+        assert!(blocking.is_computing_finished());
+
+        threaded.await_finished();
+        let expected = threaded.collect_finished_states().unwrap();
+        assert_eq!(blocking.collect_finished_states().unwrap(), expected);
+        assert_eq!(expected[&0].len(), 5);
+        assert_eq!(expected[&1].len(), 5);
+    }
+
+    #[test]
+    fn test_precomputed_alphabet_matches_recomputed() {
+        let vocab = abc_vocab();
+        let options = || ComputeOptions { canonicalize_fsm: false, ..ComputeOptions::default() };
+        let recomputed = LazyFSMIndex::new_with_options(abc_plus_fsm("alphabet/recomputed"), &vocab, 0, options());
+
+        let alphabet = Arc::new(abc_plus_fsm("alphabet/table").char_symbol_mapping());
+        let precomputed = LazyFSMIndex::new_with_alphabet(
+            abc_plus_fsm("alphabet/precomputed"),
+            &vocab,
+            0,
+            options(),
+            Arc::clone(&alphabet),
+        );
+        assert!(Arc::ptr_eq(&precomputed.alphabet_symbol_mapping, &alphabet));
+        assert_eq!(
+            precomputed.to_outlines_states_to_token_maps(),
+            recomputed.to_outlines_states_to_token_maps()
+        );
+
+        // A table for another alphabet is not used, so the maps cached for the pattern stay right.
+        let mut without_c = FxHashMap::clone(&alphabet);
+        without_c.remove(&'c');
+        let without_c = Arc::new(without_c);
+        let mismatched = LazyFSMIndex::new_with_alphabet(
+            abc_plus_fsm("alphabet/mismatched"),
+            &vocab,
+            0,
+            options(),
+            Arc::clone(&without_c),
+        );
+        assert!(!Arc::ptr_eq(&mismatched.alphabet_symbol_mapping, &without_c));
+        assert_eq!(
+            mismatched.to_outlines_states_to_token_maps(),
+            recomputed.to_outlines_states_to_token_maps()
+        );
+    }
+
+    #[test]
+    fn test_allowed_token_ids_above_i32_max() {
+        let high_id = i32::MAX as u32 + 10;
+        let mut vocab = abc_vocab();
+        vocab.add_token("cc".to_string(), vec![high_id]);
+
+        let index = LazyFSMIndex::new_blocking(abc_plus_fsm("allowed_ids/high_id"), &vocab, 0);
+
+        // The i32 API cannot represent the id, it comes back negative.
+        assert!(index.get_allowed_token_ids(1).iter().any(|&id| id < 0));
+        assert!(index.get_allowed_token_ids_u32(1).contains(&high_id));
+    }
+
+    #[test]
+    fn test_state_callback_sees_every_reachable_state() {
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = Arc::clone(&calls);
+
+        let index = LazyFSMIndex::new(abc_plus_fsm("state_callback/count"), &abc_vocab(), 0)
+            .with_state_callback(move |_| {
+                calls_clone.fetch_add(1, Ordering::SeqCst);
+            });
+        index.await_finished();
+
+        // "[a-c]+" has two reachable states, 0 and 1.
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_max_tokens_per_state_bounds_wildcard_maps() {
+        // ".*": a single accepting state looping on "a" and on anything else.
+        let mut transitions = FxHashMap::default();
+        transitions.insert((0, 0), 0);
+        transitions.insert((0, 1), 0);
+        let fsm_info = |pattern: &str| FSMInfo {
+            initial: 0,
+            finals: vec![0],
+            transitions: transitions.clone().try_into().unwrap(),
+            alphabet_symbol_mapping: [("a".to_string(), 0)].into_iter().collect(),
+            alphabet_anything_value: 1,
+            pattern: pattern.to_string(),
+            eof_required_finals: Vec::new(),
+        };
+        let eos_token_id = 99;
+        let tokens: FxHashMap<String, Vec<u32>> = (0..50)
+            .map(|i| (format!("tok{}", i), vec![i]))
+            .chain([("</s>".to_string(), vec![eos_token_id])])
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, eos_token_id);
+
+        let mut full = LazyFSMIndex::new_with_options(
+            fsm_info("max_tokens/full"),
+            &vocab,
+            eos_token_id,
+            ComputeOptions {
+                max_tokens_per_state: None,
+                reserved_token_ids: None,
+                canonicalize_fsm: false,
+                max_token_len: None,
+                byte_alphabet: false,
+            },
+        );
+        let mut capped = LazyFSMIndex::new_with_options(
+            fsm_info("max_tokens/capped"),
+            &vocab,
+            eos_token_id,
+            ComputeOptions {
+                max_tokens_per_state: Some(8),
+                reserved_token_ids: None,
+                canonicalize_fsm: false,
+                max_token_len: None,
+                byte_alphabet: false,
+            },
+        );
+        full.await_finished();
+        capped.await_finished();
+
+        assert_eq!(full.collect_finished_states().unwrap()[&0].len(), 51);
+        let capped_map = &capped.collect_finished_states().unwrap()[&0];
+        assert_eq!(capped_map.len(), 8);
+        assert!(capped_map.contains_key(&eos_token_id));
+    }
+
+    #[test]
+    fn test_outlines_states_to_token_maps_conformance() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("outlines_export/abc"), &abc_vocab(), 0);
+
+        // What outlines' RegexGuide builds for "[a-c]+" over this vocabulary:
+        // "d" and "ad" are never allowed, and no state is left without tokens.
+        let expected_state_map: FxHashMap<u32, u32> =
+            [(1, 1), (2, 1), (3, 1), (4, 1), (5, 1)].into_iter().collect();
+        let expected: FxHashMap<u32, FxHashMap<u32, u32>> =
+            [(0, expected_state_map.clone()), (1, expected_state_map)]
+                .into_iter()
+                .collect();
+
+        assert_eq!(index.to_outlines_states_to_token_maps(), expected);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_to_json_round_trips_the_constraint() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("to_json/abc"), &abc_vocab(), 0);
+
+        let json = index.to_json().unwrap();
+        let constraint: ConstraintJson = serde_json::from_str(&json).unwrap();
+        assert_eq!(constraint.initial, 0);
+        assert_eq!(constraint.finals, vec![1]);
+        assert_eq!(constraint.eos, 0);
+        assert_eq!(constraint.states[&0][&4], 1);
+        assert_eq!(constraint.states[&1][&3], 1);
+        assert!(!constraint.states[&0].contains_key(&6));
+
+        let states: FxHashMap<u32, FxHashMap<u32, u32>> = constraint
+            .states
+            .into_iter()
+            .map(|(state, map)| (state, map.into_iter().collect()))
+            .collect();
+        assert_eq!(states, index.to_outlines_states_to_token_maps());
+        assert!(json.starts_with(r#"{"initial":0,"finals":[1],"eos":0,"states":{"0":{"1":1,"#));
+    }
+
+    #[test]
+    fn test_cache_collision_with_different_vocab_recomputes() {
+        let vocab = abc_vocab();
+        let first = LazyFSMIndex::new(abc_plus_fsm("cache_collision/abc"), &vocab, 0);
+        first.await_finished();
+
+        // Same tokens, different EOS: the sampled vocab hash, and so the cache key, collide.
+        let mut colliding_vocab = abc_vocab();
+        colliding_vocab.eos_token_id = 7;
+        assert_eq!(
+            get_fsm_cache_key("cache_collision/abc", &[], &vocab, &ComputeOptions::default()),
+            get_fsm_cache_key("cache_collision/abc", &[], &colliding_vocab, &ComputeOptions::default()),
+        );
+
+        let second = LazyFSMIndex::new(abc_plus_fsm("cache_collision/abc"), &colliding_vocab, 7);
+        assert!(!second.is_cached);
+    }
+
+    #[test]
+    fn test_clones_collect_finished_states_independently() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let fsm_info = chain_fsm(3, "clone_collect/aaa");
+        let mut index = LazyFSMIndex::new_on_demand(fsm_info, &vocab, 0, ComputeOptions::default());
+        let mut fresh = index.clone();
+
+        // Only the initial state is computed, the rest are still pending.
+        index.get_allowed_token_ids(0);
+        assert_eq!(index.collect_finished_states().unwrap().keys().collect::<Vec<_>>(), vec![&0]);
+        let mut forked = index.clone();
+
+        index.await_finished();
+        let sorted_states = |index: &mut LazyFSMIndex| {
+            let mut states: Vec<u32> = index.collect_finished_states().unwrap().into_keys().collect();
+            states.sort_unstable();
+            states
+        };
+        // The fork resumes from the original's position, then both see each remaining state.
+        assert_eq!(sorted_states(&mut index), vec![1, 2, 3]);
+        assert_eq!(sorted_states(&mut forked), vec![1, 2, 3]);
+        // A clone made before any collection sees every state.
+        assert_eq!(sorted_states(&mut fresh), vec![0, 1, 2, 3]);
+        assert!(index.collect_finished_states().unwrap().is_empty());
+        assert!(forked.collect_finished_states().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_state_maps_mixes_valid_and_out_of_range() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("state_maps/abc"), &abc_vocab(), 0);
+        let maps = index.get_state_maps(&[1, 42, 0, u32::MAX]);
+
+        assert_eq!(maps.len(), 4);
+        assert_eq!(maps[0].as_ref(), index.get_state_map(1));
+        assert_eq!(maps[1], None);
+        assert_eq!(maps[2].as_ref(), index.get_state_map(0));
+        assert!(!maps[2].as_ref().unwrap().is_empty());
+        assert_eq!(maps[3], None);
+    }
+
+    #[test]
+    fn test_get_next_instruction_timeout_on_delayed_state() {
+        let index = LazyFSMIndex::new_on_demand(
+            abc_plus_fsm("instruction_timeout/abc"),
+            &abc_vocab(),
+            0,
+            ComputeOptions::default(),
+        );
+        // Claim state 1 as if another thread were still computing it.
+        let claim = &index.on_demand.as_ref().unwrap().claimed[1];
+        claim.store(true, Ordering::Release);
+
+        let start = std::time::Instant::now();
+        assert!(index.get_next_instruction_timeout(1, Duration::from_millis(50)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(index.get_next_instruction_timeout(-1, Duration::ZERO).is_some());
+
+        // Once the delayed state is computed, it is returned like `get_next_instruction` does.
+        claim.store(false, Ordering::Release);
+        let sorted_tokens = |instruction: Option<Instruction>| match instruction {
+            Some(Instruction::Generate(Generate { tokens: Some(mut tokens), .. })) => {
+                tokens.sort_unstable();
+                tokens
+            }
+            _ => panic!("state 1 should generate"),
+        };
+        assert_eq!(
+            sorted_tokens(index.get_next_instruction_timeout(1, Duration::from_secs(60))),
+            sorted_tokens(Some(index.get_next_instruction(1))),
+        );
+    }
+
+    #[test]
+    fn test_forced_tokens_are_written() {
+        // "hello[0-9]", every digit sharing transition key 4.
+        let mut builder = FSMInfoBuilder::new()
+            .alphabet_symbol('h', 0)
+            .alphabet_symbol('e', 1)
+            .alphabet_symbol('l', 2)
+            .alphabet_symbol('o', 3);
+        for digit in '0'..='9' {
+            builder = builder.alphabet_symbol(digit, 4);
+        }
+        let hello_digit = |pattern: &str| {
+            builder
+                .clone()
+                .add_transition(0, 0, 1)
+                .add_transition(1, 1, 2)
+                .add_transition(2, 2, 3)
+                .add_transition(3, 2, 4)
+                .add_transition(4, 3, 5)
+                .add_transition(5, 4, 6)
+                .add_final(6)
+                .pattern(pattern.to_string())
+                .build()
+                .unwrap()
+        };
+        let mut tokens: FxHashMap<String, Vec<u32>> = [("h", 1), ("e", 2), ("l", 3), ("o", 4)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        for digit in 0..10 {
+            tokens.insert(digit.to_string(), vec![10 + digit]);
+        }
+
+        let index = LazyFSMIndex::new(
+            hello_digit("forced/hello[0-9]"),
+            &TokenVocabulary::from_hashmap(tokens.clone(), 0),
+            0,
+        );
+        match index.get_next_instruction_with_forced_tokens(0) {
+            Instruction::Write(write) => assert_eq!(write.tokens, vec![1, 2, 3, 3, 4]),
+            Instruction::Generate(_) | Instruction::Stop => panic!("\"hello\" is forced"),
+        }
+        let after_hello = index.advance(0, &[1, 2, 3, 3, 4]).unwrap();
+        match index.get_next_instruction_with_forced_tokens(after_hello) {
+            Instruction::Generate(generate) => assert_eq!(generate.tokens.unwrap().len(), 10),
+            Instruction::Write(_) | Instruction::Stop => panic!("any digit may follow \"hello\""),
+        }
+
+        // With "ll" in the vocabulary, the run stops where "l" and "ll" branch.
+        tokens.insert("ll".to_string(), vec![5]);
+        let index = LazyFSMIndex::new(
+            hello_digit("forced/hello[0-9]-ll"),
+            &TokenVocabulary::from_hashmap(tokens, 0),
+            0,
+        );
+        match index.get_next_instruction_with_forced_tokens(0) {
+            Instruction::Write(write) => assert_eq!(write.tokens, vec![1, 2]),
+            Instruction::Generate(_) | Instruction::Stop => panic!("\"he\" is forced"),
+        }
+    }
+
+    #[test]
+    fn test_compute_panic_releases_waiters() {
+        // A seeded end state past the last state makes the compute thread panic.
+        let seeded: SeededStates = [(0, vec![(1, 1000)])].into_iter().collect();
+        let index = LazyFSMIndex::build(
+            abc_plus_fsm("compute_panic/abc"),
+            &abc_vocab(),
+            0,
+            ComputeOptions::default(),
+            BuildConfig { seeded, ..BuildConfig::default() },
+        );
+
+        index.await_finished();
+        assert!(index.compute_error().is_some());
+        assert!(matches!(index.await_state(1), Err(IndexError::Cancelled { .. })));
+        assert!(!index.has_transitions(0));
+
+        // The failed index was not cached, and the pool still computes new ones.
+        let fresh = LazyFSMIndex::new(abc_plus_fsm("compute_panic/abc"), &abc_vocab(), 0);
+        assert!(!fresh.is_cached);
+        fresh.await_finished();
+        assert_eq!(fresh.compute_error(), None);
+        assert!(fresh.has_transitions(0));
+    }
+
+    #[test]
+    fn test_panicked_state_callback_does_not_poison_the_index() {
+        let index = LazyFSMIndex::new_on_demand(
+            abc_plus_fsm("callback_poison/abc"),
+            &abc_vocab(),
+            0,
+            ComputeOptions::default(),
+        );
+        let slot = Arc::clone(&index.state_callback);
+        let _ = std::thread::spawn(move || {
+            let _slot = slot.lock().unwrap();
+            panic!("state callback panicked");
+        })
+        .join();
+        assert!(index.state_callback.is_poisoned());
+
+        // Publishing states and replacing the hook both still work.
+        assert!(index.has_transitions(0));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = Arc::clone(&seen);
+        index.set_state_callback(Arc::new(move |state| seen_clone.lock().unwrap().push(state)));
+        assert_eq!(*seen.lock().unwrap(), vec![0]);
+        index.await_state(1).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_dangling_target_state_fails_the_index() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        // State 1 is the last state with transitions, so state 3 has no slot.
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 0, 3)
+            .add_final(3)
+            .pattern("dangling_target/aa".to_string())
+            .build()
+            .unwrap();
+
+        let threaded = LazyFSMIndex::new(fsm_info.clone(), &vocab, 0);
+        let on_demand = LazyFSMIndex::new_on_demand(fsm_info, &vocab, 0, ComputeOptions::default());
+        for index in [threaded, on_demand] {
+            index.await_finished();
+            let Err(IndexError::Cancelled { reason }) = index.await_state(0) else {
+                panic!("expected the index to be refused");
+            };
+            assert!(reason.contains("targets state 3"), "{}", reason);
+            assert_eq!(index.compute_error(), Some(reason));
+            assert!(!index.is_cached);
+        }
+    }
+
+    #[test]
+    fn test_with_eos_token_id_retargets_cached_index() {
+        let vocab = abc_vocab();
+        let first = LazyFSMIndex::new(abc_plus_fsm("retarget_eos/abc"), &vocab, 0);
+        first.await_finished();
+        let cached = LazyFSMIndex::new(abc_plus_fsm("retarget_eos/abc"), &vocab, 0);
+        assert!(cached.is_cached);
+
+        let retargeted = cached.with_eos_token_id(7);
+        assert_eq!(retargeted.eos_token_id(), 7);
+        assert!(Arc::ptr_eq(&retargeted.states_to_token_maps, &first.states_to_token_maps));
+        assert!(retargeted.get_allowed_token_ids(1).contains(&7));
+        assert!(!retargeted.get_allowed_token_ids(1).contains(&0));
+        match retargeted.get_next_instruction(-1) {
+            Instruction::Write(write) => assert_eq!(write.tokens, vec![7]),
+            Instruction::Generate(_) | Instruction::Stop => panic!("state -1 should write EOS"),
+        }
+
+        // The original keeps its EOS.
+        assert_eq!(first.eos_token_id(), 0);
+        assert!(first.get_allowed_token_ids(1).contains(&0));
+    }
+
+    /// FSM for "a{len}": a chain 0 --a--> 1 --a--> ... --a--> len, where only len is final.
+    pub(crate) fn chain_fsm(len: u32, pattern: &str) -> FSMInfo {
+        let transitions: FxHashMap<(u32, u32), u32> =
+            (0..len).map(|state| ((state, 0), state + 1)).collect();
+        FSMInfo {
+            initial: 0,
+            finals: vec![len],
+            transitions: transitions.try_into().unwrap(),
+            alphabet_symbol_mapping: [("a".to_string(), 0)].into_iter().collect(),
+            alphabet_anything_value: 1,
+            pattern: pattern.to_string(),
+            eof_required_finals: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_await_state_out_of_bounds() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("await_errors/out_of_bounds"), &abc_vocab(), 0);
+        let total = index.states_to_token_maps.len();
+        assert_eq!(
+            index.await_state(total as u32),
+            Err(IndexError::OutOfBounds { state: total as u32, total })
+        );
+        assert_eq!(index.await_state(1), Ok(()));
+    }
+
+    #[test]
+    fn test_await_state_unreachable_is_not_computed() {
+        // State 2 only leads into the FSM, nothing leads to it.
+        let fsm_info = || {
+            FSMInfoBuilder::new()
+                .alphabet_symbol('a', 0)
+                .add_transition(0, 0, 1)
+                .add_transition(2, 0, 1)
+                .add_final(1)
+                .pattern("await_errors/unreachable".to_string())
+                .build()
+                .unwrap()
+        };
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+
+        let index = LazyFSMIndex::new(fsm_info(), &vocab, 0);
+        assert_eq!(index.await_state(2), Err(IndexError::NotComputed { state: 2 }));
+        assert_eq!(index.await_state(1), Ok(()));
+        // A running index treats it as a dead end too once computation ends, instead of blocking.
+        assert!(!index.has_transitions(2));
+        assert!(index.get_next_instruction_timeout(2, Duration::ZERO).is_some());
+
+        // Cache entries mark every state as computed, unreachable ones as dead ends.
+        // The unreachable state is released before the maps are cached.
+        index.await_finished();
+        let cached = LazyFSMIndex::new(fsm_info(), &vocab, 0);
+        assert!(cached.is_cached);
+        assert_eq!(cached.await_state(2), Ok(()));
+        assert!(!cached.has_transitions(2));
+    }
+
+    #[test]
+    fn test_pause_halts_computation_until_resumed() {
+        let chain_len = 5000;
+        let fsm_info = chain_fsm(chain_len, "pause_resume/chain");
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+
+        let index = LazyFSMIndex::new(fsm_info, &vocab, 0);
+        index.pause();
+        assert!(index.clone().is_paused());
+        // Lets a state in flight when pausing finish.
+        std::thread::sleep(Duration::from_millis(20));
+        let computed = index.completion_log.since(0).count();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(index.completion_log.since(0).count(), computed, "no progress while paused");
+
+        if computed > 0 {
+            index.await_state(index.first_state).unwrap();
+        }
+        if computed <= chain_len as usize {
+            assert!(index.get_next_instruction_timeout(chain_len as i32, Duration::from_millis(20)).is_none());
+        }
+
+        index.resume();
+        index.await_finished();
+        assert!(!index.is_paused());
+        assert_eq!(index.completion_log.since(0).count(), chain_len as usize + 1);
+    }
+
+    #[test]
+    fn test_collect_finished_states_returns_each_state_once() {
+        let chain_len = 300;
+        let fsm_info = chain_fsm(chain_len, "collect_once/chain");
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+
+        let mut index = LazyFSMIndex::new(fsm_info, &vocab, 0);
+        let mut seen = FxHashMap::default();
+        while !index.is_computing_finished() {
+            for (state, _) in index.collect_finished_states_ordered().unwrap() {
+                *seen.entry(state).or_insert(0) += 1;
+            }
+        }
+        for (state, _) in index.collect_finished_states_ordered().unwrap() {
+            *seen.entry(state).or_insert(0) += 1;
+        }
+
+        assert_eq!(seen.len(), chain_len as usize + 1);
+        assert!(seen.values().all(|&count| count == 1));
+        assert!(index.collect_finished_states().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reset_stream_cursor_replays_every_state() {
+        let mut index = LazyFSMIndex::new(abc_plus_fsm("reset_stream/abc"), &abc_vocab(), 0);
+        index.await_finished();
+        let first = index.collect_finished_states_ordered().unwrap();
+        assert!(!first.is_empty());
+        assert!(index.collect_finished_states().unwrap().is_empty());
+
+        index.reset_stream_cursor();
+        assert_eq!(index.collect_finished_states_ordered().unwrap(), first);
+        assert!(index.collect_finished_states().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_advance_over_prefix() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("aa", 2)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let index = LazyFSMIndex::new(chain_fsm(4, "advance/aaaa"), &vocab, 0);
+
+        assert_eq!(index.advance(0, &[]), Some(0));
+        assert_eq!(index.advance(0, &[2]), Some(2));
+        assert_eq!(index.advance(0, &[1, 1, 1]), Some(3));
+        assert_eq!(index.advance(0, &[1, 1, 1]), index.get_next_state(2, 1));
+        // Reaching the final state ends generation.
+        assert_eq!(index.advance(0, &[2, 2]), Some(-1));
+        // A fifth "a" overshoots the pattern.
+        assert_eq!(index.advance(0, &[2, 2, 1]), None);
+        assert_eq!(index.advance(0, &[2, 1, 2]), None);
+    }
+
+    #[test]
+    fn test_transition_key_for_char() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("transition_key/abc"), &abc_vocab(), 0);
+        assert_eq!(index.transition_key_for_char('a'), 0);
+        assert_eq!(index.transition_key_for_char('c'), 2);
+        assert_eq!(index.transition_key_for_char('z'), 3);
+    }
+
+    #[test]
+    fn test_finalize_shrinks_maps() {
+        let vocab = abc_vocab();
+        let mut index = LazyFSMIndex::new(abc_plus_fsm("finalize/abc+"), &vocab, 0);
+        index.await_finished();
+        for cell in index.states_to_token_maps.iter() {
+            // SAFETY: computation is finished and nothing else reads this test-only pattern.
+            Arc::make_mut(unsafe { cell.get() }).reserve(1024);
+        }
+
+        let capacity = |index: &LazyFSMIndex| -> usize {
+            index
+                .states_to_token_maps
+                .iter()
+                .map(|cell| unsafe { cell.get_ref() }.capacity())
+                .sum()
+        };
+        let before_capacity = capacity(&index);
+        let before = index.to_outlines_states_to_token_maps();
+
+        index.finalize();
+
+        let after_capacity = capacity(&index);
+        assert!(
+            after_capacity * 10 < before_capacity,
+            "capacity {} -> {}",
+            before_capacity,
+            after_capacity
+        );
+        assert_eq!(index.to_outlines_states_to_token_maps(), before);
+        let mut allowed = index.get_allowed_token_ids(1);
+        allowed.sort_unstable();
+        assert_eq!(allowed, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_memory_footprint_grows_with_vocab() {
+        let vocab_of = |size: u32| {
+            let tokens: FxHashMap<String, Vec<u32>> =
+                (1..=size).map(|id| ("a".repeat(id as usize), vec![id])).collect();
+            TokenVocabulary::from_hashmap(tokens, 0)
+        };
+        let small = LazyFSMIndex::new(chain_fsm(64, "footprint_small/a{64}"), &vocab_of(4), 0);
+        let large = LazyFSMIndex::new(chain_fsm(64, "footprint_large/a{64}"), &vocab_of(32), 0);
+
+        let entries = |index: &LazyFSMIndex| -> usize {
+            index.to_outlines_states_to_token_maps().values().map(|map| map.len()).sum()
+        };
+        let extra_entries = entries(&large) - entries(&small);
+
+        let (small_bytes, large_bytes) = (small.memory_footprint(), large.memory_footprint());
+        assert!(small_bytes > 0);
+        assert!(
+            large_bytes >= small_bytes + extra_entries * std::mem::size_of::<(u32, u32)>(),
+            "{} bytes for 4 tokens vs {} bytes for 32",
+            small_bytes,
+            large_bytes
+        );
+    }
+
+    #[test]
+    fn test_dedup_maps_preserves_every_state_map() {
+        // "[a-h].": each of the 8 states after the first symbol accepts any single char,
+        // so they compute identical maps, as an unminimized FSM would.
+        let branches = 8;
+        let mut builder = FSMInfoBuilder::new();
+        for branch in 1..=branches {
+            builder = builder
+                .alphabet_symbol((b'a' + branch as u8 - 1) as char, branch - 1)
+                .add_transition(0, branch - 1, branch)
+                .add_transition(branch, branches, branches + 1);
+        }
+        let fsm_info = builder
+            .anything_value(branches)
+            .add_final(branches + 1)
+            .pattern("dedup_maps/[a-h].".to_string())
+            .build()
+            .unwrap();
+        let tokens: FxHashMap<String, Vec<u32>> = (0..200)
+            .map(|id| (char::from_u32(0x100 + id).unwrap().to_string(), vec![id + 1]))
+            .chain(('a'..='h').zip(201..).map(|(c, id)| (c.to_string(), vec![id])))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+
+        let mut index = LazyFSMIndex::new(fsm_info, &vocab, 0);
+        let states: Vec<u32> = (0..index.states_to_token_maps.len() as u32).collect();
+        let before = index.get_state_maps(&states);
+        let bytes_before = index.memory_footprint();
+
+        assert_eq!(index.dedup_maps(), branches as usize - 1);
+        assert_eq!(index.get_state_maps(&states), before);
+        let bytes_after = index.memory_footprint();
+        assert!(
+            bytes_after + (branches as usize - 1) * 200 * std::mem::size_of::<(u32, u32)>() <= bytes_before,
+            "{} bytes before dedup, {} after",
+            bytes_before,
+            bytes_after
+        );
+        // Maps which already share one are counted again.
+        assert_eq!(index.dedup_maps(), branches as usize - 1);
+    }
+
+    #[test]
+    fn test_patch_vocab_only_changes_affected_states() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let original = LazyFSMIndex::new(chain_fsm(4, "patch_vocab/aaaa"), &vocab, 0);
+        let before = original.to_outlines_states_to_token_maps();
+
+        let mut patched = original.clone();
+        patched.patch_vocab(&[("aaa".to_string(), vec![9])]).unwrap();
+        let after = patched.to_outlines_states_to_token_maps();
+
+        // "aaa" fits from states 0 and 1 only.
+        assert_eq!(after[&0].get(&9), Some(&3));
+        assert_eq!(after[&1].get(&9), Some(&4));
+        for state in [0, 1] {
+            let mut expected = before[&state].clone();
+            expected.insert(9, state + 3);
+            assert_eq!(after[&state], expected);
+        }
+        for state in [2, 3] {
+            assert_eq!(after[&state], before[&state]);
+        }
+        // The original index, and anything else sharing its maps, is untouched.
+        assert_eq!(original.to_outlines_states_to_token_maps(), before);
+    }
+
+    #[test]
+    fn test_byte_alphabet_index_walks_partial_characters() {
+        use crate::tokenizer_index::tests::{e_acute_plus_byte_fsm, e_acute_vocab};
+
+        let options = ComputeOptions { byte_alphabet: true, ..ComputeOptions::default() };
+        let mut index =
+            LazyFSMIndex::new_with_options(e_acute_plus_byte_fsm("byte_alphabet/index"), &e_acute_vocab(), 0, options);
+
+        // Generation stopped after the first byte of "é", in the middle of the character.
+        let mut allowed = index.allowed_after_bytes(0, &[0xC3]);
+        allowed.sort_unstable();
+        assert_eq!(allowed, [4, 5]);
+        assert!(index.allowed_after_bytes(0, &[0xA9]).is_empty());
+
+        index.patch_vocab(&[("\x00A9é".to_string(), vec![7])]).unwrap();
+        assert_eq!(index.get_next_state(1, 7), Some(2));
+        assert!(!index.get_allowed_token_ids(0).contains(&7));
+    }
+
+    #[test]
+    fn test_allowed_token_ids_are_memoized_from_the_second_lookup() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("allowed_memo/abc"), &abc_vocab(), 0);
+        let first = index.get_allowed_token_ids(1);
+        let memo = |index: &LazyFSMIndex| index.allowed_tokens.get().unwrap()[1].sorted.get().cloned();
+        assert!(memo(&index).is_none(), "a state looked up once is not kept");
+
+        assert_eq!(index.get_allowed_token_ids(1), first);
+        assert!(memo(&index).is_some());
+        assert_eq!(index.get_allowed_token_ids(1), first);
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]), "not sorted: {:?}", first);
+        assert!(first.contains(&0), "EOS is allowed at the final state");
+
+        // A different EOS must not be served the old one.
+        let retargeted = index.with_eos_token_id(9);
+        assert!(retargeted.get_allowed_token_ids(1).contains(&9));
+    }
+
+    #[test]
+    fn test_total_allowed_transitions_tracks_permissiveness() {
+        let vocab = TokenVocabulary::from_hashmap(
+            ["y", "e", "s", "n", "o", "yes", "no", "ye", "x", "hello"]
+                .into_iter()
+                .enumerate()
+                .map(|(id, token)| (token.to_string(), vec![id as u32 + 1]))
+                .collect(),
+            0,
+        );
+        // ".*": one final state looping on every symbol.
+        let anything = FSMInfoBuilder::new()
+            .anything_value(0)
+            .add_transition(0, 0, 0)
+            .add_final(0)
+            .pattern("total_allowed/.*".to_string())
+            .build()
+            .unwrap();
+        // "yes|no"
+        let mut yes_or_no = FSMInfoBuilder::new();
+        for (key, c) in "yesno".chars().enumerate() {
+            yes_or_no = yes_or_no.alphabet_symbol(c, key as u32);
+        }
+        let yes_or_no = yes_or_no
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 2)
+            .add_transition(2, 2, 5)
+            .add_transition(0, 3, 4)
+            .add_transition(4, 4, 5)
+            .add_final(5)
+            .pattern("total_allowed/yes|no".to_string())
+            .build()
+            .unwrap();
+
+        let anything = LazyFSMIndex::new(anything, &vocab, 0);
+        let yes_or_no = LazyFSMIndex::new(yes_or_no, &vocab, 0);
+
+        // Every token from the single state.
+        assert_eq!(anything.total_allowed_transitions(), 10);
+        // "y", "ye", "yes", "n", "no" from 0, "e" from 1, "s" from 2, "o" from 4.
+        assert_eq!(yes_or_no.total_allowed_transitions(), 8);
+    }
+
+    #[test]
+    fn test_concurrent_builds_share_one_computation() {
+        // Long enough to still be computing while paused right after the build returns.
+        let tokens: FxHashMap<String, Vec<u32>> =
+            (1..=50).map(|len| ("a".repeat(len), vec![len as u32])).collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let fsm_info = chain_fsm(1000, "in_flight/a1000");
+
+        let first = LazyFSMIndex::new(fsm_info.clone(), &vocab, 0);
+        first.pause();
+        let second = LazyFSMIndex::new(fsm_info.clone(), &vocab, 0);
+        assert!(!second.is_cached, "the first build finished before it was paused");
+        assert!(Arc::ptr_eq(&first.states_to_token_maps, &second.states_to_token_maps));
+        assert!(IN_FLIGHT.lock().unwrap().contains_key(&get_fsm_cache_key(
+            "in_flight/a1000",
+            &[],
+            &vocab,
+            &ComputeOptions::default()
+        )));
+
+        // The attached index shares the callback slot, and dropping it keeps the pause.
+        let reported = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let reported_clone = Arc::clone(&reported);
+        second.set_state_callback(Arc::new(move |_| {
+            reported_clone.fetch_add(1, Ordering::Relaxed);
+        }));
+        assert!(Arc::ptr_eq(&first.state_callback, &second.state_callback));
+        let second_maps = Arc::clone(&second.states_to_token_maps);
+        drop(second);
+        assert!(first.is_paused());
+        // Lets a state in flight when pausing finish.
+        std::thread::sleep(Duration::from_millis(20));
+        let computed = first.completion_log.since(0).count();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(first.completion_log.since(0).count(), computed, "dropping the attached index resumed");
+
+        first.resume();
+        first.await_finished();
+        assert_eq!(reported.load(Ordering::Relaxed), first.completion_log.since(0).count());
+        assert!(Arc::ptr_eq(&first.states_to_token_maps, &second_maps));
+
+        // Once finished, the entry is gone and builds are served from the cache.
+        let third = LazyFSMIndex::new(fsm_info, &vocab, 0);
+        assert!(third.is_cached);
+    }
+
+    #[test]
+    fn test_deadline_cancels_a_long_computation() {
+        let tokens: FxHashMap<String, Vec<u32>> =
+            (1..=50).map(|len| ("a".repeat(len), vec![len as u32])).collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let fsm_info = chain_fsm(1000, "deadline/a1000");
+
+        let (index, completed) =
+            LazyFSMIndex::new_with_deadline(fsm_info.clone(), &vocab, 0, Duration::from_millis(1));
+        assert!(!completed);
+        assert!(index.compute_error().is_some());
+        assert!(index.completion_log.since(0).count() < 1000, "every state was computed");
+        // The end of the chain is never reached in time, so generation there stops with EOS.
+        assert!(matches!(index.get_next_instruction(999), Instruction::Write(w) if w.tokens == vec![0]));
+        assert!(!LazyFSMIndex::new(fsm_info, &vocab, 0).is_cached, "a cancelled index was cached");
+
+        let (index, completed) =
+            LazyFSMIndex::new_with_deadline(abc_plus_fsm("deadline/abc"), &abc_vocab(), 0, Duration::from_secs(60));
+        assert!(completed);
+        assert_eq!(index.compute_error(), None);
+    }
+
+    #[test]
+    fn test_deadline_is_kept_while_the_pool_is_busy() {
+        static POOL: Lazy<ComputePool> = Lazy::new(|| ComputePool::new(1));
+        let busy = Arc::new(AtomicBool::new(true));
+        let busy_clone = Arc::clone(&busy);
+        POOL.spawn(move || wait(&busy_clone, true));
+
+        // Queued behind the busy job, the computation is cancelled without ever starting.
+        let (index, completed) = LazyFSMIndex::build_with_deadline(
+            abc_plus_fsm("deadline/busy pool"),
+            &abc_vocab(),
+            0,
+            Duration::from_millis(10),
+            &POOL,
+        );
+        assert!(!completed);
+        assert!(index.compute_error().is_some());
+        assert_eq!(index.completion_log.since(0).count(), 0);
+        assert!(matches!(index.get_next_instruction(0), Instruction::Write(w) if w.tokens == vec![0]));
+
+        busy.store(false, Ordering::Release);
+        wake_all(&*busy);
+    }
+
+    #[test]
+    fn test_stop_instruction_at_strict_finals() {
+        let tokens: FxHashMap<String, Vec<u32>> =
+            [("a".to_string(), vec![1]), ("aa".to_string(), vec![2])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let index = LazyFSMIndex::new(chain_fsm(2, "stop/aa"), &vocab, 0);
+        assert!(matches!(index.get_next_instruction(2), Instruction::Write(w) if w.tokens == vec![0]));
+
+        // Only the anchored end of "aa" and -1 stop, state 1 may still go on.
+        let index = index.with_stop_instruction(true);
+        assert!(matches!(index.get_next_instruction(2), Instruction::Stop));
+        assert!(matches!(index.get_next_instruction(-1), Instruction::Stop));
+        assert!(matches!(index.get_next_instruction_with_priorities(2), Instruction::Stop));
+        assert!(matches!(index.get_next_instruction(1), Instruction::Generate(_)));
+    }
+
+    #[test]
+    fn test_terminal_choices_when_every_token_completes_the_pattern() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("b", 2), ("ab", 3)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        // "[ab]": one character, then done.
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 0)
+            .anything_value(1)
+            .add_transition(0, 0, 1)
+            .add_final(1)
+            .pattern("terminal_choices/[ab]".to_string())
+            .build()
+            .unwrap();
+        let index = LazyFSMIndex::new(fsm_info, &vocab, 0);
+        match index.get_next_instruction(0) {
+            Instruction::Generate(generate) => assert!(generate.terminal_choices),
+            Instruction::Write(_) | Instruction::Stop => panic!("\"a\" and \"b\" are both allowed"),
+        }
+        match index.get_next_instruction_with_priorities(0) {
+            Instruction::Generate(generate) => assert!(generate.terminal_choices),
+            Instruction::Write(_) | Instruction::Stop => panic!("\"a\" and \"b\" are both allowed"),
+        }
+
+        // "[a-c]+" can go on after any token.
+        let index = LazyFSMIndex::new(abc_plus_fsm("terminal_choices/abc"), &abc_vocab(), 0);
+        match index.get_next_instruction(0) {
+            Instruction::Generate(generate) => assert!(!generate.terminal_choices),
+            Instruction::Write(_) | Instruction::Stop => panic!("expected a Generate"),
+        }
+    }
+
+    #[test]
+    fn test_has_transitions_on_dead_end() {
+        // "ab" where no token contains "b": state 1 is reachable but dead-ended.
+        let transitions: FxHashMap<(u32, u32), u32> =
+            [((0, 0), 1), ((1, 1), 2)].into_iter().collect();
+        let fsm_info = FSMInfo {
+            initial: 0,
+            finals: vec![2],
+            transitions: transitions.try_into().unwrap(),
+            alphabet_symbol_mapping: [("a".to_string(), 0), ("b".to_string(), 1)]
+                .into_iter()
+                .collect(),
+            alphabet_anything_value: 2,
+            pattern: "has_transitions/ab".to_string(),
+            eof_required_finals: Vec::new(),
+        };
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let index = LazyFSMIndex::new(fsm_info, &TokenVocabulary::from_hashmap(tokens, 0), 0);
+
+        assert!(index.has_transitions(0));
+        assert!(!index.has_transitions(1));
+        assert!(!index.has_transitions(-1));
+        assert!(!index.has_transitions(42));
+        match index.get_next_instruction(1) {
+            Instruction::Write(write) => assert_eq!(write.tokens, vec![0]),
+            Instruction::Generate(_) | Instruction::Stop => panic!("dead-ended state should write EOS"),
+        }
+    }
+
+    #[test]
+    fn test_branching_factor_counts_end_states() {
+        // "cat|dog": the "cat" and "dog" tokens both lead to the final state.
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('c', 0)
+            .alphabet_symbol('a', 1)
+            .alphabet_symbol('t', 2)
+            .alphabet_symbol('d', 3)
+            .alphabet_symbol('o', 4)
+            .alphabet_symbol('g', 5)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 2)
+            .add_transition(2, 2, 3)
+            .add_transition(0, 3, 4)
+            .add_transition(4, 4, 5)
+            .add_transition(5, 5, 3)
+            .add_final(3)
+            .pattern("branching/cat|dog".to_string())
+            .build()
+            .unwrap();
+        let tokens: FxHashMap<String, Vec<u32>> = [("cat", 1), ("dog", 2), ("c", 3)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let index = LazyFSMIndex::new(fsm_info, &TokenVocabulary::from_hashmap(tokens, 0), 0);
+
+        assert_eq!(index.get_allowed_token_ids(0).len(), 3);
+        assert_eq!(index.branching_factor(0), 2);
+        assert_eq!(index.branching_factor(1), 0);
+        assert_eq!(index.branching_factor(-1), 0);
+    }
+
+    #[test]
+    fn test_allowed_tokens_union() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("aa", 2), ("aaa", 3)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let index = LazyFSMIndex::new(chain_fsm(3, "union/aaa"), &vocab, 0);
+
+        assert_eq!(index.allowed_tokens_union(&[]), Vec::<i32>::new());
+        assert_eq!(index.allowed_tokens_union(&[2]), vec![1]);
+        assert_eq!(index.allowed_tokens_union(&[1, 2]), vec![1, 2]);
+        assert_eq!(index.allowed_tokens_union(&[2, 0, 1]), vec![1, 2, 3]);
+        assert_eq!(index.allowed_tokens_union(&[2, -1]), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_eof_required_final_offers_no_tokens() {
+        // "[a-c]$": same transitions as "[a-c]+", but state 1 has to end the sequence.
+        let mut fsm_info = abc_plus_fsm("eof_required/abc");
+        fsm_info.eof_required_finals = vec![1];
+        let mut index = LazyFSMIndex::new(fsm_info, &abc_vocab(), 0);
+        index.await_finished();
+        let maps = index.collect_finished_states().unwrap();
+
+        // Only single characters match, "ab" and "bc" would walk past the end.
+        let mut from_start: Vec<u32> = maps[&0].keys().copied().collect();
+        from_start.sort_unstable();
+        assert_eq!(from_start, vec![1, 2, 3]);
+        assert!(maps[&1].is_empty());
+
+        assert!(!index.has_transitions(1));
+        assert_eq!(index.get_allowed_token_ids(1), vec![0]);
+        match index.get_next_instruction(1) {
+            Instruction::Write(write) => assert_eq!(write.tokens, vec![0]),
+            Instruction::Generate(_) | Instruction::Stop => panic!("an EOF required final should write EOS"),
+        }
+        assert_eq!(index.advance(0, &[1]), Some(-1));
+        assert_eq!(index.advance(0, &[1, 2]), None);
+    }
+
+    #[test]
+    fn test_eof_required_finals_are_part_of_the_cache_key() {
+        let vocab = abc_vocab();
+        let plus = LazyFSMIndex::new(abc_plus_fsm("eof_required_key/abc"), &vocab, 0);
+        plus.await_finished();
+
+        // Same pattern string, but state 1 has to end the sequence.
+        let mut fsm_info = abc_plus_fsm("eof_required_key/abc");
+        fsm_info.eof_required_finals = vec![1];
+        let anchored = LazyFSMIndex::new(fsm_info, &vocab, 0);
+        assert!(!anchored.is_cached);
+        anchored.await_finished();
+        assert!(plus.get_allowed_token_ids(0).contains(&4));
+        assert!(!anchored.get_allowed_token_ids(0).contains(&4));
+    }
+
+    // cargo test --release bench_hot_state -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_hot_state_allowed_tokens() {
+        use std::time::Instant;
+
+        let tokens: FxHashMap<String, Vec<u32>> =
+            (1..=128_000u32).map(|id| (format!("t{}", id), vec![id])).collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        // ".*", every token loops on the one state.
+        let fsm_info = FSMInfoBuilder::new()
+            .anything_value(0)
+            .add_transition(0, 0, 0)
+            .add_final(0)
+            .pattern("bench/hot_state".to_string())
+            .build()
+            .unwrap();
+        let index = LazyFSMIndex::new_blocking(fsm_info, &vocab, 0);
+
+        let iterations = 100;
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let map = index.get_state_map(0).unwrap();
+            let mut allowed: Vec<u32> = map.keys().cloned().collect();
+            allowed.sort_unstable();
+        }
+        println!("collect and sort: {:?}", start.elapsed() / iterations);
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            index.get_allowed_token_ids(0);
+        }
+        println!("memoized:         {:?}", start.elapsed() / iterations);
+    }
+
+    #[test]
+    fn test_allowed_after_multi_byte_prefix() {
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('é', 0)
+            .alphabet_symbol('ü', 1)
+            .alphabet_symbol('a', 2)
+            .alphabet_symbol('b', 2)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 2)
+            .add_transition(2, 2, 3)
+            .add_final(3)
+            .pattern("allowed_after_bytes/éü[ab]".to_string())
+            .build()
+            .unwrap();
+        let tokens: FxHashMap<String, Vec<u32>> =
+            [("é", 1), ("ü", 2), ("éü", 3), ("üa", 4), ("a", 5), ("b", 6)]
+                .into_iter()
+                .map(|(token, id)| (token.to_string(), vec![id]))
+                .collect();
+        let index = LazyFSMIndex::new(fsm_info, &TokenVocabulary::from_hashmap(tokens, 0), 0);
+
+        let sorted = |mut tokens: Vec<i32>| {
+            tokens.sort_unstable();
+            tokens
+        };
+        assert_eq!(sorted(index.allowed_after_bytes(0, b"")), vec![1, 3]);
+        assert_eq!(sorted(index.allowed_after_bytes(0, "é".as_bytes())), vec![2, 4]);
+        assert_eq!(sorted(index.allowed_after_bytes(0, "éü".as_bytes())), vec![5, 6]);
+        assert_eq!(sorted(index.allowed_after_bytes(1, "ü".as_bytes())), vec![5, 6]);
+        // Stopping halfway through "ü", and bytes the pattern does not allow.
+        assert!(index.allowed_after_bytes(0, &"éü".as_bytes()[..3]).is_empty());
+        assert!(index.allowed_after_bytes(0, "ü".as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn test_allowed_after_bytes_at_states_no_token_reaches() {
+        // Only "abc" is a whole token from 0, so states 1 and 2 are never computed.
+        let fsm_info = |pattern: &str| {
+            FSMInfoBuilder::new()
+                .alphabet_symbol('a', 0)
+                .alphabet_symbol('b', 1)
+                .alphabet_symbol('c', 2)
+                .add_transition(0, 0, 1)
+                .add_transition(1, 1, 2)
+                .add_transition(2, 2, 3)
+                .add_final(3)
+                .pattern(pattern.to_string())
+                .build()
+                .unwrap()
+        };
+        let tokens: FxHashMap<String, Vec<u32>> = [("abc", 1), ("bc", 2), ("c", 3)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocabulary = TokenVocabulary::from_hashmap(tokens, 0);
+
+        let index = LazyFSMIndex::new(fsm_info("allowed_after_bytes/abc"), &vocabulary, 0);
+        index.await_finished();
+        let cached = LazyFSMIndex::new(fsm_info("allowed_after_bytes/abc"), &vocabulary, 0);
+        let on_demand = LazyFSMIndex::new_on_demand(
+            fsm_info("allowed_after_bytes/abc on demand"),
+            &vocabulary,
+            0,
+            ComputeOptions::default(),
+        );
+        for index in [&index, &cached, &on_demand] {
+            assert_eq!(index.allowed_after_bytes(0, b"a"), vec![2]);
+            assert_eq!(index.allowed_after_bytes(0, b"ab"), vec![3]);
+            assert_eq!(index.allowed_after_bytes(0, b"abc"), vec![0]);
+        }
+    }
+
+    #[test]
+    fn test_reachable_states() {
+        // 0 --a--> 1 --a--> 2, while 3 --a--> 4 is never reached from 0.
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 0, 2)
+            .add_transition(3, 0, 4)
+            .add_final(2)
+            .add_final(4)
+            .pattern("reachable/aa".to_string())
+            .build()
+            .unwrap();
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let index = LazyFSMIndex::new(fsm_info, &TokenVocabulary::from_hashmap(tokens, 0), 0);
+
+        assert_eq!(index.reachable_states().ones().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let index = LazyFSMIndex::new(abc_plus_fsm("reachable/abc"), &abc_vocab(), 0);
+        assert_eq!(index.reachable_states().ones().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_reserved_token_ids_are_never_offered() {
+        let vocab = abc_vocab();
+        let options = ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: Some([2, 4].into_iter().collect()),
+            canonicalize_fsm: false,
+            max_token_len: None,
+            byte_alphabet: false,
+        };
+        let index = LazyFSMIndex::new_with_options(abc_plus_fsm("reserved/abc"), &vocab, 0, options);
+
+        let allowed = |state| {
+            let mut allowed = index.get_allowed_token_ids(state);
+            allowed.sort_unstable();
+            allowed
+        };
+        assert_eq!(allowed(0), vec![1, 3, 5]);
+        assert_eq!(allowed(1), vec![0, 1, 3, 5]);
+
+        // Same pattern without reserved ids is a separate cache entry offering them.
+        let unreserved = LazyFSMIndex::new(abc_plus_fsm("reserved/abc"), &vocab, 0);
+        assert!(unreserved.get_allowed_token_ids(0).contains(&2));
+    }
+
+    #[test]
+    fn test_reserved_token_ids_absent_from_every_state() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("aa", 2), ("aaa", 3), ("aaaa", 4)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let options = ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: Some([2, 4].into_iter().collect()),
+            canonicalize_fsm: false,
+            max_token_len: None,
+            byte_alphabet: false,
+        };
+
+        let eager = LazyFSMIndex::new_with_options(chain_fsm(6, "reserved/a6"), &vocab, 0, options.clone());
+        let on_demand = LazyFSMIndex::new_on_demand(chain_fsm(6, "reserved/a6-lazy"), &vocab, 0, options);
+        for index in [eager, on_demand] {
+            index.await_finished();
+            let maps = index.to_outlines_states_to_token_maps();
+            assert!(!maps.is_empty());
+            for (state, map) in &maps {
+                assert!(!map.contains_key(&2) && !map.contains_key(&4), "state {}: {:?}", state, map);
+                assert!(!index.get_allowed_token_ids(*state as i32).contains(&2));
+            }
+            assert!(maps.values().any(|map| map.contains_key(&3)));
+        }
+    }
+
+    #[test]
+    fn test_initial_instruction_matches_index() {
+        let sorted_tokens = |instruction: Instruction| match instruction {
+            Instruction::Generate(Generate { tokens: Some(mut tokens), terminal_choices, .. }) => {
+                tokens.sort_unstable();
+                (tokens, terminal_choices)
+            }
+            _ => panic!("expected a Generate"),
+        };
+
+        let vocab = abc_vocab();
+        let a_star = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .add_transition(0, 0, 0)
+            .add_final(0)
+            .pattern("initial_instruction/a*".to_string())
+            .build()
+            .unwrap();
+        // "[ab]": every first token completes the pattern.
+        let a_or_b = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 0)
+            .anything_value(1)
+            .add_transition(0, 0, 1)
+            .add_final(1)
+            .pattern("initial_instruction/[ab]".to_string())
+            .build()
+            .unwrap();
+        for fsm_info in [abc_plus_fsm("initial_instruction/abc"), a_star, a_or_b] {
+            let expected = LazyFSMIndex::new(fsm_info.clone(), &vocab, 0).get_next_instruction(0);
+            assert_eq!(
+                sorted_tokens(crate::initial_instruction(&fsm_info, &vocab)),
+                sorted_tokens(expected),
+                "{}",
+                fsm_info.pattern
+            );
+        }
+    }
+
+    fn digits_fsm(prefix: Option<char>, pattern: &str) -> FSMInfo {
+        let mut builder = FSMInfoBuilder::new();
+        for digit in '0'..='9' {
+            builder = builder.alphabet_symbol(digit, 0);
+        }
+        let start = match prefix {
+            Some(c) => {
+                builder = builder.alphabet_symbol(c, 1).add_transition(0, 1, 1);
+                1
+            }
+            None => 0,
+        };
+        builder
+            .add_transition(start, 0, start + 1)
+            .add_transition(start + 1, 0, start + 1)
+            .add_final(start + 1)
+            .pattern(pattern.to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_extend_pattern_accepts_decimals() {
+        let tokens: FxHashMap<String, Vec<u32>> =
+            [("1", 1), ("2", 2), ("12", 3), (".", 4), ("1.5", 5), (".5", 6), ("a", 7)]
+                .into_iter()
+                .map(|(token, id)| (token.to_string(), vec![id]))
+                .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let integer = LazyFSMIndex::new(digits_fsm(None, "extend/[0-9]+"), &vocab, 0);
+        let decimal = integer
+            .extend_pattern(&digits_fsm(Some('.'), "extend/\\.[0-9]+"), &vocab)
+            .unwrap();
+
+        let mut allowed = decimal.get_allowed_token_ids(0);
+        allowed.sort_unstable();
+        assert_eq!(allowed, vec![1, 2, 3, 5]);
+
+        let integer_part = decimal.advance(0, &[3]).unwrap();
+        assert!(!decimal.is_final_state(integer_part));
+        let after_point = decimal.advance(0, &[3, 4]).unwrap();
+        assert!(!decimal.is_final_state(after_point));
+        for tokens in [&[3, 6][..], &[5], &[1, 4, 2], &[5, 3]] {
+            let state = decimal.advance(0, tokens).unwrap();
+            assert!(decimal.is_final_state(state), "{:?}", tokens);
+            assert!(decimal.get_allowed_token_ids(state).contains(&0));
+        }
+        assert_eq!(decimal.advance(0, &[4]), None);
+        assert_eq!(decimal.advance(0, &[5, 4]), None);
+    }
+
+    #[test]
+    fn test_extend_pattern_matches_fresh_index() {
+        let tokens: FxHashMap<String, Vec<u32>> =
+            [("a", 1), ("aa", 2), ("b", 3), ("ab", 4), ("aab", 5)]
+                .into_iter()
+                .map(|(token, id)| (token.to_string(), vec![id]))
+                .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let prefix = chain_fsm(8, "extend_fresh/a{8}");
+        let suffix = FSMInfoBuilder::new()
+            .alphabet_symbol('b', 0)
+            .add_transition(0, 0, 1)
+            .add_final(1)
+            .pattern("extend_fresh/b".to_string())
+            .build()
+            .unwrap();
+
+        // States 0 to 5 are at least 3 characters from the final, so their maps are reused.
+        let extended = LazyFSMIndex::new(prefix.clone(), &vocab, 0)
+            .extend_pattern(&suffix, &vocab)
+            .unwrap();
+        let mut fresh_fsm = prefix.concat(&suffix).unwrap();
+        fresh_fsm.pattern = "extend_fresh/fresh".to_string();
+        let fresh = LazyFSMIndex::new(fresh_fsm, &vocab, 0);
+
+        assert_eq!(
+            extended.to_outlines_states_to_token_maps(),
+            fresh.to_outlines_states_to_token_maps()
+        );
+        assert_eq!(extended.advance(0, &[2, 2, 2, 5]), Some(-1));
+    }
+
+    #[test]
+    fn test_extend_pattern_keeps_compute_options() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("aa", 2), ("b", 3), ("ab", 4)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let suffix = FSMInfoBuilder::new()
+            .alphabet_symbol('b', 0)
+            .add_transition(0, 0, 1)
+            .add_final(1)
+            .pattern("extend_options/b".to_string())
+            .build()
+            .unwrap();
+        let options = ComputeOptions {
+            reserved_token_ids: Some([4].into_iter().collect()),
+            canonicalize_fsm: false,
+            ..ComputeOptions::default()
+        };
+
+        let prefix = LazyFSMIndex::new_with_options(chain_fsm(8, "extend_options/a{8}"), &vocab, 0, options);
+        let extended = prefix.extend_pattern(&suffix, &vocab).unwrap();
+        extended.await_finished();
+
+        // "ab" ends the prefix and starts the suffix, it is only allowed if the options were dropped.
+        for (state, map) in extended.to_outlines_states_to_token_maps() {
+            assert!(!map.contains_key(&4), "state {} allows the reserved token", state);
+        }
+        assert_eq!(extended.advance(0, &[2, 2, 2, 2, 3]), Some(-1));
+    }
+
+    #[test]
+    fn test_built_index_is_listed_in_cache() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("list_cached/abc"), &abc_vocab(), 0);
+        index.await_finished();
+
+        let listed = crate::caching::list_cached_patterns();
+        assert!(listed.iter().any(|(_, pattern)| pattern == "list_cached/abc"), "{:?}", listed);
+    }
+
+    #[test]
+    fn test_cache_key_matches_built_index() {
+        let vocab = abc_vocab();
+        let options = ComputeOptions { canonicalize_fsm: false, ..ComputeOptions::default() };
+        let key = crate::caching::get_fsm_cache_key("cache_key/abc", &[], &vocab, &options);
+        assert!(!crate::caching::is_fsm_cached(key));
+
+        let index = LazyFSMIndex::new_with_options(abc_plus_fsm("cache_key/abc"), &vocab, 0, options.clone());
+        index.await_finished();
+        assert!(crate::caching::is_fsm_cached(key));
+
+        let snapshot = crate::caching::cached_fsm_snapshot(key).unwrap();
+        assert_eq!(snapshot.pattern, "cache_key/abc");
+        assert_eq!(snapshot.first_state, index.initial_state());
+        assert_eq!(snapshot.vocab_len, vocab.len());
+        assert_eq!(snapshot.states_to_token_maps, index.to_outlines_states_to_token_maps());
+
+        let other_options = ComputeOptions { max_token_len: Some(1), ..options };
+        let other_key = crate::caching::get_fsm_cache_key("cache_key/abc", &[], &vocab, &other_options);
+        assert!(!crate::caching::is_fsm_cached(other_key));
+        assert_eq!(crate::caching::cached_fsm_snapshot(other_key), None);
+    }
+
+    #[test]
+    fn test_canonicalized_equivalent_patterns_share_cache_entry() {
+        let options = ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: None,
+            canonicalize_fsm: true,
+            max_token_len: None,
+            byte_alphabet: false,
+        };
+        // "a|bc" twice: once with a final per branch, once with the finals merged
+        // and an extra symbol behaving like the anything value.
+        let branchy = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 1)
+            .alphabet_symbol('c', 2)
+            .add_transition(0, 0, 1)
+            .add_transition(0, 1, 2)
+            .add_transition(2, 2, 3)
+            .add_final(1)
+            .add_final(3)
+            .pattern("canonical/a|bc".to_string())
+            .build()
+            .unwrap();
+        let merged = FSMInfoBuilder::new()
+            .alphabet_symbol('c', 0)
+            .alphabet_symbol('a', 1)
+            .alphabet_symbol('b', 2)
+            .alphabet_symbol('z', 3)
+            .anything_value(3)
+            .add_transition(0, 1, 2)
+            .add_transition(0, 2, 1)
+            .add_transition(1, 0, 2)
+            .add_final(2)
+            .pattern("canonical/(?:a|bc)".to_string())
+            .build()
+            .unwrap();
+
+        let first = LazyFSMIndex::new_with_options(branchy, &abc_vocab(), 0, options.clone());
+        first.await_finished();
+        let second = LazyFSMIndex::new_with_options(merged, &abc_vocab(), 0, options);
+
+        let listed = crate::caching::list_cached_patterns();
+        assert!(listed.iter().any(|(_, pattern)| pattern == "canonical/a|bc"), "{:?}", listed);
+        assert!(!listed.iter().any(|(_, pattern)| pattern == "canonical/(?:a|bc)"), "{:?}", listed);
+        assert_eq!(first.to_outlines_states_to_token_maps(), second.to_outlines_states_to_token_maps());
+        let end = second.advance(0, &[5]).unwrap();
+        assert!(second.is_final_state(end));
+        assert_eq!(second.advance(0, &[2, 3]), Some(end));
+    }
+
+    #[test]
+    fn test_on_demand_index_skips_unvisited_states() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("aa", 2)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let options = ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: None,
+            canonicalize_fsm: false,
+            max_token_len: None,
+            byte_alphabet: false,
+        };
+        let mut index = LazyFSMIndex::new_on_demand(chain_fsm(12, "on_demand/a{12}"), &vocab, 0, options);
+
+        assert!(index.collect_finished_states_ordered().unwrap().is_empty());
+        assert_eq!(index.advance(0, &[2, 1]), Some(3));
+        let computed: Vec<u32> = index
+            .collect_finished_states_ordered()
+            .unwrap()
+            .into_iter()
+            .map(|(state, _)| state)
+            .collect();
+        assert_eq!(computed, vec![0, 2]);
+        assert!(!index.state_notifiers[11].load(Ordering::Acquire));
+
+        let eager = LazyFSMIndex::new(chain_fsm(12, "on_demand_eager/a{12}"), &vocab, 0);
+        assert_eq!(index.to_outlines_states_to_token_maps(), eager.to_outlines_states_to_token_maps());
+        assert!(index.state_notifiers[11].load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_panicking_on_demand_scan_still_publishes_the_state() {
+        let mut vocab = abc_vocab();
+        // A token without ids makes the scan panic when it takes the token's id.
+        let a = vocab.tokens.iter().position(|token| token == "a").unwrap();
+        vocab.values[a].clear();
+        let index = LazyFSMIndex::new_on_demand(
+            abc_plus_fsm("on_demand_panic/abc"),
+            &vocab,
+            0,
+            ComputeOptions::default(),
+        );
+
+        let scan = std::thread::scope(|scope| scope.spawn(|| index.await_state(0)).join());
+        assert!(scan.is_err());
+        assert!(matches!(index.await_state(0), Err(IndexError::Cancelled { .. })));
+        assert_eq!(index.compute_error(), Some("computing state 0 on demand panicked".to_string()));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_write_transitions_jsonl_round_trips() {
+        #[derive(Deserialize)]
+        struct Line {
+            state: u32,
+            token: u32,
+            next: u32,
+        }
+
+        let index = LazyFSMIndex::new(abc_plus_fsm("transitions_jsonl/abc"), &abc_vocab(), 0);
+        let mut out = Vec::new();
+        index.write_transitions_jsonl(&mut out).unwrap();
+
+        let mut parsed: FxHashMap<u32, FxHashMap<u32, u32>> = FxHashMap::default();
+        for line in String::from_utf8(out).unwrap().lines() {
+            let Line { state, token, next } = serde_json::from_str(line).unwrap();
+            assert!(parsed.entry(state).or_default().insert(token, next).is_none());
+        }
+        assert_eq!(parsed, index.to_outlines_states_to_token_maps());
+    }
+
+    #[test]
+    fn test_write_transitions_jsonl_skips_intermediate_states() {
+        // Only "aa" exists, so no token ends in the odd states of the chain.
+        let tokens: FxHashMap<String, Vec<u32>> = [("aa".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let index =
+            LazyFSMIndex::new_on_demand(chain_fsm(4, "transitions_jsonl/aaaa"), &vocab, 0, ComputeOptions::default());
+
+        let mut out = Vec::new();
+        index.write_transitions_jsonl(&mut out).unwrap();
+
+        let lines = String::from_utf8(out).unwrap();
+        assert_eq!(lines, "{\"state\":0,\"token\":1,\"next\":2}\n{\"state\":2,\"token\":1,\"next\":4}\n");
+        // The export walked tokens, it never waited on or computed an intermediate state.
+        assert!(!index.state_notifiers[1].load(Ordering::Acquire));
+        assert!(!index.state_notifiers[3].load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_vocab_order_keeps_allowed_tokens_in_vocab_order() {
+        let mut vocab = TokenVocabulary::default();
+        for (token, id) in [("bc", 5), ("ab", 4), ("c", 3), ("b", 2), ("a", 1), ("d", 6), ("ad", 7)] {
+            vocab.add_token(token.to_string(), vec![id]);
+        }
+        let by_id = LazyFSMIndex::new(abc_plus_fsm("vocab_order/abc+"), &vocab, 0);
+        let in_vocab_order = by_id.clone().with_vocab_order(&vocab);
+
+        assert_eq!(by_id.get_allowed_token_ids(0), vec![1, 2, 3, 4, 5]);
+        // EOS is not in the vocabulary, so it comes last. Repeated, the lookups hit the memo.
+        for _ in 0..3 {
+            assert_eq!(in_vocab_order.get_allowed_token_ids(0), vec![5, 4, 3, 2, 1]);
+            assert_eq!(in_vocab_order.get_allowed_token_ids(1), vec![5, 4, 3, 2, 1, 0]);
+        }
+        assert_eq!(by_id.get_allowed_token_ids(1), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_initial_final_state_offers_eos_through_alias() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        // "a?" with the initial state numbered 1, so state 0 only reaches it as the alias.
+        let fsm_info = FSMInfoBuilder::new()
+            .initial(1)
+            .alphabet_symbol('a', 0)
+            .add_transition(1, 0, 2)
+            .add_final(1)
+            .add_final(2)
+            .pattern("initial_final/a?".to_string())
+            .build()
+            .unwrap();
+        let index = LazyFSMIndex::new(fsm_info, &vocab, 0);
+
+        assert!(index.is_final_state(0));
+        match index.get_next_instruction(0) {
+            Instruction::Generate(generate) => {
+                let mut tokens = generate.tokens.unwrap();
+                tokens.sort_unstable();
+                assert_eq!(tokens, vec![0, 1]);
+            }
+            _ => panic!("expected a Generate"),
+        }
+    }
+
+    #[test]
+    fn test_allowed_tokens_resolve_the_initial_state_alias() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        // "a?" with the initial state numbered 1; state 0 has no slot of its own to wait on.
+        let fsm_info = FSMInfoBuilder::new()
+            .initial(1)
+            .alphabet_symbol('a', 0)
+            .add_transition(1, 0, 2)
+            .add_final(1)
+            .add_final(2)
+            .pattern("initial_alias_allowed/a?".to_string())
+            .build()
+            .unwrap();
+        let threaded = LazyFSMIndex::new(fsm_info.clone(), &vocab, 0);
+        let on_demand = LazyFSMIndex::new_on_demand(fsm_info, &vocab, 0, ComputeOptions::default());
+
+        for index in [threaded, on_demand] {
+            index.await_state(0).unwrap();
+            // Twice, so the second lookup goes through the memo.
+            for _ in 0..2 {
+                assert_eq!(index.get_allowed_token_ids(0), vec![0, 1]);
+            }
+            assert_eq!(index.allowed_tokens_union(&[0]), vec![0, 1]);
+            assert_eq!(index.longest_allowed_token(0, &vocab), Some(1));
+        }
+    }
+
+    #[test]
+    fn test_longest_allowed_token_prefers_more_chars() {
+        let tokens: FxHashMap<String, Vec<u32>> = [
+            ("a".to_string(), vec![1]),
+            ("ab".to_string(), vec![2]),
+            ("b".to_string(), vec![3]),
+            ("abc".to_string(), vec![4]),
+        ]
+        .into_iter()
+        .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 1)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 2)
+            .add_final(2)
+            .pattern("longest_token/ab".to_string())
+            .build()
+            .unwrap();
+        let index = LazyFSMIndex::new(fsm_info, &vocab, 0);
+
+        // "a" and "ab" are both allowed at the start, "abc" overruns the pattern.
+        assert_eq!(index.get_allowed_token_ids(0), vec![1, 2]);
+        assert_eq!(index.longest_allowed_token(0, &vocab), Some(2));
+        assert_eq!(index.longest_allowed_token(1, &vocab), Some(3));
+        assert_eq!(index.longest_allowed_token(2, &vocab), None);
+    }
+
+    #[test]
+    fn test_await_state_drives_an_on_demand_index() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let index = LazyFSMIndex::new_on_demand(chain_fsm(8, "await_drives/a{8}"), &vocab, 0, ComputeOptions::default());
+
+        let mut state = 0;
+        for _ in 0..3 {
+            index.await_state(state as u32).unwrap();
+            assert!(matches!(index.get_next_instruction(state), Instruction::Generate(_)));
+            state = index.get_next_state(state, 1).unwrap();
+        }
+        let computed: Vec<u32> = index.completion_log.since(0).collect();
+        assert_eq!(computed, vec![0, 1, 2], "only the visited states are computed");
+    }
+
+    #[test]
+    fn test_repr_does_not_wait_for_computation() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a".to_string(), vec![1])].into_iter().collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        // Never finishes on its own, so a blocking repr would hang here.
+        let index = LazyFSMIndex::new_on_demand(
+            chain_fsm(5, "repr/a{5}"),
+            &vocab,
+            0,
+            ComputeOptions::default(),
+        );
+
+        let repr = index.__repr__();
+        assert!(repr.contains("status=computing, computed_states=0,"), "{}", repr);
+        assert!(repr.contains("finals=[5], states={})"), "{}", repr);
+
+        index.get_allowed_token_ids(0);
+        let repr = index.__repr__();
+        assert!(repr.contains("computed_states=1,"), "{}", repr);
+        assert!(repr.contains("states={0: {1: 1}})"), "{}", repr);
+
+        index.await_finished();
+        assert!(index.__repr__().contains("status=finished, computed_states=6,"));
+    }
+
+    #[test]
+    fn test_llama_byte_token_matches_newline() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("x", 1), ("<0x0A>", 2), ("x\n", 3), ("<", 4)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let mut vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        vocab.byte_tokens = true;
+
+        // "x\n"
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('x', 0)
+            .alphabet_symbol('\n', 1)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 2)
+            .add_final(2)
+            .pattern("llama_byte/x\\n".to_string())
+            .build()
+            .unwrap();
+        let index = LazyFSMIndex::new(fsm_info.clone(), &vocab, 0);
+        index.await_finished();
+
+        let mut allowed = index.get_allowed_token_ids(1);
+        allowed.sort_unstable();
+        assert_eq!(allowed, vec![2]);
+        assert_eq!(index.advance(0, &[1, 2]), index.get_next_state(0, 3));
+
+        // Outside sentencepiece vocabularies "<0x0A>" is just text.
+        vocab.byte_tokens = false;
+        let index = LazyFSMIndex::new(fsm_info, &vocab, 0);
+        assert!(!index.is_cached);
+        assert!(!index.get_allowed_token_ids(1).contains(&2));
+    }
+
+    #[test]
+    fn test_allowed_token_ids_include_eos_at_finals() {
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("aa", 2)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+        let allowed = |index: &LazyFSMIndex, state| {
+            let mut allowed = index.get_allowed_token_ids(state);
+            allowed.sort_unstable();
+            allowed
+        };
+
+        // "a+": only the looping state 1 accepts.
+        let a_plus = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 0, 1)
+            .add_final(1)
+            .pattern("allowed_eos/a+".to_string())
+            .build()
+            .unwrap();
+        let index = LazyFSMIndex::new(a_plus, &vocab, 0);
+        assert_eq!(allowed(&index, 0), vec![1, 2]);
+        assert_eq!(allowed(&index, 1), vec![0, 1, 2]);
+        assert_eq!(index.allowed_tokens_union(&[0, 1]), vec![0, 1, 2]);
+
+        // "a*": the start state accepts too.
+        let a_star = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .add_transition(0, 0, 0)
+            .add_final(0)
+            .pattern("allowed_eos/a*".to_string())
+            .build()
+            .unwrap();
+        let index = LazyFSMIndex::new(a_star, &vocab, 0);
+        assert_eq!(allowed(&index, 0), vec![0, 1, 2]);
+        assert_eq!(index.allowed_tokens_union(&[0]), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_final_start_state_offers_eos_and_tokens() {
+        // "a*": the start state is final and loops on "a".
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .add_transition(0, 0, 0)
+            .add_final(0)
+            .pattern("final_start/a*".to_string())
+            .build()
+            .unwrap();
+        let tokens: FxHashMap<String, Vec<u32>> = [("a", 1), ("aa", 2), ("b", 3)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let index = LazyFSMIndex::new(fsm_info, &TokenVocabulary::from_hashmap(tokens, 0), 0);
+
+        match index.get_next_instruction(0) {
+            Instruction::Generate(Generate { tokens: Some(mut tokens), .. }) => {
+                tokens.sort_unstable();
+                assert_eq!(tokens, vec![0, 1, 2]);
+            }
+            _ => panic!("a final state with outgoing tokens should offer them alongside EOS"),
+        }
+        assert_eq!(index.get_next_state(0, 1), Some(0));
+        assert_eq!(index.get_next_state(0, 0), Some(-1));
+        assert_eq!(index.get_next_state(0, 3), Some(-1));
+        assert_eq!(index.advance(0, &[2, 1]), Some(0));
+    }
+
+    #[test]
+    fn test_priorities_prefer_nearest_final() {
+        // "cat|catalog": 0 -c-> 1 -a-> 2 -t-> 3 (final) -a-> 4 -l-> 5 -o-> 6 -g-> 7 (final)
+        let mut builder = FSMInfoBuilder::new().add_final(3).add_final(7);
+        for (key, c) in ['c', 'a', 't', 'l', 'o', 'g'].into_iter().enumerate() {
+            builder = builder.alphabet_symbol(c, key as u32);
+        }
+        for (state, c) in "catalog".chars().enumerate() {
+            let key = "catlog".find(c).unwrap() as u32;
+            builder = builder.add_transition(state as u32, key, state as u32 + 1);
+        }
+        let fsm_info = builder.pattern("priorities/cat|catalog".to_string()).build().unwrap();
+        assert_eq!(fsm_info.distances_to_final(), vec![3, 2, 1, 0, 3, 2, 1, 0]);
+
+        let tokens: FxHashMap<String, Vec<u32>> =
+            [("c", 1), ("ca", 2), ("cat", 3), ("catalog", 4), ("a", 5), ("at", 6), ("atal", 7)]
+                .into_iter()
+                .map(|(token, id)| (token.to_string(), vec![id]))
+                .collect();
+        let index = LazyFSMIndex::new(fsm_info, &TokenVocabulary::from_hashmap(tokens, 0), 0);
+
+        let priorities = |state| match index.get_next_instruction_with_priorities(state) {
+            Instruction::Generate(Generate { tokens: Some(tokens), priorities: Some(priorities), .. }) => {
+                let mut pairs: Vec<(i32, f32)> = tokens.into_iter().zip(priorities).collect();
+                pairs.sort_by_key(|&(token_id, _)| token_id);
+                pairs
+            }
+            _ => panic!("expected a Generate with priorities"),
+        };
+        assert_eq!(priorities(0), vec![(1, 2.0), (2, 1.0), (3, 0.0), (4, 0.0)]);
+        assert_eq!(priorities(1), vec![(5, 1.0), (6, 0.0), (7, 2.0)]);
+        // "cat" may end the match or go on to "catalog".
+        assert_eq!(priorities(3), vec![(0, 0.0), (5, 3.0)]);
+        assert!(matches!(index.get_next_instruction_with_priorities(7), Instruction::Write(_)));
+        match index.get_next_instruction(0) {
+            Instruction::Generate(generate) => assert!(generate.priorities.is_none()),
+            Instruction::Write(_) | Instruction::Stop => panic!("expected a Generate"),
+        }
+    }
+
+    #[test]
+    fn test_export_all_masks_size() {
+        let index = LazyFSMIndex::new(abc_plus_fsm("export_masks/abc"), &abc_vocab(), 0);
+
+        for vocab_size in [1, 6, 64, 65, 130] {
+            let (states, masks) = index.export_all_masks(vocab_size);
+            let words_per_state = vocab_size.div_ceil(64);
+            assert_eq!(states, vec![0, 1]);
+            assert_eq!(masks.len(), states.len() * words_per_state);
+
+            for (row, &state) in masks.chunks_exact(words_per_state).zip(&states) {
+                let mut expected: Vec<usize> = index
+                    .get_allowed_token_ids_u32(state as i32)
+                    .into_iter()
+                    .map(|token_id| token_id as usize)
+                    .filter(|&token_id| token_id < vocab_size)
+                    .collect();
+                expected.sort_unstable();
+                let set: Vec<usize> = (0..vocab_size)
+                    .filter(|&token_id| row[token_id / 64] & (1 << (token_id % 64)) != 0)
+                    .collect();
+                assert_eq!(set, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_index_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LazyFSMIndex>();
+    }
+
+    #[test]
+    fn test_cached_index_collects_every_state() {
+        let vocab = abc_vocab();
+        let computed = LazyFSMIndex::new(abc_plus_fsm("cached_collect/every_state"), &vocab, 0);
+        computed.await_finished();
+
+        let mut cached = LazyFSMIndex::new(abc_plus_fsm("cached_collect/every_state"), &vocab, 0);
+        assert!(cached.is_cached);
+        let finished = cached.collect_finished_states().unwrap();
+        assert_eq!(finished.len(), cached.states_to_token_maps.len());
+        assert_eq!(finished[&1].len(), 5);
     }
 }
@@ -29,9 +29,130 @@ pub mod lazy_index;
 mod caching;
 pub mod types;
 pub mod vocab;
+// pyo3's #[pymethods] expansion trips this lint on every PyResult return.
+#[allow(clippy::useless_conversion)]
 mod bindings;
 mod atomic_wait;
+mod compute_pool;
 mod sp_decode;
 
 #[cfg(feature = "python_bindings")]
-pub use crate::bindings::lib;
\ No newline at end of file
+pub use crate::bindings::lib;
+
+pub use crate::caching::{
+    cached_fsm_snapshot, get_fsm_cache_key, is_fsm_cached, list_cached_patterns, CachedFSMSnapshot,
+};
+
+use crate::lazy_index::LazyFSMIndex;
+use crate::tokenizer_index::compute_initial_state_tokens;
+use crate::types::{ComplexityReport, ComputeOptions, FSMInfo, Generate, Instruction, Write};
+use crate::vocab::TokenVocabulary;
+use rustc_hash::FxHashMap;
+
+/// Builds the token index for `pattern_fsm` over `vocab`, the plain Rust counterpart
+/// of the Python `create_fsm_index_end_to_end_rs`, usable without the `python_bindings` feature.
+///
+/// States are computed in the background, lookups wait for the states they need.
+///
+/// ```
+/// use faster_outlines_rs::build_index;
+/// use faster_outlines_rs::types::FSMInfoBuilder;
+/// use faster_outlines_rs::vocab::TokenVocabulary;
+/// use rustc_hash::FxHashMap;
+///
+/// // "ab"
+/// let fsm = FSMInfoBuilder::new()
+///     .alphabet_symbol('a', 0)
+///     .alphabet_symbol('b', 1)
+///     .add_transition(0, 0, 1)
+///     .add_transition(1, 1, 2)
+///     .add_final(2)
+///     .pattern("ab".to_string())
+///     .build()
+///     .unwrap();
+///
+/// let mut tokens = FxHashMap::default();
+/// tokens.insert("a".to_string(), vec![1]);
+/// tokens.insert("b".to_string(), vec![2]);
+/// tokens.insert("ab".to_string(), vec![3]);
+/// let vocab = TokenVocabulary::from_hashmap(tokens, 0);
+///
+/// let index = build_index(fsm, &vocab);
+/// let mut allowed = index.get_allowed_token_ids(0);
+/// allowed.sort();
+/// assert_eq!(allowed, vec![1, 3]);
+///
+/// let end = index.get_next_state(0, 3).unwrap();
+/// assert_eq!(index.get_allowed_token_ids(end), vec![0]);
+/// ```
+pub fn build_index(pattern_fsm: FSMInfo, vocab: &TokenVocabulary) -> LazyFSMIndex {
+    LazyFSMIndex::new(pattern_fsm, vocab, vocab.eos_token_id)
+}
+
+/// The instruction for the first token only, computed synchronously without building an index.
+///
+/// Gives the same instruction as `build_index(..).get_next_instruction(0)`, usually a
+/// `Generate`, for callers which only need to constrain the very first token, e.g. to
+/// choose among a fixed set of openers, and would otherwise compute every state.
+pub fn initial_instruction(fsm_info: &FSMInfo, vocabulary: &TokenVocabulary) -> Instruction {
+    let eos_token_id = vocabulary.eos_token_id;
+    let token_ids_end_states =
+        compute_initial_state_tokens(fsm_info, vocabulary, &ComputeOptions::default());
+    if token_ids_end_states.is_empty() {
+        return Instruction::Write(Write::new(vec![eos_token_id as i32]));
+    }
+
+    let terminal_choices =
+        token_ids_end_states.iter().all(|&(_, end_state)| fsm_info.is_terminal(end_state));
+    let mut allowed: Vec<u32> = token_ids_end_states.into_iter().map(|(token_id, _)| token_id).collect();
+    if fsm_info.finals.contains(&fsm_info.initial) {
+        allowed.push(eos_token_id);
+    }
+    allowed.sort_unstable();
+    allowed.dedup();
+    let mut generate = Generate::new(Some(allowed.into_iter().map(|k| k as i32).collect()));
+    generate.terminal_choices = terminal_choices;
+    Instruction::Generate(generate)
+}
+
+/// Gauges how large the index of `fsm_info` would get, without computing any state,
+/// e.g. for a gateway to reject pattern bombs before building them.
+///
+/// Only the transition structure and alphabet are inspected, the vocabulary just
+/// contributes its size, so this is cheap even for huge FSMs and vocabularies.
+pub fn estimate_complexity(fsm_info: &FSMInfo, vocab: &TokenVocabulary) -> ComplexityReport {
+    let reachable = fsm_info.reachable_states();
+
+    let mut symbols_per_key: FxHashMap<u32, usize> = FxHashMap::default();
+    for &key in fsm_info.alphabet_symbol_mapping.values() {
+        *symbols_per_key.entry(key).or_default() += 1;
+    }
+
+    let mut has_wildcard = false;
+    let mut estimated_max_transitions = 0usize;
+    for state in reachable.ones() {
+        let per_state = if fsm_info.has_wildcard_transition(state) {
+            has_wildcard = true;
+            vocab.len()
+        } else {
+            symbols_per_key
+                .iter()
+                .filter(|(&key, _)| fsm_info.transitions.get_transition(state, key as usize).is_some())
+                .map(|(_, &symbols)| symbols)
+                .sum::<usize>()
+                .min(vocab.len())
+        };
+        estimated_max_transitions = estimated_max_transitions.saturating_add(per_state);
+    }
+
+    ComplexityReport {
+        num_states: reachable
+            .ones()
+            .chain(fsm_info.finals.iter().map(|&state| state as usize))
+            .map(|state| state + 1)
+            .fold(fsm_info.transitions.len(), usize::max),
+        num_reachable: reachable.count_ones(..),
+        has_wildcard,
+        estimated_max_transitions,
+    }
+}
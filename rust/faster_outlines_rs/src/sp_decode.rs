@@ -287,20 +287,66 @@ pub static UNICODE_TO_BYTES: Lazy<HashMap<char, u8>> = Lazy::new(|| {
         .collect()
 });
 
-pub fn convert_tokens_to_string(tokens: Vec<String>) -> String {
-    let tokens = tokens
-        .iter()
-        .map(|s| s.as_str())
-        .collect::<String>() 
-        .replace('▁', " ") // Replace SP underline character with a space
-        .trim()
-        .chars()
-        .map(|character| {
-            UNICODE_TO_BYTES
-                .get(&character)
-                .cloned()
-                .unwrap_or(character as u8)
-        })
-        .collect::<Vec<u8>>();
-    String::from_utf8_lossy(&tokens).to_string()
-}
\ No newline at end of file
+/// Sentencepiece's word boundary marker, standing in for a space.
+pub const SPIECE_UNDERLINE: char = '▁';
+
+/// Converts tokens back to the text they decode to.
+///
+/// Space handling depends on the tokenizer type:
+/// - Sentencepiece (`from_sentencepiece`, e.g. Llama): `▁` is replaced by a space,
+///   and the leading space is stripped like HF's `convert_tokens_to_string` does.
+///   Pieces are plain text, so every other character is kept as is.
+/// - Byte-level BPE (e.g. GPT-2): every character stands for one byte through
+///   `UNICODE_TO_BYTES`, so `Ġ` becomes a space. `▁` gets no special treatment.
+///   Characters outside the byte alphabet are kept as is.
+pub fn convert_tokens_to_string(tokens: Vec<String>, from_sentencepiece: bool) -> String {
+    let text = tokens.concat();
+
+    if from_sentencepiece {
+        return text
+            .replace(SPIECE_UNDERLINE, " ")
+            .trim()
+            .to_string();
+    }
+
+    let mut bytes = Vec::with_capacity(text.len());
+    for character in text.chars() {
+        match UNICODE_TO_BYTES.get(&character) {
+            Some(&byte) => bytes.push(byte),
+            None => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(character.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(tokens: &[&str], from_sentencepiece: bool) -> String {
+        convert_tokens_to_string(tokens.iter().map(|t| t.to_string()).collect(), from_sentencepiece)
+    }
+
+    #[test]
+    fn test_gpt2_byte_level_spaces() {
+        assert_eq!(convert(&["Ġhello"], false), " hello");
+        assert_eq!(convert(&["Ġhello", "Ġworld"], false), " hello world");
+        assert_eq!(convert(&["ĠĠ"], false), "  ");
+        // "Ċ" is the byte-level newline.
+        assert_eq!(convert(&["Ċ"], false), "\n");
+        // No sentencepiece substitution for byte-level tokenizers.
+        assert_eq!(convert(&["▁a"], false), "▁a");
+    }
+
+    #[test]
+    fn test_llama_sentencepiece_spaces() {
+        assert_eq!(convert(&["▁hello"], true), "hello");
+        assert_eq!(convert(&["▁hello", "▁world"], true), "hello world");
+        // Non-ASCII pieces are plain text, not byte-level characters.
+        assert_eq!(convert(&["▁café"], true), "café");
+        assert_eq!(convert(&["Ġ"], true), "Ġ");
+    }
+}
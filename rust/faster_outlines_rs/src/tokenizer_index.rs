@@ -22,35 +22,92 @@
 
 use crate::{
     atomic_wait::platform::wake_all,
-    types::{FSMInfo, StateNotifierMap, StatesToTokenMaps},
-    vocab::TokenVocabulary,
+    compute_pool::wait_while_set,
+    types::{
+        ComputeOptions, FSMInfo, SeededStates, StateCallbackSlot, StateCompletionLog,
+        StateNotifierMap, StatesToTokenMaps,
+    },
+    vocab::{byte_to_symbol, byte_token_value, token_bytes, TokenVocabulary},
 };
-use rustc_hash::FxHashMap;
+use crossbeam_deque::{Injector, Stealer, Worker};
+use rustc_hash::{FxHashMap, FxHashSet};
 use fixedbitset::FixedBitSet;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use once_cell::sync::OnceCell;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
 
 #[inline(always)]
 fn create_vocab_transition_vector(
-    alphabet_symbol_mapping: &FxHashMap<char, u32>,
-    alphabet_anything_value: u32,
+    fsm_info: &FSMInfo,
     vocabulary: &TokenVocabulary,
+    alphabet_symbol_mapping: &FxHashMap<char, u32>,
+    options: &ComputeOptions,
 ) -> Vec<Vec<u32>> {
+    let byte_tokens = vocabulary.byte_tokens;
+    if options.byte_alphabet {
+        return vocabulary
+            .into_iter()
+            .map(|(token_str, _)| byte_transition_keys(token_str, byte_tokens))
+            .collect();
+    }
     vocabulary
         .into_iter()
-        .map(|(token_str, _)| {
-            token_str
-                .chars()
-                .map(|c| {
-                    *alphabet_symbol_mapping
-                        .get(&c)
-                        .unwrap_or(&alphabet_anything_value)
-                })
-                .collect()
-        })
+        .map(|(token_str, _)| token_transition_keys(token_str, fsm_info, alphabet_symbol_mapping, byte_tokens))
         .collect()
 }
 
+/// Transition keys of `token` for an FSM over the byte alphabet, see
+/// `ComputeOptions::byte_alphabet`: the raw bytes it stands for, each byte its own key.
+pub(crate) fn byte_transition_keys(token: &str, byte_tokens: bool) -> Vec<u32> {
+    token_bytes(token, byte_tokens).into_iter().map(u32::from).collect()
+}
+
+/// Transition keys of the symbols `token` spells, `alphabet_symbol_mapping` being
+/// `fsm_info.char_symbol_mapping()`.
+///
+/// The FSM's alphabet is built over raw characters, so byte tokens are reconciled with
+/// it through the byte they stand for, instead of taking the wildcard path:
+/// - With `byte_tokens`, see `TokenVocabulary::byte_tokens`, a llama style `<0x0A>`
+///   token is looked up as `'\n'`, like a `"\n"` token.
+/// - A `"\x00XX"` byte symbol, see `byte_to_symbol`, is looked up whole when the
+///   alphabet has it, as in byte level FSMs, and char by char otherwise.
+pub(crate) fn token_transition_keys(
+    token: &str,
+    fsm_info: &FSMInfo,
+    alphabet_symbol_mapping: &FxHashMap<char, u32>,
+    byte_tokens: bool,
+) -> Vec<u32> {
+    let symbols = match byte_token_value(token, byte_tokens) {
+        Some(byte) => Cow::Owned(byte_to_symbol(byte)),
+        None => Cow::Borrowed(token),
+    };
+    let char_key = |c: char| *alphabet_symbol_mapping.get(&c).unwrap_or(&fsm_info.alphabet_anything_value);
+
+    let mut keys = Vec::with_capacity(symbols.len());
+    let mut rest = symbols.as_ref();
+    while let Some(c) = rest.chars().next() {
+        let byte_symbol_key = rest
+            .get(..3)
+            .filter(|_| c == '\x00')
+            .and_then(|symbol| fsm_info.alphabet_symbol_mapping.get(symbol));
+        match byte_symbol_key {
+            Some(&key) => {
+                keys.push(key);
+                rest = &rest[3..];
+            }
+            None => {
+                keys.push(char_key(c));
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    keys
+}
+
+// Kept as the reference implementation `walk_fsm_len` is tested against.
+#[cfg(test)]
 fn walk_fsm(
     fsm_info: &FSMInfo,
     token_transition_keys: &[u32],
@@ -69,6 +126,12 @@ fn walk_fsm(
                     last_final_idx = i + 1;
                 }
                 accepted_states.push(state);
+                if i + 1 < token_transition_keys.len() && fsm_info.is_eof_required(state) {
+                    if !full_match {
+                        return accepted_states;
+                    }
+                    return Vec::new();
+                }
             }
             None => {
                 if !full_match && last_final_idx > 0 {
@@ -86,6 +149,54 @@ fn walk_fsm(
     accepted_states
 }
 
+/// Allocation free version of `walk_fsm`, for callers which only need the outcome of a walk.
+///
+/// Returns `(matched_len, last_state)`, the length and last element of the state sequence
+/// `walk_fsm` would return, or `None` where `walk_fsm` would return an empty sequence.
+/// `state_scan_tokens` runs this for every token at every state, so skipping the
+/// intermediate `Vec` saves vocab_size × state_count allocations per FSM.
+pub(crate) fn walk_fsm_len(
+    fsm_info: &FSMInfo,
+    token_transition_keys: &[u32],
+    start_state: u32,
+    full_match: bool,
+) -> Option<(usize, u32)> {
+    let mut state = start_state;
+    let mut last_final: Option<(usize, u32)> = None;
+
+    for (i, &trans_key) in token_transition_keys.iter().enumerate() {
+        match fsm_info.transitions.get_transition(state as usize, trans_key as usize) {
+            Some(new_state) => {
+                state = new_state;
+                if fsm_info.finals.contains(&state) {
+                    last_final = Some((i + 1, state));
+                }
+                // The pattern has to end here, so the rest of the token cannot match.
+                if i + 1 < token_transition_keys.len() && fsm_info.is_eof_required(state) {
+                    if !full_match {
+                        return last_final;
+                    }
+                    return None;
+                }
+            }
+            None => {
+                if !full_match {
+                    return last_final;
+                }
+                return None;
+            }
+        }
+    }
+
+    if token_transition_keys.is_empty()
+        || (full_match && last_final.map(|(idx, _)| idx) != Some(token_transition_keys.len()))
+    {
+        return None;
+    }
+
+    Some((token_transition_keys.len(), state))
+}
+
 /// Maps a single FSM state to its valid token transitions.
 /// 
 /// For each vocabulary token:
@@ -125,25 +236,72 @@ fn walk_fsm(
 /// In other implementations, this code returns a hashset, 
 /// but the results of this function will be dropped in a hashmap anyway,
 /// so no need to deduplicate twice.
+///
+/// Tokens longer than `max_token_len` are skipped without walking them,
+/// see `ComputeOptions::max_token_len`.
 fn state_scan_tokens(
     fsm_info: &FSMInfo,
-    vocabulary: Vec<&Vec<u32>>,
+    vocabulary: &[Vec<u32>],
     vocabulary_transition_keys: &[Vec<u32>],
     start_state: u32,
+    max_token_len: Option<usize>,
 ) -> Vec<(u32, u32)> {
+    #[cfg(test)]
+    tests::SCANS.with(|scans| scans.set(scans.get() + 1));
+
+    let max_token_len = max_token_len.unwrap_or(usize::MAX);
     vocabulary.iter()
         .zip(vocabulary_transition_keys.iter())
+        .filter(|(_, token_transition_keys)| token_transition_keys.len() <= max_token_len)
         .filter_map(|(token_ids, token_transition_keys)| {
-            let state_seq = walk_fsm(fsm_info, token_transition_keys, start_state, false);
-            if state_seq.len() == token_transition_keys.len() {
-                Some((*token_ids.last().unwrap(), *state_seq.last().unwrap()))
-            } else {
-                None
+            match walk_fsm_len(fsm_info, token_transition_keys, start_state, false) {
+                Some((matched_len, last_state)) if matched_len == token_transition_keys.len() => {
+                    Some((*token_ids.last().unwrap(), last_state))
+                }
+                _ => None,
             }
         })
         .collect()
 }
 
+/// Lossily trims a state's (token_id, end_state) pairs down to roughly `cap` entries.
+///
+/// The lowest token id leading to each distinct end state is kept first, so every
+/// end state stays reachable, then the lowest remaining token ids fill up to `cap`.
+/// The EOS token is always kept. If there are more distinct end states than `cap`,
+/// the result holds one token per end state and exceeds `cap`.
+fn cap_state_tokens(token_ids_end_states: &mut Vec<(u32, u32)>, cap: usize, eos_token_id: u32) {
+    if token_ids_end_states.len() <= cap {
+        return;
+    }
+    token_ids_end_states.sort_unstable();
+
+    let mut keep = FixedBitSet::with_capacity(token_ids_end_states.len());
+    let mut seen_end_states = FxHashSet::default();
+    for (i, &(token_id, end_state)) in token_ids_end_states.iter().enumerate() {
+        if token_id == eos_token_id || seen_end_states.insert(end_state) {
+            keep.insert(i);
+        }
+    }
+
+    let mut kept = keep.count_ones(..);
+    for i in 0..token_ids_end_states.len() {
+        if kept >= cap {
+            break;
+        }
+        if !keep.contains(i) {
+            keep.insert(i);
+            kept += 1;
+        }
+    }
+
+    let mut i = 0;
+    token_ids_end_states.retain(|_| {
+        i += 1;
+        keep.contains(i - 1)
+    });
+}
+
 
 /// Core FSM computation function that builds token transition maps.
 /// 
@@ -162,9 +320,10 @@ fn state_scan_tokens(
 ///    - Processes each FSM state independently
 ///    - For each state:
 ///      a. Simulates FSM walks for all vocabulary tokens
-///      b. Records valid (token_id, end_state) pairs
+///      b. Records valid (token_id, end_state) pairs, capped if configured
 ///      c. Writes results directly to shared memory
-///      d. Signals completion via atomic flag
+///      d. Signals completion via atomic flag, and appends to the completion log
+///      e. Calls the registered state callback, if any
 /// 
 /// # Memory Safety
 /// - Writes to shared memory are safe because:
@@ -233,40 +392,58 @@ fn state_scan_tokens(
 ///    Still accepted because they still follow transitions which are valid
 ///    for state 1 ( [a-c]+ ).
 /// ```
+///
+/// States in `seeded` skip the vocabulary scan, their precomputed pairs are published as is.
+/// While `paused` is set, the loop blocks before starting its next state, without holding
+/// a `COMPUTE_POOL` slot, see `wait_while_set`.
+/// Once `cancelled` is set, it returns before starting its next state, leaving the rest unpublished.
+/// Returns whether every reachable state was computed, so a cancellation after the last state is not one.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn create_fsm_index_end_to_end(
     fsm_info: &FSMInfo,
     vocabulary: &TokenVocabulary,
+    alphabet_symbol_mapping: &FxHashMap<char, u32>,
     return_to: &StatesToTokenMaps,
     state_notifiers: &StateNotifierMap,
-) {   
-    let alphabet_symbol_mapping: FxHashMap<char, u32> = fsm_info
-        .alphabet_symbol_mapping
-        .iter()
-        .map(|(k, &v)| (k.chars().next().unwrap(), v))
-        .collect();
+    state_callback: &StateCallbackSlot,
+    completion_log: &StateCompletionLog,
+    options: &ComputeOptions,
+    seeded: &SeededStates,
+    paused: &AtomicBool,
+    cancelled: &AtomicBool,
+) -> bool {
+    let vocabulary_transition_keys =
+        create_vocab_transition_vector(fsm_info, vocabulary, alphabet_symbol_mapping, options);
 
-    let vocabulary_transition_keys = create_vocab_transition_vector(
-        &alphabet_symbol_mapping,
-        fsm_info.alphabet_anything_value,
-        &vocabulary,
-    );
+    let vocabulary_values = &vocabulary.values;
 
     let mut seen = FixedBitSet::with_capacity(fsm_info.transitions.len() + 1);
     let mut next_states = FixedBitSet::with_capacity(fsm_info.transitions.len() + 1);
     next_states.insert(fsm_info.initial as usize);
 
     while let Some(start_state) = next_states.ones().next() {
+        wait_while_set(paused);
+        if cancelled.load(Ordering::Acquire) {
+            return false;
+        }
         next_states.set(start_state, false);
+        // Mark before scanning, so a state that transitions to itself is not queued again.
+        seen.insert(start_state);
 
-        let token_ids_end_states = state_scan_tokens(
-            fsm_info,
-            vocabulary.get_values(),
-            &vocabulary_transition_keys,
-            start_state as u32,
-        );
+        let token_ids_end_states = match seeded.get(&start_state) {
+            Some(token_ids_end_states) => token_ids_end_states.clone(),
+            None => compute_state_tokens(
+                fsm_info,
+                vocabulary_values,
+                &vocabulary_transition_keys,
+                start_state,
+                vocabulary.eos_token_id,
+                options,
+            ),
+        };
 
         unsafe {
-            let map = return_to[start_state].get();
+            let map = Arc::make_mut(return_to[start_state].get());
             for (token_id, end_state) in &token_ids_end_states {
                 map.insert(*token_id, *end_state);
                 
@@ -276,9 +453,1142 @@ pub(crate) fn create_fsm_index_end_to_end(
             }
         }
 
-        seen.insert(start_state);
-        let notifier = Arc::clone(&state_notifiers[start_state]);
-        notifier.store(true, Ordering::Release);
-        wake_all(&*notifier);
+        publish_state(start_state, state_notifiers, state_callback, completion_log);
+    }
+    true
+}
+
+/// Computes only the initial state's `(token_id, end_state)` pairs, on the calling thread.
+///
+/// Nothing is allocated for the other states, so this is much cheaper than building
+/// an index when only the first token has to be constrained.
+pub(crate) fn compute_initial_state_tokens(
+    fsm_info: &FSMInfo,
+    vocabulary: &TokenVocabulary,
+    options: &ComputeOptions,
+) -> Vec<(u32, u32)> {
+    let vocabulary_transition_keys =
+        create_vocab_transition_vector(fsm_info, vocabulary, &fsm_info.char_symbol_mapping(), options);
+    let vocabulary_values = &vocabulary.values;
+
+    compute_state_tokens(
+        fsm_info,
+        vocabulary_values,
+        &vocabulary_transition_keys,
+        fsm_info.initial as usize,
+        vocabulary.eos_token_id,
+        options,
+    )
+}
+
+/// What is needed to compute single states of one FSM after construction, for indexes
+/// which compute each state on first access instead of all of them upfront.
+pub(crate) struct StateScanner {
+    fsm_info: Arc<FSMInfo>,
+    vocabulary: Arc<TokenVocabulary>,
+    vocabulary_transition_keys: Vec<Vec<u32>>,
+    options: ComputeOptions,
+}
+
+impl StateScanner {
+    pub(crate) fn new(
+        fsm_info: Arc<FSMInfo>,
+        vocabulary: Arc<TokenVocabulary>,
+        alphabet_symbol_mapping: &FxHashMap<char, u32>,
+        options: ComputeOptions,
+    ) -> Self {
+        let vocabulary_transition_keys =
+            create_vocab_transition_vector(&fsm_info, &vocabulary, alphabet_symbol_mapping, &options);
+        StateScanner {
+            fsm_info,
+            vocabulary,
+            vocabulary_transition_keys,
+            options,
+        }
+    }
+
+    /// `(token_id, end_state)` pairs of `state`, as the compute thread would find them.
+    pub(crate) fn scan(&self, state: usize) -> Vec<(u32, u32)> {
+        compute_state_tokens(
+            &self.fsm_info,
+            &self.vocabulary.values,
+            &self.vocabulary_transition_keys,
+            state,
+            self.vocabulary.eos_token_id,
+            &self.options,
+        )
     }
-}
\ No newline at end of file
+}
+
+/// A `StateScanner` built on first use, over the vocabulary an index was computed from.
+///
+/// Shared by every index reading the same maps, cache hits included, for the rare
+/// lookups of a state no whole token leads to, see `LazyFSMIndex::allowed_after_bytes`.
+pub(crate) struct LazyStateScanner {
+    vocabulary: Arc<TokenVocabulary>,
+    scanner: OnceCell<StateScanner>,
+}
+
+impl LazyStateScanner {
+    pub(crate) fn new(vocabulary: Arc<TokenVocabulary>) -> Self {
+        LazyStateScanner { vocabulary, scanner: OnceCell::new() }
+    }
+
+    pub(crate) fn vocabulary(&self) -> &TokenVocabulary {
+        &self.vocabulary
+    }
+
+    /// The scanner, building it on the first call with the arguments given then.
+    pub(crate) fn get(
+        &self,
+        fsm_info: &Arc<FSMInfo>,
+        alphabet_symbol_mapping: &FxHashMap<char, u32>,
+        options: &ComputeOptions,
+    ) -> &StateScanner {
+        self.scanner.get_or_init(|| {
+            StateScanner::new(
+                Arc::clone(fsm_info),
+                Arc::clone(&self.vocabulary),
+                alphabet_symbol_mapping,
+                options.clone(),
+            )
+        })
+    }
+}
+
+/// Parallel version of `create_fsm_index_end_to_end`, computing states on `num_workers` threads.
+///
+/// The reachable states are discovered by the same BFS, but the frontier is shared:
+/// each worker pops states from its own FIFO queue, steals from the global injector
+/// or from other workers once it runs dry, and pushes the newly discovered end states
+/// of every state it computes back onto its own queue.
+///
+/// # Memory Safety
+/// A state is claimed with an atomic swap on `claimed` before it is queued,
+/// so every state's map still has exactly one writer. Pushes to the completion log
+/// happen under the state callback lock, which serializes them across workers.
+///
+/// # Termination
+/// `pending` counts states which were claimed but not yet finished. A worker only
+/// decrements it after queueing the children of its state, so it reaches 0 exactly
+/// once every reachable state has been computed, and the idle workers exit.
+/// If a worker panics, `pending` never gets there, so the others stop at their next
+/// state instead, and the panic propagates out of this function.
+///
+/// Workers without a state to compute park on `IdleWorkers` until another worker
+/// queues states, finishes the last one, or exits, rather than spinning.
+///
+/// The resulting maps are identical to the sequential version, only the completion
+/// order differs. Returns once every reachable state is computed, or once cancelled,
+/// and whether every reachable state was computed, like the sequential version.
+/// Like the sequential version, each worker blocks between states while `paused` is set,
+/// and stops at its next state once `cancelled` is set. One of the workers runs on the
+/// calling thread, which gives up its `COMPUTE_POOL` slot while paused.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn create_fsm_index_end_to_end_parallel(
+    fsm_info: &FSMInfo,
+    vocabulary: &TokenVocabulary,
+    alphabet_symbol_mapping: &FxHashMap<char, u32>,
+    return_to: &StatesToTokenMaps,
+    state_notifiers: &StateNotifierMap,
+    state_callback: &StateCallbackSlot,
+    completion_log: &StateCompletionLog,
+    options: &ComputeOptions,
+    seeded: &SeededStates,
+    paused: &AtomicBool,
+    cancelled: &AtomicBool,
+    num_workers: usize,
+) -> bool {
+    let vocabulary_transition_keys =
+        create_vocab_transition_vector(fsm_info, vocabulary, alphabet_symbol_mapping, options);
+    let vocabulary_values = &vocabulary.values;
+
+    let claimed: Vec<AtomicBool> = (0..fsm_info.transitions.len() + 1)
+        .map(|_| AtomicBool::new(false))
+        .collect();
+    let pending = AtomicUsize::new(1);
+    let aborted = AtomicBool::new(false);
+    let idle = IdleWorkers::default();
+    let injector = Injector::new();
+    claimed[fsm_info.initial as usize].store(true, Ordering::Relaxed);
+    injector.push(fsm_info.initial as usize);
+
+    let workers: Vec<Worker<usize>> = (0..num_workers.max(1)).map(|_| Worker::new_fifo()).collect();
+    let stealers: Vec<Stealer<usize>> = workers.iter().map(Worker::stealer).collect();
+
+    let work = |local: Worker<usize>| {
+        let _exit = WorkerExit { aborted: &aborted, idle: &idle };
+        loop {
+            wait_while_set(paused);
+            if aborted.load(Ordering::Acquire) || cancelled.load(Ordering::Acquire) {
+                break;
+            }
+            // Read before looking for work, so states queued after the lookup wake us.
+            let seen = idle.epoch();
+            let start_state = match find_state(&local, &injector, &stealers) {
+                Some(state) => state,
+                None if pending.load(Ordering::Acquire) == 0 => break,
+                None => {
+                    idle.park(seen);
+                    continue;
+                }
+            };
+
+            let token_ids_end_states = match seeded.get(&start_state) {
+                Some(token_ids_end_states) => token_ids_end_states.clone(),
+                None => compute_state_tokens(
+                    fsm_info,
+                    vocabulary_values,
+                    &vocabulary_transition_keys,
+                    start_state,
+                    vocabulary.eos_token_id,
+                    options,
+                ),
+            };
+
+            let mut queued = false;
+            // SAFETY: this worker claimed `start_state`, so it is the only writer of its map.
+            unsafe {
+                let map = Arc::make_mut(return_to[start_state].get());
+                for (token_id, end_state) in &token_ids_end_states {
+                    map.insert(*token_id, *end_state);
+
+                    if !claimed[*end_state as usize].swap(true, Ordering::Relaxed) {
+                        pending.fetch_add(1, Ordering::Relaxed);
+                        local.push(*end_state as usize);
+                        queued = true;
+                    }
+                }
+            }
+
+            publish_state(start_state, state_notifiers, state_callback, completion_log);
+            let finished_last = pending.fetch_sub(1, Ordering::AcqRel) == 1;
+            if queued || finished_last {
+                idle.wake_all();
+            }
+        }
+    };
+
+    let mut workers = workers.into_iter();
+    let own = workers.next().expect("at least one worker");
+    thread::scope(|scope| {
+        for local in workers {
+            scope.spawn(|| work(local));
+        }
+        work(own);
+    });
+    pending.load(Ordering::Acquire) == 0
+}
+
+/// Parking lot for workers of `create_fsm_index_end_to_end_parallel` which ran out of states.
+///
+/// `epoch` is bumped whenever there may be new work or a reason to exit. A worker reads it
+/// before looking for a state, and only sleeps while it is unchanged, checked under the lock
+/// which `wake_all` also takes, so a wake up between the lookup and the sleep is never lost.
+#[derive(Default)]
+struct IdleWorkers {
+    epoch: AtomicUsize,
+    lock: Mutex<()>,
+    wake: Condvar,
+}
+
+impl IdleWorkers {
+    fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    /// Sleeps until `epoch` moves past `seen`.
+    fn park(&self, seen: usize) {
+        let mut guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        while self.epoch() == seen {
+            guard = self.wake.wait(guard).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn wake_all(&self) {
+        self.epoch.fetch_add(1, Ordering::Release);
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.wake.notify_all();
+    }
+}
+
+/// Wakes the parked workers when a worker stops, so they notice a cancellation or the
+/// end of the work. If the worker panicked, it also sets its flag first, so the other
+/// workers stop waiting for states it will never finish.
+struct WorkerExit<'a> {
+    aborted: &'a AtomicBool,
+    idle: &'a IdleWorkers,
+}
+
+impl Drop for WorkerExit<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.aborted.store(true, Ordering::Release);
+        }
+        self.idle.wake_all();
+    }
+}
+
+/// Pops a state from the worker's own queue, or steals one from the injector or another worker.
+fn find_state(local: &Worker<usize>, injector: &Injector<usize>, stealers: &[Stealer<usize>]) -> Option<usize> {
+    local.pop().or_else(|| {
+        std::iter::repeat_with(|| {
+            injector
+                .steal_batch_and_pop(local)
+                .or_else(|| stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(|steal| steal.success())
+    })
+}
+
+/// Scans the vocabulary from `start_state`, capping the result if the state has a wildcard transition.
+/// States requiring EOS get no tokens.
+///
+/// States without outgoing transitions, like the accepting sinks many patterns end in, get
+/// no tokens either, since no token can be walked from them. They are not scanned, so
+/// patterns with many distinct sink finals do not pay for a vocabulary scan on each one.
+fn compute_state_tokens(
+    fsm_info: &FSMInfo,
+    vocabulary_values: &[Vec<u32>],
+    vocabulary_transition_keys: &[Vec<u32>],
+    start_state: usize,
+    eos_token_id: u32,
+    options: &ComputeOptions,
+) -> Vec<(u32, u32)> {
+    if fsm_info.is_eof_required(start_state as u32) {
+        return Vec::new();
+    }
+    if fsm_info.is_sink(start_state as u32) {
+        return Vec::new();
+    }
+
+    let mut token_ids_end_states = state_scan_tokens(
+        fsm_info,
+        vocabulary_values,
+        vocabulary_transition_keys,
+        start_state as u32,
+        options.max_token_len,
+    );
+
+    if let Some(reserved) = &options.reserved_token_ids {
+        token_ids_end_states.retain(|(token_id, _)| !reserved.contains(token_id));
+    }
+
+    if let Some(cap) = options.max_tokens_per_state {
+        let has_wildcard = fsm_info
+            .transitions
+            .get_transition(start_state, fsm_info.alphabet_anything_value as usize)
+            .is_some();
+        if has_wildcard {
+            cap_state_tokens(&mut token_ids_end_states, cap, eos_token_id);
+        }
+    }
+
+    token_ids_end_states
+}
+
+/// Signals that `state`'s map is complete, logs it and calls the state callback.
+///
+/// Holding the callback lock across all of it keeps completion log pushes serialized,
+/// and lets a callback registered mid-computation see every state exactly once.
+/// A callback which panicked poisons the lock, the slot itself is still consistent,
+/// so the poison is ignored rather than failing every later state.
+pub(crate) fn publish_state(
+    state: usize,
+    state_notifiers: &StateNotifierMap,
+    state_callback: &StateCallbackSlot,
+    completion_log: &StateCompletionLog,
+) {
+    let callback = state_callback.lock().unwrap_or_else(PoisonError::into_inner);
+    let notifier = Arc::clone(&state_notifiers[state]);
+    notifier.store(true, Ordering::Release);
+    wake_all(&*notifier);
+    completion_log.push(state as u32);
+    if let Some(callback) = callback.as_ref() {
+        callback(state as u32);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::types::{FSMInfoBuilder, ThreadSafeCell};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Counts allocations made by the current thread, so tests running
+    /// in parallel do not skew each other's numbers.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    thread_local! {
+        /// `state_scan_tokens` calls made by the current thread.
+        pub(super) static SCANS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub(crate) fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
+
+    /// Small deterministic generator, so the tests need no extra dependency.
+    pub(crate) struct Lcg(pub u64);
+
+    impl Lcg {
+        pub fn next(&mut self, bound: u32) -> u32 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((self.0 >> 33) % bound as u64) as u32
+        }
+    }
+
+    /// Random FSM over `num_keys` transition keys, with roughly half the transitions present.
+    pub(crate) fn random_fsm(rng: &mut Lcg, num_states: u32, num_keys: u32) -> FSMInfo {
+        let mut transitions = FxHashMap::default();
+        for state in 0..num_states {
+            for key in 0..num_keys {
+                if rng.next(2) == 0 {
+                    transitions.insert((state, key), rng.next(num_states));
+                }
+            }
+        }
+        // Make sure the largest state exists, so every walk stays in bounds.
+        transitions.insert((num_states - 1, 0), 0);
+
+        FSMInfo {
+            initial: 0,
+            finals: (0..num_states).filter(|_| rng.next(3) == 0).collect(),
+            transitions: transitions.try_into().unwrap(),
+            alphabet_symbol_mapping: FxHashMap::default(),
+            alphabet_anything_value: num_keys,
+            pattern: String::new(),
+            eof_required_finals: Vec::new(),
+        }
+    }
+
+    fn random_keys(rng: &mut Lcg, num_keys: u32, max_len: u32) -> Vec<u32> {
+        (0..rng.next(max_len + 1)).map(|_| rng.next(num_keys)).collect()
+    }
+
+    #[test]
+    fn test_walk_fsm_len_matches_walk_fsm() {
+        let mut rng = Lcg(7);
+        for _ in 0..50 {
+            let fsm_info = random_fsm(&mut rng, 8, 4);
+            for _ in 0..200 {
+                let keys = random_keys(&mut rng, 4, 6);
+                let start_state = rng.next(8);
+                for full_match in [false, true] {
+                    let states = walk_fsm(&fsm_info, &keys, start_state, full_match);
+                    let expected = states.last().map(|&last| (states.len(), last));
+                    assert_eq!(walk_fsm_len(&fsm_info, &keys, start_state, full_match), expected);
+                }
+            }
+        }
+    }
+
+    /// Small regex AST over 'a', 'b', 'c' (keys 0 to 2) and `.`, for comparing
+    /// walks against the `regex` crate.
+    enum Re {
+        Lit(u32),
+        Any,
+        Alt(Box<Re>, Box<Re>),
+        Cat(Box<Re>, Box<Re>),
+        Star(Box<Re>),
+        Opt(Box<Re>),
+    }
+
+    /// Keys of the FSMs built by `re_to_fsm`: 'a' to 'c', then the anything value.
+    const RE_KEYS: u32 = 4;
+
+    impl Re {
+        fn random(rng: &mut Lcg, depth: u32) -> Re {
+            let leaf = depth == 0 || rng.next(3) == 0;
+            match if leaf { rng.next(2) } else { 2 + rng.next(4) } {
+                0 => Re::Lit(rng.next(RE_KEYS - 1)),
+                1 => Re::Any,
+                2 => Re::Alt(Box::new(Re::random(rng, depth - 1)), Box::new(Re::random(rng, depth - 1))),
+                3 => Re::Cat(Box::new(Re::random(rng, depth - 1)), Box::new(Re::random(rng, depth - 1))),
+                4 => Re::Star(Box::new(Re::random(rng, depth - 1))),
+                _ => Re::Opt(Box::new(Re::random(rng, depth - 1))),
+            }
+        }
+
+        fn pattern(&self) -> String {
+            match self {
+                Re::Lit(key) => ((b'a' + *key as u8) as char).to_string(),
+                Re::Any => ".".to_string(),
+                Re::Alt(left, right) => format!("(?:{}|{})", left.pattern(), right.pattern()),
+                Re::Cat(left, right) => format!("{}{}", left.pattern(), right.pattern()),
+                Re::Star(inner) => format!("(?:{})*", inner.pattern()),
+                Re::Opt(inner) => format!("(?:{})?", inner.pattern()),
+            }
+        }
+
+        /// Adds a Thompson NFA fragment, returning its start and end states.
+        /// Edges are `(key, target)`, `None` for epsilon and `Some(RE_KEYS)` for any key.
+        fn compile(&self, nfa: &mut Vec<Vec<(Option<u32>, usize)>>) -> (usize, usize) {
+            let add = |nfa: &mut Vec<Vec<(Option<u32>, usize)>>| {
+                nfa.push(Vec::new());
+                nfa.len() - 1
+            };
+            let (start, end) = (add(nfa), add(nfa));
+            match self {
+                Re::Lit(key) => nfa[start].push((Some(*key), end)),
+                Re::Any => nfa[start].push((Some(RE_KEYS), end)),
+                Re::Alt(left, right) => {
+                    for inner in [left, right] {
+                        let (inner_start, inner_end) = inner.compile(nfa);
+                        nfa[start].push((None, inner_start));
+                        nfa[inner_end].push((None, end));
+                    }
+                }
+                Re::Cat(left, right) => {
+                    let (left_start, left_end) = left.compile(nfa);
+                    let (right_start, right_end) = right.compile(nfa);
+                    nfa[start].push((None, left_start));
+                    nfa[left_end].push((None, right_start));
+                    nfa[right_end].push((None, end));
+                }
+                Re::Star(inner) | Re::Opt(inner) => {
+                    let (inner_start, inner_end) = inner.compile(nfa);
+                    nfa[start].push((None, inner_start));
+                    nfa[inner_end].push((None, end));
+                    nfa[start].push((None, end));
+                    if matches!(self, Re::Star(_)) {
+                        nfa[inner_end].push((None, inner_start));
+                    }
+                }
+            }
+            (start, end)
+        }
+    }
+
+    /// DFA of `re` by subset construction, in the shape interegular produces.
+    fn re_to_fsm(re: &Re) -> FSMInfo {
+        let mut nfa = Vec::new();
+        let (start, end) = re.compile(&mut nfa);
+        let closure = |mut states: Vec<usize>| -> Vec<usize> {
+            let mut stack = states.clone();
+            while let Some(state) = stack.pop() {
+                for &(key, target) in &nfa[state] {
+                    if key.is_none() && !states.contains(&target) {
+                        states.push(target);
+                        stack.push(target);
+                    }
+                }
+            }
+            states.sort_unstable();
+            states
+        };
+
+        let mut builder = crate::types::FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 1)
+            .alphabet_symbol('c', 2)
+            .anything_value(RE_KEYS - 1)
+            .pattern(re.pattern());
+        let mut subsets = vec![closure(vec![start])];
+        let mut next = 0;
+        while next < subsets.len() {
+            if subsets[next].contains(&end) {
+                builder = builder.add_final(next as u32);
+            }
+            for key in 0..RE_KEYS {
+                let moved: Vec<usize> = subsets[next]
+                    .iter()
+                    .flat_map(|&state| &nfa[state])
+                    .filter(|(edge, _)| *edge == Some(key) || *edge == Some(RE_KEYS))
+                    .map(|&(_, target)| target)
+                    .collect();
+                if moved.is_empty() {
+                    continue;
+                }
+                let moved = closure(moved);
+                let target = subsets.iter().position(|subset| *subset == moved).unwrap_or_else(|| {
+                    subsets.push(moved);
+                    subsets.len() - 1
+                });
+                builder = builder.add_transition(next as u32, key, target as u32);
+            }
+            next += 1;
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_walk_fsm_matches_regex_crate() {
+        let mut rng = Lcg(11);
+        for _ in 0..200 {
+            let re = Re::random(&mut rng, 4);
+            let fsm_info = re_to_fsm(&re);
+            let reference = regex::Regex::new(&format!("^(?:{})$", re.pattern())).unwrap();
+
+            for _ in 0..50 {
+                // 'd' is outside the alphabet, so it takes the anything value.
+                let input: String = (0..rng.next(7)).map(|_| (b'a' + rng.next(4) as u8) as char).collect();
+                let keys: Vec<u32> = input.bytes().map(|b| (b - b'a') as u32).collect();
+                let context = format!("{:?} on {:?}", re.pattern(), input);
+
+                let full = walk_fsm_len(&fsm_info, &keys, fsm_info.initial, true);
+                if keys.is_empty() {
+                    assert_eq!(full, None, "{}", context);
+                } else {
+                    assert_eq!(full.is_some(), reference.is_match(&input), "{}", context);
+                }
+                assert_eq!(
+                    walk_fsm(&fsm_info, &keys, fsm_info.initial, true).is_empty(),
+                    full.is_none(),
+                    "{}",
+                    context
+                );
+
+                // A walk which dies early keeps the longest matching prefix.
+                let longest_match = (1..=input.len()).rev().find(|&len| reference.is_match(&input[..len]));
+                match walk_fsm_len(&fsm_info, &keys, fsm_info.initial, false) {
+                    Some((len, _)) if len < keys.len() => {
+                        assert_eq!(Some(len), longest_match, "{}", context)
+                    }
+                    Some(_) => {}
+                    None => assert!(keys.is_empty() || longest_match.is_none(), "{}", context),
+                }
+            }
+        }
+    }
+
+    /// Random FSM over the characters 'a'.., one transition key per character,
+    /// with a vocabulary of random strings over the same characters.
+    fn random_fsm_and_vocab(
+        rng: &mut Lcg,
+        num_states: u32,
+        num_keys: u32,
+        vocab_size: u32,
+    ) -> (FSMInfo, TokenVocabulary) {
+        let mut fsm_info = random_fsm(rng, num_states, num_keys);
+        let symbols: Vec<char> = (0..num_keys).map(|key| (b'a' + key as u8) as char).collect();
+        fsm_info.alphabet_symbol_mapping = symbols
+            .iter()
+            .enumerate()
+            .map(|(key, symbol)| (symbol.to_string(), key as u32))
+            .collect();
+
+        let mut vocabulary = TokenVocabulary::default();
+        for token_id in 0..vocab_size {
+            let keys = random_keys(rng, num_keys, 4);
+            vocabulary.add_token(keys.iter().map(|&key| symbols[key as usize]).collect(), vec![token_id]);
+        }
+        (fsm_info, vocabulary)
+    }
+
+    /// Runs one of the compute functions into fresh maps, returning the computed states.
+    fn compute_maps(
+        fsm_info: &FSMInfo,
+        vocabulary: &TokenVocabulary,
+        num_workers: Option<usize>,
+    ) -> FxHashMap<u32, FxHashMap<u32, u32>> {
+        let options = ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: None,
+            canonicalize_fsm: false,
+            max_token_len: None,
+            byte_alphabet: false,
+        };
+        compute_maps_with_options(fsm_info, vocabulary, num_workers, &options)
+    }
+
+    fn compute_maps_with_options(
+        fsm_info: &FSMInfo,
+        vocabulary: &TokenVocabulary,
+        num_workers: Option<usize>,
+        options: &ComputeOptions,
+    ) -> FxHashMap<u32, FxHashMap<u32, u32>> {
+        let num_states = fsm_info.transitions.len() + 1;
+        let return_to: StatesToTokenMaps =
+            Arc::new((0..num_states).map(|_| ThreadSafeCell::new(Arc::default())).collect());
+        let state_notifiers: StateNotifierMap =
+            Arc::new((0..num_states).map(|_| Arc::new(AtomicBool::new(false))).collect());
+        let state_callback = Arc::new(Mutex::new(None));
+        let completion_log = StateCompletionLog::with_capacity(num_states);
+
+        match num_workers {
+            Some(num_workers) => create_fsm_index_end_to_end_parallel(
+                fsm_info, vocabulary, &fsm_info.char_symbol_mapping(), &return_to, &state_notifiers, &state_callback,
+                &completion_log, options, &SeededStates::default(), &AtomicBool::new(false),
+                &AtomicBool::new(false), num_workers,
+            ),
+            None => create_fsm_index_end_to_end(
+                fsm_info, vocabulary, &fsm_info.char_symbol_mapping(), &return_to, &state_notifiers, &state_callback,
+                &completion_log, options, &SeededStates::default(), &AtomicBool::new(false),
+                &AtomicBool::new(false),
+            ),
+        };
+
+        let mut logged: Vec<u32> = completion_log.since(0).collect();
+        logged.sort_unstable();
+        let computed: Vec<u32> = (0..num_states as u32)
+            .filter(|&state| state_notifiers[state as usize].load(Ordering::Acquire))
+            .collect();
+        assert_eq!(logged, computed, "every computed state is logged exactly once");
+
+        computed
+            .into_iter()
+            .map(|state| {
+                let map = return_to[state as usize].read_after(&state_notifiers[state as usize]);
+                (state, FxHashMap::clone(map.unwrap()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sink_finals_are_never_scanned() {
+        // "a|ba|bba|bbb": each branch ends in its own final without exits, states 1, 3 and 5.
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 1)
+            .add_transition(0, 0, 1)
+            .add_transition(0, 1, 2)
+            .add_transition(2, 0, 3)
+            .add_transition(2, 1, 4)
+            .add_transition(4, 0, 5)
+            .add_transition(4, 1, 5)
+            .add_final(1)
+            .add_final(3)
+            .add_final(5)
+            .pattern("sink_finals".to_string())
+            .build()
+            .unwrap();
+        let mut vocabulary = TokenVocabulary::default();
+        for (token, token_id) in [("a", 1), ("b", 2), ("ba", 3), ("bbb", 4)] {
+            vocabulary.add_token(token.to_string(), vec![token_id]);
+        }
+
+        let scans_before = SCANS.with(Cell::get);
+        let maps = compute_maps(&fsm_info, &vocabulary, None);
+        assert_eq!(SCANS.with(Cell::get) - scans_before, 3, "only states 0, 2 and 4 are scanned");
+
+        // The sinks are still published, with empty maps.
+        for sink in [1, 3, 5] {
+            assert_eq!(maps[&sink], FxHashMap::default());
+        }
+        assert_eq!(maps[&0], [(1, 1), (2, 2), (3, 3), (4, 5)].into_iter().collect());
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let mut rng = Lcg(11);
+        for _ in 0..20 {
+            let (fsm_info, vocabulary) = random_fsm_and_vocab(&mut rng, 40, 4, 300);
+            let expected = compute_maps(&fsm_info, &vocabulary, None);
+            for num_workers in [1, 2, 4] {
+                assert_eq!(compute_maps(&fsm_info, &vocabulary, Some(num_workers)), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential_on_thousands_of_states() {
+        let mut rng = Lcg(23);
+        let (fsm_info, vocabulary) = random_fsm_and_vocab(&mut rng, 3000, 4, 100);
+        let expected = compute_maps(&fsm_info, &vocabulary, None);
+        assert!(expected.len() > 1000, "only {} states reachable", expected.len());
+        assert_eq!(compute_maps(&fsm_info, &vocabulary, Some(4)), expected);
+    }
+
+    #[test]
+    fn test_parallel_worker_panic_does_not_hang() {
+        let mut rng = Lcg(5);
+        let (fsm_info, vocabulary) = random_fsm_and_vocab(&mut rng, 40, 4, 300);
+        let num_states = fsm_info.transitions.len() + 1;
+        let return_to: StatesToTokenMaps =
+            Arc::new((0..num_states).map(|_| ThreadSafeCell::new(Arc::default())).collect());
+        let state_notifiers: StateNotifierMap =
+            Arc::new((0..num_states).map(|_| Arc::new(AtomicBool::new(false))).collect());
+        // An end state past the last state panics whichever worker computes the initial state.
+        let seeded: SeededStates = [(fsm_info.initial as usize, vec![(1, num_states as u32 + 10)])]
+            .into_iter()
+            .collect();
+
+        let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            create_fsm_index_end_to_end_parallel(
+                &fsm_info, &vocabulary, &fsm_info.char_symbol_mapping(), &return_to, &state_notifiers, &Arc::new(Mutex::new(None)),
+                &StateCompletionLog::with_capacity(num_states), &ComputeOptions::default(), &seeded,
+                &AtomicBool::new(false), &AtomicBool::new(false), 4,
+            )
+        }));
+        assert!(outcome.is_err());
+    }
+
+    #[test]
+    fn test_paused_computation_makes_no_progress_until_resumed() {
+        let mut rng = Lcg(17);
+        let (fsm_info, vocabulary) = random_fsm_and_vocab(&mut rng, 40, 4, 300);
+        let expected = compute_maps(&fsm_info, &vocabulary, None);
+        let num_states = fsm_info.transitions.len() + 1;
+
+        for num_workers in [None, Some(4)] {
+            let return_to: StatesToTokenMaps =
+                Arc::new((0..num_states).map(|_| ThreadSafeCell::new(Arc::default())).collect());
+            let state_notifiers: StateNotifierMap =
+                Arc::new((0..num_states).map(|_| Arc::new(AtomicBool::new(false))).collect());
+            let completion_log = StateCompletionLog::with_capacity(num_states);
+            let paused = AtomicBool::new(true);
+
+            thread::scope(|scope| {
+                let compute = scope.spawn(|| {
+                    let (state_callback, options) = (Arc::new(Mutex::new(None)), ComputeOptions::default());
+                    match num_workers {
+                        Some(num_workers) => create_fsm_index_end_to_end_parallel(
+                            &fsm_info, &vocabulary, &fsm_info.char_symbol_mapping(), &return_to, &state_notifiers, &state_callback,
+                            &completion_log, &options, &SeededStates::default(), &paused, &AtomicBool::new(false),
+                            num_workers,
+                        ),
+                        None => create_fsm_index_end_to_end(
+                            &fsm_info, &vocabulary, &fsm_info.char_symbol_mapping(), &return_to, &state_notifiers, &state_callback,
+                            &completion_log, &options, &SeededStates::default(), &paused, &AtomicBool::new(false),
+                        ),
+                    }
+                });
+
+                thread::sleep(std::time::Duration::from_millis(50));
+                assert_eq!(completion_log.since(0).count(), 0, "no state is computed while paused");
+                assert!(!compute.is_finished());
+
+                paused.store(false, Ordering::Release);
+                wake_all(&paused);
+                compute.join().unwrap();
+            });
+            assert_eq!(completion_log.since(0).count(), expected.len());
+        }
+    }
+
+    #[test]
+    fn test_cancelling_after_the_last_state_still_finishes() {
+        // "aa", computed as 0 -> 1 -> 2 with the token "a".
+        let fsm_info = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 0, 2)
+            .add_final(2)
+            .pattern("cancel_after_last".to_string())
+            .build()
+            .unwrap();
+        let mut vocabulary = TokenVocabulary::default();
+        vocabulary.add_token("a".to_string(), vec![1]);
+
+        // The flag is set from the callback of `cancel_at`, right after that state is published.
+        for (num_workers, cancel_at, finished) in [(None, 2, true), (Some(4), 2, true), (None, 0, false), (Some(4), 0, false)] {
+            let return_to: StatesToTokenMaps = Arc::new((0..3).map(|_| ThreadSafeCell::new(Arc::default())).collect());
+            let state_notifiers: StateNotifierMap = Arc::new((0..3).map(|_| Arc::new(AtomicBool::new(false))).collect());
+            let completion_log = StateCompletionLog::with_capacity(3);
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let cancelled_clone = Arc::clone(&cancelled);
+            let state_callback: StateCallbackSlot = Arc::new(Mutex::new(Some(Arc::new(move |state| {
+                if state == cancel_at {
+                    cancelled_clone.store(true, Ordering::Release);
+                }
+            }))));
+            let options = ComputeOptions::default();
+
+            let outcome = match num_workers {
+                Some(num_workers) => create_fsm_index_end_to_end_parallel(
+                    &fsm_info, &vocabulary, &fsm_info.char_symbol_mapping(), &return_to, &state_notifiers, &state_callback,
+                    &completion_log, &options, &SeededStates::default(), &AtomicBool::new(false), &cancelled, num_workers,
+                ),
+                None => create_fsm_index_end_to_end(
+                    &fsm_info, &vocabulary, &fsm_info.char_symbol_mapping(), &return_to, &state_notifiers, &state_callback,
+                    &completion_log, &options, &SeededStates::default(), &AtomicBool::new(false), &cancelled,
+                ),
+            };
+            assert!(cancelled.load(Ordering::Acquire));
+            assert_eq!(outcome, finished, "{:?} workers, cancelled at state {}", num_workers, cancel_at);
+        }
+    }
+
+    #[test]
+    fn test_negated_class_rejects_excluded_symbols() {
+        // "[^0-9]+": the excluded digits get a transition key of their own, which no state
+        // has a transition for, so only characters outside the alphabet take the wildcard.
+        let mut builder = crate::types::FSMInfoBuilder::new()
+            .anything_value(1)
+            .add_transition(0, 1, 1)
+            .add_transition(1, 1, 1)
+            .add_final(1)
+            .pattern("[^0-9]+".to_string());
+        for digit in '0'..='9' {
+            builder = builder.alphabet_symbol(digit, 0);
+        }
+        let fsm_info = builder.build().unwrap();
+        let tokens = ["a", "ab", "x y", "7", "a1", "12", "1a"];
+        let mut vocabulary = TokenVocabulary::default();
+        for (token_id, token) in tokens.iter().enumerate() {
+            vocabulary.add_token(token.to_string(), vec![token_id as u32]);
+        }
+
+        let maps = compute_maps(&fsm_info, &vocabulary, None);
+        for state in [0, 1] {
+            let mut allowed: Vec<&str> = maps[&state].keys().map(|&token_id| tokens[token_id as usize]).collect();
+            allowed.sort_unstable();
+            assert_eq!(allowed, ["a", "ab", "x y"], "state {}", state);
+        }
+    }
+
+    #[test]
+    fn test_scan_follows_anything_else_transition() {
+        // ".a": out-of-alphabet characters like 'x' and 'd' take the anything value.
+        let fsm_info = re_to_fsm(&Re::Cat(Box::new(Re::Any), Box::new(Re::Lit(0))));
+        let tokens = ["x", "xa", "d", "da", "a", "aa", "b", "ba", "ab", "xb", "xab", "xaa"];
+        let mut vocabulary = TokenVocabulary::default();
+        for (token_id, token) in tokens.iter().enumerate() {
+            vocabulary.add_token(token.to_string(), vec![token_id as u32]);
+        }
+
+        let maps = compute_maps(&fsm_info, &vocabulary, None);
+        let initial = &maps[&fsm_info.initial];
+        let mut allowed: Vec<&str> = initial.keys().map(|&token_id| tokens[token_id as usize]).collect();
+        allowed.sort_unstable();
+        assert_eq!(allowed, ["a", "aa", "b", "ba", "d", "da", "x", "xa"]);
+        assert_eq!(initial[&1], initial[&5], "\"xa\" and \"aa\" both reach the final state");
+        assert!(fsm_info.finals.contains(&initial[&1]));
+        assert_eq!(compute_maps(&fsm_info, &vocabulary, Some(2)), maps);
+    }
+
+    #[test]
+    fn test_byte_symbols_are_looked_up_whole() {
+        let mut fsm_info = random_fsm(&mut Lcg(1), 2, 3);
+        fsm_info.alphabet_symbol_mapping =
+            [("a", 0), ("\x00C3", 1), (" ", 2)].into_iter().map(|(symbol, key)| (symbol.to_string(), key)).collect();
+        fsm_info.alphabet_anything_value = 3;
+        let symbols = fsm_info.char_symbol_mapping();
+        let keys = |token: &str| token_transition_keys(token, &fsm_info, &symbols, true);
+
+        assert_eq!(keys("a\x00C3a"), [0, 1, 0]);
+        assert_eq!(keys("<0xC3>"), [1]);
+        assert_eq!(keys(" <0x20>"), [2]);
+        // Not in the alphabet as a whole, so looked up char by char.
+        assert_eq!(keys("\x00A9").len(), 3);
+        assert_eq!(keys("<0xZZ>"), [3, 3, 3, 3, 3, 3]);
+        // Only the space byte is preceded by a space, other such tokens are text.
+        assert_eq!(keys(" <0xC3>"), [2, 3, 3, 3, 3, 3, 3]);
+        // Vocabularies without byte tokens spell them out.
+        assert_eq!(token_transition_keys("<0xC3>", &fsm_info, &symbols, false), [3, 3, 3, 3, 3, 3]);
+    }
+
+    /// `(é)+` over the byte alphabet, spelled in UTF-8: `C3 A9`, repeated.
+    pub(crate) fn e_acute_plus_byte_fsm(pattern: &str) -> FSMInfo {
+        let transitions: FxHashMap<(u32, u32), u32> =
+            [((0, 0xC3), 1), ((1, 0xA9), 2), ((2, 0xC3), 1)].into_iter().collect();
+        FSMInfo {
+            initial: 0,
+            finals: vec![2],
+            transitions: transitions.try_into().unwrap(),
+            alphabet_symbol_mapping: FxHashMap::default(),
+            alphabet_anything_value: 256,
+            pattern: pattern.to_string(),
+            eof_required_finals: Vec::new(),
+        }
+    }
+
+    /// Whole "é" tokens, its two bytes as byte symbols and as a llama byte token, and an "e".
+    pub(crate) fn e_acute_vocab() -> TokenVocabulary {
+        let mut vocabulary = TokenVocabulary::default();
+        for (token, token_id) in [("é", 1), ("éé", 2), ("\x00C3", 3), ("\x00A9", 4), ("<0xA9>", 5), ("e", 6)] {
+            vocabulary.add_token(token.to_string(), vec![token_id]);
+        }
+        vocabulary.byte_tokens = true;
+        vocabulary
+    }
+
+    #[test]
+    fn test_byte_alphabet_walks_tokens_as_raw_bytes() {
+        let fsm_info = e_acute_plus_byte_fsm("byte_alphabet/é+");
+        let vocabulary = e_acute_vocab();
+        let options = ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: None,
+            canonicalize_fsm: false,
+            max_token_len: None,
+            byte_alphabet: true,
+        };
+
+        assert_eq!(byte_transition_keys("é\x00C3", true), [0xC3, 0xA9, 0xC3]);
+        assert_eq!(byte_transition_keys("<0xA9>", true), [0xA9]);
+
+        let whole_chars: FxHashMap<u32, u32> = [(1, 2), (2, 2), (3, 1)].into_iter().collect();
+        let expected: FxHashMap<u32, FxHashMap<u32, u32>> = [
+            (0, whole_chars.clone()),
+            // Mid-character: only the trailing byte continues.
+            (1, [(4, 2), (5, 2)].into_iter().collect()),
+            (2, whole_chars),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(compute_maps_with_options(&fsm_info, &vocabulary, None, &options), expected);
+        assert_eq!(compute_maps_with_options(&fsm_info, &vocabulary, Some(2), &options), expected);
+        assert_eq!(compute_initial_state_tokens(&fsm_info, &vocabulary, &options).len(), 3);
+    }
+
+    /// `.{1,max_len}`, and a vocabulary of `num_tokens` tokens, every fourth one a
+    /// 64 char long merged token, the rest spelling at most `max_len` chars.
+    fn bounded_fsm_and_long_token_vocab(max_len: u32, num_tokens: u32) -> (FSMInfo, TokenVocabulary) {
+        let transitions: FxHashMap<(u32, u32), u32> =
+            (0..max_len).map(|state| ((state, 0), state + 1)).collect();
+        let fsm_info = FSMInfo {
+            initial: 0,
+            finals: (1..=max_len).collect(),
+            transitions: transitions.try_into().unwrap(),
+            alphabet_symbol_mapping: FxHashMap::default(),
+            alphabet_anything_value: 0,
+            pattern: String::new(),
+            eof_required_finals: Vec::new(),
+        };
+
+        let mut rng = Lcg(9);
+        let mut vocabulary = TokenVocabulary::default();
+        for token_id in 0..num_tokens {
+            let len = if token_id % 4 == 0 { 64 } else { 1 + rng.next(max_len) };
+            let token: String = (0..len).map(|_| (b'a' + rng.next(26) as u8) as char).collect();
+            vocabulary.add_token(token, vec![token_id]);
+        }
+        (fsm_info, vocabulary)
+    }
+
+    #[test]
+    fn test_max_token_len_is_exact_for_bounded_patterns() {
+        let (fsm_info, vocabulary) = bounded_fsm_and_long_token_vocab(8, 400);
+        let options = |max_token_len| ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: None,
+            canonicalize_fsm: false,
+            max_token_len,
+            byte_alphabet: false,
+        };
+
+        let expected = compute_maps(&fsm_info, &vocabulary, None);
+        for num_workers in [None, Some(2)] {
+            let skipped = compute_maps_with_options(&fsm_info, &vocabulary, num_workers, &options(Some(8)));
+            assert_eq!(skipped, expected);
+        }
+
+        // Unsafe for an unbounded pattern: `.+` matches the long tokens too.
+        let mut unbounded = fsm_info.clone();
+        unbounded.transitions = FxHashMap::from_iter([((0, 0), 1), ((1, 0), 1)]).try_into().unwrap();
+        unbounded.finals = vec![1];
+        let full = compute_maps(&unbounded, &vocabulary, None);
+        let skipped = compute_maps_with_options(&unbounded, &vocabulary, None, &options(Some(8)));
+        assert_eq!(full[&0].len(), vocabulary.len());
+        assert_eq!(skipped[&0].len(), vocabulary.len() - 100);
+    }
+
+    // cargo test --release bench_max_token_len -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_max_token_len_on_long_tokens() {
+        let (fsm_info, vocabulary) = bounded_fsm_and_long_token_vocab(8, 128_000);
+        let options = |max_token_len| ComputeOptions {
+            max_tokens_per_state: None,
+            reserved_token_ids: None,
+            canonicalize_fsm: false,
+            max_token_len,
+            byte_alphabet: false,
+        };
+
+        let start = Instant::now();
+        let expected = compute_maps_with_options(&fsm_info, &vocabulary, None, &options(None));
+        println!("all tokens:         {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let skipped = compute_maps_with_options(&fsm_info, &vocabulary, None, &options(Some(8)));
+        println!("max_token_len = 8:  {:?}", start.elapsed());
+        assert_eq!(skipped, expected);
+    }
+
+    // cargo test --release bench_parallel -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_parallel_vs_sequential() {
+        let mut rng = Lcg(42);
+        let (fsm_info, vocabulary) = random_fsm_and_vocab(&mut rng, 256, 8, 50_000);
+
+        let start = Instant::now();
+        let expected = compute_maps(&fsm_info, &vocabulary, None);
+        println!("sequential:   {:>4} states, {:?}", expected.len(), start.elapsed());
+
+        for num_workers in [2, 4, 8] {
+            let start = Instant::now();
+            let maps = compute_maps(&fsm_info, &vocabulary, Some(num_workers));
+            println!("{} workers:    {:>4} states, {:?}", num_workers, maps.len(), start.elapsed());
+            assert_eq!(maps, expected);
+        }
+    }
+
+    // cargo test --release bench_parallel_thousands -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_parallel_thousands_of_reachable_states() {
+        let mut rng = Lcg(42);
+        let (fsm_info, vocabulary) = random_fsm_and_vocab(&mut rng, 8192, 4, 20_000);
+
+        let start = Instant::now();
+        let expected = compute_maps(&fsm_info, &vocabulary, None);
+        println!("sequential:   {:>5} states, {:?}", expected.len(), start.elapsed());
+
+        for num_workers in [2, 4, 8] {
+            let start = Instant::now();
+            let maps = compute_maps(&fsm_info, &vocabulary, Some(num_workers));
+            println!("{} workers:    {:>5} states, {:?}", num_workers, maps.len(), start.elapsed());
+            assert_eq!(maps, expected);
+        }
+    }
+
+    // cargo test --release bench_walk_fsm -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_walk_fsm_vs_walk_fsm_len() {
+        let mut rng = Lcg(42);
+        let num_states = 32;
+        let fsm_info = random_fsm(&mut rng, num_states, 16);
+        let vocabulary: Vec<Vec<u32>> = (0..100_000).map(|_| random_keys(&mut rng, 16, 8)).collect();
+
+        let mut accepted = 0;
+        let (start_allocs, start) = (allocations(), Instant::now());
+        for state in 0..num_states {
+            for keys in &vocabulary {
+                let states = walk_fsm(&fsm_info, keys, state, false);
+                accepted += (states.len() == keys.len() && !keys.is_empty()) as usize;
+            }
+        }
+        let (vec_allocs, vec_time) = (allocations() - start_allocs, start.elapsed());
+
+        let mut accepted_len = 0;
+        let (start_allocs, start) = (allocations(), Instant::now());
+        for state in 0..num_states {
+            for keys in &vocabulary {
+                let walk = walk_fsm_len(&fsm_info, keys, state, false);
+                accepted_len += matches!(walk, Some((len, _)) if len == keys.len()) as usize;
+            }
+        }
+        let (len_allocs, len_time) = (allocations() - start_allocs, start.elapsed());
+
+        assert_eq!(accepted, accepted_len);
+        println!("walk_fsm:     {:>10} allocations, {:?}", vec_allocs, vec_time);
+        println!("walk_fsm_len: {:>10} allocations, {:?}", len_allocs, len_time);
+    }
+}
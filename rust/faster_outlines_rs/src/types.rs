@@ -20,50 +20,105 @@
 * THE SOFTWARE.
 */
 
-use rustc_hash::FxHashMap;
+use crate::environment::{CANONICALIZE_FSM, MAX_TOKENS_PER_STATE, MAX_TRANSITION_CELLS};
+#[cfg(feature = "json")]
+use anyhow::Context;
+use fixedbitset::FixedBitSet;
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Serialize, Deserialize};
 use std::cell::UnsafeCell;
-use std::sync::atomic::AtomicBool;
-use smallvec::SmallVec;
-use std::sync::Arc;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// Memory layout for FSM state transition tables.
 /// 
 /// Structure breakdown:
 /// - Arc<Vec<...>>: Shared ownership across threads
 /// - ThreadSafeCell: Zero-copy access between threads
-/// - FxHashMap<u32,u32>: Individual state transition table
+/// - Arc<FxHashMap<u32,u32>>: Individual state transition table, shared between
+///   states with identical tables after `LazyFSMIndex::dedup_maps`
 /// 
 /// We split the FSM into per-state maps rather than one giant transition table.
 /// This approach:
-/// 1. Enables parallel computation of different states (we dont do this, but it would be easy to add with say rayon)
+/// 1. Enables parallel computation of different states (see `FASTER_OUTLINES_COMPUTE_THREADS`)
 /// 2. May improve memory locality (each state's transitions are contiguous) depending on allocator.
 /// 3. Avoids large contiguous allocations that could cause fragmentation
-pub(crate) type StatesToTokenMaps = Arc<Vec<ThreadSafeCell<FxHashMap<u32, u32>>>>;
+pub(crate) type StatesToTokenMaps = Arc<Vec<ThreadSafeCell<Arc<FxHashMap<u32, u32>>>>>;
+
+/// Fraction of a state's row that must be filled before the dense representation
+/// is used, below it the transitions go in a hash map. Dense rows cost 4 bytes per
+/// transition key, hash map entries roughly 2-3x that per present transition.
+const DENSE_MIN_FILL: f64 = 0.25;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct StateMap {
-    /// Sparse array where `u32::MAX` represents None.
+pub enum StateMap {
+    /// Array where `u32::MAX` represents None.
     /// The index is the transition key, and the u32 in the transitions
     /// slot signifies the resultant state if you follow that transition.
-    transitions: Vec<u32>,
+    Dense(Vec<u32>),
+    /// Transition key to resultant state, for states with few outgoing transitions.
+    Sparse(FxHashMap<u32, u32>),
 }
 
 impl StateMap {
+    /// Builds a state's row from its `(transition key, target)` entries, picking the
+    /// dense representation only if at least `DENSE_MIN_FILL` of the `width` keys are used.
+    pub fn from_entries(entries: Vec<(u32, u32)>, width: usize) -> StateMap {
+        if (entries.len() as f64) < width as f64 * DENSE_MIN_FILL {
+            StateMap::Sparse(entries.into_iter().collect())
+        } else {
+            let mut transitions = vec![u32::MAX; width];
+            for (transition, target) in entries {
+                if let Some(slot) = transitions.get_mut(transition as usize) {
+                    *slot = target;
+                }
+            }
+            StateMap::Dense(transitions)
+        }
+    }
+
     pub fn get(&self, transition: usize) -> Option<u32> {
-        self.transitions.get(transition).and_then(|&state| {
-            if state == u32::MAX {
-                None
-            } else {
-                Some(state)
+        match self {
+            StateMap::Dense(transitions) => transitions
+                .get(transition)
+                .copied()
+                .filter(|&state| state != u32::MAX),
+            StateMap::Sparse(transitions) => {
+                u32::try_from(transition).ok().and_then(|key| transitions.get(&key).copied())
             }
-        })
+        }
+    }
+
+    /// Resultant states of every present transition, in no particular order.
+    pub fn targets(&self) -> impl Iterator<Item = u32> + '_ {
+        let (dense, sparse) = match self {
+            StateMap::Dense(transitions) => (Some(transitions.iter()), None),
+            StateMap::Sparse(transitions) => (None, Some(transitions.values())),
+        };
+        dense
+            .into_iter()
+            .flatten()
+            .chain(sparse.into_iter().flatten())
+            .copied()
+            .filter(|&state| state != u32::MAX)
+    }
+
+    /// Approximate heap bytes held by this row.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            StateMap::Dense(transitions) => transitions.capacity() * std::mem::size_of::<u32>(),
+            // hashbrown keeps one control byte per bucket next to each `(key, value)` pair.
+            StateMap::Sparse(transitions) => {
+                transitions.capacity() * (std::mem::size_of::<(u32, u32)>() + 1)
+            }
+        }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransitionMap {
-    transitions: SmallVec<[StateMap; 1024]>,
+    transitions: Vec<StateMap>,
 }
 
 impl TransitionMap {
@@ -75,8 +130,9 @@ impl TransitionMap {
         self.get_state(state).and_then(|state_map| state_map.get(transition))
     }
 
-    pub fn iter_state(&self, state: usize) -> Option<impl Iterator<Item = &u32>> {
-        self.get_state(state).map(|state_map| state_map.transitions.iter())
+    /// Resultant states of every transition out of `state`, see `StateMap::targets`.
+    pub fn iter_state(&self, state: usize) -> Option<impl Iterator<Item = u32> + '_> {
+        self.get_state(state).map(|state_map| state_map.targets())
     }
 
     pub fn states(&self) -> impl Iterator<Item = usize> {
@@ -86,33 +142,69 @@ impl TransitionMap {
     pub fn len(&self) -> usize {
         self.transitions.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+
+    /// Approximate heap bytes held by the row table and the rows.
+    pub fn heap_size(&self) -> usize {
+        let table = self.transitions.capacity() * std::mem::size_of::<StateMap>();
+        table + self.transitions.iter().map(StateMap::heap_size).sum::<usize>()
+    }
 }
 
-impl From<FxHashMap<(u32, u32), u32>> for TransitionMap {
-    fn from(map: FxHashMap<(u32, u32), u32>) -> TransitionMap {
-        // Determine the maximum state_id and transition_id to size the sparse arrays
+impl TransitionMap {
+    /// Builds the table from `(state, transition key) -> target` entries, refusing to
+    /// allocate more than `max_cells` cells.
+    ///
+    /// The table is bounded as if every state were dense, `(max_state_id + 1) * (max_transition_id + 1)`
+    /// cells, so a single huge id, say from a buggy converter or a sparse numbering, is refused
+    /// before it can allocate gigabytes.
+    ///
+    /// Rows are as wide as the largest transition key in `map`, so an `alphabet_anything_value`
+    /// which any transition uses always has a slot, however large it is compared to the
+    /// alphabet's keys. A sentinel like `u32::MAX` is refused by the limit, not wrapped.
+    pub fn from_map_with_limit(map: FxHashMap<(u32, u32), u32>, max_cells: usize) -> anyhow::Result<TransitionMap> {
+        // Determine the maximum state_id and transition_id to size the table
         let max_state_id = map.keys().map(|(state_id, _)| *state_id).max().unwrap_or(0) as usize;
         let max_transition_id = map.keys().map(|(_, transition_id)| *transition_id).max().unwrap_or(0) as usize;
 
-        // Initialize a SmallVec for TransitionMap with StateMaps containing sparse arrays
-        let mut transitions: SmallVec<[StateMap; 1024]> = SmallVec::new();
-        transitions.resize_with(max_state_id + 1, || StateMap {
-            transitions: vec![u32::MAX; max_transition_id + 1],
-        });
+        let cells = max_state_id
+            .checked_add(1)
+            .zip(max_transition_id.checked_add(1))
+            .and_then(|(rows, width)| rows.checked_mul(width));
+        if cells.filter(|&cells| cells <= max_cells).is_none() {
+            bail!(
+                "Transition table for max state id {} and max transition key {} exceeds {} cells, \
+                 see FASTER_OUTLINES_MAX_TRANSITION_CELLS",
+                max_state_id,
+                max_transition_id,
+                max_cells
+            );
+        }
 
-        // Populate each StateMap's sparse array with transition states
+        // Group the entries by state, then pick each state's representation by its fill.
+        let mut entries: Vec<Vec<(u32, u32)>> = vec![Vec::new(); max_state_id + 1];
         for ((state_id, transition_id), target_state) in map {
-            let state_idx = state_id as usize;
-            let transition_idx = transition_id as usize;
-
-            if let Some(state_map) = transitions.get_mut(state_idx) {
-                if transition_idx < state_map.transitions.len() {
-                    state_map.transitions[transition_idx] = target_state;
-                }
-            }
+            entries[state_id as usize].push((transition_id, target_state));
         }
 
-        TransitionMap { transitions }
+        let transitions: Vec<StateMap> = entries
+            .into_iter()
+            .map(|state_entries| StateMap::from_entries(state_entries, max_transition_id + 1))
+            .collect();
+
+        Ok(TransitionMap { transitions })
+    }
+}
+
+impl TryFrom<FxHashMap<(u32, u32), u32>> for TransitionMap {
+    type Error = anyhow::Error;
+
+    /// Same as `from_map_with_limit`, with the limit from `FASTER_OUTLINES_MAX_TRANSITION_CELLS`.
+    fn try_from(map: FxHashMap<(u32, u32), u32>) -> anyhow::Result<TransitionMap> {
+        TransitionMap::from_map_with_limit(map, *MAX_TRANSITION_CELLS)
     }
 }
 
@@ -130,6 +222,54 @@ impl From<FxHashMap<(u32, u32), u32>> for TransitionMap {
 /// 3. Lock-free synchronization via atomic operations
 pub(crate) type StateNotifierMap = Arc<Vec<Arc<AtomicBool>>>;
 
+/// Append-only record of the order in which states finished computing.
+///
+/// The compute thread(s) are the only writers. A state is appended right after signaling
+/// that state's notifier, publishing the entry with a `Release` store of `len`.
+/// Readers `Acquire`-load `len`, after which every entry before it, and the notifiers
+/// of those states, are visible. This lets pollers find new states in O(newly finished).
+#[derive(Debug)]
+pub(crate) struct StateCompletionLog {
+    order: Vec<AtomicU32>,
+    len: AtomicUsize,
+}
+
+impl StateCompletionLog {
+    pub fn with_capacity(capacity: usize) -> Self {
+        StateCompletionLog {
+            order: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends `state`. Pushes must be serialized, either by coming from the single
+    /// compute thread or, with several workers, by holding the state callback lock.
+    pub fn push(&self, state: u32) {
+        let index = self.len.load(Ordering::Relaxed);
+        if let Some(slot) = self.order.get(index) {
+            slot.store(state, Ordering::Relaxed);
+            self.len.store(index + 1, Ordering::Release);
+        }
+    }
+
+    /// States which finished at or after position `cursor`, in completion order.
+    pub fn since(&self, cursor: usize) -> impl Iterator<Item = u32> + '_ {
+        let end = self.len.load(Ordering::Acquire);
+        self.order[cursor.min(end)..end]
+            .iter()
+            .map(|state| state.load(Ordering::Relaxed))
+    }
+}
+
+/// Hook called with a state id right after that state's notifier is signaled.
+pub type StateCallback = Arc<dyn Fn(u32) + Send + Sync>;
+
+/// Shared slot the compute thread reads the state callback from.
+///
+/// The compute thread holds the lock while it signals a state and calls the
+/// callback, so a callback registered mid-computation sees every state exactly once.
+pub(crate) type StateCallbackSlot = Arc<Mutex<Option<StateCallback>>>;
+
 // Zero-copy cross-thread memory access for FSM computation.
 // 
 // ThreadSafeCell enables the main thread (FSMIndex::new) and computation thread 
@@ -149,7 +289,15 @@ pub(crate) struct ThreadSafeCell<T> {
 
 // Tell compiler this type can be shared across threads.
 // This is safe due to our manual synchronization.
-unsafe impl<T> Sync for ThreadSafeCell<T> {}
+//
+// Send/Sync audit:
+// - Send is left to the compiler: `UnsafeCell<T>` is Send whenever `T` is,
+//   and moving the cell moves the value with it.
+// - Sync needs `T: Send`, since the compute thread mutates a value other threads own,
+//   and `T: Sync`, since readers on several threads hold `&T` at the same time.
+//   The rest of the argument is the single-writer / notifier discipline above,
+//   which `read_after` encodes for readers.
+unsafe impl<T: Send + Sync> Sync for ThreadSafeCell<T> {}
 
 impl<T> ThreadSafeCell<T> {
     pub fn new(value: T) -> Self {
@@ -164,6 +312,7 @@ impl<T> ThreadSafeCell<T> {
     /// In our case, this is guaranteed by the atomic flags.
     /// In any other scenario other than ours,
     /// this would be a horrible idea.
+    #[allow(clippy::mut_from_ref)]
     pub unsafe fn get(&self) -> &mut T {
         &mut *self.value.get()
     }
@@ -175,8 +324,74 @@ impl<T> ThreadSafeCell<T> {
     pub unsafe fn get_ref(&self) -> &T {
         &*self.value.get()
     }
+
+    /// Safe read access, gated on the notifier guarding this cell.
+    ///
+    /// Returns `None` until the writer has stored `true` into `notifier`.
+    /// The writer never touches the value after that `Release` store,
+    /// so once our `Acquire` load observes it, shared reads are sound.
+    pub fn read_after(&self, notifier: &AtomicBool) -> Option<&T> {
+        if notifier.load(Ordering::Acquire) {
+            // SAFETY: the notifier is set, so the single writer is done with the value.
+            Some(unsafe { self.get_ref() })
+        } else {
+            None
+        }
+    }
+}
+
+/// Options controlling how the per-state token maps are built.
+///
+/// `Default` reads each option from its environment variable (see `environment.rs`),
+/// so indexes built through `LazyFSMIndex::new` honor the environment, while
+/// `LazyFSMIndex::new_with_options` lets Rust callers set them per index.
+/// The options are part of the cache key, so differently configured
+/// indexes of the same pattern never share cache entries.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ComputeOptions {
+    /// Lossy cap on tokens kept for states with a wildcard transition.
+    /// `None` keeps every allowed token. See `FASTER_OUTLINES_MAX_TOKENS_PER_STATE`.
+    pub max_tokens_per_state: Option<usize>,
+    /// Token ids which are never offered, e.g. reserved `<unused42>` slots.
+    /// Unlike `special_tokens`, which drops token strings when the vocabulary is built,
+    /// this filters ids out of every state's scan results.
+    pub reserved_token_ids: Option<BTreeSet<u32>>,
+    /// Build the index from `FSMInfo::canonicalize`, and cache it by structure rather
+    /// than pattern, so equivalent patterns share a cache entry.
+    /// See `FASTER_OUTLINES_CANONICALIZE_FSM`.
+    pub canonicalize_fsm: bool,
+    /// Tokens spelling more symbols than this are skipped by the vocabulary scan.
+    /// Only safe for bounded patterns which cannot match that many characters from
+    /// any state, e.g. `[0-9]{1,4}`. With an unbounded pattern like `.*` it drops
+    /// tokens which would have matched.
+    pub max_token_len: Option<usize>,
+    /// The FSM's transition keys are the 256 byte values, as for an FSM built over UTF-8
+    /// bytes for a byte-level BPE tokenizer. Tokens are then walked as the raw bytes they
+    /// stand for, see `TokenVocabulary`'s byte symbols, and `alphabet_symbol_mapping`
+    /// is not consulted.
+    pub byte_alphabet: bool,
 }
 
+impl Default for ComputeOptions {
+    fn default() -> Self {
+        ComputeOptions {
+            max_tokens_per_state: *MAX_TOKENS_PER_STATE,
+            reserved_token_ids: None,
+            canonicalize_fsm: *CANONICALIZE_FSM,
+            max_token_len: None,
+            byte_alphabet: false,
+        }
+    }
+}
+
+/// A state of `FSMInfo::concat`: the prefix FSM's state, `None` once it dead-ended,
+/// and the sorted suffix FSM states active alongside it.
+pub type ConcatState = (Option<u32>, Vec<u32>);
+
+/// Precomputed `(token_id, end_state)` pairs for some states of an index being built,
+/// keyed by state. The compute thread publishes these instead of scanning the vocabulary.
+pub(crate) type SeededStates = FxHashMap<usize, Vec<(u32, u32)>>;
+
 /// FSMInfo implements a Finite State Machine optimized for token sequence matching.
 /// Built on interegular's FSM design (https://github.com/MegaIng/interegular),
 /// but modified to work with tokenizer outputs instead of raw characters.
@@ -225,13 +440,720 @@ pub struct FSMInfo {
     
     /// Source pattern, retained for cache key generation
     pub pattern: String,
+
+    /// Final states which require EOS, like the end of a `$` anchored pattern.
+    /// Each must also be in `finals`. No tokens are offered from these states,
+    /// and tokens may not walk through them, even if outgoing transitions exist.
+    #[serde(default)]
+    pub eof_required_finals: Vec<u32>,
 }
 
-/// Instructions for controlling LLM token generation.
-/// Design inspired by outlines-dev (https://github.com/outlines-dev/outlines)
-/// 
-/// These instructions map FSM states to allowed token sequences, enabling
-/// guided text generation that follows regex patterns.
+impl FSMInfo {
+    /// Whether `state` is a final state which must be followed by EOS.
+    #[inline(always)]
+    pub fn is_eof_required(&self, state: u32) -> bool {
+        self.eof_required_finals.contains(&state)
+    }
+
+    /// Whether no transition leaves `state`, like the accepting sink many patterns end in.
+    pub fn is_sink(&self, state: u32) -> bool {
+        self.transitions
+            .iter_state(state as usize)
+            .is_none_or(|mut targets| targets.next().is_none())
+    }
+
+    /// Whether generation must end once it reaches `state`: a final state
+    /// without exits, or one which must be followed by EOS.
+    pub fn is_terminal(&self, state: u32) -> bool {
+        self.finals.contains(&state) && (self.is_sink(state) || self.is_eof_required(state))
+    }
+
+    /// Checks that every transition target has a slot in an index.
+    ///
+    /// Indexes keep one slot per state with transitions, plus one for a final state without
+    /// exits numbered right after them. A target past that, typically from a converter
+    /// numbering states off by one, would have no map, so generations reaching it would
+    /// silently end at EOS. `LazyFSMIndex` refuses to compute such an FSM.
+    pub fn check_transition_targets(&self) -> anyhow::Result<()> {
+        let num_slots = self.transitions.len() + 1;
+        for state in self.transitions.states() {
+            let Some(targets) = self.transitions.iter_state(state) else {
+                continue;
+            };
+            if let Some(target) = targets.into_iter().find(|&target| target as usize >= num_slots) {
+                bail!(
+                    "Transition from state {} targets state {}, but the FSM only has states 0 to {}, \
+                     check the state numbering",
+                    state,
+                    target,
+                    num_slots - 1
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// States reachable from the initial state by following transitions, see
+    /// `LazyFSMIndex::reachable_states`.
+    pub fn reachable_states(&self) -> FixedBitSet {
+        let transitions = &self.transitions;
+        let mut reachable = FixedBitSet::with_capacity(transitions.len() + 1);
+        let mut frontier = vec![self.initial as usize];
+        reachable.grow(self.initial as usize + 1);
+        reachable.insert(self.initial as usize);
+
+        while let Some(state) = frontier.pop() {
+            let Some(targets) = transitions.iter_state(state) else {
+                continue;
+            };
+            for target in targets {
+                let target = target as usize;
+                reachable.grow(target + 1);
+                if !reachable.put(target) {
+                    frontier.push(target);
+                }
+            }
+        }
+
+        reachable
+    }
+
+    /// Whether `state` has a transition on `alphabet_anything_value`, i.e. accepts
+    /// characters outside the pattern's alphabet, like the states of `.*`.
+    pub fn has_wildcard_transition(&self, state: usize) -> bool {
+        self.transitions
+            .get_transition(state, self.alphabet_anything_value as usize)
+            .is_some()
+    }
+
+    /// Number of transitions from each state to the nearest final state,
+    /// or `u32::MAX` if no final state can be reached from it. Indexed by state id.
+    pub fn distances_to_final(&self) -> Vec<u32> {
+        let num_states = self
+            .transitions
+            .states()
+            .flat_map(|state| self.transitions.iter_state(state).into_iter().flatten())
+            .map(|target| target as usize + 1)
+            .chain(self.finals.iter().map(|&state| state as usize + 1))
+            .fold(self.transitions.len(), usize::max);
+
+        let mut predecessors: Vec<Vec<u32>> = vec![Vec::new(); num_states];
+        for state in self.transitions.states() {
+            for target in self.transitions.iter_state(state).into_iter().flatten() {
+                predecessors[target as usize].push(state as u32);
+            }
+        }
+
+        let mut distances = vec![u32::MAX; num_states];
+        let mut frontier = std::collections::VecDeque::new();
+        for &state in &self.finals {
+            distances[state as usize] = 0;
+            frontier.push_back(state);
+        }
+        while let Some(state) = frontier.pop_front() {
+            for &predecessor in &predecessors[state as usize] {
+                if distances[predecessor as usize] == u32::MAX {
+                    distances[predecessor as usize] = distances[state as usize] + 1;
+                    frontier.push_back(predecessor);
+                }
+            }
+        }
+
+        distances
+    }
+
+    /// FSM accepting exactly the strings this one rejects, for "anything except the
+    /// pattern" generation.
+    ///
+    /// The FSM is not a complete DFA, a missing transition means the input is rejected.
+    /// So the complement first routes every missing transition, including the
+    /// `alphabet_anything_value` one, to an added trap state which loops on every key,
+    /// then swaps final and non-final states. `eof_required_finals` become non-final,
+    /// so the complement has none.
+    ///
+    /// # Errors
+    /// The complete transition table exceeds `FASTER_OUTLINES_MAX_TRANSITION_CELLS`. Every
+    /// state gets a transition on every key, so it can be far larger than this FSM's, e.g.
+    /// with an `alphabet_anything_value` much larger than the keys of the alphabet.
+    pub fn complement(&self) -> anyhow::Result<FSMInfo> {
+        let num_states = self
+            .transitions
+            .states()
+            .flat_map(|state| self.transitions.iter_state(state).into_iter().flatten())
+            .chain(self.finals.iter().copied())
+            .chain(std::iter::once(self.initial))
+            .map(|state| state as usize + 1)
+            .fold(self.transitions.len(), usize::max);
+        let trap = num_states as u32;
+
+        let mut keys: Vec<u32> = self.alphabet_symbol_mapping.values().copied().collect();
+        keys.push(self.alphabet_anything_value);
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut transitions: FxHashMap<(u32, u32), u32> = FxHashMap::default();
+        for state in 0..=trap {
+            for &key in &keys {
+                let target = self
+                    .transitions
+                    .get_transition(state as usize, key as usize)
+                    .unwrap_or(trap);
+                transitions.insert((state, key), target);
+            }
+        }
+
+        let finals = (0..=trap).filter(|state| !self.finals.contains(state)).collect();
+
+        Ok(FSMInfo {
+            initial: self.initial,
+            finals,
+            transitions: transitions.try_into()?,
+            alphabet_symbol_mapping: self.alphabet_symbol_mapping.clone(),
+            alphabet_anything_value: self.alphabet_anything_value,
+            pattern: format!("complement({})", self.pattern),
+            eof_required_finals: Vec::new(),
+        })
+    }
+
+    /// Equivalent FSM with the fewest states, by Hopcroft's algorithm.
+    ///
+    /// Only states reachable from `initial` are kept, and states from which no final can
+    /// be reached are dropped, as a missing transition already rejects. The remaining
+    /// states which accept the same suffixes are merged, and numbered in the order of their
+    /// smallest original state. Keys, alphabet and pattern are unchanged. Fewer states
+    /// means fewer token maps for an index to compute.
+    pub fn minimize(&self) -> FSMInfo {
+        let states: Vec<usize> = self.reachable_states().ones().collect();
+        let index_of: FxHashMap<usize, usize> =
+            states.iter().enumerate().map(|(index, &state)| (state, index)).collect();
+        let mut keys: Vec<u32> = self.alphabet_symbol_mapping.values().copied().collect();
+        keys.push(self.alphabet_anything_value);
+        keys.sort_unstable();
+        keys.dedup();
+
+        // Missing transitions go to a trap state, making the DFA complete as Hopcroft needs.
+        // `delta[state * keys + key]` is the target, `inverse[key * n + target]` the sources.
+        let trap = states.len();
+        let n = trap + 1;
+        let num_keys = keys.len();
+        let mut delta = vec![trap; n * num_keys];
+        for (index, &state) in states.iter().enumerate() {
+            for (key_index, &key) in keys.iter().enumerate() {
+                if let Some(target) = self.transitions.get_transition(state, key as usize) {
+                    delta[index * num_keys + key_index] = index_of[&(target as usize)];
+                }
+            }
+        }
+        let mut inverse: Vec<Vec<usize>> = vec![Vec::new(); n * num_keys];
+        for source in 0..n {
+            for key_index in 0..num_keys {
+                inverse[key_index * n + delta[source * num_keys + key_index]].push(source);
+            }
+        }
+
+        // Start from rejecting (with the trap), accepting, and EOF required states.
+        let kind = |index: usize| -> usize {
+            match states.get(index).map(|&state| state as u32) {
+                Some(state) if self.is_eof_required(state) => 2,
+                Some(state) if self.finals.contains(&state) => 1,
+                _ => 0,
+            }
+        };
+        let mut blocks: Vec<Vec<usize>> = vec![Vec::new(); 3];
+        for index in 0..n {
+            blocks[kind(index)].push(index);
+        }
+        blocks.retain(|block| !block.is_empty());
+        let mut block_of = vec![0; n];
+        let mut position = vec![0; n];
+        for (block, members) in blocks.iter().enumerate() {
+            for (at, &member) in members.iter().enumerate() {
+                block_of[member] = block;
+                position[member] = at;
+            }
+        }
+
+        let mut worklist: Vec<(usize, usize)> =
+            (0..blocks.len()).flat_map(|block| (0..num_keys).map(move |key| (block, key))).collect();
+        let mut in_worklist: FxHashSet<(usize, usize)> = worklist.iter().copied().collect();
+        while let Some((splitter, key_index)) = worklist.pop() {
+            in_worklist.remove(&(splitter, key_index));
+
+            // States entering the splitter on this key, grouped by their current block.
+            let mut touched: FxHashMap<usize, Vec<usize>> = FxHashMap::default();
+            for &target in &blocks[splitter] {
+                for &source in &inverse[key_index * n + target] {
+                    touched.entry(block_of[source]).or_default().push(source);
+                }
+            }
+
+            for (block, sources) in touched {
+                if sources.len() == blocks[block].len() {
+                    continue;
+                }
+                let new_block = blocks.len();
+                blocks.push(Vec::with_capacity(sources.len()));
+                for source in sources {
+                    let at = position[source];
+                    blocks[block].swap_remove(at);
+                    if let Some(&moved) = blocks[block].get(at) {
+                        position[moved] = at;
+                    }
+                    position[source] = blocks[new_block].len();
+                    blocks[new_block].push(source);
+                    block_of[source] = new_block;
+                }
+                for key in 0..num_keys {
+                    // Either half suffices as a splitter, unless the whole block was still pending.
+                    if in_worklist.contains(&(block, key)) || blocks[new_block].len() <= blocks[block].len() {
+                        worklist.push((new_block, key));
+                        in_worklist.insert((new_block, key));
+                    } else {
+                        worklist.push((block, key));
+                        in_worklist.insert((block, key));
+                    }
+                }
+            }
+        }
+
+        // The trap's block holds the dead states, which are dropped.
+        let dead = block_of[trap];
+        let initial_index = index_of[&(self.initial as usize)];
+        let mut new_ids: FxHashMap<usize, u32> = FxHashMap::default();
+        for (index, &block) in block_of[..trap].iter().enumerate() {
+            if block != dead || index == initial_index {
+                let next_id = new_ids.len() as u32;
+                new_ids.entry(block).or_insert(next_id);
+            }
+        }
+
+        let mut transitions: FxHashMap<(u32, u32), u32> = FxHashMap::default();
+        for (&block, &id) in &new_ids {
+            let representative = blocks[block][0];
+            for (key_index, &key) in keys.iter().enumerate() {
+                let target_block = block_of[delta[representative * num_keys + key_index]];
+                if target_block != dead {
+                    transitions.insert((id, key), new_ids[&target_block]);
+                }
+            }
+        }
+
+        let renumbered = |states: &[u32]| -> Vec<u32> {
+            let mut renumbered: Vec<u32> = states
+                .iter()
+                .filter_map(|&state| index_of.get(&(state as usize)))
+                .filter_map(|&index| new_ids.get(&block_of[index]))
+                .copied()
+                .collect();
+            renumbered.sort_unstable();
+            renumbered.dedup();
+            renumbered
+        };
+
+        FSMInfo {
+            initial: new_ids[&block_of[initial_index]],
+            finals: renumbered(&self.finals),
+            // At most as many states as this FSM over the same keys.
+            transitions: TransitionMap::from_map_with_limit(transitions, usize::MAX)
+                .expect("minimized transition table size overflowed usize"),
+            alphabet_symbol_mapping: self.alphabet_symbol_mapping.clone(),
+            alphabet_anything_value: self.alphabet_anything_value,
+            pattern: self.pattern.clone(),
+            eof_required_finals: renumbered(&self.eof_required_finals),
+        }
+    }
+
+    /// FSM matching a string of this pattern followed by a string of `suffix`.
+    pub fn concat(&self, suffix: &FSMInfo) -> anyhow::Result<FSMInfo> {
+        self.concat_with_states(suffix).map(|(fsm_info, _)| fsm_info)
+    }
+
+    /// Same as `concat`, also returning the `ConcatState` each state stands for, indexed by state id.
+    ///
+    /// Built by subset construction: at each final of this FSM the suffix may start while this
+    /// FSM may also go on, so several suffix states can be active at once. The alphabets are
+    /// merged by giving every distinct pair of (this key, suffix key) of a character its own key.
+    ///
+    /// # Errors
+    /// - This FSM has `eof_required_finals`, so nothing can follow it.
+    /// - The combined transition table exceeds `FASTER_OUTLINES_MAX_TRANSITION_CELLS`.
+    pub fn concat_with_states(&self, suffix: &FSMInfo) -> anyhow::Result<(FSMInfo, Vec<ConcatState>)> {
+        if !self.eof_required_finals.is_empty() {
+            bail!("Pattern {:?} has to end at EOS, nothing can follow it", self.pattern);
+        }
+
+        let prefix_symbols = self.char_symbol_mapping();
+        let suffix_symbols = suffix.char_symbol_mapping();
+        let mut chars: Vec<char> = prefix_symbols.keys().chain(suffix_symbols.keys()).copied().collect();
+        chars.sort_unstable();
+        chars.dedup();
+
+        // Key 0 is the merged anything value.
+        let mut symbol_pairs = vec![(self.alphabet_anything_value, suffix.alphabet_anything_value)];
+        let mut pair_keys: FxHashMap<(u32, u32), u32> = FxHashMap::default();
+        pair_keys.insert(symbol_pairs[0], 0);
+        let mut alphabet_symbol_mapping = FxHashMap::default();
+        for c in chars {
+            let pair = (
+                prefix_symbols.get(&c).copied().unwrap_or(self.alphabet_anything_value),
+                suffix_symbols.get(&c).copied().unwrap_or(suffix.alphabet_anything_value),
+            );
+            let key = *pair_keys.entry(pair).or_insert_with(|| {
+                symbol_pairs.push(pair);
+                symbol_pairs.len() as u32 - 1
+            });
+            alphabet_symbol_mapping.insert(c.to_string(), key);
+        }
+
+        // Reaching a final of this FSM starts the suffix.
+        let enter_suffix = |prefix_state: Option<u32>, mut suffix_states: Vec<u32>| -> ConcatState {
+            if prefix_state.is_some_and(|state| self.finals.contains(&state)) {
+                suffix_states.push(suffix.initial);
+            }
+            suffix_states.sort_unstable();
+            suffix_states.dedup();
+            (prefix_state, suffix_states)
+        };
+
+        let mut states = vec![enter_suffix(Some(self.initial), Vec::new())];
+        let mut ids: FxHashMap<ConcatState, u32> = FxHashMap::default();
+        ids.insert(states[0].clone(), 0);
+        let mut transitions: FxHashMap<(u32, u32), u32> = FxHashMap::default();
+        let mut next = 0;
+        while next < states.len() {
+            let (prefix_state, suffix_states) = states[next].clone();
+            for (key, &(prefix_key, suffix_key)) in symbol_pairs.iter().enumerate() {
+                let next_prefix = prefix_state
+                    .and_then(|state| self.transitions.get_transition(state as usize, prefix_key as usize));
+                let next_suffix = suffix_states
+                    .iter()
+                    .filter_map(|&state| suffix.transitions.get_transition(state as usize, suffix_key as usize))
+                    .collect();
+                let target = enter_suffix(next_prefix, next_suffix);
+                if target.0.is_none() && target.1.is_empty() {
+                    continue;
+                }
+
+                let target_id = match ids.get(&target) {
+                    Some(&id) => id,
+                    None => {
+                        let id = states.len() as u32;
+                        ids.insert(target.clone(), id);
+                        states.push(target);
+                        id
+                    }
+                };
+                transitions.insert((next as u32, key as u32), target_id);
+            }
+            next += 1;
+        }
+
+        let accepting = |(_, suffix_states): &ConcatState| {
+            suffix_states.iter().any(|state| suffix.finals.contains(state))
+        };
+        let finals = (0..states.len() as u32)
+            .filter(|&id| accepting(&states[id as usize]))
+            .collect();
+        // Only strict if nothing but EOF required suffix finals is left active.
+        let eof_required_finals = (0..states.len() as u32)
+            .filter(|&id| {
+                let (prefix_state, suffix_states) = &states[id as usize];
+                prefix_state.is_none()
+                    && !suffix_states.is_empty()
+                    && suffix_states.iter().all(|&state| suffix.is_eof_required(state))
+            })
+            .collect();
+
+        let fsm_info = FSMInfo {
+            initial: 0,
+            finals,
+            transitions: transitions.try_into()?,
+            alphabet_symbol_mapping,
+            alphabet_anything_value: 0,
+            pattern: format!("concat({}, {})", self.pattern, suffix.pattern),
+            eof_required_finals,
+        };
+        Ok((fsm_info, states))
+    }
+
+    /// The minimal FSM equivalent to this one, numbered canonically, so that patterns
+    /// accepting the same strings, like `"a|b"` and `"[ab]"`, give identical FSMs.
+    ///
+    /// Starts from `minimize`. Symbols which behave alike share a key, and symbols which
+    /// behave like `alphabet_anything_value` are dropped from the alphabet. Keys are then
+    /// numbered by their smallest symbol, with the anything value last, and states in BFS
+    /// order from the initial state, which becomes 0. The pattern is kept.
+    pub fn canonicalize(&self) -> anyhow::Result<FSMInfo> {
+        let minimal = self.minimize();
+        let states: Vec<usize> = minimal.reachable_states().ones().collect();
+
+        // Group symbols by the states their key leads to from every state.
+        let column = |key: u32| -> Vec<Option<u32>> {
+            states
+                .iter()
+                .map(|&state| minimal.transitions.get_transition(state, key as usize))
+                .collect()
+        };
+        let anything_column = column(minimal.alphabet_anything_value);
+        let mut groups: FxHashMap<Vec<Option<u32>>, (Vec<&String>, u32)> = FxHashMap::default();
+        for (symbol, &key) in &minimal.alphabet_symbol_mapping {
+            let symbol_column = column(key);
+            if symbol_column != anything_column {
+                groups.entry(symbol_column).or_insert_with(|| (Vec::new(), key)).0.push(symbol);
+            }
+        }
+        let mut groups: Vec<(Vec<&String>, u32)> = groups
+            .into_values()
+            .map(|(mut symbols, key)| {
+                symbols.sort_unstable();
+                (symbols, key)
+            })
+            .collect();
+        groups.sort_unstable();
+        let anything_value = groups.len() as u32;
+        let old_keys: Vec<u32> = groups
+            .iter()
+            .map(|&(_, key)| key)
+            .chain(std::iter::once(minimal.alphabet_anything_value))
+            .collect();
+
+        let mut new_ids: FxHashMap<u32, u32> = FxHashMap::default();
+        new_ids.insert(minimal.initial, 0);
+        let mut frontier = std::collections::VecDeque::from([minimal.initial]);
+        let mut transitions: FxHashMap<(u32, u32), u32> = FxHashMap::default();
+        while let Some(current) = frontier.pop_front() {
+            for (new_key, &old_key) in old_keys.iter().enumerate() {
+                let Some(target) = minimal.transitions.get_transition(current as usize, old_key as usize)
+                else {
+                    continue;
+                };
+                let next_id = new_ids.len() as u32;
+                let target_id = *new_ids.entry(target).or_insert_with(|| {
+                    frontier.push_back(target);
+                    next_id
+                });
+                transitions.insert((new_ids[&current], new_key as u32), target_id);
+            }
+        }
+
+        let renumbered = |states: &[u32]| -> Vec<u32> {
+            let mut renumbered: Vec<u32> = states.iter().filter_map(|state| new_ids.get(state)).copied().collect();
+            renumbered.sort_unstable();
+            renumbered
+        };
+        let alphabet_symbol_mapping = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(key, (symbols, _))| symbols.iter().map(move |&symbol| (symbol.clone(), key as u32)))
+            .collect();
+
+        Ok(FSMInfo {
+            initial: 0,
+            finals: renumbered(&minimal.finals),
+            transitions: transitions.try_into()?,
+            alphabet_symbol_mapping,
+            alphabet_anything_value: anything_value,
+            pattern: minimal.pattern.clone(),
+            eof_required_finals: renumbered(&minimal.eof_required_finals),
+        })
+    }
+
+    /// Reads an FSM serialized with serde JSON, the format of `PyFSMInfo.__getstate__`.
+    ///
+    /// Loading from a file skips building a Python dict entry by entry, which is the
+    /// slow part of constructing huge FSMs from Python. Needs the `json` feature.
+    #[cfg(feature = "json")]
+    pub fn from_json_file(path: impl AsRef<std::path::Path>) -> anyhow::Result<FSMInfo> {
+        let file = std::fs::File::open(path.as_ref())
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse FSMInfo from {}", path.as_ref().display()))
+    }
+
+    /// `alphabet_symbol_mapping` keyed by `char`, the form used for lookups.
+    pub fn char_symbol_mapping(&self) -> FxHashMap<char, u32> {
+        self.alphabet_symbol_mapping
+            .iter()
+            .map(|(k, &v)| (k.chars().next().unwrap(), v))
+            .collect()
+    }
+
+    /// Whether `mapping` is what `char_symbol_mapping` would build, without building it.
+    pub fn matches_char_symbol_mapping(&self, mapping: &FxHashMap<char, u32>) -> bool {
+        mapping.len() == self.alphabet_symbol_mapping.len()
+            && self
+                .alphabet_symbol_mapping
+                .iter()
+                .all(|(k, &v)| k.chars().next().and_then(|c| mapping.get(&c)) == Some(&v))
+    }
+}
+
+/// Builds an `FSMInfo` by hand, for embedders which do not get their FSM from interegular.
+///
+/// Transitions are collected as `(state, symbol key) -> target` and converted into the
+/// sparse `TransitionMap` on `build`, which also validates the FSM.
+///
+/// # Example
+/// The pattern `"[0-9]+"`:
+/// ```
+/// use faster_outlines_rs::types::FSMInfoBuilder;
+///
+/// let mut builder = FSMInfoBuilder::new()
+///     .initial(0)
+///     .add_transition(0, 0, 1)
+///     .add_transition(1, 0, 1)
+///     .add_final(1)
+///     .anything_value(1)
+///     .pattern("[0-9]+".to_string());
+/// for digit in '0'..='9' {
+///     builder = builder.alphabet_symbol(digit, 0);
+/// }
+/// let fsm_info = builder.build().unwrap();
+///
+/// assert_eq!(fsm_info.finals, vec![1]);
+/// assert_eq!(fsm_info.transitions.get_transition(0, 0), Some(1));
+/// assert_eq!(fsm_info.transitions.get_transition(1, 1), None);
+/// ```
+///
+/// Conflicting transitions, and symbol keys outside the alphabet, are rejected:
+/// ```
+/// use faster_outlines_rs::types::FSMInfoBuilder;
+///
+/// let conflicting = FSMInfoBuilder::new()
+///     .alphabet_symbol('a', 0)
+///     .add_transition(0, 0, 1)
+///     .add_transition(0, 0, 2)
+///     .pattern("a".to_string())
+///     .build();
+/// assert!(conflicting.is_err());
+///
+/// let unknown_symbol = FSMInfoBuilder::new()
+///     .alphabet_symbol('a', 0)
+///     .add_transition(0, 5, 1)
+///     .pattern("a".to_string())
+///     .build();
+/// assert!(unknown_symbol.is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FSMInfoBuilder {
+    initial: u32,
+    finals: Vec<u32>,
+    eof_required_finals: Vec<u32>,
+    transitions: FxHashMap<(u32, u32), u32>,
+    alphabet_symbol_mapping: FxHashMap<String, u32>,
+    alphabet_anything_value: Option<u32>,
+    pattern: Option<String>,
+    /// First error found while adding to the builder, reported by `build`.
+    error: Option<String>,
+}
+
+impl FSMInfoBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the start state. Defaults to 0.
+    pub fn initial(mut self, state: u32) -> Self {
+        self.initial = state;
+        self
+    }
+
+    /// Adds the transition `state --symbol--> target`, where `symbol` is a transition key.
+    pub fn add_transition(mut self, state: u32, symbol: u32, target: u32) -> Self {
+        if let Some(&existing) = self.transitions.get(&(state, symbol)) {
+            if existing != target && self.error.is_none() {
+                self.error = Some(format!(
+                    "State {} already transitions to {} on symbol {}, cannot also transition to {}",
+                    state, existing, symbol, target
+                ));
+            }
+            return self;
+        }
+        self.transitions.insert((state, symbol), target);
+        self
+    }
+
+    /// Marks `state` as accepting.
+    pub fn add_final(mut self, state: u32) -> Self {
+        if !self.finals.contains(&state) {
+            self.finals.push(state);
+        }
+        self
+    }
+
+    /// Marks `state` as accepting, and requires EOS once it is reached.
+    pub fn add_eof_required_final(mut self, state: u32) -> Self {
+        if !self.eof_required_finals.contains(&state) {
+            self.eof_required_finals.push(state);
+        }
+        self.add_final(state)
+    }
+
+    /// Maps the character `c` to the transition key `key`. Several characters may share a key.
+    pub fn alphabet_symbol(mut self, c: char, key: u32) -> Self {
+        self.alphabet_symbol_mapping.insert(c.to_string(), key);
+        self
+    }
+
+    /// Sets the transition key used for characters outside the alphabet.
+    /// Defaults to one past the largest key given to `alphabet_symbol`.
+    pub fn anything_value(mut self, key: u32) -> Self {
+        self.alphabet_anything_value = Some(key);
+        self
+    }
+
+    /// Sets the source pattern. Required, since it is part of the FSM cache key.
+    pub fn pattern(mut self, pattern: String) -> Self {
+        self.pattern = Some(pattern);
+        self
+    }
+
+    /// Validates the FSM and computes its `TransitionMap`.
+    ///
+    /// # Errors
+    /// - Two transitions were added for the same state and symbol with different targets
+    /// - A transition uses a symbol key which is neither in the alphabet nor the anything value
+    /// - No pattern was set
+    /// - The transition table would exceed `FASTER_OUTLINES_MAX_TRANSITION_CELLS`
+    pub fn build(self) -> anyhow::Result<FSMInfo> {
+        if let Some(error) = self.error {
+            bail!(error);
+        }
+
+        let pattern = match self.pattern {
+            Some(pattern) => pattern,
+            None => bail!("FSMInfo requires a pattern, it is part of the cache key"),
+        };
+
+        let alphabet_anything_value = self.alphabet_anything_value.unwrap_or_else(|| {
+            self.alphabet_symbol_mapping
+                .values()
+                .max()
+                .map_or(0, |&key| key + 1)
+        });
+
+        if let Some(((state, symbol), _)) = self.transitions.iter().find(|((_, symbol), _)| {
+            *symbol != alphabet_anything_value
+                && !self.alphabet_symbol_mapping.values().any(|key| key == symbol)
+        }) {
+            bail!(
+                "Transition from state {} uses symbol {}, which is not in the alphabet",
+                state,
+                symbol
+            );
+        }
+
+        Ok(FSMInfo {
+            initial: self.initial,
+            finals: self.finals,
+            transitions: self.transitions.try_into()?,
+            alphabet_symbol_mapping: self.alphabet_symbol_mapping,
+            alphabet_anything_value,
+            pattern,
+            eof_required_finals: self.eof_required_finals,
+        })
+    }
+}
 
 /// Write instruction for fixed token sequences.
 /// Used when an FSM state has a single deterministic path.
@@ -252,6 +1174,22 @@ impl Write {
     }
 }
 
+/// How large the index of an FSM could get, see `estimate_complexity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComplexityReport {
+    /// Number of states in the transition table.
+    pub num_states: usize,
+    /// States reachable from the initial state, the only ones an index computes.
+    pub num_reachable: usize,
+    /// Whether any reachable state has a wildcard transition, whose map holds
+    /// nearly the whole vocabulary.
+    pub has_wildcard: bool,
+    /// Rough size of the index in `(token, next state)` entries: each reachable
+    /// wildcard state counts the whole vocabulary, every other reachable state one
+    /// entry per character it accepts.
+    pub estimated_max_transitions: usize,
+}
+
 /// Generate instruction for branching paths.
 /// Used when an FSM state has multiple possible transitions.
 /// 
@@ -261,19 +1199,318 @@ impl Write {
 ///        \-[d]--> State 3 --[o]--> State 4 --[g]--> Final
 /// ```
 /// At State 0, we emit Generate([token_id_for_c, token_id_for_d])
+///
+/// `priorities`, when present, runs parallel to `tokens` so samplers can bias between
+/// allowed tokens. See `LazyFSMIndex::get_next_instruction_with_priorities`.
+///
+/// `terminal_choices` is set when every allowed token completes the pattern, so the
+/// caller knows whichever token it picks, generation ends right after it.
 #[derive(Clone)]
 pub struct Generate {
     pub tokens: Option<Vec<i32>>,
+    pub priorities: Option<Vec<f32>>,
+    pub terminal_choices: bool,
 }
 
 impl Generate {
     pub fn new(tokens: Option<Vec<i32>>) -> Self {
-        Generate { tokens }
+        Generate { tokens, priorities: None, terminal_choices: false }
+    }
+
+    pub fn with_priorities(tokens: Vec<i32>, priorities: Vec<f32>) -> Self {
+        Generate {
+            tokens: Some(tokens),
+            priorities: Some(priorities),
+            terminal_choices: false,
+        }
     }
 }
 
+/// Instructions for controlling LLM token generation.
+/// Design inspired by outlines-dev (<https://github.com/outlines-dev/outlines>)
+///
+/// These instructions map FSM states to allowed token sequences, enabling
+/// guided text generation that follows regex patterns.
 #[derive(Clone)]
 pub enum Instruction {
     Write(Write),
     Generate(Generate),
+    /// The pattern is complete and nothing may follow, so generation should end here
+    /// without emitting anything. Only given by indexes opting in, see
+    /// `LazyFSMIndex::with_stop_instruction`, others write EOS instead.
+    Stop,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_huge_state_id_is_an_error() {
+        let map: FxHashMap<(u32, u32), u32> = [((0, 0), 1), ((u32::MAX - 1, 3), 0)].into_iter().collect();
+        let err = TransitionMap::try_from(map).unwrap_err();
+        assert!(err.to_string().contains("exceeds"));
+
+        let map: FxHashMap<(u32, u32), u32> = [((0, 0), 1), ((9, 9), 0)].into_iter().collect();
+        assert!(TransitionMap::from_map_with_limit(map.clone(), 99).is_err());
+        let transitions = TransitionMap::from_map_with_limit(map, 100).unwrap();
+        assert_eq!(transitions.get_transition(9, 9), Some(0));
+    }
+
+    #[test]
+    fn test_dangling_target_state_is_an_error() {
+        let fsm = |last_target| {
+            FSMInfoBuilder::new()
+                .alphabet_symbol('a', 0)
+                .add_transition(0, 0, 1)
+                .add_transition(1, 0, last_target)
+                .add_final(last_target)
+                .pattern("dangling_target".to_string())
+                .build()
+                .unwrap()
+        };
+        // State 2, a final without exits, has a slot. State 3 is one past it.
+        assert!(fsm(2).check_transition_targets().is_ok());
+        assert_eq!(
+            fsm(3).check_transition_targets().unwrap_err().to_string(),
+            "Transition from state 1 targets state 3, but the FSM only has states 0 to 2, \
+             check the state numbering"
+        );
+    }
+
+    #[test]
+    fn test_dense_and_sparse_state_maps_agree() {
+        let width = 500;
+        let entries = vec![(0, 3), (7, 1), (499, 2)];
+        let sparse = StateMap::from_entries(entries.clone(), width);
+        assert!(matches!(sparse, StateMap::Sparse(_)));
+
+        let mut dense_row = vec![u32::MAX; width];
+        for &(transition, target) in &entries {
+            dense_row[transition as usize] = target;
+        }
+        let dense = StateMap::Dense(dense_row);
+
+        for transition in 0..width + 10 {
+            assert_eq!(dense.get(transition), sparse.get(transition), "transition {}", transition);
+        }
+        let mut dense_targets: Vec<u32> = dense.targets().collect();
+        let mut sparse_targets: Vec<u32> = sparse.targets().collect();
+        dense_targets.sort_unstable();
+        sparse_targets.sort_unstable();
+        assert_eq!(dense_targets, sparse_targets);
+
+        assert!(
+            sparse.heap_size() * 10 < dense.heap_size(),
+            "sparse {} bytes vs dense {} bytes",
+            sparse.heap_size(),
+            dense.heap_size()
+        );
+
+        let full: Vec<(u32, u32)> = (0..width as u32).map(|transition| (transition, 1)).collect();
+        assert!(matches!(StateMap::from_entries(full, width), StateMap::Dense(_)));
+    }
+
+    #[test]
+    fn test_wildcard_transitions_resolve_at_any_anything_value() {
+        // "a.", with the anything value just past the alphabet (dense rows) and far past it (sparse rows).
+        let a_dot = |anything: u32| {
+            FSMInfoBuilder::new()
+                .alphabet_symbol('a', 0)
+                .anything_value(anything)
+                .add_transition(0, 0, 1)
+                .add_transition(1, 0, 2)
+                .add_transition(1, anything, 2)
+                .add_final(2)
+                .pattern("a.".to_string())
+                .build()
+        };
+        for (anything, dense) in [(1, true), (1000, false)] {
+            let fsm_info = a_dot(anything).unwrap();
+            let row = fsm_info.transitions.get_state(1).unwrap();
+            assert_eq!(matches!(row, StateMap::Dense(_)), dense, "anything value {}", anything);
+            assert_eq!(fsm_info.transitions.get_transition(1, anything as usize), Some(2));
+            assert_eq!(fsm_info.transitions.get_transition(0, anything as usize), None);
+            assert!(fsm_info.has_wildcard_transition(1));
+        }
+
+        // A sentinel anything value would need a row of 2^32 cells, so it is refused instead.
+        let sentinel = a_dot(u32::MAX).unwrap_err().to_string();
+        assert!(sentinel.contains("FASTER_OUTLINES_MAX_TRANSITION_CELLS"), "{}", sentinel);
+    }
+
+    #[test]
+    fn test_estimate_complexity_flags_wildcard() {
+        let tokens: FxHashMap<String, Vec<u32>> = ["a", "b", "ab", "x", "xyz"]
+            .into_iter()
+            .zip(1..)
+            .map(|(token, id)| (token.to_string(), vec![id]))
+            .collect();
+        let vocab = crate::vocab::TokenVocabulary::from_hashmap(tokens, 0);
+
+        let dot_star = FSMInfoBuilder::new()
+            .anything_value(0)
+            .add_transition(0, 0, 0)
+            .add_final(0)
+            .pattern(".*".to_string())
+            .build()
+            .unwrap();
+        let report = crate::estimate_complexity(&dot_star, &vocab);
+        assert!(report.has_wildcard);
+        assert_eq!(report.num_reachable, 1);
+        assert_eq!(report.estimated_max_transitions, vocab.len());
+
+        // "ab", plus an unreachable wildcard state which an index never computes.
+        let ab = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 1)
+            .anything_value(2)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 2)
+            .add_transition(3, 2, 3)
+            .add_final(2)
+            .pattern("ab".to_string())
+            .build()
+            .unwrap();
+        let report = crate::estimate_complexity(&ab, &vocab);
+        assert_eq!(
+            report,
+            ComplexityReport {
+                num_states: 4,
+                num_reachable: 3,
+                has_wildcard: false,
+                estimated_max_transitions: 2,
+            }
+        );
+    }
+
+    fn accepts(fsm: &FSMInfo, input: &str) -> bool {
+        let symbols = fsm.char_symbol_mapping();
+        let mut state = fsm.initial;
+        for c in input.chars() {
+            let key = symbols.get(&c).copied().unwrap_or(fsm.alphabet_anything_value);
+            match fsm.transitions.get_transition(state as usize, key as usize) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        fsm.finals.contains(&state)
+    }
+
+    #[test]
+    fn test_minimize_merges_redundant_states() {
+        // "a(b|c)d*" with a copy of the tail per branch, a dead end after "x",
+        // and an unreachable state.
+        let redundant = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 1)
+            .alphabet_symbol('c', 2)
+            .alphabet_symbol('d', 3)
+            .alphabet_symbol('x', 4)
+            .anything_value(5)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 2)
+            .add_transition(1, 2, 3)
+            .add_transition(2, 3, 4)
+            .add_transition(3, 3, 5)
+            .add_transition(4, 3, 4)
+            .add_transition(5, 3, 5)
+            .add_transition(0, 4, 6)
+            .add_transition(6, 5, 6)
+            .add_transition(7, 0, 0)
+            .add_final(2)
+            .add_final(3)
+            .add_final(4)
+            .add_final(5)
+            .pattern("a(b|c)d*".to_string())
+            .build()
+            .unwrap();
+        let minimal = redundant.minimize();
+
+        let num_states = |fsm: &FSMInfo| fsm.reachable_states().count_ones(..);
+        assert_eq!(num_states(&redundant), 7);
+        assert_eq!(num_states(&minimal), 3);
+        assert_eq!(minimal.finals.len(), 1);
+        for input in ["ab", "ac", "abd", "acddd", "", "a", "x", "xz", "abc", "ad", "abdx", "b"] {
+            assert_eq!(accepts(&minimal, input), accepts(&redundant, input), "{:?}", input);
+        }
+
+        // Already minimal FSMs only get renumbered, complete ones keep every state.
+        assert_eq!(num_states(&minimal.minimize()), 3);
+        let complement = redundant.complement().unwrap();
+        assert_eq!(num_states(&complement.minimize()), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_from_json_file_round_trip() {
+        let fsm = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .alphabet_symbol('b', 1)
+            .anything_value(2)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 1, 1)
+            .add_transition(1, 2, 2)
+            .add_final(1)
+            .add_eof_required_final(2)
+            .add_final(2)
+            .pattern("ab*.$".to_string())
+            .build()
+            .unwrap();
+        let serialized = serde_json::to_string(&fsm).unwrap();
+        let path = std::env::temp_dir().join(format!("faster_outlines_fsm_{}.json", std::process::id()));
+        std::fs::write(&path, &serialized).unwrap();
+
+        let loaded = FSMInfo::from_json_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(serde_json::to_string(&loaded.unwrap()).unwrap(), serialized);
+
+        let err = FSMInfo::from_json_file(&path).unwrap_err();
+        assert!(err.to_string().contains("Failed to open"), "{}", err);
+    }
+
+    #[test]
+    fn test_complement_of_digits() {
+        // "[0-9]+"
+        let mut builder = FSMInfoBuilder::new();
+        for digit in '0'..='9' {
+            builder = builder.alphabet_symbol(digit, 0);
+        }
+        let digits = builder
+            .anything_value(1)
+            .add_transition(0, 0, 1)
+            .add_transition(1, 0, 1)
+            .add_final(1)
+            .pattern("[0-9]+".to_string())
+            .build()
+            .unwrap();
+        let complement = digits.complement().unwrap();
+
+        for input in ["0", "123", "9876543210"] {
+            assert!(accepts(&digits, input), "{:?}", input);
+            assert!(!accepts(&complement, input), "{:?}", input);
+        }
+        for input in ["", "a", "12a", "a12", "1 2", "٣"] {
+            assert!(!accepts(&digits, input), "{:?}", input);
+            assert!(accepts(&complement, input), "{:?}", input);
+        }
+        assert_ne!(complement.pattern, digits.pattern);
+    }
+
+    #[test]
+    fn test_complement_respects_the_cell_limit() {
+        // The anything value is unused here, but the complement needs a transition on it
+        // from every state, so its rows are 2^25 + 1 cells wide.
+        let fsm = FSMInfoBuilder::new()
+            .alphabet_symbol('a', 0)
+            .anything_value(1 << 25)
+            .add_transition(0, 0, 1)
+            .add_final(1)
+            .pattern("a".to_string())
+            .build()
+            .unwrap();
+
+        let err = fsm.complement().unwrap_err();
+        assert!(err.to_string().contains("FASTER_OUTLINES_MAX_TRANSITION_CELLS"), "{}", err);
+    }
 }
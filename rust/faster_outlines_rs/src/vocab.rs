@@ -20,109 +20,153 @@
 * THE SOFTWARE.
 */
 
-use anyhow::{Result, bail, anyhow};
 use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use regex::Regex;
 use once_cell::sync::Lazy;
 
-use crate::sp_decode::{UNICODE_TO_BYTES, convert_tokens_to_string};
+use crate::sp_decode::{UNICODE_TO_BYTES, SPIECE_UNDERLINE, convert_tokens_to_string};
+
+/// Map keyed by token strings, used while building and merging vocabularies.
+///
+/// FxHash by default. The `ahash` feature switches to aHash, which is keyed per process,
+/// so adversarial token strings cannot force collisions, at some cost on short keys,
+/// see `bench_token_map_hashers`.
+#[cfg(not(feature = "ahash"))]
+pub type TokenMap<V> = FxHashMap<String, V>;
+#[cfg(feature = "ahash")]
+pub type TokenMap<V> = ahash::AHashMap<String, V>;
+
+/// Errors from building a `TokenVocabulary`, so callers can match on the failure kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VocabError {
+    /// The raw vocabulary had no tokens.
+    Empty,
+}
+
+impl std::fmt::Display for VocabError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VocabError::Empty => write!(f, "Empty vocabulary provided"),
+        }
+    }
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl std::error::Error for VocabError {}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TokenVocabulary {
     pub tokens: Vec<String>,
     pub values: Vec<Vec<u32>>,
     pub eos_token_id: u32,
+    /// Whether llama style `<0xXX>` tokens stand for the byte they name, as in
+    /// sentencepiece vocabularies. In other vocabularies they are plain text.
+    #[serde(default)]
+    pub byte_tokens: bool,
 }
 
 impl TokenVocabulary {
-    pub fn default() -> Self {
-        Self {
-            tokens: Vec::new(),
-            values: Vec::new(),
-            eos_token_id: 0,
-        }
-    }
-
     pub fn from_hashmap(vocab_map: FxHashMap<String, Vec<u32>>, eos_token_id: u32) -> Self {
         let (tokens, values): (Vec<_>, Vec<_>) = vocab_map.into_iter().unzip();
         TokenVocabulary {
             tokens,
             values,
             eos_token_id,
+            byte_tokens: false,
         }
     }
 
+    /// Builds a vocabulary from a raw `{token: id}` map, as returned by HF's `get_vocab`.
+    ///
+    /// `from_sentencepiece` picks how token strings are decoded, see `convert_tokens_to_string`.
+    /// When `None`, vocabularies containing the sentencepiece `▁` marker are treated as
+    /// sentencepiece, and all others as byte-level BPE. Only sentencepiece vocabularies
+    /// get `byte_tokens`.
     pub fn from_raw_vocab(
         raw_vocab: FxHashMap<String, u32>,
         eos_token_id: u32,
         special_tokens: Option<FxHashSet<String>>,
         from_sentencepiece: Option<bool>
-    ) -> Result<Self> {
-        if raw_vocab.is_empty() {
-            bail!("Empty vocabulary provided");
+    ) -> Result<Self, VocabError> {
+        Self::from_raw_pairs(raw_vocab.into_iter().collect(), eos_token_id, special_tokens, from_sentencepiece)
+    }
+
+    /// Same as `from_raw_vocab`, but from `(token, id)` pairs, e.g. a tokenizer's vocabulary
+    /// already listed in id order, so callers need not build a map first.
+    ///
+    /// Tokens keep the order of the first pair decoding to each processed string.
+    pub fn from_raw_pairs(
+        raw_pairs: Vec<(String, u32)>,
+        eos_token_id: u32,
+        special_tokens: Option<FxHashSet<String>>,
+        from_sentencepiece: Option<bool>
+    ) -> Result<Self, VocabError> {
+        if raw_pairs.is_empty() {
+            return Err(VocabError::Empty);
         }
 
         let mut processed_tokens = Vec::new();
-        let mut processed_values = Vec::new();
-        let mut processed_vocab: FxHashMap<String, Vec<u32>> = FxHashMap::default();
+        let mut processed_values: Vec<Vec<u32>> = Vec::new();
+        let mut positions: TokenMap<usize> = TokenMap::default();
+        let from_sentencepiece = from_sentencepiece.unwrap_or_else(|| {
+            raw_pairs.iter().any(|(token, _)| token.contains(SPIECE_UNDERLINE))
+        });
 
-        for (mut token, token_id) in raw_vocab {
+        for (mut token, token_id) in raw_pairs {
             if let Some(ref special) = special_tokens {
                 if special.contains(&token) {
                     continue;
                 }
             }
 
-            if from_sentencepiece.unwrap_or(false) {
-                token = convert_tokens_to_string(vec![token]);
-            }
+            token = convert_tokens_to_string(vec![token], from_sentencepiece);
 
-            match preprocess_token(&token) {
-                Ok(processed_token) => {
-                    processed_vocab
-                        .entry(processed_token)
-                        .or_insert_with(Vec::new)
-                        .push(token_id);
-                },
-                Err(e) => {
-                    return Err(anyhow!("Failed to process token '{}': {}", token, e));
-                }
-            }
+            let position = *positions.entry(preprocess_token(&token)).or_insert_with_key(|token| {
+                processed_tokens.push(token.clone());
+                processed_values.push(Vec::new());
+                processed_tokens.len() - 1
+            });
+            processed_values[position].push(token_id);
         }
 
-        for (token, value) in processed_vocab {
-            processed_tokens.push(token);
-            processed_values.push(value);
+        for value in &mut processed_values {
+            // Map iteration order is arbitrary, sort so tokens sharing
+            // a processed string always group their ids the same way.
+            value.sort_unstable();
         }
 
         Ok(TokenVocabulary {
             tokens: processed_tokens,
             values: processed_values,
             eos_token_id,
+            byte_tokens: from_sentencepiece,
         })
     }
 
+    /// Tokens of `other` are appended, ids of a token in both vocabularies are combined.
+    ///
+    /// Tokens keep the order they are first seen in, like `from_raw_pairs`, so merging the
+    /// same vocabularies always gives the same order, and cache key, whatever the hasher.
     pub fn merge(self, other: TokenVocabulary) -> Self {
-        let mut combined: FxHashMap<String, Vec<u32>> = FxHashMap::default();
-
-        for (token, ids) in self.tokens.into_iter().zip(self.values) {
-            combined.insert(token, ids);
+        let mut tokens: Vec<String> = Vec::with_capacity(self.tokens.len() + other.tokens.len());
+        let mut values: Vec<Vec<u32>> = Vec::with_capacity(tokens.capacity());
+        let mut positions: TokenMap<usize> = TokenMap::default();
+
+        let entries = self.tokens.into_iter().zip(self.values).chain(other.tokens.into_iter().zip(other.values));
+        for (token, ids) in entries {
+            let position = *positions.entry(token).or_insert_with_key(|token| {
+                tokens.push(token.clone());
+                values.push(Vec::new());
+                tokens.len() - 1
+            });
+            values[position].extend(ids);
         }
 
-        for (token, ids) in other.tokens.into_iter().zip(other.values) {
-            combined
-                .entry(token)
-                .and_modify(|existing| existing.extend(ids.iter()))
-                .or_insert(ids);
-        }
-
-        let (tokens, values): (Vec<_>, Vec<_>) = combined.into_iter().unzip();
-
         TokenVocabulary {
             tokens,
             values,
             eos_token_id: self.eos_token_id,
+            byte_tokens: self.byte_tokens || other.byte_tokens,
         }
     }
 
@@ -152,6 +196,15 @@ impl TokenVocabulary {
         self.tokens.iter().zip(self.values.iter())
     }
 
+    /// Same as `iter`, but in token-string order, so tries and hashes built from it do not
+    /// depend on insertion order. Entries are borrowed, only the index order is allocated,
+    /// and sorted on every call, so it always reflects the current `tokens`.
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (&String, &Vec<u32>)> {
+        let mut order: Vec<u32> = (0..self.tokens.len() as u32).collect();
+        order.sort_unstable_by(|&a, &b| self.tokens[a as usize].cmp(&self.tokens[b as usize]));
+        order.into_iter().map(move |i| (&self.tokens[i as usize], &self.values[i as usize]))
+    }
+
     /// Returns an iterator over all values in order
     pub fn iter_values(&self) -> impl Iterator<Item = &Vec<u32>> {
         self.values.iter()
@@ -183,7 +236,7 @@ static REPLACEMENT_SEQ_RE: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"^▁�+\.$").unwrap()
 });
 
-fn byte_to_symbol(byte: u8) -> String {
+pub(crate) fn byte_to_symbol(byte: u8) -> String {
     if byte >= 0x80 {
         format!("\x00{:02X}", byte)
     } else {
@@ -191,9 +244,60 @@ fn byte_to_symbol(byte: u8) -> String {
     }
 }
 
-fn preprocess_token(token: &str) -> Result<String> {
+/// Parses the byte out of a llama style `<0xXX>` byte token.
+fn parse_byte_token(token: &str) -> Option<u8> {
+    token
+        .strip_prefix("<0x")
+        .and_then(|rest| rest.strip_suffix('>'))
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+}
+
+/// The byte a llama style `<0xXX>` token stands for, including the `" <0x20>"`
+/// form `preprocess_token` gives the space byte. `None` for any other token, and
+/// for every token unless `byte_tokens` is set, see `TokenVocabulary::byte_tokens`.
+pub(crate) fn byte_token_value(token: &str, byte_tokens: bool) -> Option<u8> {
+    if !byte_tokens {
+        return None;
+    }
+    let token = if token == " <0x20>" { "<0x20>" } else { token };
+    if !LLAMA_BYTE_TOKEN_RE.is_match(token) {
+        return None;
+    }
+    parse_byte_token(token)
+}
+
+/// The raw bytes a processed token stands for, undoing `byte_to_symbol` and, with
+/// `byte_tokens`, llama style `<0xXX>` tokens. Other characters give their UTF-8 bytes.
+pub(crate) fn token_bytes(token: &str, byte_tokens: bool) -> Vec<u8> {
+    if let Some(byte) = byte_token_value(token, byte_tokens) {
+        return vec![byte];
+    }
+
+    let mut bytes = Vec::with_capacity(token.len());
+    let mut rest = token;
+    while let Some(c) = rest.chars().next() {
+        let symbol_byte = rest
+            .get(1..3)
+            .filter(|_| c == '\x00')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(|&byte| byte >= 0x80);
+        match symbol_byte {
+            Some(byte) => {
+                bytes.push(byte);
+                rest = &rest[3..];
+            }
+            None => {
+                bytes.extend_from_slice(&rest.as_bytes()[..c.len_utf8()]);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    bytes
+}
+
+fn preprocess_token(token: &str) -> String {
     if token.is_empty() {
-        return Ok(token.to_string());
+        return token.to_string();
     }
 
     let processed_token = if token == "<0x20>" {
@@ -204,10 +308,7 @@ fn preprocess_token(token: &str) -> Result<String> {
 
     if processed_token.contains('\u{fffd}') && !REPLACEMENT_SEQ_RE.is_match(&processed_token) {
         if LLAMA_BYTE_TOKEN_RE.is_match(&processed_token) {
-            match u8::from_str_radix(&processed_token[3..5], 16) {
-                Ok(byte) => return Ok(byte_to_symbol(byte)),
-                Err(_) => return Err(anyhow!("Invalid byte in token")),
-            }
+            return parse_byte_token(&processed_token).map_or(processed_token, byte_to_symbol);
         } else {
             let mut bytes = Vec::new();
             for c in processed_token.chars() {
@@ -215,12 +316,202 @@ fn preprocess_token(token: &str) -> Result<String> {
                     Some(&byte) => bytes.push(byte),
                     None => {
                         // If character not found, return the original token
-                        return Ok(processed_token);
+                        return processed_token;
                     }
                 }
             }
-            return Ok(bytes.into_iter().map(byte_to_symbol).collect());
+            return bytes.into_iter().map(byte_to_symbol).collect();
+        }
+    }
+    processed_token
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_vocab(tokens: &[(&str, u32)]) -> FxHashMap<String, u32> {
+        tokens.iter().map(|&(token, id)| (token.to_string(), id)).collect()
+    }
+
+    #[test]
+    fn test_from_raw_vocab_detects_tokenizer_type() {
+        let gpt2 = TokenVocabulary::from_raw_vocab(raw_vocab(&[("Ġhello", 1), ("hi", 2)]), 0, None, None)
+            .unwrap();
+        assert!(gpt2.tokens.contains(&" hello".to_string()));
+        assert!(!gpt2.byte_tokens);
+
+        let llama = TokenVocabulary::from_raw_vocab(raw_vocab(&[("▁hello", 1), ("Ġ", 2)]), 0, None, None)
+            .unwrap();
+        assert!(llama.tokens.contains(&"hello".to_string()));
+        assert!(llama.tokens.contains(&"Ġ".to_string()));
+        assert!(llama.byte_tokens);
+    }
+
+    #[test]
+    fn test_from_raw_vocab_grouped_ids_are_sorted() {
+        // All three "hi" tokens decode to the same string with sentencepiece.
+        let tokens = [("▁hi", 7), ("hi", 3), ("▁▁hi", 5), ("a", 1)];
+        let mut reversed = tokens;
+        reversed.reverse();
+
+        let grouped = |tokens: &[(&str, u32)]| {
+            let vocab = TokenVocabulary::from_raw_vocab(raw_vocab(tokens), 0, None, Some(true)).unwrap();
+            let mut grouped: Vec<(String, Vec<u32>)> =
+                vocab.iter().map(|(token, ids)| (token.clone(), ids.clone())).collect();
+            grouped.sort();
+            grouped
+        };
+        let first = grouped(&tokens);
+        assert_eq!(first, grouped(&reversed));
+        assert_eq!(first, vec![("a".to_string(), vec![1]), ("hi".to_string(), vec![3, 5, 7])]);
+    }
+
+    #[test]
+    fn test_from_raw_pairs_matches_from_raw_vocab() {
+        let pairs = [("▁hi", 7), ("b", 2), ("hi", 3), ("<eos>", 0), ("a", 1)];
+        let special: FxHashSet<String> = ["<eos>".to_string()].into_iter().collect();
+        let owned_pairs: Vec<(String, u32)> =
+            pairs.iter().map(|&(token, id)| (token.to_string(), id)).collect();
+
+        let from_pairs =
+            TokenVocabulary::from_raw_pairs(owned_pairs, 0, Some(special.clone()), None).unwrap();
+        let from_map = TokenVocabulary::from_raw_vocab(raw_vocab(&pairs), 0, Some(special), None).unwrap();
+
+        // Same tokens and ids, but the pairs keep their order.
+        assert_eq!(from_pairs.tokens, ["hi", "b", "a"]);
+        assert_eq!(from_pairs.values, [vec![3, 7], vec![2], vec![1]]);
+        let as_map = |vocab: &TokenVocabulary| -> FxHashMap<String, Vec<u32>> {
+            vocab.iter().map(|(token, ids)| (token.clone(), ids.clone())).collect()
+        };
+        assert_eq!(as_map(&from_pairs), as_map(&from_map));
+        assert_eq!(from_pairs.eos_token_id, from_map.eos_token_id);
+    }
+
+    #[test]
+    fn test_from_raw_vocab_empty_error() {
+        let err = TokenVocabulary::from_raw_vocab(FxHashMap::default(), 0, None, None).unwrap_err();
+        assert_eq!(err, VocabError::Empty);
+    }
+
+    #[test]
+    fn test_malformed_byte_token_is_kept_as_text() {
+        // Only well-formed byte tokens are bytes, anything else is plain text.
+        let raw: FxHashMap<String, u32> = [("<0x41>", 1), ("<0xZZ>", 2), ("<0x>", 3)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), id))
+            .collect();
+        let vocab = TokenVocabulary::from_raw_vocab(raw, 0, None, Some(false)).unwrap();
+        for token in ["<0xZZ>", "<0x>"] {
+            assert!(vocab.tokens.contains(&token.to_string()), "{:?}", vocab.tokens);
+        }
+        assert_eq!(parse_byte_token("<0x41>"), Some(0x41));
+        assert_eq!(parse_byte_token("<0xZZ>"), None);
+    }
+
+    #[test]
+    fn test_merge_keeps_first_seen_order() {
+        let vocab = |pairs: &[(&str, u32)]| {
+            let mut vocab = TokenVocabulary::default();
+            for &(token, id) in pairs {
+                vocab.add_token(token.to_string(), vec![id]);
+            }
+            vocab
+        };
+        let merged = vocab(&[("c", 3), ("a", 1), ("b", 2)]).merge(vocab(&[("d", 4), ("a", 5), ("e", 6)]));
+
+        assert_eq!(merged.tokens, vec!["c", "a", "b", "d", "e"]);
+        assert_eq!(merged.values, vec![vec![3], vec![1, 5], vec![2], vec![4], vec![6]]);
+    }
+
+    #[test]
+    fn test_iter_sorted_borrows_entries_in_token_order() {
+        let mut vocab = TokenVocabulary::default();
+        for (token, id) in [("b", 2), ("ab", 4), ("c", 1), ("a", 3)] {
+            vocab.add_token(token.to_string(), vec![id]);
+        }
+
+        let sorted: Vec<_> = vocab.iter_sorted().collect();
+        let tokens: Vec<&str> = sorted.iter().map(|(token, _)| token.as_str()).collect();
+        assert_eq!(tokens, ["a", "ab", "b", "c"]);
+        for (token, ids) in &sorted {
+            let pos = vocab.tokens.iter().position(|t| t == *token).unwrap();
+            // Same allocations as the vocabulary's own entries, nothing was cloned.
+            assert!(std::ptr::eq(*token, &vocab.tokens[pos]));
+            assert!(std::ptr::eq(*ids, &vocab.values[pos]));
         }
+
+        vocab.add_token("aa".to_string(), vec![5]);
+        vocab.remove_token("c");
+        let tokens: Vec<&str> = vocab.iter_sorted().map(|(token, _)| token.as_str()).collect();
+        assert_eq!(tokens, ["a", "aa", "ab", "b"]);
+
+        // Writes to the public fields, and struct literals, are seen too.
+        vocab.tokens.push("0".to_string());
+        vocab.values.push(vec![6]);
+        let tokens: Vec<&str> = vocab.iter_sorted().map(|(token, _)| token.as_str()).collect();
+        assert_eq!(tokens, ["0", "a", "aa", "ab", "b"]);
+        let literal = TokenVocabulary {
+            tokens: vec!["y".to_string(), "x".to_string()],
+            values: vec![vec![1], vec![2]],
+            eos_token_id: 0,
+            byte_tokens: false,
+        };
+        assert_eq!(literal.iter_sorted().map(|(_, ids)| ids[0]).collect::<Vec<_>>(), [2, 1]);
+    }
+
+    #[test]
+    fn test_token_bytes_undoes_byte_symbols() {
+        for byte in 0..=u8::MAX {
+            assert_eq!(token_bytes(&byte_to_symbol(byte), false), vec![byte]);
+        }
+        assert_eq!(token_bytes("<0x0A>", true), vec![b'\n']);
+        assert_eq!(token_bytes(" <0x20>", true), vec![b' ']);
+        assert_eq!(token_bytes("é!", true), "é!".as_bytes());
+        let split = format!("a{}{}", byte_to_symbol(0xC3), byte_to_symbol(0xA9));
+        assert_eq!(token_bytes(&split, false), "aé".as_bytes());
+
+        // Only sentencepiece vocabularies spell bytes this way, and only the space
+        // byte keeps the space `preprocess_token` puts in front of it.
+        assert_eq!(token_bytes("<0x0A>", false), b"<0x0A>");
+        assert_eq!(token_bytes(" <0x41>", true), b" <0x41>");
+    }
+
+    // cargo test --release --features ahash bench_token_map_hashers -- --ignored --nocapture
+    #[cfg(feature = "ahash")]
+    #[test]
+    #[ignore]
+    fn bench_token_map_hashers() {
+        use std::collections::HashMap;
+        use std::hash::BuildHasher;
+        use std::time::Instant;
+
+        // 128k distinct BPE-like tokens, mostly short words with a leading space.
+        let mut seed = 42u64;
+        let mut tokens = FxHashSet::default();
+        while tokens.len() < 128_000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let len = 1 + (seed >> 33) as usize % 10;
+            let word: String = (0..len).map(|i| (b'a' + ((seed >> (i * 5)) % 26) as u8) as char).collect();
+            tokens.insert(if seed & 1 == 0 { format!(" {}", word) } else { word });
+        }
+        let tokens: Vec<String> = tokens.into_iter().collect();
+
+        fn run<S: BuildHasher + Default>(name: &str, tokens: &[String]) {
+            let start = Instant::now();
+            let mut map: HashMap<String, usize, S> = HashMap::default();
+            for (position, token) in tokens.iter().enumerate() {
+                map.insert(token.clone(), position);
+            }
+            let build = start.elapsed();
+
+            let start = Instant::now();
+            let found = (0..10).flat_map(|_| tokens).filter(|token| map.contains_key(*token)).count();
+            println!("{}: build {:?}, 10 lookups per token {:?}", name, build, start.elapsed());
+            assert_eq!(found, tokens.len() * 10);
+        }
+
+        run::<rustc_hash::FxBuildHasher>("FxHash", &tokens);
+        run::<ahash::RandomState>("aHash ", &tokens);
     }
-    Ok(processed_token)
 }
@@ -0,0 +1,33 @@
+import os
+import subprocess
+import sys
+
+
+def effective_num_threads_with_env(**env) -> str:
+    # The thread count is resolved once per process, so each case needs a fresh interpreter.
+    script = "from faster_outlines.lib import effective_num_threads; print(effective_num_threads())"
+    result = subprocess.run(
+        [sys.executable, "-c", script],
+        env={**os.environ, **env},
+        capture_output=True,
+        text=True,
+        check=True,
+    )
+    return result.stdout
+
+
+def test_reflects_compute_threads_env():
+    assert effective_num_threads_with_env(FASTER_OUTLINES_COMPUTE_THREADS="3") == "3\n"
+
+
+def test_defaults_to_one_thread():
+    assert effective_num_threads_with_env(FASTER_OUTLINES_COMPUTE_THREADS="not a number") == "1\n"
+
+
+def test_import_is_silent():
+    # The disabled cache message goes through the `log` crate, never to stdout.
+    quiet = effective_num_threads_with_env(
+        FASTER_OUTLINES_COMPUTE_THREADS="2",
+        FASTER_OUTLINES_DISABLE_CACHE="1",
+    )
+    assert quiet == "2\n"
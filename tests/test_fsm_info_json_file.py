@@ -0,0 +1,37 @@
+import pytest
+
+from faster_outlines.lib import FSMInfo
+
+
+def make_fsm() -> FSMInfo:
+    # "ab*"
+    return FSMInfo(
+        initial=0,
+        finals=[1],
+        transitions={(0, 0): 1, (1, 1): 1},
+        alphabet_symbol_mapping={"a": 0, "b": 1},
+        alphabet_anything_value=2,
+        pattern="ab*",
+    )
+
+
+def test_json_file_round_trip(tmp_path):
+    fsm = make_fsm()
+    path = tmp_path / "fsm.json"
+    path.write_bytes(fsm.__getstate__())
+
+    loaded = FSMInfo.from_json_file(str(path))
+    assert loaded.__getstate__() == fsm.__getstate__()
+    assert loaded.initial == fsm.initial
+    assert loaded.finals == fsm.finals
+    assert loaded.pattern == fsm.pattern
+
+
+def test_json_file_errors(tmp_path):
+    with pytest.raises(ValueError):
+        FSMInfo.from_json_file(str(tmp_path / "missing.json"))
+
+    path = tmp_path / "garbage.json"
+    path.write_text("not an fsm")
+    with pytest.raises(ValueError):
+        FSMInfo.from_json_file(str(path))
@@ -0,0 +1,49 @@
+import inspect
+
+import pytest
+
+from faster_outlines import RegexGuide
+from faster_outlines.fsm import TokenVocabulary
+from faster_outlines.lib import RegexGuide as RegexGuideAdapter
+
+OUTLINES_GUIDE_METHODS = ["get_next_instruction", "get_next_state", "is_final_state"]
+
+
+def make_guide() -> RegexGuide:
+    vocab = TokenVocabulary({"a": 1, "b": 2, "ab": 3, "</s>": 0}, 0, {"</s>"})
+    return RegexGuide("a+b?", vocab)
+
+
+def test_adapter_has_outlines_surface():
+    for name in OUTLINES_GUIDE_METHODS + ["initial_state"]:
+        assert hasattr(RegexGuideAdapter, name), name
+
+
+def test_guide_has_outlines_surface():
+    guide = make_guide()
+    for name in OUTLINES_GUIDE_METHODS:
+        assert callable(getattr(guide, name)), name
+    assert guide.initial_state == 0
+
+
+def test_guide_surface_matches_outlines():
+    outlines_guide = pytest.importorskip("outlines.fsm.guide")
+    guide = make_guide()
+    for name in OUTLINES_GUIDE_METHODS:
+        expected = inspect.signature(getattr(outlines_guide.RegexGuide, name))
+        # The outlines signature includes `self`.
+        assert len(expected.parameters) - 1 == len(inspect.signature(getattr(guide, name)).parameters), name
+
+
+def test_guide_walk():
+    guide = make_guide()
+    state = guide.initial_state
+    assert not guide.is_final_state(state)
+
+    state = guide.get_next_state(state, 1)
+    assert guide.is_final_state(state)
+    instruction = guide.get_next_instruction(state)
+    assert guide.eos_token_id in instruction.tokens
+
+    assert guide.get_next_state(state, guide.eos_token_id) == -1
+    assert guide.is_final_state(-1)
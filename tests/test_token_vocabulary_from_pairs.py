@@ -0,0 +1,50 @@
+import json
+
+import pytest
+
+from faster_outlines.fsm.regex import build_regex
+from faster_outlines.lib import FSMInfo, TokenVocabulary, create_fsm_index_end_to_end_rs
+
+
+PAIRS = [("▁hi", 7), ("b", 2), ("hi", 3), ("<eos>", 0), ("a", 1)]
+
+
+def token_ids(vocabulary: TokenVocabulary) -> dict:
+    state = json.loads(vocabulary.__getstate__())
+    return dict(zip(state["tokens"], state["values"]))
+
+
+def test_from_pairs_matches_dict_constructor():
+    from_pairs = TokenVocabulary.from_pairs(PAIRS, 0, {"<eos>"})
+    from_dict = TokenVocabulary(dict(PAIRS), 0, {"<eos>"})
+
+    assert len(from_pairs) == len(from_dict)
+    assert from_pairs.eos_token_id == from_dict.eos_token_id
+    assert token_ids(from_pairs) == token_ids(from_dict)
+
+
+def test_from_pairs_keeps_order():
+    state = json.loads(TokenVocabulary.from_pairs(PAIRS, 0, {"<eos>"}).__getstate__())
+    assert state["tokens"] == ["hi", "b", "a"]
+
+
+def test_from_pairs_empty():
+    with pytest.raises(ValueError):
+        TokenVocabulary.from_pairs([], 0)
+
+
+def test_dict_constructor_keeps_insertion_order():
+    state = json.loads(TokenVocabulary(dict(PAIRS), 0, {"<eos>"}).__getstate__())
+    assert state["tokens"] == ["hi", "b", "a"]
+
+
+def test_vocab_order_follows_dict_insertion_order():
+    # Inserted from "z" down to "a", so the dict order is the reverse of the ID order.
+    letters = [chr(c) for c in range(ord("z"), ord("a") - 1, -1)]
+    vocab_dict = {letter: ord(letter) - ord("a") + 1 for letter in letters}
+    vocab_dict["<eos>"] = 0
+    vocabulary = TokenVocabulary(vocab_dict, 0, {"<eos>"}, from_sentencepiece=False)
+
+    fsm_info = FSMInfo(**build_regex("[a-z]"))
+    index = create_fsm_index_end_to_end_rs(fsm_info, vocabulary, vocab_order=True)
+    assert index.get_allowed_token_ids(0) == [vocab_dict[letter] for letter in letters]